@@ -0,0 +1,141 @@
+//! Flash addresses, checked when they're constructed and in arithmetic, so a value beyond the
+//! flash's address space is an error rather than an address silently wrapped in the bytes sent
+//! to the chip.
+
+use crate::input;
+use anyhow::{Context, Result};
+use std::fmt;
+
+/// The size of the address space reachable with 3-byte addresses, beyond which the flash is
+/// sent 4-byte addresses.
+pub const THREE_BYTE_SPACE: usize = 1 << 24;
+
+/// The size of the address space, capped at 2 GiB so it fits a 32-bit `usize`, which is still
+/// far beyond any SPI NOR flash.
+pub const ADDRESS_SPACE: usize = 1 << 31;
+
+/// An address within the flash's address space.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Deserialize)]
+#[serde(try_from = "usize")]
+pub struct FlashAddress(u32);
+
+impl FlashAddress {
+    pub const ZERO: Self = Self(0);
+
+    pub fn new(address: usize) -> Result<Self> {
+        if address >= ADDRESS_SPACE {
+            anyhow::bail!(
+                "The address {address:#x} is beyond the flash's address space \
+                 (0x0..{ADDRESS_SPACE:#x})"
+            );
+        }
+
+        Ok(Self(address as u32))
+    }
+
+    /// Parse an address given in decimal or as `0x`-prefixed hex.
+    pub fn parse(text: &str) -> Result<Self> {
+        Self::new(input::parse_size(text)?)
+    }
+
+    pub fn get(self) -> usize {
+        self.0 as usize
+    }
+
+    /// The address `offset` bytes further on.
+    pub fn offset(self, offset: usize) -> Result<Self> {
+        self.get()
+            .checked_add(offset)
+            .context("Address arithmetic overflowed")
+            .and_then(Self::new)
+            .with_context(|| format!("{offset:#x} bytes past {self:#x}"))
+    }
+
+    /// The end of `length` bytes starting here, which may be the end of the address space
+    /// itself.
+    pub fn end(self, length: usize) -> Result<usize> {
+        match self.get().checked_add(length) {
+            Some(end) if end <= ADDRESS_SPACE => Ok(end),
+            _ => anyhow::bail!(
+                "{length:#x} bytes at {self:#x} extend beyond the flash's address space"
+            ),
+        }
+    }
+
+    /// The address aligned down to a multiple of `alignment`.
+    pub fn align_down(self, alignment: usize) -> Self {
+        Self(self.0 - self.0 % alignment as u32)
+    }
+
+    /// The address as sent to the flash in 4-byte mode, most significant byte first. The last
+    /// three are the 3-byte form.
+    pub fn bytes(self) -> [u8; 4] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl TryFrom<usize> for FlashAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(address: usize) -> Result<Self> {
+        Self::new(address)
+    }
+}
+
+impl fmt::Display for FlashAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+impl fmt::LowerHex for FlashAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_accepts_only_the_address_space() {
+        assert_eq!(FlashAddress::new(0).unwrap(), FlashAddress::ZERO);
+        assert_eq!(
+            FlashAddress::new(ADDRESS_SPACE - 1).unwrap().get(),
+            ADDRESS_SPACE - 1
+        );
+        assert!(FlashAddress::new(ADDRESS_SPACE).is_err());
+        assert!(FlashAddress::new(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn offset_stays_within_the_address_space() {
+        let last = FlashAddress::new(ADDRESS_SPACE - 1).unwrap();
+        assert_eq!(last.offset(0).unwrap(), last);
+        assert!(last.offset(1).is_err());
+        assert_eq!(FlashAddress::ZERO.offset(ADDRESS_SPACE - 1).unwrap(), last);
+        assert!(FlashAddress::ZERO.offset(ADDRESS_SPACE).is_err());
+        assert!(last.offset(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn end_may_be_the_end_of_the_address_space() {
+        let last = FlashAddress::new(ADDRESS_SPACE - 1).unwrap();
+        assert_eq!(last.end(1).unwrap(), ADDRESS_SPACE);
+        assert!(last.end(2).is_err());
+        assert_eq!(
+            FlashAddress::ZERO.end(ADDRESS_SPACE).unwrap(),
+            ADDRESS_SPACE
+        );
+        assert!(FlashAddress::ZERO.end(ADDRESS_SPACE + 1).is_err());
+        assert!(last.end(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn bytes_are_big_endian() {
+        let address = FlashAddress::new(THREE_BYTE_SPACE + 0x0203).unwrap();
+        assert_eq!(address.bytes(), [0x01, 0x00, 0x02, 0x03]);
+        assert_eq!(address.align_down(0x100).get(), THREE_BYTE_SPACE + 0x0200);
+    }
+}
@@ -0,0 +1,83 @@
+//! Backups of flash ranges taken before destructive operations.
+
+use crate::address::FlashAddress;
+use crate::flash::FlashProgrammer;
+use crate::plan::BLOCK_SIZE;
+use crate::status;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Extend a range outward to whole erase blocks, since erasing affects the entire block.
+pub fn erase_range(address: FlashAddress, length: usize) -> (FlashAddress, usize) {
+    let start = address.align_down(BLOCK_SIZE);
+    let end = (address.get() + length).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    (start, end - start.get())
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Save the contents of a flash range to a timestamped file in `directory`.
+///
+/// A metadata sidecar is written alongside the backup with the range, JEDEC ID, and SHA-256.
+/// Nothing is saved if the range is entirely blank, returning `None`.
+pub fn backup(
+    programmer: &mut FlashProgrammer,
+    directory: &Path,
+    address: FlashAddress,
+    length: usize,
+) -> Result<Option<PathBuf>> {
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Error creating {}", directory.display()))?;
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let path = directory.join(format!("backup-{timestamp}-{address:#08x}.bin"));
+    let mut file = std::io::BufWriter::new(
+        std::fs::File::create(&path)
+            .with_context(|| format!("Error creating {}", path.display()))?,
+    );
+
+    let jedec = programmer.info()?.jedec;
+    let mut hasher = Sha256::new();
+    let mut blank = true;
+
+    status!("Backing up {length} bytes at {address:#x}...");
+    programmer.stream(address, length, |chunk| {
+        blank &= chunk.iter().all(|b| *b == 0xFF);
+        hasher.update(chunk);
+        file.write_all(chunk)
+            .with_context(|| format!("Error writing {}", path.display()))
+    })?;
+    file.flush()?;
+    drop(file);
+
+    if blank {
+        std::fs::remove_file(&path)?;
+        status!("The range is blank, skipping the backup");
+        return Ok(None);
+    }
+
+    let metadata = format!(
+        r#"{{"offset":{},"length":{length},"jedec":"{}","sha256":"{}"}}"#,
+        address.get(),
+        hex(&jedec),
+        hex(&hasher.finalize())
+    );
+    let sidecar = path.with_extension("json");
+    std::fs::write(&sidecar, metadata + "\n")
+        .with_context(|| format!("Error writing {}", sidecar.display()))?;
+
+    status!("Saved backup to {}", path.display());
+    status!(
+        "Restore with: lattice-prog flash {} --offset {address}",
+        path.display()
+    );
+
+    Ok(Some(path))
+}
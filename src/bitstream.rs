@@ -0,0 +1,282 @@
+//! Parsing of iCE40 binary bitstreams.
+//!
+//! A bitstream may open with a comment, framed by `FF 00` and `00 FF` with each line
+//! terminated by a NUL, followed by the preamble and a series of commands.
+
+const PREAMBLE: [u8; 4] = [0x7E, 0xAA, 0x99, 0x7E];
+
+/// How far into an image the preamble is looked for, leaving room for a long comment.
+const PREAMBLE_SEARCH: usize = 4096;
+
+/// Whether `data` looks like a bitstream, rather than arbitrary data.
+pub fn is_bitstream(data: &[u8]) -> bool {
+    data[..data.len().min(PREAMBLE_SEARCH)]
+        .windows(PREAMBLE.len())
+        .any(|window| window == PREAMBLE)
+}
+
+/// Check that `data` opens as a bitstream does: any comment terminated, then the preamble, then
+/// commands up to the first configuration data.
+pub fn check(data: &[u8]) -> anyhow::Result<()> {
+    let head = &data[..data.len().min(PREAMBLE_SEARCH)];
+    if data.is_empty() {
+        anyhow::bail!("The image is empty, not a bitstream");
+    }
+    if head.starts_with(&[0xFF, 0x00]) && !head.windows(2).any(|window| window == [0x00, 0xFF]) {
+        anyhow::bail!(
+            "The image opens a bitstream comment (ff 00) that isn't closed (00 ff) within its \
+             first {PREAMBLE_SEARCH} bytes"
+        );
+    }
+    if !is_bitstream(data) {
+        let text = head
+            .iter()
+            .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+        anyhow::bail!(
+            "The image has no bitstream preamble (7e aa 99 7e) in its first {} bytes{}",
+            head.len(),
+            if text {
+                ", and reads as text rather than a binary bitstream"
+            } else {
+                ""
+            }
+        );
+    }
+    if header_length(data).is_none() {
+        anyhow::bail!(
+            "The bitstream's commands run out before any configuration data, so it's truncated \
+             or corrupt"
+        );
+    }
+
+    Ok(())
+}
+
+/// Walk the commands following the preamble, returning the CRAM bank width and height.
+///
+/// Each command byte carries the opcode in its upper nibble and the payload length in its
+/// lower nibble. Parsing stops at the first data write, since the configuration data itself
+/// isn't framed as commands.
+pub fn bank_dimensions(bitstream: &[u8]) -> Option<(u16, u16)> {
+    let start = bitstream
+        .windows(PREAMBLE.len())
+        .position(|window| window == PREAMBLE)?
+        + PREAMBLE.len();
+
+    let mut width = None;
+    let mut height = None;
+    let mut index = start;
+
+    while let Some(&command) = bitstream.get(index) {
+        let length = (command & 0x0F) as usize;
+        let payload = bitstream.get(index + 1..index + 1 + length)?;
+        index += 1 + length;
+
+        match command {
+            0x62 => width = Some(u16::from_be_bytes([payload[0], payload[1]]) + 1),
+            0x72 => height = Some(u16::from_be_bytes([payload[0], payload[1]])),
+            0x01 if matches!(payload[0], 0x01 | 0x03) => break,
+            _ => {}
+        }
+
+        if let (Some(width), Some(height)) = (width, height) {
+            return Some((width, height));
+        }
+    }
+
+    None
+}
+
+/// The length of everything up to and including the first CRAM data write command: the
+/// comment, preamble, and the commands that set up configuration.
+///
+/// The FPGA is at its most sensitive to gaps in the clock while it parses these.
+pub fn header_length(bitstream: &[u8]) -> Option<usize> {
+    let mut index = bitstream
+        .windows(PREAMBLE.len())
+        .position(|window| window == PREAMBLE)?
+        + PREAMBLE.len();
+
+    while let Some(&command) = bitstream.get(index) {
+        let length = (command & 0x0F) as usize;
+        let payload = bitstream.get(index + 1..index + 1 + length)?;
+        index += 1 + length;
+
+        if command == 0x01 && matches!(payload[0], 0x01 | 0x03) {
+            return Some(index);
+        }
+    }
+
+    None
+}
+
+/// Extract the comment at the start of a bitstream, with its lines joined by `; `.
+///
+/// Returns `None` when the bitstream has no comment or it isn't terminated within `bitstream`,
+/// which may be only the first part of an image.
+pub fn comment(bitstream: &[u8]) -> Option<String> {
+    let body = bitstream.strip_prefix(&[0xFF, 0x00])?;
+    let end = body.windows(2).position(|window| window == [0x00, 0xFF])?;
+
+    let lines: Vec<_> = body[..end]
+        .split(|b| *b == 0)
+        .map(String::from_utf8_lossy)
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+
+    (!lines.is_empty()).then(|| lines.join("; "))
+}
+
+/// The number of images a multiboot header can point to, with the power-on image first.
+const MULTIBOOT_ENTRIES: usize = 5;
+
+/// The number of images warm boots can select between.
+pub const WARMBOOT_IMAGES: usize = MULTIBOOT_ENTRIES - 1;
+
+/// The size of each multiboot header entry.
+const MULTIBOOT_ENTRY_SIZE: usize = 32;
+
+/// The size of a full multiboot header.
+pub const MULTIBOOT_HEADER_SIZE: usize = MULTIBOOT_ENTRIES * MULTIBOOT_ENTRY_SIZE;
+
+/// Build a multiboot header whose entries all point at the image at `address`, so it's
+/// configured both at power-on and on any warm boot.
+pub fn multiboot_header(address: usize) -> Vec<u8> {
+    warmboot_header(address, &[])
+}
+
+/// Build a multiboot header pointing at the image at `power_on` for power-on, and at the images
+/// at `warm` for warm boots selecting 0 to 3 with `SB_WARMBOOT`'s S1:S0.
+///
+/// Warm boots that select past the end of `warm` configure the power-on image.
+pub fn warmboot_header(power_on: usize, warm: &[usize]) -> Vec<u8> {
+    (0..MULTIBOOT_ENTRIES)
+        .flat_map(|index| {
+            let address = index
+                .checked_sub(1)
+                .and_then(|slot| warm.get(slot))
+                .copied()
+                .unwrap_or(power_on);
+            multiboot_entry(address)
+        })
+        .collect()
+}
+
+/// One entry of a multiboot header, booting the image at `address`.
+fn multiboot_entry(address: usize) -> Vec<u8> {
+    let [_, high, middle, low] = (address as u32).to_be_bytes();
+    let mut entry = PREAMBLE.to_vec();
+    entry.extend([
+        // Boot mode
+        0x92, 0x00, 0x00, //
+        // Boot address
+        0x44, 0x03, high, middle, low, //
+        // Bank offset
+        0x82, 0x00, 0x00, //
+        // Reboot
+        0x01, 0x08,
+    ]);
+    entry.resize(MULTIBOOT_ENTRY_SIZE, 0);
+
+    entry
+}
+
+/// The image addresses in a multiboot header at the start of `head`, with the power-on image
+/// first, or nothing if there's no header.
+pub fn boot_addresses(head: &[u8]) -> Vec<usize> {
+    (0..MULTIBOOT_ENTRIES)
+        .map_while(|index| {
+            let offset = index * MULTIBOOT_ENTRY_SIZE;
+            head.get(offset..offset + MULTIBOOT_ENTRY_SIZE)
+                .and_then(boot_address)
+        })
+        .collect()
+}
+
+/// Locate recognizable structures in a bitstream, as `(offset, description)` pairs in order.
+///
+/// A multiboot header is a run of short command sequences, one per entry, each opening with a
+/// preamble and naming its image's address with a `44 03` command.
+pub fn landmarks(bitstream: &[u8]) -> Vec<(usize, String)> {
+    let mut landmarks = Vec::new();
+
+    if let Some(comment) = comment(bitstream) {
+        landmarks.push((0, format!("comment: {comment}")));
+    }
+
+    let boot_addresses = boot_addresses(bitstream);
+    for (index, address) in boot_addresses.iter().enumerate() {
+        let description = match index {
+            0 => format!("multiboot entry 0 (power-on): image at {address:#x}"),
+            _ => format!("multiboot entry {index}: image at {address:#x}"),
+        };
+        landmarks.push((index * MULTIBOOT_ENTRY_SIZE, description));
+    }
+
+    if boot_addresses.is_empty() {
+        if let Some(offset) = bitstream
+            .windows(PREAMBLE.len())
+            .position(|window| window == PREAMBLE)
+        {
+            landmarks.push((offset, "preamble".into()));
+        }
+    }
+
+    landmarks
+}
+
+/// The image address in a multiboot header entry, if `entry` is one.
+fn boot_address(entry: &[u8]) -> Option<usize> {
+    let commands = entry.strip_prefix(&PREAMBLE)?;
+    let at = commands
+        .windows(2)
+        .position(|window| window == [0x44, 0x03])?;
+    let address = commands.get(at + 2..at + 5)?;
+
+    Some(u32::from_be_bytes([0, address[0], address[1], address[2]]) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_read_back_their_addresses() {
+        let header = multiboot_header(0x123456);
+        assert_eq!(header.len(), MULTIBOOT_HEADER_SIZE);
+        assert_eq!(boot_addresses(&header), [0x123456; MULTIBOOT_ENTRIES]);
+
+        let header = warmboot_header(0x20000, &[0, 0x40000]);
+        assert_eq!(
+            boot_addresses(&header),
+            [0x20000, 0, 0x40000, 0x20000, 0x20000]
+        );
+    }
+
+    #[test]
+    fn headers_end_at_the_first_entry_that_isnt_one() {
+        let mut header = multiboot_header(0x20000);
+        assert_eq!(
+            boot_addresses(&header[..2 * MULTIBOOT_ENTRY_SIZE + 8]).len(),
+            2
+        );
+
+        header[3 * MULTIBOOT_ENTRY_SIZE] = 0xFF;
+        assert_eq!(boot_addresses(&header).len(), 3);
+        assert!(boot_addresses(&[0xFF; MULTIBOOT_HEADER_SIZE]).is_empty());
+    }
+
+    #[test]
+    fn bitstreams_have_an_early_preamble() {
+        let mut data = vec![0xFF; 2 * PREAMBLE_SEARCH];
+        assert!(!is_bitstream(&data));
+
+        data[PREAMBLE_SEARCH..PREAMBLE_SEARCH + 4].copy_from_slice(&PREAMBLE);
+        assert!(!is_bitstream(&data));
+
+        data[PREAMBLE_SEARCH - 4..PREAMBLE_SEARCH].copy_from_slice(&PREAMBLE);
+        assert!(is_bitstream(&data));
+        assert!(is_bitstream(&multiboot_header(0)));
+        assert!(!is_bitstream(&PREAMBLE[..3]));
+    }
+}
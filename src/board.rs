@@ -0,0 +1,145 @@
+//! The Pi model the pi backend runs on, which decides how its GPIO is reached.
+//!
+//! The Pi 5 moved the GPIO and SPI0 behind the RP1 I/O controller, mapped through
+//! `/dev/gpiomem0` instead of `/dev/gpiomem`, and only brings out bank 0 (GPIO 0-27). rppal
+//! drives both controllers with the same pin modes, SPI0's alternate functions included, so the
+//! bit-banged flash pins and the hand-off to SPI0 behave alike; what changes is which device
+//! node has to exist and which GPIOs can be claimed.
+
+/// Where the device tree names the Pi model.
+const MODEL: &str = "/proc/device-tree/model";
+
+/// The controller behind the Pi's GPIO.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Controller {
+    /// The SoC's own GPIO, on every Pi before the 5.
+    Bcm,
+    /// The RP1, on the Pi 5, Pi 500, and Compute Module 5.
+    Rp1,
+}
+
+/// The Pi the process runs on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Board {
+    pub model: String,
+    pub controller: Controller,
+}
+
+impl Controller {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bcm => "bcm",
+            Self::Rp1 => "rp1",
+        }
+    }
+
+    /// The device node rppal maps the GPIO registers through.
+    pub fn gpiomem(self) -> &'static str {
+        match self {
+            Self::Bcm => "/dev/gpiomem",
+            Self::Rp1 => "/dev/gpiomem0",
+        }
+    }
+
+    /// The highest GPIO that can be claimed, where the controller limits it below rppal's own
+    /// checks.
+    pub fn last_gpio(self) -> Option<u8> {
+        match self {
+            Self::Bcm => None,
+            Self::Rp1 => Some(27),
+        }
+    }
+}
+
+impl Board {
+    /// Identify the board from its device tree model, or `None` off a Pi.
+    pub fn detect() -> Option<Self> {
+        let model = std::fs::read_to_string(MODEL).ok()?;
+        Self::from_model(model.trim_end_matches('\0').trim())
+    }
+
+    /// Identify the board from a device tree model such as `Raspberry Pi 5 Model B Rev 1.0`.
+    pub fn from_model(model: &str) -> Option<Self> {
+        let name = model.strip_prefix("Raspberry Pi ")?;
+        let rp1 = name.starts_with("5 ")
+            || name == "5"
+            || name.starts_with("500")
+            || name.starts_with("Compute Module 5");
+
+        Some(Self {
+            model: model.to_string(),
+            controller: if rp1 {
+                Controller::Rp1
+            } else {
+                Controller::Bcm
+            },
+        })
+    }
+
+    /// Whether the board breaks its GPIOs out on a carrier board instead of a 40-pin header.
+    pub fn compute_module(&self) -> bool {
+        self.model.contains("Compute Module")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(model: &str) -> Option<Controller> {
+        Board::from_model(model).map(|board| board.controller)
+    }
+
+    #[test]
+    fn identifies_the_rp1_boards() {
+        for model in [
+            "Raspberry Pi 5 Model B Rev 1.0",
+            "Raspberry Pi 5",
+            "Raspberry Pi 500 Rev 1.0",
+            "Raspberry Pi Compute Module 5 Rev 1.0",
+            "Raspberry Pi Compute Module 5 Lite Rev 1.0",
+        ] {
+            assert_eq!(controller(model), Some(Controller::Rp1), "{model}");
+        }
+    }
+
+    #[test]
+    fn identifies_the_bcm_boards() {
+        for model in [
+            "Raspberry Pi Model B Rev 2",
+            "Raspberry Pi 2 Model B Rev 1.1",
+            "Raspberry Pi 3 Model B Plus Rev 1.3",
+            "Raspberry Pi 4 Model B Rev 1.4",
+            "Raspberry Pi 400 Rev 1.0",
+            "Raspberry Pi Zero 2 W Rev 1.0",
+            "Raspberry Pi Compute Module 4 Rev 1.0",
+            "Raspberry Pi Compute Module 3 Plus Rev 1.0",
+        ] {
+            assert_eq!(controller(model), Some(Controller::Bcm), "{model}");
+        }
+    }
+
+    #[test]
+    fn other_boards_are_unknown() {
+        assert_eq!(Board::from_model("Xunlong Orange Pi Zero"), None);
+        assert_eq!(Board::from_model("Raspberry Pi"), None);
+        assert_eq!(Board::from_model(""), None);
+    }
+
+    #[test]
+    fn compute_modules_have_no_header() {
+        let board = |model| Board::from_model(model).unwrap();
+        assert!(board("Raspberry Pi Compute Module 4 Rev 1.0").compute_module());
+        assert!(board("Raspberry Pi Compute Module 5 Rev 1.0").compute_module());
+        assert!(!board("Raspberry Pi 5 Model B Rev 1.0").compute_module());
+    }
+
+    #[test]
+    fn controllers_name_their_devices_and_limits() {
+        assert_eq!(Controller::Bcm.gpiomem(), "/dev/gpiomem");
+        assert_eq!(Controller::Rp1.gpiomem(), "/dev/gpiomem0");
+        assert_eq!(Controller::Bcm.last_gpio(), None);
+        assert_eq!(Controller::Rp1.last_gpio(), Some(27));
+        assert_eq!(Controller::Rp1.name(), "rp1");
+    }
+}
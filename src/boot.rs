@@ -0,0 +1,129 @@
+//! Booting the FPGA from flash and checking that the design came up.
+//!
+//! Verifying the flash only proves the Pi's slow bit-banged reads see the right data. The
+//! design reads the flash itself at its own clock rate, so `--verify-after-boot` boots it and
+//! watches a heartbeat GPIO the design drives high once its own self-test has passed.
+
+use crate::pins::{ActivePin, Claims, PinConfig};
+use crate::status;
+use crate::timing::{self, Timing};
+use anyhow::{Context, Result};
+use rppal::gpio::Gpio;
+use std::time::{Duration, Instant};
+
+/// How to check the design after flashing.
+#[derive(clap::Args, Clone, Debug)]
+pub struct BootCheck {
+    /// After flashing, boot the FPGA from flash and wait for the design's heartbeat
+    ///
+    /// Needs `--cdone` and `--heartbeat-pin`.
+    #[arg(long, requires_all = ["cdone", "heartbeat_pin"])]
+    pub verify_after_boot: bool,
+
+    /// The GPIO the design drives high once its self-test has passed
+    #[arg(long)]
+    pub heartbeat_pin: Option<u8>,
+
+    /// How long to wait for CDONE after releasing reset, instead of the `cdone_timeout` timing
+    #[arg(long, value_parser = timing::parse_duration)]
+    pub boot_timeout: Option<Duration>,
+
+    /// How long to give the design, once configured, to raise its heartbeat
+    #[arg(long, default_value = "500ms", value_parser = timing::parse_duration)]
+    pub heartbeat_settle: Duration,
+}
+
+/// Wait for CDONE, read by `high`, to rise as `timing` describes, polling for up to `timeout`,
+/// and return how long it took, counting from the start of the wait.
+pub fn await_cdone(
+    mut high: impl FnMut() -> Result<bool>,
+    timing: &Timing,
+    timeout: Duration,
+) -> Result<Option<Duration>> {
+    let start = Instant::now();
+    spin_sleep::sleep(timing.cdone_delay);
+
+    let polling = Instant::now();
+    loop {
+        if high()? {
+            return Ok(Some(start.elapsed()));
+        }
+        if polling.elapsed() >= timeout {
+            return Ok(None);
+        }
+        spin_sleep::sleep(timing.cdone_poll);
+    }
+}
+
+/// Pulse CRESET_B and wait up to `timeout` for CDONE, returning the configuration time.
+pub fn configure(
+    pins: &PinConfig,
+    timing: &Timing,
+    cdone: u8,
+    timeout: Duration,
+) -> Result<Option<Duration>> {
+    let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+    let cdone = gpio
+        .get(cdone)
+        .with_context(|| format!("Failed to acquire CDONE pin {cdone}"))?
+        .into_input();
+    let mut fpga_reset = ActivePin::new(
+        gpio.get(pins.reset)
+            .with_context(|| "Failed to acquire FPGA reset pin")?,
+        pins.reset_active_low,
+        true,
+    );
+
+    spin_sleep::sleep(timing.reset_pulse);
+    fpga_reset.release();
+
+    await_cdone(|| Ok(cdone.is_high()), timing, timeout)
+}
+
+/// Boot the FPGA from flash and wait for the design's heartbeat, failing if either CDONE or
+/// the heartbeat never rises.
+pub fn verify(pins: &PinConfig, timing: &Timing, cdone: u8, check: &BootCheck) -> Result<()> {
+    let heartbeat = check
+        .heartbeat_pin
+        .context("--verify-after-boot needs --heartbeat-pin")?;
+    Claims::default()
+        .pin("FPGA reset", pins.reset)
+        .pin("CDONE", cdone)
+        .pin("heartbeat", heartbeat)
+        .check()?;
+
+    status!("Booting the FPGA from flash...");
+    let timeout = check.boot_timeout.unwrap_or(timing.cdone_timeout);
+    let config_time = configure(pins, timing, cdone, timeout)?.with_context(|| {
+        format!(
+            "CDONE (GPIO {cdone}) didn't rise after releasing reset ({}), so the FPGA didn't \
+             configure from flash",
+            timing.describe_cdone(timeout)
+        )
+    })?;
+
+    let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+    let pin = gpio
+        .get(heartbeat)
+        .with_context(|| format!("Failed to acquire heartbeat pin {heartbeat}"))?
+        .into_input();
+    let start = Instant::now();
+    while start.elapsed() < check.heartbeat_settle {
+        if pin.is_high() {
+            status!(
+                "Boot check passed: configured in {config_time:.2?}, heartbeat (GPIO {heartbeat}) \
+                 high after {:.2?}",
+                start.elapsed()
+            );
+            return Ok(());
+        }
+        spin_sleep::sleep(Duration::from_micros(100));
+    }
+
+    anyhow::bail!(
+        "Boot check failed: the FPGA configured in {config_time:.2?}, but the heartbeat (GPIO \
+         {heartbeat}) stayed low for {:?}; the design couldn't read its flash data or its \
+         self-test failed",
+        check.heartbeat_settle
+    )
+}
@@ -0,0 +1,238 @@
+//! Repeated reconfiguration from flash, for burn-in testing.
+//!
+//! Each cycle pulses CRESET_B and times how long the FPGA takes to raise CDONE as it configures
+//! itself from flash. Every few cycles, a handful of random flash pages can also be checked
+//! against the image to catch marginal retention.
+
+use crate::address::FlashAddress;
+use crate::flash::FlashProgrammer;
+use crate::pins::{Claims, PinConfig};
+use crate::plan::PAGE_SIZE;
+use crate::progress::Progress;
+use crate::sample::Random;
+use crate::timing::Timing;
+use crate::{boot, cancel, watchdog};
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The number of random pages read by each spot-verify.
+const SPOT_PAGES: usize = 16;
+
+/// Settings for a burn-in run.
+pub struct Options {
+    pub cycles: usize,
+    pub cdone: u8,
+    /// How long to wait for CDONE, instead of the `cdone_timeout` timing.
+    pub done_timeout: Option<Duration>,
+    /// The image expected in flash and its address, for spot-verifies.
+    pub image: Option<(Vec<u8>, FlashAddress)>,
+    pub verify_every: usize,
+    pub report: Option<PathBuf>,
+}
+
+/// The outcome of a single cycle.
+struct Cycle {
+    index: usize,
+    /// The time from releasing reset to CDONE, or `None` if CDONE never rose.
+    config_time: Option<Duration>,
+    /// The spot-verify result, if one ran this cycle.
+    verify: Option<Result<(), String>>,
+}
+
+impl Cycle {
+    fn failure(&self) -> Option<String> {
+        match (&self.config_time, &self.verify) {
+            (None, _) => Some("CDONE never rose".into()),
+            (_, Some(Err(e))) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    fn csv(&self) -> String {
+        format!(
+            "{},{},{},{}",
+            self.index,
+            self.config_time
+                .map(|t| t.as_micros().to_string())
+                .unwrap_or_default(),
+            match &self.verify {
+                None => "",
+                Some(Ok(_)) => "pass",
+                Some(Err(_)) => "fail",
+            },
+            self.failure().unwrap_or_default().replace(',', ";"),
+        )
+    }
+
+    fn json(&self) -> String {
+        let config = self
+            .config_time
+            .map(|t| t.as_micros().to_string())
+            .unwrap_or("null".into());
+        let verify = match &self.verify {
+            None => "null",
+            Some(Ok(_)) => "true",
+            Some(Err(_)) => "false",
+        };
+        let failure = self
+            .failure()
+            .map(|f| format!("{f:?}"))
+            .unwrap_or("null".into());
+
+        format!(
+            r#"{{"cycle":{},"config_us":{config},"verify":{verify},"failure":{failure}}}"#,
+            self.index
+        )
+    }
+}
+
+/// The statistics over all completed cycles.
+pub struct Summary {
+    requested: usize,
+    cycles: Vec<Cycle>,
+    interrupted: bool,
+}
+
+impl Summary {
+    pub fn failures(&self) -> usize {
+        self.cycles.iter().filter(|c| c.failure().is_some()).count()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.interrupted {
+            writeln!(
+                f,
+                "Interrupted after {} of {} cycles",
+                self.cycles.len(),
+                self.requested
+            )?;
+        } else {
+            writeln!(f, "Completed {} cycles", self.cycles.len())?;
+        }
+
+        let times: Vec<_> = self.cycles.iter().filter_map(|c| c.config_time).collect();
+        if let (Some(min), Some(max)) = (times.iter().min(), times.iter().max()) {
+            let mean = times.iter().sum::<Duration>() / times.len() as u32;
+            writeln!(
+                f,
+                "Configuration time: min {min:.2?}, max {max:.2?}, mean {mean:.2?}"
+            )?;
+        }
+
+        write!(f, "{} failures", self.failures())?;
+        for cycle in &self.cycles {
+            if let Some(failure) = cycle.failure() {
+                write!(f, "\n  cycle {}: {failure}", cycle.index)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the burn-in, stopping early after the current cycle on Ctrl-C.
+pub fn run(pins: &PinConfig, timing: &Timing, options: &Options) -> Result<Summary> {
+    Claims::flash(pins).pin("CDONE", options.cdone).check()?;
+
+    let mut report = match &options.report {
+        Some(path) => {
+            let mut file = std::fs::File::create(path)
+                .with_context(|| format!("Error creating {}", path.display()))?;
+            if !is_json(path) {
+                writeln!(file, "cycle,config_us,verify,failure")?;
+            }
+            Some((file, is_json(path)))
+        }
+        None => None,
+    };
+
+    let cancel = cancel::on_interrupt();
+    let mut random = Random::seeded();
+    let mut summary = Summary {
+        requested: options.cycles,
+        cycles: Vec::with_capacity(options.cycles),
+        interrupted: false,
+    };
+    let mut bar = Progress::count("burnin", options.cycles);
+    let timeout = options.done_timeout.unwrap_or(timing.cdone_timeout);
+
+    for index in 0..options.cycles {
+        if cancel.is_cancelled() {
+            summary.interrupted = true;
+            break;
+        }
+
+        watchdog::beat("burnin", index);
+        let config_time = boot::configure(pins, timing, options.cdone, timeout)?;
+
+        let verify = match &options.image {
+            Some((image, address)) if (index + 1) % options.verify_every == 0 => Some(
+                spot_verify(pins, timing, image, *address, &mut random)?.map_err(|e| e.to_string()),
+            ),
+            _ => None,
+        };
+
+        let cycle = Cycle {
+            index,
+            config_time,
+            verify,
+        };
+
+        if let Some((file, json)) = &mut report {
+            let line = if *json { cycle.json() } else { cycle.csv() };
+            writeln!(file, "{line}")?;
+            file.flush()?;
+        }
+
+        summary.cycles.push(cycle);
+        bar.inc(1);
+    }
+
+    bar.finish();
+    Ok(summary)
+}
+
+fn is_json(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|e| e == "json")
+}
+
+/// Compare random pages of the flash against the image, holding the FPGA in reset meanwhile.
+///
+/// The outer result is a hardware error, and the inner result a mismatch.
+fn spot_verify(
+    pins: &PinConfig,
+    timing: &Timing,
+    image: &[u8],
+    address: FlashAddress,
+    random: &mut Random,
+) -> Result<Result<()>> {
+    let pages = image.len().div_ceil(PAGE_SIZE);
+    let mut programmer = FlashProgrammer::new(pins, timing, None)?;
+
+    let result = (|| {
+        for _ in 0..SPOT_PAGES {
+            let page = random.below(pages);
+            let start = page * PAGE_SIZE;
+            let expected = &image[start..(start + PAGE_SIZE).min(image.len())];
+            let page = address.offset(start)?;
+            let actual = programmer.read_arbitrary(page, expected.len())?;
+
+            if actual != expected {
+                return Ok(Err(anyhow::anyhow!(
+                    "spot-verify mismatch in the page at {page:#x}"
+                )));
+            }
+        }
+
+        Ok(Ok(()))
+    })();
+
+    drop(programmer);
+    FlashProgrammer::reset(pins, true)?;
+    result
+}
@@ -0,0 +1,90 @@
+//! Cooperative cancellation of long-running hardware work.
+//!
+//! Operations check a [`CancellationToken`] between pages or chunks, so cancelling never cuts a
+//! transfer short. Once cancelled they finish whatever the flash is busy with and fail with
+//! [`Cancelled`]. Ctrl-C and SIGTERM cancel the token returned by [`on_interrupt`].
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// A shared flag that asks in-progress work to stop.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with [`Cancelled`] if the token has been cancelled, where `address` is the end of
+    /// the work completed so far.
+    pub fn check(&self, address: usize) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled { address })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The error returned by work stopped through a [`CancellationToken`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled {
+    /// The end of the last completed page or chunk.
+    pub address: usize,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cancelled after completing up to {:#x}", self.address)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+static INTERRUPT: OnceLock<CancellationToken> = OnceLock::new();
+static TERMINATED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(signal: libc::c_int) {
+    if signal == libc::SIGTERM {
+        TERMINATED.store(true, Ordering::SeqCst);
+    }
+    if let Some(token) = INTERRUPT.get() {
+        token.cancel();
+    }
+}
+
+/// The token cancelled by Ctrl-C or SIGTERM, installing the handlers on first use.
+///
+/// Until then, both signals keep their default behavior of ending the process, so only work
+/// that checks the token should ask for it.
+pub fn on_interrupt() -> CancellationToken {
+    INTERRUPT
+        .get_or_init(|| {
+            for signal in [libc::SIGINT, libc::SIGTERM] {
+                // SAFETY: the handler only stores to atomics
+                unsafe {
+                    libc::signal(
+                        signal,
+                        interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                    );
+                }
+            }
+            CancellationToken::new()
+        })
+        .clone()
+}
+
+/// Whether SIGTERM has been received, which bounds how long the work may take to stop.
+pub fn terminated() -> bool {
+    TERMINATED.load(Ordering::SeqCst)
+}
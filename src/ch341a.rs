@@ -0,0 +1,253 @@
+//! The `ch341a` backend, for reading and programming the flash from any PC with a CH341A USB
+//! programmer, typically clipped onto the flash to recover a board that no longer boots.
+//!
+//! The CH341A only reaches the flash: nothing drives CRESET_B, so the FPGA must be held in
+//! reset or left unpowered while it's attached, and SRAM configuration needs another backend.
+//! The programmer is found by its USB IDs, or picked with `--ch341a-device` when several are
+//! plugged in. Commands go through libusb, so the backend needs the `ch341a` feature.
+
+use crate::flash;
+use anyhow::{Context, Result};
+
+/// A CH341A's position on the USB bus, as listed by `lsusb`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbAddress {
+    pub bus: u8,
+    pub address: u8,
+}
+
+impl std::fmt::Display for UsbAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:03}:{:03}", self.bus, self.address)
+    }
+}
+
+/// Parse `<bus>:<address>`.
+pub fn parse_device(text: &str) -> Result<UsbAddress> {
+    let (bus, address) = text
+        .split_once(':')
+        .with_context(|| format!("Expected <bus>:<address>, got {text:?}"))?;
+    let number = |part: &str| {
+        part.trim()
+            .parse::<u8>()
+            .with_context(|| format!("{part:?} isn't a USB bus or address number"))
+    };
+
+    Ok(UsbAddress {
+        bus: number(bus)?,
+        address: number(address)?,
+    })
+}
+
+/// The programmer to open.
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    /// The programmer's position on the bus, or `None` when only one is plugged in.
+    pub device: Option<UsbAddress>,
+}
+
+#[cfg(not(feature = "ch341a"))]
+pub fn flash(_settings: &Settings) -> Result<Box<dyn flash::Port>> {
+    anyhow::bail!("This build doesn't include the ch341a backend; rebuild with --features ch341a")
+}
+
+/// Open the programmer, with the flash deselected.
+#[cfg(feature = "ch341a")]
+pub fn flash(settings: &Settings) -> Result<Box<dyn flash::Port>> {
+    Ok(Box::new(usb::Ch341a::open(settings)?))
+}
+
+#[cfg(feature = "ch341a")]
+mod usb {
+    use super::{Settings, UsbAddress};
+    use crate::flash;
+    use anyhow::{Context, Result};
+    use rusb::{Context as Usb, DeviceHandle, UsbContext};
+    use std::time::Duration;
+
+    const VENDOR: u16 = 0x1A86;
+    const PRODUCT: u16 = 0x5512;
+    const INTERFACE: u8 = 0;
+    const BULK_OUT: u8 = 0x02;
+    const BULK_IN: u8 = 0x82;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Every command is sent in packets of this many bytes, the first being the command.
+    const PACKET: usize = 32;
+    /// Packets sent before reading back their responses, within the chip's buffering.
+    const PACKETS_IN_FLIGHT: usize = 8;
+
+    const SPI_STREAM: u8 = 0xA8;
+    const I2C_STREAM: u8 = 0xAA;
+    const I2C_SET: u8 = 0x60;
+    const I2C_END: u8 = 0x00;
+    const UIO_STREAM: u8 = 0xAB;
+    const UIO_OUT: u8 = 0x80;
+    const UIO_DIR: u8 = 0x40;
+    const UIO_DELAY_US: u8 = 0xC0;
+    const UIO_END: u8 = 0x20;
+
+    /// D0-D5 as outputs, with MISO on D7 left an input.
+    const OUTPUTS: u8 = 0x3F;
+    /// CS (D0) high, SCK (D3) low, and the unused chip selects and MOSI high.
+    const IDLE: u8 = 0x37;
+    const SELECTED: u8 = IDLE & !0x01;
+    /// The I2C speed setting, which also selects the SPI clock, at about 1.5 MHz.
+    const SPEED: u8 = 0x01;
+
+    pub struct Ch341a {
+        handle: DeviceHandle<Usb>,
+        /// Bytes written since the last transfer, sent along with the next read.
+        pending: Vec<u8>,
+    }
+
+    fn find(settings: &Settings) -> Result<rusb::Device<Usb>> {
+        // An explicit context, since the global one panics where libusb can't start
+        let usb = Usb::new().context("Failed to start libusb")?;
+        let mut found = Vec::new();
+        for device in usb.devices().context("Failed to list USB devices")?.iter() {
+            let Ok(descriptor) = device.device_descriptor() else {
+                continue;
+            };
+            if descriptor.vendor_id() == VENDOR && descriptor.product_id() == PRODUCT {
+                found.push(device);
+            }
+        }
+
+        let address = |device: &rusb::Device<Usb>| UsbAddress {
+            bus: device.bus_number(),
+            address: device.address(),
+        };
+        let listed = || {
+            found
+                .iter()
+                .map(|device| address(device).to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        match settings.device {
+            Some(wanted) => match found.iter().position(|device| address(device) == wanted) {
+                Some(index) => Ok(found.swap_remove(index)),
+                None if found.is_empty() => anyhow::bail!("No CH341A at {wanted}; none found"),
+                None => anyhow::bail!("No CH341A at {wanted}; found {}", listed()),
+            },
+            None => match found.len() {
+                0 => anyhow::bail!("No CH341A found; check that it's plugged in and in SPI mode"),
+                1 => Ok(found.remove(0)),
+                _ => anyhow::bail!(
+                    "Found several CH341As ({}); pick one with --ch341a-device",
+                    listed()
+                ),
+            },
+        }
+    }
+
+    impl Ch341a {
+        pub fn open(settings: &Settings) -> Result<Self> {
+            let device = find(settings)?;
+            let mut handle = device
+                .open()
+                .context("Failed to open the CH341A; check the udev rules for its USB IDs")?;
+            // Not supported everywhere, in which case there's no kernel driver to detach
+            let _ = handle.set_auto_detach_kernel_driver(true);
+            handle
+                .claim_interface(INTERFACE)
+                .context("Failed to claim the CH341A's interface")?;
+
+            let mut programmer = Self {
+                handle,
+                pending: Vec::new(),
+            };
+            programmer.send(&[I2C_STREAM, I2C_SET | SPEED, I2C_END])?;
+            programmer.send(&[UIO_STREAM, UIO_OUT | IDLE, UIO_DIR | OUTPUTS, UIO_END])?;
+
+            Ok(programmer)
+        }
+
+        fn send(&mut self, data: &[u8]) -> Result<()> {
+            self.handle
+                .write_bulk(BULK_OUT, data, TIMEOUT)
+                .context("Failed to write to the CH341A")?;
+            Ok(())
+        }
+
+        /// Clock out the pending bytes followed by `buffer`, replacing `buffer` with the bytes
+        /// clocked in alongside it.
+        fn transfer(&mut self, buffer: &mut [u8]) -> Result<()> {
+            let mut data = std::mem::take(&mut self.pending);
+            let skip = data.len();
+            data.extend_from_slice(buffer);
+
+            let mut received = Vec::with_capacity(data.len());
+            for group in data.chunks((PACKET - 1) * PACKETS_IN_FLIGHT) {
+                let mut packets = Vec::with_capacity(PACKET * PACKETS_IN_FLIGHT);
+                for chunk in group.chunks(PACKET - 1) {
+                    packets.push(SPI_STREAM);
+                    // The CH341A shifts the least significant bit first
+                    packets.extend(chunk.iter().map(|byte| byte.reverse_bits()));
+                }
+                self.send(&packets)?;
+
+                let end = received.len() + group.len();
+                while received.len() < end {
+                    let mut response = [0; PACKET * PACKETS_IN_FLIGHT];
+                    let wanted = (end - received.len()).min(response.len());
+                    let read = self
+                        .handle
+                        .read_bulk(BULK_IN, &mut response[..wanted], TIMEOUT)
+                        .context("Failed to read from the CH341A")?;
+                    if read == 0 {
+                        anyhow::bail!("The CH341A stopped responding mid-transfer");
+                    }
+                    received.extend(response[..read].iter().map(|byte| byte.reverse_bits()));
+                }
+            }
+
+            buffer.copy_from_slice(&received[skip..]);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            if !self.pending.is_empty() {
+                self.transfer(&mut [])?;
+            }
+            Ok(())
+        }
+    }
+
+    impl flash::Port for Ch341a {
+        fn select(&mut self) -> Result<()> {
+            self.send(&[UIO_STREAM, UIO_OUT | SELECTED, UIO_DELAY_US | 0x3F, UIO_END])
+        }
+
+        fn deselect(&mut self) -> Result<()> {
+            self.flush()?;
+            self.send(&[UIO_STREAM, UIO_OUT | IDLE, UIO_END])
+        }
+
+        fn write(&mut self, byte: u8) -> Result<()> {
+            self.pending.push(byte);
+            Ok(())
+        }
+
+        fn read(&mut self) -> Result<u8> {
+            let mut byte = [0xFF];
+            self.transfer(&mut byte)?;
+            Ok(byte[0])
+        }
+
+        fn read_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+            buffer.fill(0xFF);
+            self.transfer(buffer)
+        }
+    }
+
+    impl Drop for Ch341a {
+        fn drop(&mut self) {
+            // Leave every pin floating, so the board can run with the clip still attached
+            let _ = self.send(&[UIO_STREAM, UIO_OUT | IDLE, UIO_DIR, UIO_END]);
+            let _ = self.handle.release_interface(INTERFACE);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Worst-case busy times of flash chip families.
+//!
+//! Each operation class has its own limit, since a page program finishes in milliseconds while a
+//! chip erase can take minutes. Waiting on the flash beyond the datasheet maximum for the
+//! operation in progress is an error rather than an indefinite hang.
+//!
+//! Chips also differ in which erase commands they implement, which are read from their SFDP
+//! tables.
+
+use anyhow::{Context, Result};
+use std::fmt;
+use std::time::Duration;
+
+/// A family of flash chips sharing datasheet timings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChipProfile {
+    /// Winbond W25Q series
+    W25q,
+    /// Macronix MX25 series
+    Mx25,
+    /// Conservative limits for unrecognized chips
+    Generic,
+}
+
+/// The class of operation the flash is busy with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Busy {
+    PageProgram,
+    BlockErase,
+    ChipErase,
+    /// Whatever may still be in progress before a read, which could be any of the above.
+    Pending,
+}
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Busy::PageProgram => "page program",
+            Busy::BlockErase => "block erase",
+            Busy::ChipErase => "chip erase",
+            Busy::Pending => "pending operation",
+        })
+    }
+}
+
+/// Maximum busy times for each operation class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub page_program: Duration,
+    pub block_erase: Duration,
+    pub chip_erase: Duration,
+}
+
+impl Limits {
+    pub fn get(&self, busy: Busy) -> Duration {
+        match busy {
+            Busy::PageProgram => self.page_program,
+            Busy::BlockErase => self.block_erase,
+            Busy::ChipErase | Busy::Pending => self.chip_erase,
+        }
+    }
+}
+
+/// Each profile with the JEDEC manufacturer ID it's detected from and its datasheet maximums.
+const PROFILES: [(ChipProfile, Option<u8>, Limits); 3] = [
+    (
+        ChipProfile::W25q,
+        Some(0xEF),
+        Limits {
+            page_program: Duration::from_millis(3),
+            block_erase: Duration::from_millis(2000),
+            chip_erase: Duration::from_secs(200),
+        },
+    ),
+    (
+        ChipProfile::Mx25,
+        Some(0xC2),
+        Limits {
+            page_program: Duration::from_millis(3),
+            block_erase: Duration::from_millis(2000),
+            chip_erase: Duration::from_secs(150),
+        },
+    ),
+    (
+        ChipProfile::Generic,
+        None,
+        Limits {
+            page_program: Duration::from_millis(5),
+            block_erase: Duration::from_millis(3000),
+            chip_erase: Duration::from_secs(400),
+        },
+    ),
+];
+
+impl ChipProfile {
+    /// Pick the profile for a JEDEC ID, falling back to [`ChipProfile::Generic`].
+    pub fn detect(jedec: [u8; 3]) -> Self {
+        PROFILES
+            .iter()
+            .find(|(_, manufacturer, _)| *manufacturer == Some(jedec[0]))
+            .map_or(ChipProfile::Generic, |(profile, _, _)| *profile)
+    }
+
+    pub fn limits(self) -> Limits {
+        PROFILES
+            .iter()
+            .find(|(profile, _, _)| *profile == self)
+            .map(|(_, _, limits)| *limits)
+            .expect("every profile has limits")
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChipProfile::W25q => "w25q",
+            ChipProfile::Mx25 => "mx25",
+            ChipProfile::Generic => "generic",
+        }
+    }
+}
+
+/// An erase command and the size of the aligned range it erases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Erase {
+    pub opcode: u8,
+    pub size: usize,
+}
+
+impl Erase {
+    pub const SECTOR: Self = Self {
+        opcode: 0x20,
+        size: 4096,
+    };
+    pub const HALF_BLOCK: Self = Self {
+        opcode: 0x52,
+        size: 32768,
+    };
+    pub const BLOCK: Self = Self {
+        opcode: 0xD8,
+        size: 65536,
+    };
+
+    /// The erases of nearly every SPI flash, assumed for chips without an SFDP table.
+    pub const STANDARD: [Self; 3] = [Self::SECTOR, Self::HALF_BLOCK, Self::BLOCK];
+
+    /// Parse one of the [`Erase::STANDARD`] opcodes, given in hex.
+    pub fn parse(text: &str) -> Result<Self> {
+        let hex = text.trim();
+        let hex = hex.strip_prefix("0x").unwrap_or(hex);
+        let opcode = u8::from_str_radix(hex, 16)
+            .with_context(|| format!("Invalid erase opcode {text:?}"))?;
+
+        match Self::STANDARD.into_iter().find(|e| e.opcode == opcode) {
+            Some(erase) => Ok(erase),
+            None => anyhow::bail!(
+                "Unknown erase opcode {opcode:#04x}, expected 0x20 (4 KiB), 0x52 (32 KiB), or \
+                 0xd8 (64 KiB)"
+            ),
+        }
+    }
+
+    /// The largest of `erases` that fits a whole number of times into an aligned `size` bytes.
+    pub fn largest_within(erases: &[Self], size: usize) -> Option<Self> {
+        erases
+            .iter()
+            .filter(|e| e.size.is_power_of_two() && e.size <= size)
+            .max_by_key(|e| e.size)
+            .copied()
+    }
+}
+
+impl fmt::Display for Erase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x} ({} KiB)", self.opcode, self.size / 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    #[test]
+    fn every_profile_has_one_row() {
+        for profile in ChipProfile::value_variants() {
+            let rows = PROFILES.iter().filter(|(p, _, _)| p == profile).count();
+            assert_eq!(rows, 1, "{profile:?}");
+            assert_eq!(
+                ChipProfile::from_str(profile.name(), false).as_ref(),
+                Ok(profile)
+            );
+        }
+    }
+
+    #[test]
+    fn limits_grow_with_the_operation() {
+        for (profile, _, limits) in PROFILES {
+            assert!(
+                limits.page_program < limits.block_erase && limits.block_erase < limits.chip_erase,
+                "{profile:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn generic_limits_are_the_most_conservative() {
+        let generic = ChipProfile::Generic.limits();
+        for (_, _, limits) in PROFILES {
+            for busy in [Busy::PageProgram, Busy::BlockErase, Busy::ChipErase] {
+                assert!(limits.get(busy) <= generic.get(busy), "{busy}");
+            }
+        }
+    }
+
+    #[test]
+    fn classes_pick_their_limit() {
+        let limits = ChipProfile::W25q.limits();
+        assert_eq!(limits.get(Busy::PageProgram), Duration::from_millis(3));
+        assert_eq!(limits.get(Busy::BlockErase), Duration::from_secs(2));
+        assert_eq!(limits.get(Busy::ChipErase), Duration::from_secs(200));
+        // Anything may be in progress before a read, so it gets the longest limit
+        assert_eq!(limits.get(Busy::Pending), limits.chip_erase);
+    }
+
+    #[test]
+    fn detects_by_manufacturer() {
+        assert_eq!(ChipProfile::detect([0xEF, 0x40, 0x18]), ChipProfile::W25q);
+        assert_eq!(ChipProfile::detect([0xC2, 0x20, 0x16]), ChipProfile::Mx25);
+        assert_eq!(
+            ChipProfile::detect([0x20, 0xBA, 0x18]),
+            ChipProfile::Generic
+        );
+        assert_eq!(ChipProfile::detect([0xFF; 3]), ChipProfile::Generic);
+    }
+}
@@ -0,0 +1,163 @@
+//! Named board profiles, so a team with several carrier boards selects one with `--profile`
+//! instead of repeating the pin and bus flags on every invocation.
+//!
+//! Profiles are read from `--config`, or `$XDG_CONFIG_HOME/lattice-prog/config.toml` (falling
+//! back to `~/.config`) when it exists. Every field is optional:
+//!
+//! ```toml
+//! [profile.carrier-v2]
+//! reset_gpio = 22
+//! fpga_cs_gpio = 13
+//! flash_cs_gpio = 5
+//! flash_sdi_gpio = 9
+//! flash_sck_gpio = 11
+//! flash_sdo_gpio = 10
+//! reset_active_high = true
+//! spi_bus = "spi0"
+//! flash_bus = "auto"
+//! baud = 8000000
+//! transfer = 4096
+//! flash_size = 0x800000
+//! device = "up5k"
+//! ```
+//!
+//! A profile only fills in flags left at their defaults, so a flag given on the command line
+//! always wins.
+
+use crate::device::Device;
+use crate::pins::{FlashBus, PinConfig, SpiBus};
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub reset_gpio: Option<u8>,
+    pub fpga_cs_gpio: Option<u8>,
+    pub flash_cs_gpio: Option<u8>,
+    pub flash_sdi_gpio: Option<u8>,
+    pub flash_sck_gpio: Option<u8>,
+    pub flash_sdo_gpio: Option<u8>,
+    #[serde(default)]
+    pub reset_active_high: bool,
+    #[serde(default)]
+    pub fpga_cs_active_high: bool,
+    #[serde(default)]
+    pub flash_cs_active_high: bool,
+    pub spi_bus: Option<SpiBus>,
+    /// How the flash is driven, over the SPI peripheral or bit-banged.
+    pub flash_bus: Option<FlashBus>,
+    /// The SRAM programmer's SPI baud rate.
+    pub baud: Option<u32>,
+    /// The SRAM programmer's SPI transfer size.
+    pub transfer: Option<usize>,
+    /// The capacity the flash must report, which also sizes a new simulated flash.
+    pub flash_size: Option<usize>,
+    /// The FPGA on the board, which bitstreams are checked against when neither `--device`
+    /// nor the layout gives one.
+    pub device: Option<Device>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default, rename = "profile")]
+    profiles: BTreeMap<String, Profile>,
+}
+
+/// The config file read when no `--config` is given.
+fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(base.join("lattice-prog").join("config.toml"))
+}
+
+/// Load the profile `name` from `path`, or from the default config file.
+pub fn load(path: Option<&Path>, name: &str) -> Result<(PathBuf, Profile)> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => default_path()
+            .filter(|path| path.exists())
+            .with_context(|| format!("--profile {name} needs --config or a config file"))?,
+    };
+
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Error reading config from {}", path.display()))?;
+    let mut config: Config =
+        toml::from_str(&text).with_context(|| format!("Invalid config in {}", path.display()))?;
+
+    match config.profiles.remove(name) {
+        Some(profile) => Ok((path, profile)),
+        None => {
+            let names: Vec<_> = config.profiles.keys().map(String::as_str).collect();
+            anyhow::bail!(
+                "No profile named {name:?} in {}; it has {}",
+                path.display(),
+                if names.is_empty() {
+                    "none".into()
+                } else {
+                    names.join(", ")
+                }
+            )
+        }
+    }
+}
+
+/// Whether `id` was left at its default in `matches`.
+pub fn defaulted(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::DefaultValue)
+}
+
+impl Profile {
+    /// Fill in the pin settings left at their defaults in `matches`.
+    pub fn apply_pins(&self, pins: &mut PinConfig, matches: &ArgMatches) {
+        for (id, value, field) in [
+            ("reset", self.reset_gpio, &mut pins.reset),
+            ("fpga_cs", self.fpga_cs_gpio, &mut pins.fpga_cs),
+            ("flash_cs", self.flash_cs_gpio, &mut pins.flash_cs),
+            ("flash_sdi", self.flash_sdi_gpio, &mut pins.flash_sdi),
+            ("flash_sck", self.flash_sck_gpio, &mut pins.flash_sck),
+            ("flash_sdo", self.flash_sdo_gpio, &mut pins.flash_sdo),
+        ] {
+            if let Some(value) = value.filter(|_| defaulted(matches, id)) {
+                *field = value;
+            }
+        }
+
+        for (id, active_high, field) in [
+            (
+                "reset_active_low",
+                self.reset_active_high,
+                &mut pins.reset_active_low,
+            ),
+            (
+                "fpga_cs_active_low",
+                self.fpga_cs_active_high,
+                &mut pins.fpga_cs_active_low,
+            ),
+            (
+                "flash_cs_active_low",
+                self.flash_cs_active_high,
+                &mut pins.flash_cs_active_low,
+            ),
+        ] {
+            if active_high && defaulted(matches, id) {
+                *field = false;
+            }
+        }
+
+        if let Some(bus) = self.spi_bus.filter(|_| defaulted(matches, "spi_bus")) {
+            pins.spi_bus = bus;
+        }
+        if let Some(bus) = self.flash_bus.filter(|_| defaulted(matches, "flash_bus")) {
+            pins.flash_bus = bus;
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Confirmation before destructive operations.
+
+use crate::address::FlashAddress;
+use crate::backup::{erase_range, hex};
+use crate::bitstream;
+use crate::flash::FlashProgrammer;
+use crate::plan::BLOCK_SIZE;
+use crate::status;
+use crate::watchdog;
+use anyhow::Result;
+use std::io::{IsTerminal, Write};
+
+/// The number of bytes read from the start of the range, enough to hold a bitstream comment.
+const HEAD_SIZE: usize = 4096;
+
+/// The number of bytes read from the start of each later block.
+const SAMPLE_SIZE: usize = 256;
+
+/// Ask the user to type `expected` to continue.
+///
+/// Fails without asking when stdin isn't a terminal, since nobody is there to answer.
+pub fn prompt(question: &str, expected: &str) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("stdin isn't a terminal, pass --yes to continue without confirmation");
+    }
+
+    watchdog::pause();
+    print!("{question} Type '{expected}' to continue: ");
+    std::io::stdout().flush()?;
+
+    let mut response = String::new();
+    std::io::stdin().read_line(&mut response)?;
+    if response.trim() != expected {
+        anyhow::bail!("Aborted");
+    }
+
+    Ok(())
+}
+
+/// Show `message` and wait for the user to press Enter.
+pub fn pause(message: &str) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("stdin isn't a terminal, so nobody can press Enter");
+    }
+
+    watchdog::pause();
+    print!("{message} ");
+    std::io::stdout().flush()?;
+
+    let mut response = String::new();
+    std::io::stdin().read_line(&mut response)?;
+
+    Ok(())
+}
+
+/// Confirm overwriting the range an image is about to be written to, if it holds other data.
+///
+/// Only the start of the range and the start of each later block are read, so this is a quick
+/// sample rather than a full comparison. Blank ranges and ranges whose sample matches the image
+/// go ahead without asking.
+pub fn overwrite(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    address: FlashAddress,
+    yes: bool,
+) -> Result<()> {
+    let head = programmer.read_arbitrary(address, data.len().min(HEAD_SIZE))?;
+    let mut blank = head.iter().all(|b| *b == 0xFF);
+    let mut differs = head[..] != data[..head.len()];
+
+    for offset in (BLOCK_SIZE..data.len()).step_by(BLOCK_SIZE) {
+        let length = (data.len() - offset).min(SAMPLE_SIZE);
+        let sample = programmer.read_arbitrary(address.offset(offset)?, length)?;
+        blank &= sample.iter().all(|b| *b == 0xFF);
+        differs |= sample[..] != data[offset..offset + length];
+    }
+
+    if blank || !differs {
+        return Ok(());
+    }
+
+    let (start, length) = erase_range(address, data.len());
+    status!("The flash already holds other data:");
+    if let Some(comment) = bitstream::comment(&head) {
+        status!("  existing bitstream: {comment}");
+    }
+    status!(
+        "  affected range:     {start:#08x}..{:#08x} ({length} bytes)",
+        start.get() + length
+    );
+    status!("  JEDEC ID:           {}", hex(&programmer.info()?.jedec));
+
+    if yes {
+        return Ok(());
+    }
+
+    prompt("It will be overwritten.", "yes")
+}
@@ -0,0 +1,322 @@
+//! A rollback-protection version counter kept in its own 4 KiB flash sector.
+//!
+//! The sector is divided into 8 byte slots, each holding a value and its bitwise complement,
+//! both little endian. Bumping the counter programs the next erased slot, so the sector is
+//! only erased once all 512 slots are used. The counter's value is the highest valid slot.
+//!
+//! A slot left half-written by a power loss fails its complement check and is skipped, so the
+//! counter keeps its previous value and the next bump goes to the following slot.
+
+use crate::address::FlashAddress;
+use crate::flash::FlashProgrammer;
+use crate::layout::Layout;
+use anyhow::{Context, Result};
+
+pub const SECTOR_SIZE: usize = 4096;
+const SLOT_SIZE: usize = 8;
+const SLOTS: usize = SECTOR_SIZE / SLOT_SIZE;
+
+/// Where the counter's sector is, as an address or a layout partition.
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct Location {
+    /// The address of the version counter's 4 KiB sector
+    #[arg(long, value_parser = FlashAddress::parse, conflicts_with = "counter_partition")]
+    pub counter_address: Option<FlashAddress>,
+
+    /// The layout partition whose first 4 KiB hold the version counter
+    #[arg(long)]
+    pub counter_partition: Option<String>,
+}
+
+impl Location {
+    /// Resolve the sector's address, or `None` when no counter was given.
+    pub fn resolve(&self, layout: Option<&Layout>) -> Result<Option<FlashAddress>> {
+        let address = match (&self.counter_partition, self.counter_address) {
+            (Some(name), _) => {
+                let layout = layout.context("--counter-partition requires a layout")?;
+                Some(FlashAddress::new(layout.partition(name)?.offset)?)
+            }
+            (None, address) => address,
+        };
+        if let Some(address) = address {
+            address.end(SECTOR_SIZE)?;
+        }
+
+        if let Some(address) = address.filter(|a| a.get() % SECTOR_SIZE != 0) {
+            anyhow::bail!("The counter address {address:#x} isn't aligned to a 4 KiB sector");
+        }
+
+        Ok(address)
+    }
+
+    /// Resolve the sector's address, failing when no counter was given.
+    pub fn require(&self, layout: Option<&Layout>) -> Result<FlashAddress> {
+        self.resolve(layout)?
+            .context("Pass --counter-address or --counter-partition to locate the counter")
+    }
+}
+
+/// The decoded state of a counter sector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Counter {
+    /// The highest valid value, or `None` for a sector never written.
+    pub value: Option<u32>,
+    /// The first erased slot after every used one, or `None` when the sector is full.
+    next: Option<usize>,
+    /// The number of half-written slots that were skipped.
+    pub corrupt: usize,
+}
+
+impl Counter {
+    pub fn parse(sector: &[u8]) -> Self {
+        let mut value = None;
+        let mut last_used = None;
+        let mut corrupt = 0;
+
+        for (index, slot) in sector.chunks_exact(SLOT_SIZE).take(SLOTS).enumerate() {
+            if slot.iter().all(|b| *b == 0xFF) {
+                continue;
+            }
+            last_used = Some(index);
+
+            let stored = u32::from_le_bytes(slot[..4].try_into().unwrap());
+            let check = u32::from_le_bytes(slot[4..].try_into().unwrap());
+            if stored == !check {
+                value = value.max(Some(stored));
+            } else {
+                corrupt += 1;
+            }
+        }
+
+        let next = match last_used {
+            Some(index) => (index + 1 < SLOTS).then_some(index + 1),
+            None => Some(0),
+        };
+
+        Self {
+            value,
+            next,
+            corrupt,
+        }
+    }
+
+    /// The value, treating a never-written counter as zero.
+    pub fn current(&self) -> u32 {
+        self.value.unwrap_or(0)
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+fn encode(value: u32) -> [u8; SLOT_SIZE] {
+    let mut slot = [0; SLOT_SIZE];
+    slot[..4].copy_from_slice(&value.to_le_bytes());
+    slot[4..].copy_from_slice(&(!value).to_le_bytes());
+    slot
+}
+
+pub fn read(programmer: &mut FlashProgrammer, address: FlashAddress) -> Result<Counter> {
+    let counter = Counter::parse(&programmer.read_arbitrary(address, SECTOR_SIZE)?);
+    if counter.corrupt > 0 {
+        crate::warning!(
+            "Skipped {} half-written slots in the version counter at {address:#x}",
+            counter.corrupt
+        );
+    }
+
+    Ok(counter)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Raise the counter at `address` to `value`, which must be above its current value.
+pub fn bump(programmer: &mut FlashProgrammer, address: FlashAddress, value: u32) -> Result<()> {
+    let counter = read(programmer, address)?;
+    if counter.value.is_some_and(|current| value <= current) {
+        anyhow::bail!(
+            "The version counter only increases, and is already at {}",
+            counter.current()
+        );
+    }
+
+    let slot = match counter.next {
+        Some(slot) => slot,
+        None => {
+            crate::status!("The version counter's sector is full, erasing it");
+            programmer.await_ready()?;
+            programmer.erase_sector(address)?;
+            0
+        }
+    };
+
+    programmer.await_ready()?;
+    programmer.write_page(&encode(value), address.offset(slot * SLOT_SIZE)?)?;
+    programmer.await_ready()?;
+
+    let written = read(programmer, address)?;
+    if written.value != Some(value) {
+        anyhow::bail!(
+            "The version counter reads {:?} after writing {value} to slot {slot}",
+            written.value
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "read-only"))]
+    use crate::mock::{MockFlash, Settings};
+    #[cfg(not(feature = "read-only"))]
+    use crate::timing::Timing;
+
+    const ADDRESS: usize = 0x3000;
+
+    fn slot(value: u32, check: u32) -> [u8; SLOT_SIZE] {
+        let mut slot = [0; SLOT_SIZE];
+        slot[..4].copy_from_slice(&value.to_le_bytes());
+        slot[4..].copy_from_slice(&check.to_le_bytes());
+        slot
+    }
+
+    /// A sector with `slots` written from its start.
+    fn sector(slots: &[[u8; SLOT_SIZE]]) -> Vec<u8> {
+        let mut sector = vec![0xFF; SECTOR_SIZE];
+        for (i, slot) in slots.iter().enumerate() {
+            sector[i * SLOT_SIZE..][..SLOT_SIZE].copy_from_slice(slot);
+        }
+        sector
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    /// A programmer over a simulated flash with `sector` written at [`ADDRESS`].
+    fn programmer(sector: &[u8]) -> FlashProgrammer {
+        let flash = MockFlash::open(&Settings {
+            image: None,
+            size: 65536,
+            ignore_writes: false,
+            sector_erase_only: false,
+        })
+        .unwrap();
+        let mut programmer =
+            FlashProgrammer::with_port(Box::new(flash), &Timing::default(), None).unwrap();
+        for (i, page) in sector.chunks(256).enumerate() {
+            programmer.await_ready().unwrap();
+            programmer
+                .write_page(page, address().offset(i * 256).unwrap())
+                .unwrap();
+        }
+        programmer.await_ready().unwrap();
+        programmer
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    fn address() -> FlashAddress {
+        FlashAddress::new(ADDRESS).unwrap()
+    }
+
+    #[test]
+    fn blank_sectors_start_at_the_first_slot() {
+        let counter = Counter::parse(&sector(&[]));
+        assert_eq!(
+            counter,
+            Counter {
+                value: None,
+                next: Some(0),
+                corrupt: 0
+            }
+        );
+        assert_eq!(counter.current(), 0);
+    }
+
+    #[test]
+    fn the_highest_valid_slot_is_the_value() {
+        let counter = Counter::parse(&sector(&[slot(3, !3), slot(7, !7), slot(5, !5)]));
+        assert_eq!(counter.value, Some(7));
+        assert_eq!(counter.next, Some(3));
+    }
+
+    #[test]
+    fn half_written_slots_are_skipped() {
+        // Power lost after the value, before the complement
+        let counter = Counter::parse(&sector(&[slot(3, !3), slot(9, 0xFFFF_FFFF)]));
+        assert_eq!(counter.value, Some(3));
+        assert_eq!(counter.next, Some(2));
+        assert_eq!(counter.corrupt, 1);
+
+        // Power lost partway through the value
+        let mut partial = slot(9, !9);
+        partial[2..].fill(0xFF);
+        let counter = Counter::parse(&sector(&[partial]));
+        assert_eq!(
+            (counter.value, counter.next, counter.corrupt),
+            (None, Some(1), 1)
+        );
+    }
+
+    #[test]
+    fn full_sectors_have_no_next_slot() {
+        let slots: Vec<_> = (0..SLOTS as u32).map(|v| slot(v, !v)).collect();
+        let counter = Counter::parse(&sector(&slots));
+        assert_eq!(counter.value, Some(SLOTS as u32 - 1));
+        assert_eq!(counter.next, None);
+    }
+
+    #[test]
+    fn locations_must_be_sector_aligned() {
+        let location = |address| Location {
+            counter_address: Some(FlashAddress::new(address).unwrap()),
+            counter_partition: None,
+        };
+        assert_eq!(
+            location(0x3000).resolve(None).unwrap(),
+            Some(FlashAddress::new(0x3000).unwrap())
+        );
+        assert!(location(0x3100).resolve(None).is_err());
+        assert!(Location::default().require(None).is_err());
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn bumps_fill_the_next_slot_without_erasing() {
+        let mut programmer = programmer(&sector(&[slot(1, !1)]));
+        bump(&mut programmer, address(), 2).unwrap();
+        bump(&mut programmer, address(), 10).unwrap();
+
+        let sector = programmer.read_arbitrary(address(), SECTOR_SIZE).unwrap();
+        assert_eq!(sector, self::sector(&[slot(1, !1), encode(2), encode(10)]));
+        assert_eq!(read(&mut programmer, address()).unwrap().value, Some(10));
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn bumps_only_increase() {
+        let mut programmer = programmer(&sector(&[slot(5, !5)]));
+        assert!(bump(&mut programmer, address(), 5).is_err());
+        assert!(bump(&mut programmer, address(), 4).is_err());
+        assert_eq!(read(&mut programmer, address()).unwrap().next, Some(1));
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn bumps_recover_from_a_half_written_slot() {
+        let mut programmer = programmer(&sector(&[slot(3, !3), slot(4, 0xFFFF_FFFF)]));
+        bump(&mut programmer, address(), 4).unwrap();
+
+        let counter = read(&mut programmer, address()).unwrap();
+        assert_eq!(counter.value, Some(4));
+        assert_eq!(counter.next, Some(3));
+        assert_eq!(counter.corrupt, 1);
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn full_sectors_are_erased_before_bumping() {
+        let slots: Vec<_> = (0..SLOTS as u32).map(|v| slot(v, !v)).collect();
+        let mut programmer = programmer(&sector(&slots));
+        bump(&mut programmer, address(), 1000).unwrap();
+
+        let sector = programmer.read_arbitrary(address(), SECTOR_SIZE).unwrap();
+        assert_eq!(sector, self::sector(&[encode(1000)]));
+    }
+}
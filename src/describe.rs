@@ -0,0 +1,636 @@
+//! A machine-readable description of this build's capabilities and the active configuration,
+//! for tooling deciding how to drive it.
+//!
+//! The document is a single JSON object whose `schema` field is bumped whenever a field is
+//! removed or changes meaning; fields may be added without a bump. The subcommands and flags are
+//! read from the parser's own definitions, so they always match what this build accepts.
+//! Nothing here touches the hardware.
+
+use crate::board::{Board, Controller};
+use crate::ch341a;
+use crate::ftdi;
+use crate::layout::Layout;
+use crate::linux;
+use crate::mock;
+use crate::pins::PinConfig;
+use crate::timing::Timing;
+use clap::{Arg, Command, ValueEnum};
+use std::fmt::Write;
+use std::path::Path;
+
+pub const SCHEMA: u32 = 1;
+
+/// The resolved configuration, after every flag and file has been applied.
+pub struct Active<'a> {
+    pub pins: &'a PinConfig,
+    pub timing: &'a Timing,
+    pub layout: Option<(&'a Path, &'a Layout)>,
+    pub mock: Option<&'a mock::Settings>,
+    pub linux: Option<&'a linux::Settings>,
+    pub ftdi: Option<&'a ftdi::Settings>,
+    pub ch341a: Option<&'a ch341a::Settings>,
+    pub wear_file: Option<&'a Path>,
+}
+
+/// Render the description of `command` and `active`.
+pub fn describe(command: &Command, active: &Active) -> String {
+    let mut features = Vec::new();
+    if cfg!(feature = "read-only") {
+        features.push("read-only");
+    }
+    if cfg!(feature = "systemd") {
+        features.push("systemd");
+    }
+    if cfg!(feature = "embedded-hal") {
+        features.push("embedded-hal");
+    }
+    if cfg!(feature = "linux") {
+        features.push("linux");
+    }
+    if cfg!(feature = "ftdi") {
+        features.push("ftdi");
+    }
+    if cfg!(feature = "ch341a") {
+        features.push("ch341a");
+    }
+
+    // The device nodes the Pi backend opens, which depend on the GPIO controller
+    let board = Board::detect();
+    let controller = board
+        .as_ref()
+        .map_or(Controller::Bcm, |board| board.controller);
+    let pi_devices = [controller.gpiomem(), "/dev/spidev0.0"];
+    let pi_available = pi_devices.iter().all(|path| Path::new(path).exists());
+    // The linux backend's devices are whichever were given, or the defaults
+    let default_linux = linux::Settings::new("/dev/gpiochip0".into(), "/dev/spidev0.0".into());
+    let linux = active.linux.unwrap_or(&default_linux);
+    let linux_devices = [&linux.gpiochip, &linux.spidev];
+    let linux_available = cfg!(feature = "linux") && linux_devices.iter().all(|path| path.exists());
+    let backends = format!(
+        r#"[{{"name":"pi","available":{pi_available},"requires":{},"model":{},"gpio_controller":{}}},{{"name":"mock","available":true,"requires":[]}},{{"name":"linux","available":{linux_available},"requires":{}}},{{"name":"ftdi","available":{},"requires":[]}},{{"name":"ch341a","available":{},"requires":[]}}]"#,
+        list(pi_devices.iter().map(|path| format!("{path:?}"))),
+        optional(board.as_ref().map(|board| format!("{:?}", board.model))),
+        optional(board.map(|board| format!("{:?}", board.controller.name()))),
+        list(
+            linux_devices
+                .iter()
+                .map(|path| format!("{:?}", path.display().to_string()))
+        ),
+        cfg!(feature = "ftdi"),
+        cfg!(feature = "ch341a"),
+    );
+
+    let subcommands = list(command.get_subcommands().map(subcommand));
+    format!(
+        r#"{{"schema":{SCHEMA},"version":{:?},"features":{},"backends":{backends},"global_args":{},"subcommands":{subcommands},"config":{}}}"#,
+        env!("CARGO_PKG_VERSION"),
+        list(features.iter().map(|feature| format!("{feature:?}"))),
+        arguments(command),
+        config(active),
+    )
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn optional(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "null".into())
+}
+
+fn subcommand(command: &Command) -> String {
+    format!(
+        r#"{{"name":{:?},"about":{},"args":{},"subcommands":{}}}"#,
+        command.get_name(),
+        optional(
+            command
+                .get_about()
+                .map(|about| format!("{:?}", about.to_string()))
+        ),
+        arguments(command),
+        list(command.get_subcommands().map(subcommand)),
+    )
+}
+
+fn arguments(command: &Command) -> String {
+    list(
+        command
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_id().as_str(), "help" | "version"))
+            .map(argument),
+    )
+}
+
+fn argument(arg: &Arg) -> String {
+    let possible = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| format!("{:?}", value.get_name()));
+    let defaults = arg
+        .get_default_values()
+        .iter()
+        .map(|value| format!("{:?}", value.to_string_lossy()));
+
+    format!(
+        r#"{{"id":{:?},"long":{},"short":{},"positional":{},"takes_value":{},"required":{},"possible_values":{},"default":{},"help":{}}}"#,
+        arg.get_id().as_str(),
+        optional(arg.get_long().map(|long| format!("{long:?}"))),
+        optional(arg.get_short().map(|short| format!("\"{short}\""))),
+        arg.is_positional(),
+        arg.get_action().takes_values(),
+        arg.is_required_set(),
+        list(possible),
+        list(defaults),
+        optional(arg.get_help().map(|help| format!("{:?}", help.to_string()))),
+    )
+}
+
+fn config(active: &Active) -> String {
+    let pins = active.pins;
+    let pins = format!(
+        r#"{{"reset":{},"fpga_cs":{},"flash_cs":{},"flash_sdi":{},"flash_sck":{},"flash_sdo":{},"reset_active_low":{},"fpga_cs_active_low":{},"flash_cs_active_low":{},"spi_bus":{:?},"flash_bus":{:?}}}"#,
+        pins.reset,
+        pins.fpga_cs,
+        pins.flash_cs,
+        pins.flash_sdi,
+        pins.flash_sck,
+        pins.flash_sdo,
+        pins.reset_active_low,
+        pins.fpga_cs_active_low,
+        pins.flash_cs_active_low,
+        pins.spi_bus
+            .to_possible_value()
+            .map(|bus| bus.get_name().to_string())
+            .unwrap_or_default(),
+        pins.flash_bus
+            .to_possible_value()
+            .map(|bus| bus.get_name().to_string())
+            .unwrap_or_default()
+    );
+
+    let mut timing = String::from("{");
+    for (i, (key, value)) in active.timing.values().into_iter().enumerate() {
+        if i > 0 {
+            timing.push(',');
+        }
+        let _ = write!(timing, "{key:?}:{}", value.as_nanos());
+    }
+    timing.push('}');
+
+    let layout = active.layout.map(|(path, layout)| {
+        format!(
+            r#"{{"path":{:?},"device":{},"partitions":{}}}"#,
+            path.display().to_string(),
+            optional(
+                layout
+                    .device
+                    .and_then(|device| device.to_possible_value())
+                    .map(|device| format!("{:?}", device.get_name()))
+            ),
+            list(layout.partitions.iter().map(|p| format!(
+                r#"{{"name":{:?},"offset":{},"size":{},"readonly":{}}}"#,
+                p.name, p.offset, p.size, p.readonly
+            ))),
+        )
+    });
+
+    let mock = active.mock.map(|settings| {
+        format!(
+            r#"{{"image":{},"size":{},"ignore_writes":{},"sector_erase_only":{}}}"#,
+            optional(
+                settings
+                    .image
+                    .as_ref()
+                    .map(|path| format!("{:?}", path.display().to_string()))
+            ),
+            settings.size,
+            settings.ignore_writes,
+            settings.sector_erase_only
+        )
+    });
+
+    format!(
+        r#"{{"backend":{:?},"pins":{pins},"timing_ns":{timing},"chip_profile":{},"erase_opcodes":{},"slow_block_factor":{},"flash_clock_hz":{},"fast_read_dummy_clocks":{},"layout":{},"mock":{},"linux":{},"ftdi":{},"ch341a":{},"wear_file":{}}}"#,
+        if active.mock.is_some() {
+            "mock"
+        } else if active.linux.is_some() {
+            "linux"
+        } else if active.ftdi.is_some() {
+            "ftdi"
+        } else if active.ch341a.is_some() {
+            "ch341a"
+        } else {
+            "pi"
+        },
+        optional(
+            active
+                .timing
+                .chip_profile
+                .map(|profile| format!("{:?}", profile.name()))
+        ),
+        optional(
+            active
+                .timing
+                .erases
+                .as_ref()
+                .map(|erases| list(erases.iter().map(|erase| erase.opcode.to_string())))
+        ),
+        active.timing.slow_factor,
+        optional(active.timing.flash_clock.map(|clock| clock.to_string())),
+        optional(active.timing.fast_read.map(|clocks| clocks.to_string())),
+        optional(layout),
+        optional(mock),
+        optional(active.linux.map(|settings| format!(
+            r#"{{"gpiochip":{:?},"spidev":{:?}}}"#,
+            settings.gpiochip.display().to_string(),
+            settings.spidev.display().to_string()
+        ))),
+        optional(active.ftdi.map(|settings| format!(
+            r#"{{"serial":{},"cs":{},"reset":{},"cdone":{}}}"#,
+            optional(settings.serial.as_ref().map(|serial| format!("{serial:?}"))),
+            settings.cs,
+            settings.reset,
+            settings.cdone
+        ))),
+        optional(active.ch341a.map(|settings| format!(
+            r#"{{"device":{}}}"#,
+            optional(settings.device.map(|device| format!("\"{device}\"")))
+        ))),
+        optional(
+            active
+                .wear_file
+                .map(|path| format!("{:?}", path.display().to_string()))
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Partition;
+    use clap::CommandFactory;
+    use std::path::PathBuf;
+
+    /// A parsed JSON value, strict enough that anything tooling would reject fails to parse.
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Null,
+        Bool(bool),
+        Number(f64),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    impl Json {
+        fn parse(text: &str) -> Result<Self, String> {
+            let mut parser = Parser {
+                text: text.as_bytes(),
+                index: 0,
+            };
+            let value = parser.value()?;
+            parser.whitespace();
+            if parser.index != text.len() {
+                return Err(format!("Trailing characters at {}", parser.index));
+            }
+            Ok(value)
+        }
+
+        fn get(&self, key: &str) -> &Json {
+            match self {
+                Json::Object(fields) => {
+                    let mut values = fields.iter().filter(|(k, _)| k == key);
+                    let value = values.next().map(|(_, v)| v);
+                    assert!(values.next().is_none(), "{key} is repeated");
+                    value.unwrap_or_else(|| panic!("no {key} in {self:?}"))
+                }
+                _ => panic!("{self:?} isn't an object"),
+            }
+        }
+
+        fn items(&self) -> &[Json] {
+            match self {
+                Json::Array(items) => items,
+                _ => panic!("{self:?} isn't an array"),
+            }
+        }
+
+        fn str(&self) -> &str {
+            match self {
+                Json::String(text) => text,
+                _ => panic!("{self:?} isn't a string"),
+            }
+        }
+
+        /// The item of an array of objects whose `field` is `value`.
+        fn find(&self, field: &str, value: &str) -> &Json {
+            self.items()
+                .iter()
+                .find(|item| item.get(field).str() == value)
+                .unwrap_or_else(|| panic!("no {field} {value}"))
+        }
+    }
+
+    struct Parser<'a> {
+        text: &'a [u8],
+        index: usize,
+    }
+
+    impl Parser<'_> {
+        fn whitespace(&mut self) {
+            while self
+                .text
+                .get(self.index)
+                .is_some_and(|b| b" \t\r\n".contains(b))
+            {
+                self.index += 1;
+            }
+        }
+
+        fn expect(&mut self, token: &str) -> Result<(), String> {
+            if self.text[self.index..].starts_with(token.as_bytes()) {
+                self.index += token.len();
+                Ok(())
+            } else {
+                Err(format!("Expected {token:?} at {}", self.index))
+            }
+        }
+
+        fn value(&mut self) -> Result<Json, String> {
+            self.whitespace();
+            match self.text.get(self.index) {
+                Some(b'n') => self.expect("null").map(|_| Json::Null),
+                Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+                Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+                Some(b'"') => self.string().map(Json::String),
+                Some(b'[') => {
+                    self.index += 1;
+                    let mut items = Vec::new();
+                    self.whitespace();
+                    if self.expect("]").is_ok() {
+                        return Ok(Json::Array(items));
+                    }
+                    loop {
+                        items.push(self.value()?);
+                        self.whitespace();
+                        if self.expect("]").is_ok() {
+                            return Ok(Json::Array(items));
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                Some(b'{') => {
+                    self.index += 1;
+                    let mut fields = Vec::new();
+                    self.whitespace();
+                    if self.expect("}").is_ok() {
+                        return Ok(Json::Object(fields));
+                    }
+                    loop {
+                        self.whitespace();
+                        let key = self.string()?;
+                        self.whitespace();
+                        self.expect(":")?;
+                        fields.push((key, self.value()?));
+                        self.whitespace();
+                        if self.expect("}").is_ok() {
+                            return Ok(Json::Object(fields));
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                _ => {
+                    let start = self.index;
+                    while self
+                        .text
+                        .get(self.index)
+                        .is_some_and(|b| b"-+.eE0123456789".contains(b))
+                    {
+                        self.index += 1;
+                    }
+                    let number = std::str::from_utf8(&self.text[start..self.index]).unwrap();
+                    // A digit first, and no leading zeroes
+                    let digits = number.strip_prefix('-').unwrap_or(number).as_bytes();
+                    let valid = digits.first().is_some_and(u8::is_ascii_digit)
+                        && !(digits[0] == b'0' && digits.get(1).is_some_and(u8::is_ascii_digit));
+                    number
+                        .parse()
+                        .ok()
+                        .filter(|_| valid)
+                        .map(Json::Number)
+                        .ok_or_else(|| format!("Invalid value at {start}"))
+                }
+            }
+        }
+
+        fn string(&mut self) -> Result<String, String> {
+            self.expect("\"")?;
+            let mut bytes = Vec::new();
+            loop {
+                let byte = *self.text.get(self.index).ok_or("Unterminated string")?;
+                self.index += 1;
+                match byte {
+                    b'"' => return String::from_utf8(bytes).map_err(|e| e.to_string()),
+                    b'\\' => {
+                        let escape = *self.text.get(self.index).ok_or("Unterminated escape")?;
+                        self.index += 1;
+                        let unescaped = match escape {
+                            b'"' => '"',
+                            b'\\' => '\\',
+                            b'/' => '/',
+                            b'b' => '\u{8}',
+                            b'f' => '\u{c}',
+                            b'n' => '\n',
+                            b'r' => '\r',
+                            b't' => '\t',
+                            b'u' => {
+                                let hex = self
+                                    .text
+                                    .get(self.index..self.index + 4)
+                                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                                    .ok_or(format!("Invalid \\u escape at {}", self.index))?;
+                                self.index += 4;
+                                char::from_u32(hex).ok_or("Unpaired surrogate")?
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "Invalid escape \\{} at {}",
+                                    escape as char, self.index
+                                ))
+                            }
+                        };
+                        bytes.extend(unescaped.to_string().bytes());
+                    }
+                    0x00..=0x1F => return Err(format!("Control character at {}", self.index)),
+                    _ => bytes.push(byte),
+                }
+            }
+        }
+    }
+
+    fn document(active: &Active) -> Json {
+        let text = describe(&crate::Cli::command(), active);
+        Json::parse(&text).unwrap_or_else(|e| panic!("{e}: {text}"))
+    }
+
+    fn defaults<'a>(pins: &'a PinConfig, timing: &'a Timing) -> Active<'a> {
+        Active {
+            pins,
+            timing,
+            layout: None,
+            mock: None,
+            linux: None,
+            ftdi: None,
+            ch341a: None,
+            wear_file: None,
+        }
+    }
+
+    #[test]
+    fn parser_rejects_what_tooling_would() {
+        for text in [
+            r#"{"a":1,}"#,
+            r#"{"a":"\u{1b}"}"#,
+            r#"{"a":'b'}"#,
+            r#"{"a":01}"#,
+            "[1 2]",
+            "\"a\nb\"",
+        ] {
+            assert!(Json::parse(text).is_err(), "{text}");
+        }
+        assert_eq!(
+            Json::parse(r#" {"a" : [null, true, -1.5e3, "é\"\\"]} "#).unwrap(),
+            Json::Object(vec![(
+                "a".into(),
+                Json::Array(vec![
+                    Json::Null,
+                    Json::Bool(true),
+                    Json::Number(-1500.0),
+                    Json::String("\u{e9}\"\\".into())
+                ])
+            )])
+        );
+    }
+
+    #[test]
+    fn document_is_versioned_json() {
+        let (pins, timing) = (PinConfig::default(), Timing::default());
+        let document = document(&defaults(&pins, &timing));
+        assert_eq!(document.get("schema"), &Json::Number(SCHEMA.into()));
+        assert_eq!(document.get("version").str(), env!("CARGO_PKG_VERSION"));
+
+        let features: Vec<_> = document
+            .get("features")
+            .items()
+            .iter()
+            .map(Json::str)
+            .collect();
+        assert_eq!(features.contains(&"read-only"), cfg!(feature = "read-only"));
+        assert_eq!(features.contains(&"ftdi"), cfg!(feature = "ftdi"));
+
+        let backends = document.get("backends");
+        for name in ["pi", "mock", "linux", "ftdi", "ch341a"] {
+            backends.find("name", name);
+        }
+        assert_eq!(
+            backends.find("name", "mock").get("available"),
+            &Json::Bool(true)
+        );
+    }
+
+    #[test]
+    fn lists_every_subcommand_and_flag() {
+        let (pins, timing) = (PinConfig::default(), Timing::default());
+        let document = document(&defaults(&pins, &timing));
+        let subcommands = document.get("subcommands");
+        let command = crate::Cli::command();
+        assert_eq!(subcommands.items().len(), command.get_subcommands().count());
+
+        let dump = subcommands.find("name", "dump");
+        let address = dump.get("args").find("id", "address");
+        assert_eq!(address.get("long").str(), "address");
+        assert_eq!(address.get("short").str(), "a");
+        assert_eq!(address.get("takes_value"), &Json::Bool(true));
+        #[cfg(not(feature = "read-only"))]
+        subcommands.find("name", "flash");
+        #[cfg(feature = "read-only")]
+        assert!(subcommands
+            .items()
+            .iter()
+            .all(|command| command.get("name").str() != "flash"));
+
+        let backend = document.get("global_args").find("id", "backend");
+        let possible: Vec<_> = backend
+            .get("possible_values")
+            .items()
+            .iter()
+            .map(Json::str)
+            .collect();
+        assert!(possible.contains(&"mock"), "{possible:?}");
+        assert!(
+            document
+                .get("global_args")
+                .find("id", "describe")
+                .get("long")
+                .str()
+                == "describe"
+        );
+    }
+
+    #[test]
+    fn reports_the_resolved_configuration() {
+        let pins = PinConfig {
+            reset: 5,
+            ..PinConfig::default()
+        };
+        let timing = Timing {
+            erases: Some(vec![crate::chip::Erase::SECTOR]),
+            ..Timing::default()
+        };
+        let layout = Layout {
+            device: Some(crate::device::Device::Up5k),
+            partitions: vec![Partition {
+                name: "boot\"loader".into(),
+                offset: 0,
+                size: 0x20000,
+                readonly: true,
+            }],
+        };
+        let mock = mock::Settings {
+            image: Some(PathBuf::from("/tmp/a \\ b.bin")),
+            size: 1 << 22,
+            ignore_writes: true,
+            sector_erase_only: false,
+        };
+        let active = Active {
+            layout: Some((Path::new("layout.toml"), &layout)),
+            mock: Some(&mock),
+            wear_file: Some(Path::new("wear.log")),
+            ..defaults(&pins, &timing)
+        };
+
+        let config = document(&active);
+        let config = config.get("config");
+        assert_eq!(config.get("backend").str(), "mock");
+        assert_eq!(config.get("pins").get("reset"), &Json::Number(5.0));
+        assert_eq!(
+            config.get("erase_opcodes"),
+            &Json::Array(vec![Json::Number(0x20.into())])
+        );
+        let layout = config.get("layout");
+        assert_eq!(layout.get("device").str(), "up5k");
+        let partition = layout.get("partitions").find("name", "boot\"loader");
+        assert_eq!(partition.get("size"), &Json::Number(0x20000.into()));
+        assert_eq!(partition.get("readonly"), &Json::Bool(true));
+        let mock = config.get("mock");
+        assert_eq!(mock.get("image").str(), "/tmp/a \\ b.bin");
+        assert_eq!(mock.get("ignore_writes"), &Json::Bool(true));
+        assert_eq!(config.get("wear_file").str(), "wear.log");
+        assert_eq!(config.get("linux"), &Json::Null);
+        for (key, _) in timing.values() {
+            config.get("timing_ns").get(key);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::bitstream;
+use anyhow::Result;
+use clap::ValueEnum;
+use indicatif::DecimalBytes;
+
+/// Supported iCE40 target devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    Lp384,
+    Lp1k,
+    Hx1k,
+    Lp4k,
+    Hx4k,
+    Lp8k,
+    Hx8k,
+    Up3k,
+    Up5k,
+}
+
+impl Device {
+    /// All devices, in the order inference considers them.
+    pub const ALL: [Device; 9] = [
+        Device::Lp384,
+        Device::Hx1k,
+        Device::Lp1k,
+        Device::Hx8k,
+        Device::Lp8k,
+        Device::Hx4k,
+        Device::Lp4k,
+        Device::Up5k,
+        Device::Up3k,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Lp384 => "iCE40LP384",
+            Device::Lp1k => "iCE40LP1K",
+            Device::Hx1k => "iCE40HX1K",
+            Device::Lp4k => "iCE40LP4K",
+            Device::Hx4k => "iCE40HX4K",
+            Device::Lp8k => "iCE40LP8K",
+            Device::Hx8k => "iCE40HX8K",
+            Device::Up3k => "iCE40UP3K",
+            Device::Up5k => "iCE40UP5K",
+        }
+    }
+
+    /// The configuration memory bank dimensions (width, height) in bits, as encoded in the
+    /// bitstream's bank width and height commands.
+    ///
+    /// The 4K parts share the 8K die, and the UP3K shares the UP5K die.
+    pub fn cram_bank(self) -> (u16, u16) {
+        match self {
+            Device::Lp384 => (182, 80),
+            Device::Lp1k | Device::Hx1k => (332, 144),
+            Device::Lp4k | Device::Hx4k | Device::Lp8k | Device::Hx8k => (872, 272),
+            Device::Up3k | Device::Up5k => (692, 256),
+        }
+    }
+
+    /// The approximate size of an uncompressed binary configuration image.
+    pub fn config_size(self) -> usize {
+        match self {
+            Device::Lp384 => 7_408,
+            Device::Lp1k | Device::Hx1k => 32_220,
+            Device::Lp4k | Device::Hx4k | Device::Lp8k | Device::Hx8k => 135_100,
+            Device::Up3k | Device::Up5k => 104_090,
+        }
+    }
+
+    /// Infer the target device from the bank dimensions in a bitstream's command header.
+    ///
+    /// Devices sharing a die can't be distinguished, so the first match in [`Device::ALL`]
+    /// is returned.
+    pub fn infer(bitstream: &[u8]) -> Option<Self> {
+        let (width, height) = bitstream::bank_dimensions(bitstream)?;
+
+        Self::ALL
+            .into_iter()
+            .find(|device| device.cram_bank() == (width, height))
+    }
+
+    /// The names of every device sharing this one's die, which its bitstreams also configure.
+    pub fn die_names(self) -> String {
+        Self::ALL
+            .into_iter()
+            .filter(|device| device.cram_bank() == self.cram_bank())
+            .map(Device::name)
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+
+    /// Check a bitstream was built for this device's die, so it doesn't leave CDONE low.
+    pub fn check_target(self, bitstream: &[u8]) -> Result<()> {
+        if let Some(inferred) = Self::infer(bitstream) {
+            if inferred.cram_bank() != self.cram_bank() {
+                anyhow::bail!(
+                    "The bitstream was built for an {}, not the declared {}",
+                    inferred.die_names(),
+                    self.name()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that a configuration payload plausibly fits this device.
+    ///
+    /// Some slack is allowed over the nominal size since toolchains may pad the image.
+    pub fn check_size(self, length: usize) -> Result<()> {
+        let size = self.config_size();
+
+        if length > size + size / 10 {
+            anyhow::bail!(
+                "bitstream is {} but an {} configuration is ~{} \u{2014} wrong file or wrong --device?",
+                DecimalBytes(length as u64),
+                self.name(),
+                DecimalBytes(size as u64),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of a bitstream for a die with the given CRAM bank dimensions.
+    fn header(device: Device) -> Vec<u8> {
+        let (width, height) = device.cram_bank();
+        let mut header = vec![0x7E, 0xAA, 0x99, 0x7E, 0x92, 0x00, 0x00];
+        header.push(0x62);
+        header.extend((width - 1).to_be_bytes());
+        header.push(0x72);
+        header.extend(height.to_be_bytes());
+        header.extend([0x01, 0x01]);
+        header
+    }
+
+    #[test]
+    fn infers_each_die() {
+        for device in Device::ALL {
+            let inferred = Device::infer(&header(device)).unwrap();
+            assert_eq!(inferred.cram_bank(), device.cram_bank());
+        }
+        assert_eq!(Device::infer(&header(Device::Up3k)), Some(Device::Up5k));
+        assert_eq!(Device::infer(&[0; 64]), None);
+    }
+
+    #[test]
+    fn names_every_device_on_a_die() {
+        assert_eq!(
+            Device::Hx8k.die_names(),
+            "iCE40HX8K or iCE40LP8K or iCE40HX4K or iCE40LP4K"
+        );
+        assert_eq!(Device::Lp384.die_names(), "iCE40LP384");
+    }
+
+    #[test]
+    fn checks_the_target_die() {
+        let bitstream = header(Device::Up5k);
+        Device::Up3k.check_target(&bitstream).unwrap();
+        let error = Device::Hx1k.check_target(&bitstream).unwrap_err();
+        assert!(error.to_string().contains("not the declared iCE40HX1K"));
+        // Nothing to check against when the die can't be inferred
+        Device::Hx1k.check_target(&[0; 64]).unwrap();
+    }
+
+    #[test]
+    fn checks_the_size_with_some_slack() {
+        let size = Device::Up5k.config_size();
+        Device::Up5k.check_size(size).unwrap();
+        Device::Up5k.check_size(size + size / 10).unwrap();
+        let error = Device::Up5k.check_size(2_400_000).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "bitstream is 2.40 MB but an iCE40UP5K configuration is ~104.09 kB \u{2014} wrong \
+             file or wrong --device?"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! Finding which GPIO actually selects the flash on a board with miswired chip selects.
+//!
+//! Swapped flash and FPGA chip selects make every operation time out or verify garbage, with
+//! nothing pointing at the cause. Each candidate line is tried as the flash's chip select in
+//! turn, with the others held high and the FPGA held in reset, and the one that gets a JEDEC ID
+//! back is the flash's.
+
+use crate::flash::{FlashInfo, FlashProgrammer};
+use crate::pins::{Claims, PinConfig};
+use crate::status;
+use crate::timing::Timing;
+use anyhow::Result;
+use std::fmt::Write;
+
+/// Probe the configured chip selects and `extra` candidates, returning a report ending with
+/// the corrected pin settings when the flash answers on another line.
+pub fn chip_selects(pins: &PinConfig, timing: &Timing, extra: &[u8]) -> Result<String> {
+    let mut candidates = vec![
+        (pins.flash_cs, "configured flash CS"),
+        (pins.fpga_cs, "configured FPGA CS"),
+    ];
+    for pin in extra {
+        if candidates.iter().all(|(gpio, _)| gpio != pin) {
+            candidates.push((*pin, "candidate"));
+        }
+    }
+
+    let claims = Claims::default().pin("FPGA reset", pins.reset).bus(pins);
+    candidates
+        .iter()
+        .fold(claims, |claims, (gpio, role)| claims.pin(*role, *gpio))
+        .check()?;
+
+    let mut output = String::new();
+    let mut responding = Vec::new();
+    for (gpio, role) in &candidates {
+        let others: Vec<_> = candidates
+            .iter()
+            .map(|(other, _)| *other)
+            .filter(|other| other != gpio)
+            .collect();
+
+        status!("Probing GPIO {gpio} ({role}) as the flash chip select...");
+        let result = FlashProgrammer::probe_cs(pins, timing, *gpio, &others);
+        let line = match &result {
+            Ok(info) if !info.unresponsive() => {
+                responding.push((*gpio, *info));
+                format!("responds with JEDEC ID {}", id(info))
+            }
+            Ok(info) => format!("no response (JEDEC ID {})", id(info)),
+            Err(e) => format!("probe failed: {e:#}"),
+        };
+        writeln!(output, "GPIO {gpio:>2} ({role}): {line}")?;
+    }
+
+    match responding.as_slice() {
+        [] => write!(
+            output,
+            "No line selected a responding flash. Check the flash's power and the SPI data \
+             lines, and try --flash-cs-active-high if its chip select is inverted."
+        )?,
+        [(gpio, _)] if *gpio == pins.flash_cs => {
+            write!(output, "The flash chip select is wired as configured.")?
+        }
+        [(gpio, _)] => {
+            // The configured flash CS most likely goes to the FPGA instead
+            let fpga_cs = if *gpio == pins.fpga_cs {
+                pins.flash_cs
+            } else {
+                pins.fpga_cs
+            };
+            write!(
+                output,
+                "The flash is selected by GPIO {gpio}, not GPIO {}. Use these pin settings:\n  \
+                 --flash-cs-gpio {gpio} --fpga-cs-gpio {fpga_cs}",
+                pins.flash_cs
+            )?;
+        }
+        several => {
+            let gpios: Vec<_> = several.iter().map(|(gpio, _)| gpio.to_string()).collect();
+            write!(
+                output,
+                "Several lines selected a responding flash (GPIOs {}), so they may be shorted \
+                 together.",
+                gpios.join(", ")
+            )?;
+        }
+    }
+
+    Ok(output)
+}
+
+fn id(info: &FlashInfo) -> String {
+    crate::backup::hex(&info.jedec)
+}
@@ -0,0 +1,207 @@
+//! The library's error type.
+//!
+//! The programmers fail with an [`Error`], whose variants separate the failures a caller acts
+//! on, a cancellation or an SRAM write that needs configuration restarted, from everything else,
+//! which carries a readable chain of context. The [`Port`](crate::flash::Port) traits stay on
+//! [`anyhow::Error`], so implementing one needs nothing from here.
+
+use crate::cancel::Cancelled;
+use crate::sram::Corrupted;
+use std::fmt;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The operation was cancelled through its token.
+    Cancelled(Cancelled),
+    /// An SRAM write may have left part of a chunk in the FPGA, so configuration has to restart.
+    Corrupted(Corrupted),
+    /// Reading or writing a file failed.
+    Io(std::io::Error),
+    /// Anything else, with its context.
+    Other(anyhow::Error),
+}
+
+impl Error {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Self::Cancelled(_))
+    }
+
+    pub fn is_corrupted(&self) -> bool {
+        matches!(self, Self::Corrupted(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled(e) => e.fmt(f),
+            Self::Corrupted(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    // The context of `Other` is already in its message
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<Cancelled> for Error {
+    fn from(error: Cancelled) -> Self {
+        Self::Cancelled(error)
+    }
+}
+
+impl From<Corrupted> for Error {
+    fn from(error: Corrupted) -> Self {
+        Self::Corrupted(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Cancellation and corruption are pulled out of any context wrapped around them, since that's
+/// what a caller matches on, and the context is dropped with it. Anything else keeps its context.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        if let Some(cancelled) = cancellation(&error) {
+            return Self::Cancelled(cancelled);
+        }
+        if let Some(corrupted) = corruption(&error) {
+            return Self::Corrupted(corrupted);
+        }
+        if error.chain().count() > 1 {
+            return Self::Other(error);
+        }
+        let error = match error.downcast::<Self>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        match error.downcast::<std::io::Error>() {
+            Ok(error) => Self::Io(error),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
+
+/// The cancellation behind `error`, through any context or [`Error`] wrapped around it.
+pub fn cancellation(error: &anyhow::Error) -> Option<Cancelled> {
+    match error.downcast_ref::<Error>() {
+        Some(Error::Cancelled(cancelled)) => Some(*cancelled),
+        _ => error.downcast_ref::<Cancelled>().copied(),
+    }
+}
+
+/// The SRAM corruption behind `error`, through any context or [`Error`] wrapped around it.
+pub fn corruption(error: &anyhow::Error) -> Option<Corrupted> {
+    match error.downcast_ref::<Error>() {
+        Some(Error::Corrupted(corrupted)) => Some(corrupted.clone()),
+        _ => error.downcast_ref::<Corrupted>().cloned(),
+    }
+}
+
+/// Like [`anyhow::bail!`], for functions returning either [`Result`].
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(anyhow::anyhow!($($arg)*).into())
+    };
+}
+pub(crate) use bail;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn io() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no such file")
+    }
+
+    #[test]
+    fn cancellation_is_found_through_context() {
+        let error = anyhow::Error::from(Cancelled { address: 0x100 }).context("Failed to flash");
+        assert!(matches!(
+            Error::from(error),
+            Error::Cancelled(Cancelled { address: 0x100 })
+        ));
+
+        // Nor is it lost once it's been through the library's own type
+        let error = anyhow::Error::from(Error::from(Cancelled { address: 0x200 }))
+            .context("Failed to verify");
+        assert_eq!(cancellation(&error), Some(Cancelled { address: 0x200 }));
+        assert!(Error::from(error).is_cancelled());
+    }
+
+    #[test]
+    fn corruption_is_found_through_context() {
+        let corrupted = Corrupted("Error writing to SPI bus".into());
+        let error = anyhow::Error::from(Error::from(corrupted)).context("Failed to configure");
+        assert!(corruption(&error).is_some());
+        let error = Error::from(error);
+        assert!(error.is_corrupted());
+        assert_eq!(error.to_string(), "Error writing to SPI bus");
+    }
+
+    #[test]
+    fn io_errors_are_kept_unless_wrapped() {
+        assert!(matches!(
+            Error::from(anyhow::Error::from(io())),
+            Error::Io(_)
+        ));
+        assert!(matches!(Error::from(io()), Error::Io(_)));
+
+        let error = Error::from(anyhow::Error::from(io()).context("Failed to open the image"));
+        assert!(matches!(error, Error::Other(_)));
+        assert_eq!(error.to_string(), "Failed to open the image: no such file");
+    }
+
+    #[test]
+    fn other_errors_display_their_chain_once() {
+        let error = Error::from(anyhow::anyhow!("The flash is busy").context("Failed to erase"));
+        assert_eq!(error.to_string(), "Failed to erase: The flash is busy");
+
+        // Wrapped again, the inner chain appears once, after the new context
+        let result: Result<()> = Err(error);
+        let error = result.context("Failed to flash").unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "Failed to flash: Failed to erase: The flash is busy"
+        );
+        let error = Error::from(error);
+        assert_eq!(
+            error.to_string(),
+            "Failed to flash: Failed to erase: The flash is busy"
+        );
+    }
+
+    #[test]
+    fn nested_errors_unwrap() {
+        let error = anyhow::Error::from(Error::from(io()));
+        assert!(matches!(Error::from(error), Error::Io(_)));
+    }
+
+    #[test]
+    fn bail_converts_to_either_result() {
+        fn library() -> Result<()> {
+            bail!("Failed at {:#x}", 0x10);
+        }
+        fn application() -> anyhow::Result<()> {
+            bail!("Failed at {:#x}", 0x20);
+        }
+        assert_eq!(library().unwrap_err().to_string(), "Failed at 0x10");
+        assert_eq!(application().unwrap_err().to_string(), "Failed at 0x20");
+    }
+}
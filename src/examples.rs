@@ -0,0 +1,186 @@
+//! Ready-to-run command lines for common tasks on the active setup.
+//!
+//! Every command is rendered from the parser's own argument definitions, looking each argument
+//! up by its id, so a renamed flag changes the output rather than leaving a stale example.
+
+use crate::chip::ChipProfile;
+use crate::flash::FlashProgrammer;
+use crate::layout::Layout;
+use crate::pins::PinConfig;
+use crate::timing::Timing;
+use anyhow::{Context, Result};
+use clap::Command;
+use std::fmt::Write;
+use std::path::Path;
+
+/// A task to show, as the subcommand and the `(argument id, value)` pairs it takes.
+///
+/// Arguments without a value are flags.
+struct Task<'a> {
+    description: String,
+    subcommand: &'a str,
+    args: Vec<(&'a str, Option<String>)>,
+    /// A file to redirect stdout into.
+    output: Option<String>,
+}
+
+/// The active settings that carry over into every example.
+pub struct Active<'a> {
+    pub pins: &'a PinConfig,
+    pub timing: &'a Timing,
+    pub layout: Option<(&'a Path, &'a Layout)>,
+}
+
+/// Render the examples for `active`, probing the flash if the hardware is reachable.
+pub fn generate(command: &Command, active: &Active) -> Result<String> {
+    let mut output = String::new();
+    let mut globals: Vec<(&str, Option<String>)> = Vec::new();
+
+    if let Some((path, _)) = active.layout {
+        globals.push(("layout", Some(path.display().to_string())));
+    }
+    for (id, active_low) in [
+        ("reset_active_low", active.pins.reset_active_low),
+        ("fpga_cs_active_low", active.pins.fpga_cs_active_low),
+        ("flash_cs_active_low", active.pins.flash_cs_active_low),
+    ] {
+        if !active_low {
+            globals.push((id, None));
+        }
+    }
+    let defaults = PinConfig::default();
+    for (id, gpio, default) in [
+        ("reset", active.pins.reset, defaults.reset),
+        ("fpga_cs", active.pins.fpga_cs, defaults.fpga_cs),
+        ("flash_cs", active.pins.flash_cs, defaults.flash_cs),
+        ("flash_sdi", active.pins.flash_sdi, defaults.flash_sdi),
+        ("flash_sck", active.pins.flash_sck, defaults.flash_sck),
+        ("flash_sdo", active.pins.flash_sdo, defaults.flash_sdo),
+    ] {
+        if gpio != default {
+            globals.push((id, Some(gpio.to_string())));
+        }
+    }
+
+    let profile = match active.timing.chip_profile {
+        Some(profile) => Some(profile),
+        None => detect(active),
+    };
+    match profile {
+        Some(profile) => {
+            writeln!(output, "# Flash chip profile: {}", profile.name())?;
+            globals.push(("chip_profile", Some(profile.name().into())));
+        }
+        None => writeln!(
+            output,
+            "# Flash not reachable, so no chip profile is included"
+        )?,
+    }
+
+    let mut tasks = vec![Task {
+        description: "Program the whole image into flash".into(),
+        subcommand: "flash",
+        args: vec![("path", Some("image.bin".into()))],
+        output: None,
+    }];
+
+    let partitions = active
+        .layout
+        .map_or(&[][..], |(_, layout)| &layout.partitions);
+    for partition in partitions.iter().filter(|p| !p.readonly) {
+        tasks.push(Task {
+            description: format!("Update just the {:?} partition", partition.name),
+            subcommand: "flash",
+            args: vec![
+                ("path", Some(format!("{}.bin", partition.name))),
+                ("partition", Some(partition.name.clone())),
+            ],
+            output: None,
+        });
+    }
+    for partition in partitions {
+        tasks.push(Task {
+            description: format!("Dump the {:?} partition to a file", partition.name),
+            subcommand: "dump",
+            args: vec![("partition", Some(partition.name.clone()))],
+            output: Some(format!("{}.bin", partition.name)),
+        });
+    }
+
+    tasks.push(Task {
+        description: "Check which blocks differ from an image, without writing".into(),
+        subcommand: "plan",
+        args: vec![("path", Some("image.bin".into()))],
+        output: None,
+    });
+    tasks.push(Task {
+        description: "Recover an unresponsive flash chip (erases everything)".into(),
+        subcommand: "recover",
+        args: vec![("blind_chip_erase", None)],
+        output: None,
+    });
+
+    // Read-only builds have no commands that write
+    let tasks = tasks
+        .into_iter()
+        .filter(|task| command.find_subcommand(task.subcommand).is_some());
+    for task in tasks {
+        let mut args = task.args;
+        args.extend(globals.iter().cloned());
+
+        writeln!(output, "\n# {}", task.description)?;
+        write!(output, "{}", render(command, task.subcommand, &args)?)?;
+        match task.output {
+            Some(file) => writeln!(output, " > {}", quote(&file))?,
+            None => writeln!(output)?,
+        }
+    }
+
+    Ok(output)
+}
+
+/// Identify the flash, returning `None` when the hardware can't be reached.
+fn detect(active: &Active) -> Option<ChipProfile> {
+    let profile = FlashProgrammer::new(active.pins, active.timing, None)
+        .and_then(|mut programmer| programmer.info())
+        .map(|info| ChipProfile::detect(info.jedec))
+        .ok();
+    let _ = FlashProgrammer::reset(active.pins, true);
+
+    profile
+}
+
+/// Render a command line, finding each argument in the subcommand or among the globals.
+fn render(command: &Command, subcommand: &str, args: &[(&str, Option<String>)]) -> Result<String> {
+    let sub = command
+        .find_subcommand(subcommand)
+        .with_context(|| format!("No subcommand {subcommand:?}"))?;
+    let mut words = vec![command.get_name().to_string(), sub.get_name().to_string()];
+
+    for (id, value) in args {
+        let arg = sub
+            .get_arguments()
+            .chain(command.get_arguments())
+            .find(|arg| arg.get_id() == *id)
+            .with_context(|| format!("No argument {id:?} for {subcommand}"))?;
+
+        match arg.get_long() {
+            Some(long) => words.push(format!("--{long}")),
+            None if arg.is_positional() => {}
+            None => anyhow::bail!("Argument {id:?} has no long form"),
+        }
+        if let Some(value) = value {
+            words.push(quote(value));
+        }
+    }
+
+    Ok(words.join(" "))
+}
+
+fn quote(word: &str) -> String {
+    if word.contains(|c: char| c.is_whitespace() || "'\"$\\".contains(c)) {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    } else {
+        word.into()
+    }
+}
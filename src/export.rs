@@ -0,0 +1,208 @@
+//! Output formats for `dump`.
+//!
+//! The array formats are for embedding small flash regions in firmware source, ending with a
+//! comment recording where the bytes came from and their SHA-256. Intel HEX and S-records keep
+//! the flash address of every byte, for tools that program from them.
+
+use crate::backup::hex;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// The number of bytes on each line of an array.
+const BYTES_PER_LINE: usize = 12;
+/// The number of data bytes in each Intel HEX or S-record record.
+const BYTES_PER_RECORD: usize = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// The bytes exactly as read
+    #[default]
+    Raw,
+    /// A hexdump, annotated unless `--no-annotate` is given
+    Hex,
+    /// A C `const uint8_t` array
+    CArray,
+    /// A Rust `const [u8; N]` array
+    RustArray,
+    /// Intel HEX records at the flash addresses
+    Ihex,
+    /// Motorola S-records at the flash addresses
+    Srec,
+}
+
+/// A C array named `symbol` holding `data`, read from flash at `address`.
+pub fn c_array(symbol: &str, address: usize, data: &[u8]) -> String {
+    format!(
+        "const uint8_t {symbol}[{}] = {{\n{}}};\n{}\n",
+        data.len(),
+        lines(data),
+        source(address, data)
+    )
+}
+
+/// A Rust array named `symbol` holding `data`, read from flash at `address`.
+pub fn rust_array(symbol: &str, address: usize, data: &[u8]) -> String {
+    format!(
+        "const {symbol}: [u8; {}] = [\n{}];\n{}\n",
+        data.len(),
+        lines(data),
+        source(address, data)
+    )
+}
+
+/// Intel HEX records holding `data` at `address`, with extended linear address records above
+/// 64 KiB.
+pub fn ihex(address: usize, data: &[u8]) -> String {
+    let mut output = String::new();
+    let mut segment = None;
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let start = address + offset;
+        let upper = (start >> 16) as u16;
+        if segment != Some(upper) {
+            ihex_record(&mut output, 0x04, 0, &upper.to_be_bytes());
+            segment = Some(upper);
+        }
+        // A record can't run past the end of its 64 KiB segment
+        let length = BYTES_PER_RECORD
+            .min(data.len() - offset)
+            .min(0x10000 - (start & 0xFFFF));
+        ihex_record(
+            &mut output,
+            0x00,
+            start as u16,
+            &data[offset..offset + length],
+        );
+        offset += length;
+    }
+    ihex_record(&mut output, 0x01, 0, &[]);
+
+    output
+}
+
+fn ihex_record(output: &mut String, kind: u8, address: u16, data: &[u8]) {
+    let mut record = vec![data.len() as u8];
+    record.extend(address.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(sum.wrapping_neg());
+
+    let _ = writeln!(output, ":{}", hex(&record).to_uppercase());
+}
+
+/// S-records holding `data` at `address`, with the narrowest addresses that reach its end.
+pub fn srec(address: usize, data: &[u8]) -> String {
+    let end = address + data.len();
+    let (kind, width) = if end <= 1 << 16 {
+        (1, 2)
+    } else if end <= 1 << 24 {
+        (2, 3)
+    } else {
+        (3, 4)
+    };
+
+    let mut output = String::new();
+    srec_record(&mut output, 0, 0, 2, &[]);
+    for (i, chunk) in data.chunks(BYTES_PER_RECORD).enumerate() {
+        srec_record(
+            &mut output,
+            kind,
+            address + i * BYTES_PER_RECORD,
+            width,
+            chunk,
+        );
+    }
+    // S9, S8, and S7 end the S1, S2, and S3 records
+    srec_record(&mut output, 10 - kind, 0, width, &[]);
+
+    output
+}
+
+fn srec_record(output: &mut String, kind: u8, address: usize, width: usize, data: &[u8]) {
+    let mut record = vec![(width + data.len() + 1) as u8];
+    record.extend(&(address as u32).to_be_bytes()[4 - width..]);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(!sum);
+
+    let _ = writeln!(output, "S{kind}{}", hex(&record).to_uppercase());
+}
+
+/// The array elements, indented with a trailing comma on each line.
+fn lines(data: &[u8]) -> String {
+    let mut output = String::new();
+
+    for line in data.chunks(BYTES_PER_LINE) {
+        let bytes: Vec<_> = line.iter().map(|b| format!("0x{b:02x},")).collect();
+        let _ = writeln!(output, "    {}", bytes.join(" "));
+    }
+
+    output
+}
+
+/// The trailing comment, which is valid in both languages.
+fn source(address: usize, data: &[u8]) -> String {
+    format!(
+        "// Read from flash at {address:#x}..{:#x}, SHA-256 {}",
+        address + data.len(),
+        hex(&Sha256::digest(data))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fourteen bytes, for a full line and a partial one.
+    const DATA: [u8; 14] = *b"0123456789:;<=";
+
+    const SOURCE: &str = "// Read from flash at 0x21000..0x2100e, SHA-256 \
+                          3f77e4e518cbef6eebec3961833a5d6f5b7ed95486b0d5a3a317e762228bf5e3";
+
+    #[test]
+    fn c_array_snapshot() {
+        assert_eq!(
+            c_array("cal_table", 0x21000, &DATA),
+            format!(
+                "const uint8_t cal_table[14] = {{\n    \
+                 0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b,\n    \
+                 0x3c, 0x3d,\n}};\n{SOURCE}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn rust_array_snapshot() {
+        assert_eq!(
+            rust_array("CAL_TABLE", 0x21000, &DATA),
+            format!(
+                "const CAL_TABLE: [u8; 14] = [\n    \
+                 0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x3b,\n    \
+                 0x3c, 0x3d,\n];\n{SOURCE}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn empty_arrays_still_compile() {
+        assert_eq!(
+            rust_array("EMPTY", 0, &[]),
+            "const EMPTY: [u8; 0] = [\n];\n// Read from flash at 0x0..0x0, SHA-256 \
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n"
+        );
+    }
+
+    #[test]
+    fn array_lines_hold_a_line_of_bytes() {
+        let data = [0xA5; 5 * BYTES_PER_LINE - 1];
+        let array = c_array("data", 0, &data);
+        let counts: Vec<_> = array
+            .lines()
+            .filter(|line| line.starts_with("    "))
+            .map(|line| line.matches("0xa5,").count())
+            .collect();
+        assert_eq!(counts, [12, 12, 12, 12, 11]);
+    }
+}
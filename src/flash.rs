@@ -1,13 +1,206 @@
-use super::sleep;
-use anyhow::{Context, Ok, Result};
-use rppal::gpio::{Gpio, InputPin, OutputPin};
+use crate::address::{FlashAddress, THREE_BYTE_SPACE};
+use crate::cancel::CancellationToken;
+use crate::chip::{Busy, ChipProfile, Erase};
+use crate::error::bail;
+use crate::latency::Latency;
+use crate::mask::Mask;
+use crate::parts::Part;
+use crate::pins::{driven_high, ActivePin, Claims, PinConfig, SpiBus};
+use crate::plan;
+use crate::report::{self, Progress};
+use crate::sample::{self, Sample};
+use crate::sfdp::{self, AddressBytes, Parameters, QuadEnable};
+use crate::timing::Timing;
+use crate::trace::{Trace, Transaction};
+use crate::{status, verbose, warning, Result};
+use anyhow::Context;
+use rppal::gpio::{Gpio, InputPin, IoPin, Mode, OutputPin};
+use rppal::spi::{Bus, SlaveSelect, Spi};
+use sha2::{Digest, Sha256};
+use spin_sleep::sleep;
+use std::time::{Duration, Instant};
 
+#[cfg(not(feature = "read-only"))]
+mod program;
+
+/// How written data is checked against the flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum VerifyMode {
+    /// Compare every byte against the image held in memory
+    Full,
+    /// Compare digests of 64K windows, only byte-comparing windows that differ
+    Windowed,
+    /// Compare a seeded random sample of pages, for a quick statistical check
+    Sample,
+}
+
+/// How to verify, as chosen on the command line.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Verification {
+    /// How to verify the written image
+    ///
+    /// Defaults to windowed verification for images over 1 MiB and full otherwise.
+    #[arg(long, value_enum)]
+    pub verify_mode: Option<VerifyMode>,
+
+    /// The number of pages compared by `--verify-mode sample`, always including the first and
+    /// last
+    #[arg(long, default_value = "256")]
+    pub sample_pages: usize,
+
+    /// Seed the page choice of `--verify-mode sample`, to reproduce an earlier run
+    ///
+    /// Taken from the clock and printed when omitted.
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+impl Default for Verification {
+    fn default() -> Self {
+        Self {
+            verify_mode: None,
+            sample_pages: 256,
+            seed: None,
+        }
+    }
+}
+
+impl Verification {
+    /// The pages a sample verification of a `length` byte image compares.
+    pub fn sample(&self, length: usize) -> Sample {
+        let seed = self.seed.unwrap_or_else(sample::clock_seed);
+        Sample::select(length, self.sample_pages, seed)
+    }
+}
+
+impl VerifyMode {
+    /// Images above this size are verified in windows unless a mode is given.
+    pub const WINDOWED_THRESHOLD: usize = 1 << 20;
+    pub const WINDOW_SIZE: usize = 65536;
+
+    pub fn resolve(mode: Option<Self>, length: usize) -> Self {
+        mode.unwrap_or(if length > Self::WINDOWED_THRESHOLD {
+            Self::Windowed
+        } else {
+            Self::Full
+        })
+    }
+}
+
+/// Split `length` bytes into consecutive windows of at most `size` bytes, as (offset, length).
+pub fn windows(length: usize, size: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..length)
+        .step_by(size)
+        .map(move |offset| (offset, size.min(length - offset)))
+}
+
+/// How many data lines a read clocks in at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReadWidth {
+    Single,
+    /// Fast Read Dual Output, on IO0 and IO1.
+    Dual,
+    /// Fast Read Quad Output, on IO0 to IO3, which needs the flash's quad enable bit set.
+    Quad,
+}
+
+impl ReadWidth {
+    /// The read opcode, taking a 3-byte address.
+    fn opcode(self) -> u8 {
+        match self {
+            ReadWidth::Single => 0x03,
+            ReadWidth::Dual => 0x3B,
+            ReadWidth::Quad => 0x6B,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ReadWidth::Single => "single",
+            ReadWidth::Dual => "dual output",
+            ReadWidth::Quad => "quad output",
+        }
+    }
+}
+
+/// The byte-level transport underlying a [`FlashProgrammer`].
+///
+/// Every transaction is framed by `select` and `deselect`, mirroring the flash's CS line.
+pub trait Port {
+    fn select(&mut self) -> anyhow::Result<()>;
+    fn deselect(&mut self) -> anyhow::Result<()>;
+    fn write(&mut self, byte: u8) -> anyhow::Result<()>;
+    fn read(&mut self) -> anyhow::Result<u8>;
+
+    /// The widest read [`Port::read_wide`] takes.
+    fn read_width(&self) -> ReadWidth {
+        ReadWidth::Single
+    }
+
+    /// Fill `buffer` with the data of a read clocked in `width` bits at a time, with IO0 on the
+    /// SDI line, IO1 on SDO, and IO2 and IO3 on WP# and HOLD#.
+    fn read_wide(&mut self, width: ReadWidth, buffer: &mut [u8]) -> anyhow::Result<()> {
+        match width {
+            ReadWidth::Single => self.read_into(buffer),
+            _ => anyhow::bail!("This port can't make {} reads", width.name()),
+        }
+    }
+
+    /// Deselect the flash and keep the FPGA held in reset after the port is dropped, for
+    /// handing the pins to the SRAM programmer.
+    fn hand_off(&mut self) {}
+
+    /// Fill `buffer` with consecutive reads, in one transfer on ports where each has a high
+    /// fixed cost, such as USB adapters.
+    fn read_into(&mut self, buffer: &mut [u8]) -> anyhow::Result<()> {
+        for byte in buffer {
+            *byte = self.read()?;
+        }
+        Ok(())
+    }
+}
+
+/// The flash's chip select and the lines keeping the FPGA off the bus, on the Pi's GPIO.
 #[allow(dead_code)]
-pub struct FlashProgrammer {
-    fpga_reset: OutputPin,
-    fpga_cs: InputPin,
-    flash_cs: OutputPin,
-    flash_sdi: OutputPin,
+struct Control {
+    /// Left alone entirely when reading while the FPGA keeps running.
+    fpga_reset: Option<ActivePin>,
+    /// Left floating so the FPGA can't drive the bus, except while probing chip selects.
+    fpga_cs: Option<InputPin>,
+    /// Other candidate chip selects, held high while probing.
+    held: Vec<OutputPin>,
+    flash_cs: ActivePin,
+    cs_setup: Duration,
+    cs_hold: Duration,
+}
+
+impl Control {
+    fn select(&mut self) {
+        self.flash_cs.assert();
+        sleep(self.cs_setup);
+    }
+
+    fn deselect(&mut self) {
+        self.flash_cs.release();
+        sleep(self.cs_hold);
+    }
+
+    fn hand_off(&mut self) {
+        self.flash_cs.release();
+        self.flash_cs.hold();
+        if let Some(fpga_reset) = &mut self.fpga_reset {
+            fpga_reset.assert();
+            fpga_reset.hold();
+        }
+    }
+}
+
+/// Bit-banged SPI over the Pi's GPIO, reading two bits per clock where SDI and SDO are IO0 and
+/// IO1.
+struct Pins {
+    control: Control,
+    /// Switched to an input while reading two bits per clock.
+    flash_sdi: IoPin,
     flash_sdo: InputPin,
     flash_sck: OutputPin,
 }
@@ -16,248 +209,1741 @@ fn pin_sleep() {
     spin_sleep::sleep(std::time::Duration::from_micros(1));
 }
 
+impl Port for Pins {
+    fn select(&mut self) -> anyhow::Result<()> {
+        self.control.select();
+        Ok(())
+    }
+
+    fn deselect(&mut self) -> anyhow::Result<()> {
+        self.control.deselect();
+        Ok(())
+    }
+
+    fn read(&mut self) -> anyhow::Result<u8> {
+        let mut value = 0;
+        for i in 0..8 {
+            self.flash_sck.set_high();
+            pin_sleep();
+            let level: u8 = matches!(self.flash_sdo.read(), rppal::gpio::Level::High) as u8;
+            value |= level;
+            if i < 7 {
+                value <<= 1;
+            }
+            self.flash_sck.set_low();
+            pin_sleep();
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+        if self.flash_sdi.mode() != Mode::Output {
+            self.flash_sdi.set_mode(Mode::Output);
+        }
+
+        for i in (0..8).rev() {
+            let level = (byte & (1 << i)) > 0;
+            self.flash_sdi.write(level.into());
+            self.flash_sck.set_high();
+            pin_sleep();
+
+            self.flash_sck.set_low();
+            pin_sleep();
+        }
+        Ok(())
+    }
+
+    fn read_width(&self) -> ReadWidth {
+        ReadWidth::Dual
+    }
+
+    fn read_wide(&mut self, width: ReadWidth, buffer: &mut [u8]) -> anyhow::Result<()> {
+        match width {
+            ReadWidth::Single => self.read_into(buffer),
+            ReadWidth::Dual => {
+                if self.flash_sdi.mode() != Mode::Input {
+                    self.flash_sdi.set_mode(Mode::Input);
+                }
+
+                for byte in buffer {
+                    let mut value = 0;
+                    for _ in 0..4 {
+                        self.flash_sck.set_high();
+                        pin_sleep();
+                        let io1 = self.flash_sdo.is_high() as u8;
+                        let io0 = self.flash_sdi.is_high() as u8;
+                        value = (value << 2) | (io1 << 1) | io0;
+                        self.flash_sck.set_low();
+                        pin_sleep();
+                    }
+                    *byte = value;
+                }
+                Ok(())
+            }
+            ReadWidth::Quad => anyhow::bail!("The Pi's pins don't reach IO2 and IO3"),
+        }
+    }
+
+    // The bus pins return to their original modes when dropped, giving SPI0 back its
+    // alternate functions before the SRAM programmer opens it
+    fn hand_off(&mut self) {
+        self.control.hand_off();
+    }
+}
+
+/// The default clock for the flash over the SPI peripheral, within every supported part's
+/// limit for plain reads.
+const SPI_SPEED: u32 = 8_000_000;
+/// The most spidev takes in one transfer without raising its buffer size.
+const SPI_TRANSFER: usize = 4096;
+
+/// The flash on the Pi's SPI peripheral, with its chip select on a GPIO.
+///
+/// Writes are held until the next read or deselect, so that a command and the data read after
+/// it go in one transfer.
+struct HardwareSpi {
+    control: Control,
+    spi: Spi,
+    pending: Vec<u8>,
+}
+
+impl HardwareSpi {
+    /// Clock out the pending bytes followed by `buffer`, replacing `buffer` with the bytes
+    /// clocked in alongside it.
+    fn transfer(&mut self, buffer: &mut [u8]) -> anyhow::Result<()> {
+        let mut write = std::mem::take(&mut self.pending);
+        let skip = write.len();
+        write.extend_from_slice(buffer);
+
+        let mut read = vec![0; write.len()];
+        for (out, into) in write
+            .chunks(SPI_TRANSFER)
+            .zip(read.chunks_mut(SPI_TRANSFER))
+        {
+            self.spi
+                .transfer(into, out)
+                .with_context(|| "Failed to transfer over SPI")?;
+        }
+
+        buffer.copy_from_slice(&read[skip..]);
+        Ok(())
+    }
+}
+
+impl Port for HardwareSpi {
+    fn select(&mut self) -> anyhow::Result<()> {
+        self.control.select();
+        Ok(())
+    }
+
+    fn deselect(&mut self) -> anyhow::Result<()> {
+        if !self.pending.is_empty() {
+            self.transfer(&mut [])?;
+        }
+        self.control.deselect();
+        Ok(())
+    }
+
+    fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+        self.pending.push(byte);
+        Ok(())
+    }
+
+    fn read(&mut self) -> anyhow::Result<u8> {
+        let mut byte = [0xFF];
+        self.transfer(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_into(&mut self, buffer: &mut [u8]) -> anyhow::Result<()> {
+        buffer.fill(0xFF);
+        self.transfer(buffer)
+    }
+
+    fn hand_off(&mut self) {
+        self.control.hand_off();
+    }
+}
+
+/// Identification probed from the flash, shared by everything in a session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlashInfo {
+    pub jedec: [u8; 3],
+}
+
+impl FlashInfo {
+    /// The part's entry in the database, when it's a known part.
+    pub fn part(&self) -> Option<&'static Part> {
+        Part::lookup(self.jedec)
+    }
+
+    /// The capacity in bytes, from the database or else encoded in the last byte of the JEDEC
+    /// ID.
+    ///
+    /// Nearly every vendor encodes the capacity as a power of two there. Implausible values,
+    /// such as the all-ones ID of an absent chip, give `None`.
+    pub fn capacity(&self) -> Option<usize> {
+        if let Some(part) = self.part() {
+            return Some(part.capacity);
+        }
+        matches!(self.jedec[2], 0x10..=0x1F).then(|| 1 << self.jedec[2])
+    }
+
+    /// Whether the ID looks like a chip that isn't responding.
+    pub fn unresponsive(&self) -> bool {
+        self.jedec == [0x00; 3] || self.jedec == [0xFF; 3]
+    }
+}
+
+/// Command sequences that return a chip to standard SPI, in the order tried when the JEDEC ID
+/// reads invalid. Each command is sent in its own chip select.
+///
+/// A design running on the FPGA may leave the flash in continuous read or QPI mode, where it
+/// ignores standard opcodes and reads back as all ones.
+const EXIT_SEQUENCES: [(&str, &[&[u8]]); 3] = [
+    ("continuous read exit", &[&[0xFF], &[0xFF]]),
+    ("QPI exit", &[&[0xF5], &[0xFF]]),
+    ("software reset", &[&[0x66], &[0x99]]),
+];
+
+/// The number of times the FPGA's chip select is sampled before reading while it runs.
+const BUS_SAMPLES: usize = 1000;
+
+/// Fail if the FPGA's chip select is active in any of [`BUS_SAMPLES`] reads, spread over a few
+/// milliseconds, as it would be while the running design uses the flash.
+fn check_bus_idle(pins: &PinConfig) -> anyhow::Result<()> {
+    let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+    let cs = gpio
+        .get(pins.fpga_cs)
+        .with_context(|| "Failed to acquire FPGA CS pin")?
+        .into_input();
+
+    for _ in 0..BUS_SAMPLES {
+        if cs.is_high() == driven_high(pins.fpga_cs_active_low, true) {
+            anyhow::bail!(
+                "FPGA appears to be using the bus: its CS (GPIO {}) is active",
+                pins.fpga_cs
+            );
+        }
+        pin_sleep();
+    }
+
+    Ok(())
+}
+
+/// A block erase or page program started at `started`.
+struct Pending {
+    address: FlashAddress,
+    started: Instant,
+    /// Whether an erase covered only part of its block, so its time isn't comparable with
+    /// whole block erases.
+    partial: bool,
+}
+
+/// The opcodes taking an address, each with its form taking a 4-byte address.
+///
+/// The 32 KiB erase's 0x5C is missing from some parts with the others, so it's never used
+/// with 4-byte addresses.
+const FOUR_BYTE_OPCODES: [(u8, u8); 7] = [
+    (0x03, 0x13),
+    (0x0B, 0x0C),
+    (0x3B, 0x3C),
+    (0x6B, 0x6C),
+    (0x02, 0x12),
+    (0x20, 0x21),
+    (0xD8, 0xDC),
+];
+
+/// The time allowed after an exit sequence, covering the software reset's recovery time.
+const EXIT_DELAY: Duration = Duration::from_micros(50);
+
+pub struct FlashProgrammer {
+    port: Box<dyn Port>,
+    trace: Option<Trace>,
+    info: Option<FlashInfo>,
+    profile: ChipProfile,
+    /// The lines the data of reads is clocked in on.
+    width: ReadWidth,
+    /// The dummy clocks after a read's address, with single reads using Fast Read when set.
+    fast_read: Option<u8>,
+    /// Whether addresses are sent as 4 bytes, with the opcodes taking them, for flash over
+    /// 16 MiB.
+    four_byte: bool,
+    /// The quad enable bit set only in the volatile status registers, as the opcode reading
+    /// its register and its mask, kept out of every non-volatile write.
+    volatile_quad_enable: Option<(u8, u8)>,
+    /// The operation last started, which bounds how long the flash may stay busy.
+    busy: Option<Busy>,
+    /// Called with the address of each block erase once it completes, and how long it took.
+    #[cfg(not(feature = "read-only"))]
+    on_erase: Option<Box<dyn FnMut(FlashAddress, Duration)>>,
+    /// The block erase or page program in progress, timed until the flash is ready again.
+    pending: Option<Pending>,
+    latency: Latency,
+    /// How many times the median busy time makes a block erase or page program slow.
+    slow_factor: u32,
+    /// The erase commands the flash implements, read from its SFDP table on the first erase
+    /// unless given.
+    erases: Option<Vec<Erase>>,
+    /// The SFDP parameters, read on first use, holding `None` when the flash has no table.
+    sfdp: Option<Option<Parameters>>,
+    /// Whether every write enable is refused, so nothing can modify the flash.
+    #[cfg_attr(feature = "read-only", allow(dead_code))]
+    read_only: bool,
+}
+
 impl FlashProgrammer {
-    const PROGRAM: u8 = 0x02;
-    const READ: u8 = 0x03;
     #[allow(dead_code)]
     const WRITE_DISABLE: u8 = 0x04;
     const READ_STATUS_1: u8 = 0x05;
-    const WRITE_ENABLE: u8 = 0x06;
-    const BLOCK_ERASE: u8 = 0xD8;
+    const READ_JEDEC_ID: u8 = 0x9F;
+    const READ_UNIQUE_ID: u8 = 0x4B;
+    const READ_SECURITY: u8 = 0x48;
     const WAKE: u8 = 0xAB;
+    const POWER_DOWN: u8 = 0xB9;
+    const FAST_READ: u8 = 0x0B;
+    /// The dummy clocks of every fast read unless configured otherwise.
+    const FAST_READ_DUMMY: u8 = 8;
+    /// The fastest clock plain reads are specified for across parts.
+    const PLAIN_READ_CLOCK: u32 = 50_000_000;
+    /// Write enable for the volatile copies of the status registers, on parts that have them.
+    const VOLATILE_WRITE_ENABLE: u8 = 0x50;
+
+    /// The number of bytes read each way to check dual and quad output reads.
+    const WIDTH_CHECK_SIZE: usize = 256;
 
-    pub fn new() -> Result<Self> {
+    pub fn new(pins: &PinConfig, timing: &Timing, trace: Option<Trace>) -> Result<Self> {
+        let hardware = pins.flash_hardware_spi()?;
+        Claims::flash(pins).check()?;
+        Self::connect(
+            pins,
+            timing,
+            pins.flash_cs,
+            Some(pins.fpga_cs),
+            &[],
+            true,
+            hardware,
+            trace,
+        )
+    }
+
+    /// Connect without touching CRESET_B, for reading the flash while the FPGA keeps running,
+    /// once the FPGA's chip select has stayed inactive for a while.
+    ///
+    /// This is only safe on boards where the running design tri-states its flash pins, and the
+    /// programmer refuses every write, including the quad enable bit while connecting.
+    pub fn live(pins: &PinConfig, timing: &Timing, trace: Option<Trace>) -> Result<Self> {
+        let hardware = pins.flash_hardware_spi()?;
+        Claims::flash(pins).check()?;
+        check_bus_idle(pins)?;
+
+        Self::connect(
+            pins,
+            timing,
+            pins.flash_cs,
+            Some(pins.fpga_cs),
+            &[],
+            false,
+            hardware,
+            trace,
+        )
+    }
+
+    /// Refuse every write from now on, failing before anything is sent to the flash.
+    pub fn refuse_writes(&mut self) {
+        self.read_only = true;
+    }
+
+    /// Read the JEDEC ID with `cs` as the flash's chip select, holding each of `others` high
+    /// and the FPGA in reset, for finding a miswired chip select.
+    ///
+    /// Every pin returns to its original mode afterwards, whatever the outcome. The bus is
+    /// always bit-banged, since the SPI peripheral would also drive its own chip selects.
+    pub fn probe_cs(pins: &PinConfig, timing: &Timing, cs: u8, others: &[u8]) -> Result<FlashInfo> {
+        Self::connect(pins, timing, cs, None, others, true, false, None)?.info()
+    }
+
+    /// Acquire the control lines and the bus, over the SPI peripheral when `hardware`.
+    ///
+    /// Without `reset`, the FPGA keeps running, so every write is refused.
+    #[allow(clippy::too_many_arguments)]
+    fn connect(
+        pins: &PinConfig,
+        timing: &Timing,
+        cs: u8,
+        fpga_cs: Option<u8>,
+        held: &[u8],
+        reset: bool,
+        hardware: bool,
+        trace: Option<Trace>,
+    ) -> Result<Self> {
         let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
-        let mut fpga_reset = gpio
-            .get(6)
-            .with_context(|| "Failed to acquire FPGA reset pin")?
-            .into_output_high();
-        let fpga_cs = gpio
-            .get(13)
-            .with_context(|| "Failed to acquire FPGA CS pin")?
-            .into_input();
-        let flash_cs = gpio
-            .get(5)
-            .with_context(|| "Failed to acquire flash CS pin")?
-            .into_output_high();
-        let flash_sdi = gpio
-            .get(9)
+        // Acquired already asserted, so an FPGA held in reset by an earlier run is never let go
+        // long enough to start configuring and contend for the bus
+        let fpga_reset = reset
+            .then(|| {
+                gpio.get(pins.reset)
+                    .map(|pin| ActivePin::new(pin, pins.reset_active_low, true))
+            })
+            .transpose()
+            .with_context(|| "Failed to acquire FPGA reset pin")?;
+        let fpga_cs = fpga_cs
+            .map(|pin| gpio.get(pin).map(|pin| pin.into_input()))
+            .transpose()
+            .with_context(|| "Failed to acquire FPGA CS pin")?;
+        let held = held
+            .iter()
+            .map(|pin| {
+                gpio.get(*pin)
+                    .map(|pin| pin.into_output_high())
+                    .with_context(|| format!("Failed to acquire GPIO {pin}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let flash_cs = ActivePin::new(
+            gpio.get(cs)
+                .with_context(|| "Failed to acquire flash CS pin")?,
+            pins.flash_cs_active_low,
+            false,
+        );
+        let control = Control {
+            fpga_reset,
+            fpga_cs,
+            held,
+            flash_cs,
+            cs_setup: timing.cs_setup,
+            cs_hold: timing.cs_hold,
+        };
+
+        if hardware {
+            let bus = match pins.spi_bus {
+                SpiBus::Spi0 => Bus::Spi0,
+                SpiBus::Spi1 => Bus::Spi1,
+            };
+            let speed = timing.flash_clock.unwrap_or(SPI_SPEED);
+            let spi = Spi::new(bus, SlaveSelect::Ss0, speed, rppal::spi::Mode::Mode0)
+                .with_context(|| "Failed to acquire SPI for the flash")?;
+            verbose!("Driving the flash over {bus} at {speed} Hz");
+
+            // Here we allow the FPGA to reset and fail configuration, releasing the SPI bus
+            sleep(timing.settle);
+            if reset {
+                sleep(timing.reset_pulse);
+            }
+
+            return Self::open(
+                Box::new(HardwareSpi {
+                    control,
+                    spi,
+                    pending: Vec::new(),
+                }),
+                timing,
+                trace,
+                !reset,
+            );
+        }
+
+        let mut flash_sdi = gpio
+            .get(pins.flash_sdi)
             .with_context(|| "Failed to acquire flash SDI")?
-            .into_output_high();
+            .into_io(Mode::Output);
+        flash_sdi.set_high();
         let flash_sck = gpio
-            .get(11)
+            .get(pins.flash_sck)
             .with_context(|| "Failed to acquire flash SCK")?
             .into_output_low();
         let flash_sdo = gpio
-            .get(10)
+            .get(pins.flash_sdo)
             .with_context(|| "Failed to acquire flash SDO")?
             .into_input();
 
         // Here we allow the FPGA to reset and fail configuration, releasing the SPI bus
-        sleep(1);
-        fpga_reset.set_low();
-        sleep(1);
-        // fpga_reset.set_high();
-        // sleep(1000);
+        sleep(timing.settle);
+        if reset {
+            sleep(timing.reset_pulse);
+        }
+
+        Self::open(
+            Box::new(Pins {
+                control,
+                flash_sck,
+                flash_sdi,
+                flash_sdo,
+            }),
+            timing,
+            trace,
+            !reset,
+        )
+    }
 
+    /// Construct a programmer over an arbitrary port, waking and identifying the flash.
+    ///
+    /// When a trace is provided, every transaction (including the wake) is recorded into it.
+    /// The chip profile comes from `timing`, or is detected from the JEDEC ID when unset.
+    pub fn with_port(port: Box<dyn Port>, timing: &Timing, trace: Option<Trace>) -> Result<Self> {
+        Self::open(port, timing, trace, false)
+    }
+
+    /// Like [`with_port`](Self::with_port), but refusing every write, even setting the quad
+    /// enable bit for wider reads while identifying the flash.
+    pub fn with_port_read_only(
+        port: Box<dyn Port>,
+        timing: &Timing,
+        trace: Option<Trace>,
+    ) -> Result<Self> {
+        Self::open(port, timing, trace, true)
+    }
+
+    fn open(
+        port: Box<dyn Port>,
+        timing: &Timing,
+        trace: Option<Trace>,
+        read_only: bool,
+    ) -> Result<Self> {
         let mut programmer = Self {
-            fpga_reset,
-            fpga_cs,
-            flash_cs,
-            flash_sck,
-            flash_sdi,
-            flash_sdo,
+            port,
+            trace,
+            info: None,
+            profile: ChipProfile::Generic,
+            width: ReadWidth::Single,
+            fast_read: timing.fast_read.or_else(|| {
+                timing
+                    .flash_clock
+                    .filter(|clock| *clock > Self::PLAIN_READ_CLOCK)
+                    .map(|_| Self::FAST_READ_DUMMY)
+            }),
+            four_byte: false,
+            volatile_quad_enable: None,
+            busy: None,
+            #[cfg(not(feature = "read-only"))]
+            on_erase: None,
+            erases: timing.erases.clone(),
+            sfdp: None,
+            pending: None,
+            latency: Latency::default(),
+            slow_factor: timing.slow_factor,
+            read_only,
         };
 
-        programmer.flash_cs.set_low();
-        pin_sleep();
-        programmer.write(Self::WAKE);
-        programmer.flash_cs.set_high();
-        pin_sleep();
+        programmer.select()?;
+        programmer.write(Self::WAKE)?;
+        programmer.deselect()?;
+        sleep(timing.wake_delay);
+
+        let mut info = programmer.info()?;
+        if info.unresponsive() {
+            info = programmer.exit_modes()?;
+        }
+        programmer.profile = timing
+            .chip_profile
+            .unwrap_or_else(|| ChipProfile::detect(info.jedec));
+        match info.part() {
+            Some(part) => verbose!("Detected the {}", part.name),
+            None => verbose!(
+                "The flash with JEDEC ID {:02x?} isn't in the database, so its SFDP table \
+                 describes it",
+                info.jedec
+            ),
+        }
+        if info
+            .capacity()
+            .is_some_and(|capacity| capacity > THREE_BYTE_SPACE)
+        {
+            verbose!("The flash is larger than 16 MiB, so it's sent 4-byte addresses");
+            programmer.four_byte = true;
+        }
+
+        let widest = programmer.port.read_width();
+        if widest > ReadWidth::Single {
+            programmer.check_widths(widest)?;
+        }
 
         Ok(programmer)
     }
 
-    pub fn flash_data(&mut self, data: &[u8], address: usize) -> Result<()> {
-        let mut address_offset = 0;
+    /// Work through [`EXIT_SEQUENCES`] until the flash identifies itself, reporting which one
+    /// brought it back.
+    fn exit_modes(&mut self) -> Result<FlashInfo> {
+        let id = self.info()?.jedec;
+        verbose!(
+            "The JEDEC ID reads {id:02x?}, trying to bring the flash out of modes that ignore \
+             standard opcodes"
+        );
 
-        let bar = indicatif::ProgressBar::new(data.len() as u64);
+        for (name, commands) in EXIT_SEQUENCES {
+            verbose!("Trying the {name}: {commands:02x?}");
+            for command in commands {
+                self.select()?;
+                for byte in command.iter() {
+                    self.write(*byte)?;
+                }
+                self.deselect()?;
+            }
+            sleep(EXIT_DELAY);
 
-        for block in data.chunks(65536) {
-            self.await_ready();
-            self.erase_block(address + address_offset);
+            self.info = None;
+            let info = self.refresh()?;
+            verbose!("JEDEC ID after the {name}: {:02x?}", info.jedec);
 
-            for page in block.chunks(256) {
-                self.await_ready();
-                self.write_page(page, address + address_offset)?;
-                address_offset += page.len();
-                bar.inc(page.len() as u64);
+            if !info.unresponsive() {
+                status!("The flash responded again after the {name}");
+                return Ok(info);
+            }
+        }
+
+        let names: Vec<_> = EXIT_SEQUENCES.iter().map(|(name, _)| *name).collect();
+        warning!(
+            "The flash still reads JEDEC ID {id:02x?} after trying the {}",
+            names.join(", the ")
+        );
+        self.info()
+    }
+
+    /// Switch to the widest read, up to `widest`, that reads the start of the flash back the
+    /// same as single reads, staying with single reads otherwise.
+    ///
+    /// Quad reads that disagree are only noted, since boards often leave IO2 and IO3 on pull-ups
+    /// rather than wiring them to the host.
+    fn check_widths(&mut self, widest: ReadWidth) -> Result<()> {
+        let single = self.read_arbitrary(FlashAddress::ZERO, Self::WIDTH_CHECK_SIZE)?;
+
+        for width in [ReadWidth::Quad, ReadWidth::Dual] {
+            if width > widest || (width == ReadWidth::Quad && !self.quad_enable()?) {
+                continue;
+            }
+
+            self.width = width;
+            if self.read_arbitrary(FlashAddress::ZERO, Self::WIDTH_CHECK_SIZE)? == single {
+                verbose!("Reading with {} reads", width.name());
+                return Ok(());
+            }
+            self.width = ReadWidth::Single;
+
+            if width == ReadWidth::Quad {
+                verbose!(
+                    "Quad output reads disagree with single reads, so IO2 and IO3 may not be wired"
+                );
+            } else {
+                warning!(
+                    "Dual output reads disagree with single reads, falling back to single reads"
+                );
             }
         }
 
         Ok(())
     }
 
-    pub fn verify_data(&mut self, data: &[u8], address: usize) -> Result<()> {
+    /// Make sure the quad enable bit is set, returning whether it is.
+    ///
+    /// A clear bit is only set in the volatile status registers, so it's gone at the next
+    /// power cycle rather than leaving WP# and HOLD# disabled for good. Parts whose bit is only
+    /// non-volatile are left alone.
+    fn quad_enable(&mut self) -> Result<bool> {
+        let quad_enable = match self.sfdp()?.and_then(|parameters| parameters.quad_enable) {
+            Some(quad_enable) => quad_enable,
+            None => match self.profile {
+                ChipProfile::W25q => QuadEnable::Status2Bit1,
+                ChipProfile::Mx25 => QuadEnable::Status1Bit6,
+                ChipProfile::Generic => {
+                    verbose!("The flash doesn't say where its quad enable bit is");
+                    return Ok(false);
+                }
+            },
+        };
+        let Some((read, mask)) = quad_enable.location() else {
+            return Ok(true);
+        };
+
+        if self.read_register(read)? & mask != 0 {
+            return Ok(true);
+        }
+        if cfg!(feature = "read-only") || self.read_only || quad_enable == QuadEnable::Status1Bit6 {
+            verbose!("The quad enable bit, {quad_enable}, is clear and left alone");
+            return Ok(false);
+        }
+
+        let first = self.read_register(Self::READ_STATUS_1)?;
+        let value = self.read_register(read)? | mask;
+        self.select()?;
+        self.write(Self::VOLATILE_WRITE_ENABLE)?;
+        self.deselect()?;
+        self.select()?;
+        match quad_enable {
+            QuadEnable::Status2Bit1Pair => {
+                self.write(0x01)?;
+                self.write(first)?;
+                self.write(value)?;
+            }
+            QuadEnable::Status2Bit7 => {
+                self.write(0x3E)?;
+                self.write(value)?;
+            }
+            _ => {
+                self.write(0x31)?;
+                self.write(value)?;
+            }
+        }
+        self.deselect()?;
+
+        let set = self.read_register(read)? & mask != 0;
+        if set {
+            self.volatile_quad_enable = Some((read, mask));
+        }
+        verbose!(
+            "{} the quad enable bit in the volatile status registers ({quad_enable})",
+            if set { "Set" } else { "Failed to set" }
+        );
+        Ok(set)
+    }
+
+    /// Stop recording, returning the transactions captured so far.
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    /// Resume recording into a trace previously returned by [`FlashProgrammer::take_trace`].
+    pub fn resume_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    /// Compare every byte of the flash against `data`, other than those in `mask`.
+    ///
+    /// `cancel` is checked before each page.
+    pub fn verify_data(
+        &mut self,
+        data: &[u8],
+        address: FlashAddress,
+        mask: &Mask,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
         let mut address_offset = 0;
+        address.end(data.len())?;
 
-        let bar = indicatif::ProgressBar::new(data.len() as u64);
-        self.await_ready();
+        let mut bar = Progress::bytes("verify", data.len());
+        self.await_ready()?;
 
         for input in data.chunks(256) {
-            let read = self.read_page(address + address_offset);
+            let current = address.offset(address_offset)?;
+            cancel.check(current.get())?;
+            report::beat("verify", current.get());
+            if !mask.covers(address_offset, input.len()) {
+                let read = self.read_page(current)?;
 
-            for (i, (input, read)) in input.iter().zip(read.iter()).enumerate() {
-                if input != read {
-                    anyhow::bail!(
-                        "Verification error at page {}, index {}: expected {input} but got {read}",
+                if let Some(i) = mask.mismatch(address_offset, input, &read) {
+                    bail!(
+                        "Verification error at page {}, index {i}: expected {} but got {}",
                         address_offset / 256,
-                        i + address_offset
+                        data[i],
+                        read[i - address_offset]
                     );
                 }
             }
 
             address_offset += input.len();
-            bar.inc(input.len() as u64);
+            bar.inc(input.len());
         }
 
         Ok(())
     }
 
-    fn read(&mut self) -> u8 {
-        let mut value = 0;
-        for i in 0..8 {
-            self.flash_sck.set_high();
-            pin_sleep();
-            let level: u8 = matches!(self.flash_sdo.read(), rppal::gpio::Level::High) as u8;
-            value |= level;
-            if i < 7 {
-                value <<= 1;
+    /// Compare the pages at the given offsets of `data` against the flash, other than bytes in
+    /// `mask`.
+    pub fn verify_pages(
+        &mut self,
+        data: &[u8],
+        address: FlashAddress,
+        pages: &[usize],
+        mask: &Mask,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        address.end(data.len())?;
+        let mut bar = Progress::count("verify", pages.len());
+        self.await_ready()?;
+
+        for &offset in pages {
+            let current = address.offset(offset)?;
+            cancel.check(current.get())?;
+            report::beat("verify", current.get());
+            let input = &data[offset..(offset + 256).min(data.len())];
+
+            if !mask.covers(offset, input.len()) {
+                let read = self.read_page(current)?;
+
+                if let Some(i) = mask.mismatch(offset, input, &read) {
+                    bail!(
+                        "Verification error at page {}, index {i}: expected {} but got {}",
+                        offset / 256,
+                        data[i],
+                        read[i - offset]
+                    );
+                }
             }
-            self.flash_sck.set_low();
-            pin_sleep();
+
+            bar.inc(1);
         }
-        value
+
+        Ok(())
     }
 
-    fn write(&mut self, byte: u8) {
-        for i in (0..8).rev() {
-            let level = (byte & (1 << i)) > 0;
-            self.flash_sdi.write(level.into());
-            self.flash_sck.set_high();
-            pin_sleep();
+    /// Verify the flash against `source` one window at a time, keeping memory use flat.
+    ///
+    /// Each window of the source and the flash is hashed and only the digests compared. A
+    /// mismatched window is read again and compared byte by byte to report the exact offset.
+    /// Masked bytes are zeroed on both sides before hashing, and fully masked windows skipped.
+    /// `cancel` is checked before each window.
+    pub fn verify_windowed(
+        &mut self,
+        source: &mut impl std::io::Read,
+        length: usize,
+        address: FlashAddress,
+        mask: &Mask,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let mut expected = vec![0; VerifyMode::WINDOW_SIZE];
+        address.end(length)?;
 
-            self.flash_sck.set_low();
-            pin_sleep();
+        let mut bar = Progress::bytes("verify", length);
+        self.await_ready()?;
+
+        for (offset, window) in windows(length, VerifyMode::WINDOW_SIZE) {
+            let current = address.offset(offset)?;
+            cancel.check(current.get())?;
+            report::beat("verify", current.get());
+            let expected = &mut expected[..window];
+            source
+                .read_exact(expected)
+                .with_context(|| "Error reading the verification source")?;
+
+            if mask.covers(offset, window) {
+                bar.inc(window);
+                continue;
+            }
+            mask.clear(offset, expected);
+
+            let mut hasher = Sha256::new();
+            let mut read = 0;
+            while read < window {
+                let chunk = (window - read).min(4096);
+                let mut bytes = self.read_arbitrary(current.offset(read)?, chunk)?;
+                mask.clear(offset + read, &mut bytes);
+                hasher.update(bytes);
+                read += chunk;
+            }
+
+            if hasher.finalize()[..] != Sha256::digest(&*expected)[..] {
+                let actual = self.read_arbitrary(current, window)?;
+                let i = mask.mismatch(offset, expected, &actual).with_context(|| {
+                    format!(
+                        "Verification error in the window at {current:#x}: the digests differ, \
+                         but a second read matched (unstable reads?)"
+                    )
+                })?;
+
+                bail!(
+                    "Verification error at page {}, index {i}: expected {} but got {}",
+                    i / 256,
+                    expected[i - offset],
+                    actual[i - offset]
+                );
+            }
+
+            bar.inc(window);
         }
+
+        Ok(())
     }
 
-    fn write_address(&mut self, address: usize) {
-        self.write((address >> 16) as u8);
-        self.write((address >> 8) as u8);
-        self.write(address as u8);
+    /// Read a flash range in small chunks, passing each to `sink` as it arrives.
+    pub fn stream(
+        &mut self,
+        address: FlashAddress,
+        length: usize,
+        mut sink: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> Result<()> {
+        let mut address_offset = 0;
+        address.end(length)?;
+
+        let mut bar = Progress::bytes("read", length);
+        self.await_ready()?;
+
+        while address_offset < length {
+            let chunk = (length - address_offset).min(4096);
+            sink(&self.read_arbitrary(address.offset(address_offset)?, chunk)?)?;
+            address_offset += chunk;
+            bar.inc(chunk);
+        }
+
+        Ok(())
     }
 
-    fn write_page(&mut self, data: &[u8], address: usize) -> anyhow::Result<()> {
-        if data.len() > 256 {
-            anyhow::bail!("Page data must not exceed 256 bytes");
+    /// Compute the digest of a flash range with `mask` applied, as [`Mask::digest`] does for
+    /// the image, streaming it in small reads.
+    pub fn hash_range(
+        &mut self,
+        address: FlashAddress,
+        length: usize,
+        mask: &Mask,
+    ) -> Result<[u8; 32]> {
+        address.end(length)?;
+        let mut bar = Progress::bytes("read", length);
+        self.await_ready()?;
+
+        let digest = mask.digest(length, |offset, window| {
+            let bytes = self.read_arbitrary(address.offset(offset)?, window)?;
+            bar.inc(window);
+            Ok(bytes)
+        })?;
+        Ok(digest)
+    }
+
+    fn select(&mut self) -> Result<()> {
+        if let Some(trace) = &mut self.trace {
+            trace.transactions.push(Transaction::default());
         }
+        Ok(self.port.select()?)
+    }
 
-        self.write_enable();
+    fn deselect(&mut self) -> Result<()> {
+        Ok(self.port.deselect()?)
+    }
 
-        self.flash_cs.set_low();
-        pin_sleep();
-        self.write(Self::PROGRAM);
+    fn read(&mut self) -> Result<u8> {
+        let value = self.port.read()?;
+        if let Some(transaction) = self.trace.as_mut().and_then(|t| t.transactions.last_mut()) {
+            transaction.read.push(value);
+        }
+        Ok(value)
+    }
 
-        self.write_address(address);
+    /// Fill `buffer` with the data of a read started with [`Self::begin_read`], as one
+    /// transfer where the port supports it.
+    fn read_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+        if self.width == ReadWidth::Single {
+            self.port.read_into(buffer)?;
+        } else {
+            self.port.read_wide(self.width, buffer)?;
+        }
+        if let Some(transaction) = self.trace.as_mut().and_then(|t| t.transactions.last_mut()) {
+            transaction.read.extend_from_slice(buffer);
+        }
+        Ok(())
+    }
 
-        for byte in data {
-            self.write(*byte);
+    fn write(&mut self, byte: u8) -> Result<()> {
+        if let Some(transaction) = self.trace.as_mut().and_then(|t| t.transactions.last_mut()) {
+            transaction.write.push(byte);
         }
-        self.flash_cs.set_high();
-        pin_sleep();
+        Ok(self.port.write(byte)?)
+    }
 
+    /// Write `opcode` followed by `address`, switching to the opcode's 4-byte address form on
+    /// flash larger than 16 MiB.
+    ///
+    /// The 4-byte opcodes leave the flash in its power-on 3-byte mode, which the FPGA expects
+    /// when it configures itself, where entering 4-byte mode with 0xB7 would have to be undone
+    /// before every hand-off.
+    fn write_addressed(&mut self, opcode: u8, address: FlashAddress) -> Result<()> {
+        let bytes = address.bytes();
+        if self.four_byte {
+            let opcode = FOUR_BYTE_OPCODES
+                .iter()
+                .find(|(three, _)| *three == opcode)
+                .map(|(_, four)| *four)
+                .with_context(|| format!("Opcode {opcode:#04x} has no 4-byte address form"))?;
+            self.write(opcode)?;
+            for byte in bytes {
+                self.write(byte)?;
+            }
+        } else {
+            if address.get() >= THREE_BYTE_SPACE {
+                bail!(
+                    "{address:#x} is beyond the 16 MiB reachable with 3-byte addresses, and the \
+                     flash doesn't report a capacity over 16 MiB"
+                );
+            }
+            self.write(opcode)?;
+            for byte in &bytes[1..] {
+                self.write(*byte)?;
+            }
+        }
         Ok(())
     }
 
-    fn status(&mut self) -> u8 {
-        self.flash_cs.set_low();
-        pin_sleep();
-        self.write(Self::READ_STATUS_1);
-        let output = self.read();
-        self.flash_cs.set_high();
-        pin_sleep();
-        output
+    fn status(&mut self) -> Result<u8> {
+        self.read_register(Self::READ_STATUS_1)
     }
 
-    fn write_enable(&mut self) {
-        self.flash_cs.set_low();
-        pin_sleep();
-        self.write(Self::WRITE_ENABLE);
-        self.flash_cs.set_high();
-        pin_sleep();
+    /// Read the status or configuration register selected by `opcode`.
+    pub fn read_register(&mut self, opcode: u8) -> Result<u8> {
+        self.select()?;
+        self.write(opcode)?;
+        let output = self.read()?;
+        self.deselect()?;
+        Ok(output)
+    }
+
+    /// Start a read at `address`, with Fast Read, or Fast Read Dual or Quad Output, when
+    /// enabled.
+    fn begin_read(&mut self, address: FlashAddress) -> Result<()> {
+        let (opcode, dummy) = match (self.width, self.fast_read) {
+            (ReadWidth::Single, None) => (self.width.opcode(), 0),
+            (ReadWidth::Single, Some(dummy)) => (Self::FAST_READ, dummy),
+            (width, dummy) => (width.opcode(), dummy.unwrap_or(Self::FAST_READ_DUMMY)),
+        };
+
+        self.write_addressed(opcode, address)?;
+        for _ in 0..dummy / 8 {
+            self.write(0)?;
+        }
+        Ok(())
     }
 
-    fn read_page(&mut self, address: usize) -> [u8; 256] {
+    fn read_page(&mut self, address: FlashAddress) -> Result<[u8; 256]> {
         let mut data = [0; 256];
+        address.end(data.len())?;
 
-        self.flash_cs.set_low();
-        pin_sleep();
-        self.write(Self::READ);
-        self.write_address(address);
+        self.select()?;
+        self.begin_read(address)?;
+        self.read_into(&mut data)?;
+        self.deselect()?;
 
-        for byte in data.iter_mut() {
-            *byte = self.read();
+        Ok(data)
+    }
+
+    /// Read `length` bytes starting at `address`, failing rather than wrapping around past
+    /// the end of the address space.
+    pub fn read_arbitrary(&mut self, address: FlashAddress, length: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        address.end(length)?;
+
+        self.select()?;
+        self.begin_read(address)?;
+
+        for (i, chunk) in data.chunks_mut(256).enumerate() {
+            report::beat("read", address.get() + i * 256);
+            self.read_into(chunk)?;
         }
-        self.flash_cs.set_high();
-        pin_sleep();
 
-        data
+        self.deselect()?;
+
+        Ok(data)
     }
 
-    pub fn read_arbitrary(&mut self, address: usize, length: usize) -> Vec<u8> {
-        let mut data = Vec::with_capacity(length);
+    /// Put the flash into deep power-down, where it ignores everything but the wake (0xAB)
+    /// sent on connecting.
+    pub fn power_down(&mut self) -> Result<()> {
+        self.select()?;
+        self.write(Self::POWER_DOWN)?;
+        self.deselect()
+    }
 
-        self.flash_cs.set_low();
-        pin_sleep();
-        self.write(Self::READ);
-        self.write_address(address);
+    /// Read the chip's factory-programmed unique ID, or `None` for chips without one.
+    pub fn unique_id(&mut self) -> Result<Option<[u8; 8]>> {
+        let mut id = [0; 8];
 
-        for _ in 0..length {
-            data.push(self.read());
+        self.select()?;
+        self.write(Self::READ_UNIQUE_ID)?;
+        for _ in 0..4 {
+            // Dummy bytes
+            self.write(0)?;
         }
+        for byte in id.iter_mut() {
+            *byte = self.read()?;
+        }
+        self.deselect()?;
 
-        self.flash_cs.set_high();
-        pin_sleep();
+        Ok((id != [0x00; 8] && id != [0xFF; 8]).then_some(id))
+    }
 
-        data
+    /// The flash's identification, probed on first use.
+    pub fn info(&mut self) -> Result<FlashInfo> {
+        match self.info {
+            Some(info) => Ok(info),
+            None => self.refresh(),
+        }
     }
 
-    fn erase_block(&mut self, address: usize) {
-        self.write_enable();
+    /// Probe the flash's identification again, such as after a software reset.
+    ///
+    /// A probe that disagrees with an earlier one points at a loose connection, so it's an
+    /// error rather than letting the session act on inconsistent information.
+    pub fn refresh(&mut self) -> Result<FlashInfo> {
+        let info = FlashInfo {
+            jedec: self.read_jedec_id()?,
+        };
 
-        self.flash_cs.set_low();
-        pin_sleep();
-        self.write(Self::BLOCK_ERASE);
-        self.write_address(address);
-        self.flash_cs.set_high();
-        pin_sleep();
+        if let Some(previous) = self.info.filter(|previous| *previous != info) {
+            bail!(
+                "The JEDEC ID changed from {:02x?} to {:02x?} during the session; check the wiring",
+                previous.jedec,
+                info.jedec
+            );
+        }
+
+        self.info = Some(info);
+        Ok(info)
+    }
+
+    /// Read the manufacturer and device ID.
+    fn read_jedec_id(&mut self) -> Result<[u8; 3]> {
+        let mut id = [0; 3];
+
+        self.select()?;
+        self.write(Self::READ_JEDEC_ID)?;
+        for byte in id.iter_mut() {
+            *byte = self.read()?;
+        }
+        self.deselect()?;
+
+        Ok(id)
     }
 
-    fn await_ready(&mut self) {
-        while (self.status() & 1) > 0 {}
+    /// The erase commands the flash implements, read from its SFDP table the first time.
+    pub fn erases(&mut self) -> Result<&[Erase]> {
+        if self.erases.is_none() {
+            let erases = self.read_erase_types()?;
+            verbose!(
+                "Erase commands: {}",
+                erases
+                    .iter()
+                    .map(Erase::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            self.erases = Some(erases);
+        }
+        if self.four_byte {
+            if let Some(erases) = &mut self.erases {
+                erases.retain(|erase| {
+                    FOUR_BYTE_OPCODES
+                        .iter()
+                        .any(|(three, _)| *three == erase.opcode)
+                });
+            }
+        }
+
+        Ok(self.erases.as_deref().unwrap_or_default())
     }
 
-    pub fn reset() -> anyhow::Result<()> {
+    /// The fewest erases covering `length` bytes at `address`, from those the flash
+    /// implements.
+    pub fn plan_erases(
+        &mut self,
+        address: FlashAddress,
+        length: usize,
+    ) -> Result<Vec<(FlashAddress, Erase)>> {
+        let available = self.erases()?.to_vec();
+        Ok(plan::erases(&available, address, length)?)
+    }
+
+    /// Read the erase types from the SFDP table, falling back to the part's entry in the
+    /// database and then to [`Erase::STANDARD`] without one.
+    fn read_erase_types(&mut self) -> Result<Vec<Erase>> {
+        let fallback = match self.info()?.part() {
+            Some(part) => (part.erases.to_vec(), part.name),
+            None => (Erase::STANDARD.to_vec(), "standard"),
+        };
+
+        let Some(parameters) = self.programming_sfdp()? else {
+            verbose!(
+                "The flash has no SFDP table, assuming the {} erase commands",
+                fallback.1
+            );
+            return Ok(fallback.0);
+        };
+
+        let erases = parameters.erases;
+        if erases.is_empty() {
+            verbose!(
+                "The SFDP table lists no erase types, assuming the {} erase commands",
+                fallback.1
+            );
+            return Ok(fallback.0);
+        }
+
+        Ok(erases)
+    }
+
+    /// The most bytes one page program writes: the part's from the database, or else from its
+    /// SFDP table, or else 256.
+    pub fn page_size(&mut self) -> Result<usize> {
+        if let Some(part) = self.info()?.part() {
+            return Ok(part.page_size);
+        }
+
+        Ok(self
+            .programming_sfdp()?
+            .and_then(|parameters| parameters.page_size)
+            .unwrap_or(plan::PAGE_SIZE))
+    }
+
+    /// The flash's SFDP parameters, read the first time, or `None` when it has no table.
+    pub fn sfdp(&mut self) -> Result<Option<Parameters>> {
+        if self.sfdp.is_none() {
+            let header = self.read_sfdp(0, sfdp::HEADER_SIZE)?;
+            let parameters = match sfdp::parameter_table(&header) {
+                Some((address, length)) => {
+                    let table = self.read_sfdp(address, length.min(sfdp::TABLE_END))?;
+                    Parameters::parse(&table)
+                }
+                None => None,
+            };
+            self.sfdp = Some(parameters);
+        }
+
+        Ok(self.sfdp.clone().flatten())
+    }
+
+    /// The SFDP parameters, failing for flash that can't be programmed with them.
+    fn programming_sfdp(&mut self) -> Result<Option<Parameters>> {
+        let parameters = self.sfdp()?;
+        if let Some(Parameters {
+            address_bytes: AddressBytes::Four,
+            ..
+        }) = parameters
+        {
+            if !self.four_byte {
+                bail!(
+                    "The flash's SFDP table says it only takes 4-byte addresses, but its capacity \
+                     doesn't need them, so commands are sent with 3-byte addresses"
+                );
+            }
+        }
+
+        Ok(parameters)
+    }
+
+    /// Write a 3-byte address for a command outside the memory array, which takes one even on
+    /// flash over 16 MiB, since it stays in 3-byte mode.
+    fn write_short_address(&mut self, address: usize) -> Result<()> {
+        for byte in &FlashAddress::new(address)?.bytes()[1..] {
+            self.write(*byte)?;
+        }
+        Ok(())
+    }
+
+    fn read_sfdp(&mut self, address: usize, length: usize) -> Result<Vec<u8>> {
+        self.select()?;
+        self.write(sfdp::READ_SFDP)?;
+        self.write_short_address(address)?;
+        // Dummy byte
+        self.write(0)?;
+        let data = (0..length).map(|_| self.read()).collect::<Result<_>>()?;
+        self.deselect()?;
+
+        Ok(data)
+    }
+
+    /// Read `length` bytes of the security registers from `address`, with Read Security
+    /// Registers.
+    pub fn read_security(&mut self, address: usize, length: usize) -> Result<Vec<u8>> {
+        self.select()?;
+        self.write(Self::READ_SECURITY)?;
+        self.write_short_address(address)?;
+        // Dummy byte
+        self.write(0)?;
+        let mut data = vec![0; length];
+        for byte in data.iter_mut() {
+            *byte = self.read()?;
+        }
+        self.deselect()?;
+
+        Ok(data)
+    }
+
+    /// The erase used for each 64 KiB block: the largest the flash implements, repeated
+    /// across the block when smaller.
+    pub fn block_erase(&mut self) -> Result<Erase> {
+        let erase = Erase::largest_within(self.erases()?, plan::BLOCK_SIZE).with_context(|| {
+            format!(
+                "The flash implements no erase of {} KiB or less",
+                plan::BLOCK_SIZE / 1024
+            )
+        })?;
+        Ok(erase)
+    }
+
+    /// Wait for the flash to finish the operation last started.
+    ///
+    /// Fails once the wait exceeds the profile's datasheet maximum for that operation.
+    pub fn await_ready(&mut self) -> Result<()> {
+        let busy = self.busy.take().unwrap_or(Busy::Pending);
+        let pending = self.pending.take();
+        let limit = self.profile.limits().get(busy);
+        let start = Instant::now();
+
+        while (self.status()? & 1) > 0 {
+            if start.elapsed() > limit {
+                bail!(
+                    "Timed out waiting for the {busy} after {:.2?} (the {} datasheet maximum is \
+                     {limit:?})",
+                    start.elapsed(),
+                    self.profile.name()
+                );
+            }
+        }
+
+        if let Some(Pending {
+            address,
+            started,
+            partial,
+        }) = pending
+        {
+            let elapsed = started.elapsed();
+            if busy == Busy::PageProgram {
+                self.latency.program(address, elapsed);
+            } else {
+                if !partial {
+                    self.latency.erase(address, elapsed);
+                }
+                #[cfg(not(feature = "read-only"))]
+                if let Some(on_erase) = &mut self.on_erase {
+                    on_erase(address, elapsed);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Warn about blocks whose erase or page programs took `slow_factor` times the session's
+    /// median or longer.
+    pub fn report_latency(&self) {
+        if let Some(description) = self.latency.describe() {
+            verbose!("{description}");
+        }
+        for outlier in self.latency.outliers(self.slow_factor) {
+            warning!("Slow flash: {outlier}");
+        }
+    }
+
+    /// Release the pins for the SRAM programmer, leaving the flash deselected and the FPGA
+    /// held in reset until the SRAM programmer takes over CRESET_B.
+    ///
+    /// This is the only place the pins change hands, so the ordering lives here: the flash CS
+    /// is released first, CRESET_B stays asserted throughout, and the bit-banged bus pins are
+    /// freed only as the programmer is dropped, before SPI0 is acquired.
+    pub fn hand_off(mut self) {
+        self.port.hand_off();
+    }
+
+    /// Release the programming pins to inputs, leaving CRESET_B untouched unless
+    /// `fpga_reset`.
+    pub fn reset(pins: &PinConfig, fpga_reset: bool) -> anyhow::Result<()> {
         let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
 
-        gpio.get(6)?.into_input().set_reset_on_drop(false);
-        gpio.get(13)?.into_input().set_reset_on_drop(false);
-        gpio.get(5)?.into_input().set_reset_on_drop(false);
-        gpio.get(9)?.into_input().set_reset_on_drop(false);
-        gpio.get(11)?.into_input().set_reset_on_drop(false);
-        gpio.get(10)?.into_input().set_reset_on_drop(false);
+        if fpga_reset {
+            gpio.get(pins.reset)?.into_input().set_reset_on_drop(false);
+        }
+        gpio.get(pins.fpga_cs)?
+            .into_input()
+            .set_reset_on_drop(false);
+        gpio.get(pins.flash_cs)?
+            .into_input()
+            .set_reset_on_drop(false);
+        for pin in [pins.flash_sdi, pins.flash_sck, pins.flash_sdo] {
+            gpio.get(pin)?.into_input().set_reset_on_drop(false);
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    const WINDOW: usize = VerifyMode::WINDOW_SIZE;
+
+    fn programmer(memory: Vec<u8>) -> FlashProgrammer {
+        FlashProgrammer::with_port(
+            Box::new(MockFlash::with_memory(memory)),
+            &Timing::default(),
+            None,
+        )
+        .unwrap()
+    }
+
+    /// A flash holding a pattern, and the image it holds at `address`.
+    fn flash_holding(address: usize, length: usize) -> (Vec<u8>, Vec<u8>) {
+        let memory: Vec<u8> = (0..4 * WINDOW).map(|i| (i * 7 + i / 251) as u8).collect();
+        let image = memory[address..address + length].to_vec();
+        (memory, image)
+    }
+
+    fn verify_windowed(memory: Vec<u8>, image: &[u8], address: usize, mask: &Mask) -> Result<()> {
+        programmer(memory).verify_windowed(
+            &mut &image[..],
+            image.len(),
+            FlashAddress::new(address).unwrap(),
+            mask,
+            &CancellationToken::new(),
+        )
+    }
+
+    #[test]
+    fn windows_cover_the_length() {
+        assert_eq!(windows(0, 4).count(), 0);
+        assert_eq!(windows(3, 4).collect::<Vec<_>>(), [(0, 3)]);
+        assert_eq!(windows(8, 4).collect::<Vec<_>>(), [(0, 4), (4, 4)]);
+        assert_eq!(windows(9, 4).collect::<Vec<_>>(), [(0, 4), (4, 4), (8, 1)]);
+    }
+
+    #[test]
+    fn windowed_verification_passes_matching_images() {
+        for length in [WINDOW - 1, WINDOW, WINDOW + 1, 2 * WINDOW + 300] {
+            let (memory, image) = flash_holding(0x1000, length);
+            verify_windowed(memory, &image, 0x1000, &Mask::EMPTY).unwrap();
+        }
+    }
+
+    #[test]
+    fn windowed_verification_reports_the_exact_offset() {
+        for length in [WINDOW - 1, WINDOW, WINDOW + 1] {
+            for offset in [0, WINDOW / 2, length - 1] {
+                let (mut memory, image) = flash_holding(0x1000, length);
+                memory[0x1000 + offset] ^= 0x5A;
+
+                let error = verify_windowed(memory, &image, 0x1000, &Mask::EMPTY).unwrap_err();
+                let expected = format!("page {}, index {offset}:", offset / 256);
+                assert!(
+                    error.to_string().contains(&expected),
+                    "{error} doesn't mention {expected}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn windowed_verification_skips_masked_differences() {
+        let (mut memory, image) = flash_holding(0, WINDOW + 1);
+        memory[WINDOW] ^= 0xFF;
+        let masked = crate::mask::parse_range(&format!("{WINDOW}:1")).unwrap();
+        let mask = Mask::new(vec![masked]);
+        verify_windowed(memory, &image, 0, &mask).unwrap();
+    }
+
+    /// The simulated flash, making reads up to `widest` lines wide, and garbling those of
+    /// width `garbled`, as a board with those lines unwired would.
+    struct Wide {
+        flash: MockFlash,
+        widest: ReadWidth,
+        garbled: Option<ReadWidth>,
+    }
+
+    impl Port for Wide {
+        fn select(&mut self) -> anyhow::Result<()> {
+            self.flash.select()
+        }
+
+        fn deselect(&mut self) -> anyhow::Result<()> {
+            self.flash.deselect()
+        }
+
+        fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+            self.flash.write(byte)
+        }
+
+        fn read(&mut self) -> anyhow::Result<u8> {
+            self.flash.read()
+        }
+
+        fn read_width(&self) -> ReadWidth {
+            self.widest
+        }
+
+        fn read_wide(&mut self, width: ReadWidth, buffer: &mut [u8]) -> anyhow::Result<()> {
+            self.flash.read_into(buffer)?;
+            if self.garbled == Some(width) {
+                buffer.iter_mut().for_each(|byte| *byte = !*byte);
+            }
+            Ok(())
+        }
+    }
+
+    /// A programmer over a [`Wide`] port, tracing what it sends, and the flash's contents.
+    fn wide(widest: ReadWidth, garbled: Option<ReadWidth>) -> (FlashProgrammer, Vec<u8>) {
+        let (memory, _) = flash_holding(0, 0);
+        let port = Wide {
+            flash: MockFlash::with_memory(memory.clone()),
+            widest,
+            garbled,
+        };
+        let programmer = FlashProgrammer::with_port(
+            Box::new(port),
+            &Timing::default(),
+            Some(Trace::new("dump", 0, 0)),
+        )
+        .unwrap();
+        (programmer, memory)
+    }
+
+    /// The command a read at 0x1000 sends, and the data it reads.
+    fn read_back(programmer: &mut FlashProgrammer) -> (Vec<u8>, Vec<u8>) {
+        programmer.take_trace();
+        programmer.trace = Some(Trace::new("dump", 0x1000, 512));
+        let data = programmer
+            .read_arbitrary(FlashAddress::new(0x1000).unwrap(), 512)
+            .unwrap();
+        let trace = programmer.take_trace().unwrap();
+        (trace.transactions[0].write.clone(), data)
+    }
+
+    #[test]
+    fn dual_reads_that_agree_are_used() {
+        let (mut programmer, memory) = wide(ReadWidth::Dual, None);
+        assert_eq!(programmer.width, ReadWidth::Dual);
+
+        // Fast Read Dual Output, the address, and a dummy byte
+        let (command, data) = read_back(&mut programmer);
+        assert_eq!(command, [0x3B, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(data, memory[0x1000..0x1200]);
+    }
+
+    #[test]
+    fn dual_reads_that_disagree_fall_back_to_single() {
+        let (mut programmer, memory) = wide(ReadWidth::Dual, Some(ReadWidth::Dual));
+        assert_eq!(programmer.width, ReadWidth::Single);
+
+        let (command, data) = read_back(&mut programmer);
+        assert_eq!(command, [0x03, 0x00, 0x10, 0x00]);
+        assert_eq!(data, memory[0x1000..0x1200]);
+    }
+
+    #[test]
+    fn quad_reads_that_disagree_fall_back_to_dual() {
+        let (mut programmer, memory) = wide(ReadWidth::Quad, Some(ReadWidth::Quad));
+        assert_eq!(programmer.width, ReadWidth::Dual);
+
+        let (command, data) = read_back(&mut programmer);
+        assert_eq!(command[0], 0x3B);
+        assert_eq!(data, memory[0x1000..0x1200]);
+    }
+
+    #[test]
+    fn read_only_programmers_leave_the_quad_enable_bit_alone() {
+        let connect = |read_only: bool| {
+            let port = Wide {
+                flash: MockFlash::with_memory(flash_holding(0, 0).0),
+                widest: ReadWidth::Quad,
+                garbled: None,
+            };
+            let trace = Some(Trace::new("dump", 0, 0));
+            let mut programmer = if read_only {
+                FlashProgrammer::with_port_read_only(Box::new(port), &Timing::default(), trace)
+            } else {
+                FlashProgrammer::with_port(Box::new(port), &Timing::default(), trace)
+            }
+            .unwrap();
+            let writes = programmer
+                .take_trace()
+                .unwrap()
+                .transactions
+                .iter()
+                .any(|t| matches!(t.write.first(), Some(0x01 | 0x06 | 0x31 | 0x3E | 0x50)));
+            (programmer.width, writes)
+        };
+
+        // The simulated flash starts with the bit clear, so only a programmer that can write
+        // gets quad reads
+        if !cfg!(feature = "read-only") {
+            assert_eq!(connect(false), (ReadWidth::Quad, true));
+        }
+        assert_eq!(connect(true), (ReadWidth::Dual, false));
+    }
+
+    #[test]
+    fn single_ports_are_left_alone() {
+        let (mut programmer, _) = wide(ReadWidth::Single, None);
+        assert_eq!(programmer.width, ReadWidth::Single);
+        assert_eq!(read_back(&mut programmer).0[0], 0x03);
+    }
+
+    /// Every command sent to a [`Locked`] flash, in order.
+    type Sent = Rc<RefCell<Vec<Vec<u8>>>>;
+
+    /// Whether `commands` were sent one after another somewhere in `sent`.
+    fn sent_in_order(sent: &[Vec<u8>], commands: &[&[u8]]) -> bool {
+        sent.windows(commands.len())
+            .any(|window| window.iter().zip(commands).all(|(a, b)| a == b))
+    }
+
+    /// The simulated flash stuck in a mode that ignores every command, reading as 0xFF, until
+    /// it's sent the commands of `exit`. Every command sent is recorded in `sent`.
+    struct Locked {
+        flash: MockFlash,
+        exit: Option<&'static [&'static [u8]]>,
+        command: Vec<u8>,
+        sent: Sent,
+    }
+
+    impl Locked {
+        fn new(exit: Option<&'static [&'static [u8]]>) -> (Self, Sent) {
+            let sent = Rc::default();
+            let port = Self {
+                flash: MockFlash::with_memory(vec![0xFF; 1 << 20]),
+                exit,
+                command: Vec::new(),
+                sent: Rc::clone(&sent),
+            };
+            (port, sent)
+        }
+    }
+
+    impl Port for Locked {
+        fn select(&mut self) -> anyhow::Result<()> {
+            self.command.clear();
+            match self.exit {
+                Some(_) => Ok(()),
+                None => self.flash.select(),
+            }
+        }
+
+        fn deselect(&mut self) -> anyhow::Result<()> {
+            let mut sent = self.sent.borrow_mut();
+            sent.push(std::mem::take(&mut self.command));
+            match self.exit {
+                Some(exit) => {
+                    let start = sent.len().saturating_sub(exit.len());
+                    if sent_in_order(&sent[start..], exit) {
+                        self.exit = None;
+                    }
+                    Ok(())
+                }
+                None => self.flash.deselect(),
+            }
+        }
+
+        fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+            self.command.push(byte);
+            match self.exit {
+                Some(_) => Ok(()),
+                None => self.flash.write(byte),
+            }
+        }
+
+        fn read(&mut self) -> anyhow::Result<u8> {
+            match self.exit {
+                Some(_) => Ok(0xFF),
+                None => self.flash.read(),
+            }
+        }
+    }
+
+    /// The names of the exit sequences sent, in the order of [`EXIT_SEQUENCES`].
+    fn sequences_sent(sent: &[Vec<u8>]) -> Vec<&'static str> {
+        EXIT_SEQUENCES
+            .iter()
+            .filter(|(_, commands)| sent_in_order(sent, commands))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    #[test]
+    fn each_exit_sequence_recovers_its_mode() {
+        for (index, (name, commands)) in EXIT_SEQUENCES.iter().enumerate() {
+            let (port, sent) = Locked::new(Some(commands));
+            let mut programmer =
+                FlashProgrammer::with_port(Box::new(port), &Timing::default(), None).unwrap();
+            assert!(!programmer.info().unwrap().unresponsive(), "{name}");
+
+            // The ladder stops at the sequence that brought the flash back
+            let names: Vec<_> = EXIT_SEQUENCES[..=index].iter().map(|(n, _)| *n).collect();
+            assert_eq!(sequences_sent(&sent.borrow()), names, "{name}");
+        }
+    }
+
+    #[test]
+    fn responsive_flash_skips_the_ladder() {
+        let (port, sent) = Locked::new(None);
+        FlashProgrammer::with_port(Box::new(port), &Timing::default(), None).unwrap();
+        assert!(sequences_sent(&sent.borrow()).is_empty());
+    }
+
+    #[test]
+    fn flash_that_never_recovers_stays_unresponsive() {
+        let (port, sent) = Locked::new(Some(&[&[0xAA]]));
+        let mut programmer =
+            FlashProgrammer::with_port(Box::new(port), &Timing::default(), None).unwrap();
+        assert!(programmer.info().unwrap().unresponsive());
+
+        let names: Vec<_> = EXIT_SEQUENCES.iter().map(|(n, _)| *n).collect();
+        assert_eq!(sequences_sent(&sent.borrow()), names);
+    }
+
+    /// The simulated flash, reporting itself busy to every status read once `stuck` is set.
+    struct Stuck {
+        flash: MockFlash,
+        stuck: Rc<Cell<bool>>,
+        status: bool,
+        first: bool,
+    }
+
+    impl Port for Stuck {
+        fn select(&mut self) -> anyhow::Result<()> {
+            self.first = true;
+            self.status = false;
+            self.flash.select()
+        }
+
+        fn deselect(&mut self) -> anyhow::Result<()> {
+            self.flash.deselect()
+        }
+
+        fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+            if std::mem::take(&mut self.first) {
+                self.status = byte == 0x05;
+            }
+            self.flash.write(byte)
+        }
+
+        fn read(&mut self) -> anyhow::Result<u8> {
+            let byte = self.flash.read()?;
+            Ok(if self.status && self.stuck.get() {
+                byte | 1
+            } else {
+                byte
+            })
+        }
+    }
+
+    #[test]
+    fn timeouts_name_the_class_and_its_limit() {
+        for profile in [ChipProfile::W25q, ChipProfile::Mx25, ChipProfile::Generic] {
+            let stuck = Rc::new(Cell::new(false));
+            let port = Stuck {
+                flash: MockFlash::with_memory(vec![0xFF; 1 << 20]),
+                stuck: stuck.clone(),
+                status: false,
+                first: false,
+            };
+            let timing = Timing {
+                chip_profile: Some(profile),
+                ..Timing::default()
+            };
+            let mut programmer = FlashProgrammer::with_port(Box::new(port), &timing, None).unwrap();
+
+            stuck.set(true);
+            programmer.busy = Some(Busy::PageProgram);
+            let start = Instant::now();
+            let error = programmer.await_ready().unwrap_err().to_string();
+            let limit = profile.limits().page_program;
+            assert!(start.elapsed() > limit);
+            assert!(
+                error.starts_with("Timed out waiting for the page program after "),
+                "{error}"
+            );
+            let maximum = format!("the {} datasheet maximum is {limit:?}", profile.name());
+            assert!(
+                error.contains(&maximum),
+                "{error} doesn't mention {maximum}"
+            );
+
+            // The class is used up by the wait, and the flash recovering ends the next one
+            stuck.set(false);
+            programmer.await_ready().unwrap();
+        }
+    }
+}
@@ -0,0 +1,834 @@
+//! Everything that modifies the flash, left out of `read-only` builds entirely so they can't
+//! erase or program it however they're invoked.
+
+use super::{FlashProgrammer, Pending, ReadWidth};
+use crate::address::FlashAddress;
+use crate::cancel::CancellationToken;
+use crate::chip::{Busy, Erase};
+use crate::error::bail;
+use crate::mask::Mask;
+use crate::plan;
+use crate::report::{self, Progress};
+use crate::{verbose, Result};
+use std::time::{Duration, Instant};
+
+impl FlashProgrammer {
+    const PROGRAM: u8 = 0x02;
+    const WRITE_ENABLE: u8 = 0x06;
+    const CHIP_ERASE: u8 = 0xC7;
+    const PROGRAM_SECURITY: u8 = 0x42;
+    const ERASE_SECURITY: u8 = 0x44;
+    /// How often the status is polled during a chip erase, which takes seconds to minutes.
+    const CHIP_ERASE_POLL: Duration = Duration::from_millis(100);
+    /// The number of bytes read back after the first erase to check it took effect.
+    const ERASE_CHECK_SIZE: usize = 256;
+
+    /// Write several `(data, address)` images in one session.
+    ///
+    /// Every block any image touches is erased once up front, and only then are the images
+    /// programmed, so images sharing an erase block don't wipe one another. Without `erase`,
+    /// the flash is assumed blank and pages are programmed straight away.
+    ///
+    /// Pages of the first image entirely within `holes` are left erased rather than
+    /// programmed, and the number of bytes skipped is returned. `cancel` is checked before
+    /// each erase and page program.
+    pub fn flash_images(
+        &mut self,
+        images: &[(&[u8], FlashAddress)],
+        erase: bool,
+        holes: &Mask,
+        cancel: &CancellationToken,
+    ) -> Result<usize> {
+        let ranges: Vec<_> = images
+            .iter()
+            .map(|(data, address)| (*address, data.len()))
+            .collect();
+        // Planned either way, since that also rejects overlapping images
+        let spans = plan::erase_spans(&ranges)?;
+        let spans = if erase { spans } else { Vec::new() };
+
+        let mut bar = Progress::bytes("program", ranges.iter().map(|(_, l)| l).sum());
+        self.erase_spans(&spans, cancel)?;
+
+        let page_size = self.page_size()?;
+        let mut skipped = 0;
+        for (i, (data, start)) in images.iter().enumerate() {
+            let mut completed = start.get();
+            for (address, page) in plan::pages(data, *start, page_size)? {
+                if i == 0 && holes.covers(address.get() - start.get(), page.len()) {
+                    skipped += page.len();
+                    bar.inc(page.len());
+                    continue;
+                }
+
+                self.check_cancelled(cancel, completed)?;
+                report::beat("program", address.get());
+                self.await_ready()?;
+                self.write_page(page, address)?;
+                completed = address.get() + page.len();
+                bar.inc(page.len());
+            }
+        }
+
+        self.await_ready()?;
+        self.report_latency();
+        Ok(skipped)
+    }
+
+    /// Erase exactly `length` bytes at `address`, which must lie on the flash's erase
+    /// boundaries. `cancel` is checked before each block.
+    pub fn erase_range(
+        &mut self,
+        address: FlashAddress,
+        length: usize,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let end = address.end(length)?;
+        let planned = self.plan_erases(address, length)?;
+        if let (Some((first, _)), Some((last, erase))) = (planned.first(), planned.last()) {
+            let erased_end = last.get() + erase.size;
+            if first.get() != address.get() || erased_end != end {
+                bail!(
+                    "Erasing {address:#x}..{end:#x} would also erase outside it, from \
+                     {:#x}..{erased_end:#x}, since it isn't aligned to the flash's erases",
+                    first.get()
+                );
+            }
+        }
+
+        let spans = plan::erase_spans(&[(address, length)])?;
+        self.erase_spans(&spans, cancel)?;
+        self.await_ready()
+    }
+
+    /// Erase each of `spans`, one block or part of one, as planned by [`plan::erase_spans`].
+    fn erase_spans(
+        &mut self,
+        spans: &[(FlashAddress, usize)],
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let mut erases = Progress::events("erase", spans.len());
+
+        let mut completed = spans.first().map_or(0, |(address, _)| address.get());
+        for (i, &(address, length)) in spans.iter().enumerate() {
+            self.check_cancelled(cancel, completed)?;
+            report::beat("erase", address.get());
+            self.await_ready()?;
+            let planned = self.plan_erases(address, length)?;
+            verbose!(
+                "Erasing {length:#x} bytes at {address:#x} with {}",
+                planned
+                    .iter()
+                    .map(|(address, erase)| format!("{erase} at {address:#x}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            self.erase_planned(&planned)?;
+            if i == 0 {
+                self.check_erased(planned[0].0, planned[0].1)?;
+            }
+            completed = address.get() + length;
+            erases.inc(1);
+        }
+
+        Ok(())
+    }
+
+    /// Check that the first `erase` issued at `address` reads back blank, failing straight
+    /// away rather than after minutes of writes when the flash isn't taking writes or its
+    /// reads are bogus.
+    pub fn check_erased(&mut self, address: FlashAddress, erase: Erase) -> Result<()> {
+        self.await_ready()?;
+        let canary = self.read_arbitrary(address, Self::ERASE_CHECK_SIZE)?;
+        if let Some(offset) = canary.iter().position(|b| *b != 0xFF) {
+            bail!(
+                "Flash contents did not change after erase: {:#x} still reads {:#04x} after \
+                 erasing {address:#x} with opcode {erase}. The flash may not implement that erase \
+                 (see --erase-opcodes), SDO may be disconnected or shorted, or writes are being \
+                 ignored (check WP#, block protection, which `unprotect` clears, and wiring)",
+                address.get() + offset,
+                canary[offset]
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check that the start of the flash reads back blank after a chip erase.
+    pub fn check_chip_erased(&mut self) -> Result<()> {
+        self.await_ready()?;
+        let canary = self.read_arbitrary(FlashAddress::ZERO, Self::ERASE_CHECK_SIZE)?;
+        if let Some(offset) = canary.iter().position(|b| *b != 0xFF) {
+            bail!(
+                "Flash contents did not change after the chip erase: {offset:#x} still reads \
+                 {:#04x}. Writes are being ignored (check WP#, block protection, which `unprotect` \
+                 clears, and wiring)",
+                canary[offset]
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Once `cancel` is set, let the operation in flight finish before failing with
+    /// [`Cancelled`](crate::cancel::Cancelled).
+    pub fn check_cancelled(&mut self, cancel: &CancellationToken, completed: usize) -> Result<()> {
+        if cancel.is_cancelled() {
+            self.await_ready()?;
+        }
+        Ok(cancel.check(completed)?)
+    }
+
+    pub fn write_page(&mut self, data: &[u8], address: FlashAddress) -> anyhow::Result<()> {
+        let page_size = self.page_size()?;
+        if data.len() > page_size {
+            bail!("Page data must not exceed {page_size} bytes");
+        }
+        address.end(data.len())?;
+
+        self.write_enable()?;
+
+        self.select()?;
+        self.write_addressed(Self::PROGRAM, address)?;
+
+        for byte in data {
+            self.write(*byte)?;
+        }
+        self.busy = Some(Busy::PageProgram);
+        self.deselect()?;
+        self.pending = Some(Pending {
+            address,
+            started: Instant::now(),
+            partial: false,
+        });
+        Ok(())
+    }
+
+    /// Write `values` to the status or configuration registers with `opcode`, non-volatilely.
+    ///
+    /// A quad enable bit set only in the volatile registers is cleared from the values, since
+    /// they're read back from the volatile registers. The write replaces the volatile copies
+    /// too, so reads go back to single reads.
+    pub fn write_register(&mut self, opcode: u8, values: &[u8]) -> Result<()> {
+        let mut values = values.to_vec();
+        if let Some((read, mask)) = self.volatile_quad_enable.take() {
+            if self.width == ReadWidth::Quad {
+                verbose!("Writing the status registers clears the quad enable bit, so reads go back to single reads");
+                self.width = ReadWidth::Single;
+            }
+            // The registers each write opcode sets, in order, by the opcode reading them
+            let registers: &[u8] = match opcode {
+                0x01 => &[0x05, 0x35],
+                0x31 => &[0x35],
+                0x3E => &[0x3F],
+                _ => &[],
+            };
+            for (value, register) in values.iter_mut().zip(registers) {
+                if *register == read {
+                    *value &= !mask;
+                }
+            }
+        }
+
+        self.write_enable()?;
+
+        self.select()?;
+        self.write(opcode)?;
+        for value in &values {
+            self.write(*value)?;
+        }
+        self.deselect_erasing()
+    }
+
+    /// Program `data` into the security registers at `address`, within one register.
+    pub fn program_security(&mut self, address: usize, data: &[u8]) -> Result<()> {
+        self.write_enable()?;
+
+        self.select()?;
+        self.write(Self::PROGRAM_SECURITY)?;
+        self.write_short_address(address)?;
+        for byte in data {
+            self.write(*byte)?;
+        }
+        self.busy = Some(Busy::PageProgram);
+        self.deselect()
+    }
+
+    /// Erase the security register holding `address`.
+    pub fn erase_security(&mut self, address: usize) -> Result<()> {
+        self.write_enable()?;
+
+        self.select()?;
+        self.write(Self::ERASE_SECURITY)?;
+        self.write_short_address(address)?;
+        self.deselect_erasing()
+    }
+
+    /// End a command the flash then stays busy with for up to the block erase time.
+    ///
+    /// Sector and half block erases, security register erases, and status register writes each
+    /// have their own limit in datasheets, but the block erase's is always the longer, so it
+    /// bounds them all.
+    fn deselect_erasing(&mut self) -> Result<()> {
+        self.busy = Some(Busy::BlockErase);
+        self.deselect()
+    }
+
+    fn write_enable(&mut self) -> Result<()> {
+        if self.read_only {
+            bail!("Writes are refused while the FPGA keeps running (--no-fpga-reset)");
+        }
+
+        self.select()?;
+        self.write(Self::WRITE_ENABLE)?;
+        self.deselect()
+    }
+
+    /// Erase the 64 KiB block at `address`, with 4 or 32 KiB erases on flash without a
+    /// block erase.
+    pub fn erase_block(&mut self, address: FlashAddress) -> Result<()> {
+        let erases = self.plan_erases(address, plan::BLOCK_SIZE)?;
+        self.erase_planned(&erases)
+    }
+
+    /// Issue `erases`, all within one block, as planned by [`plan::erases`].
+    ///
+    /// They're timed and reported to the [`FlashProgrammer::on_erase`] hook together, as one
+    /// erase of their block.
+    pub fn erase_planned(&mut self, erases: &[(FlashAddress, Erase)]) -> Result<()> {
+        let Some((first, _)) = erases.first() else {
+            return Ok(());
+        };
+
+        let started = Instant::now();
+        for (i, (address, erase)) in erases.iter().enumerate() {
+            if i > 0 {
+                self.await_ready()?;
+            }
+            self.erase(*erase, *address)?;
+        }
+
+        let size: usize = erases.iter().map(|(_, erase)| erase.size).sum();
+        self.pending = Some(Pending {
+            address: first.align_down(plan::BLOCK_SIZE),
+            started,
+            partial: size < plan::BLOCK_SIZE,
+        });
+        Ok(())
+    }
+
+    /// Erase the 4 KiB sector at `address`, for data kept apart from the 64 KiB blocks images
+    /// are written in.
+    ///
+    /// Sector erases aren't reported to the [`FlashProgrammer::on_erase`] hook, which counts
+    /// whole blocks.
+    pub fn erase_sector(&mut self, address: FlashAddress) -> Result<()> {
+        self.erase(Erase::SECTOR, address)
+    }
+
+    fn erase(&mut self, erase: Erase, address: FlashAddress) -> Result<()> {
+        self.write_enable()?;
+
+        self.select()?;
+        self.write_addressed(erase.opcode, address)?;
+        self.deselect_erasing()
+    }
+
+    /// Observe every erase of all or part of a block from now on, once it completes, such as
+    /// to count wear.
+    pub fn on_erase(&mut self, hook: Box<dyn FnMut(FlashAddress, Duration)>) {
+        self.on_erase = Some(hook);
+    }
+
+    /// Erase the whole chip and wait for it to finish, showing the elapsed seconds against the
+    /// profile's datasheet maximum. Returns how long the erase took.
+    pub fn chip_erase(&mut self) -> Result<Duration> {
+        self.await_ready()?;
+        self.start_chip_erase()?;
+        self.busy = None;
+
+        let started = Instant::now();
+        let limit = self.profile.limits().get(Busy::ChipErase);
+        let mut bar = Progress::count("chip erase", limit.as_secs() as usize);
+        let mut shown = 0;
+        while (self.status()? & 1) > 0 {
+            let elapsed = started.elapsed();
+            if elapsed > limit {
+                bail!(
+                    "Timed out waiting for the chip erase after {elapsed:.2?} (the {} datasheet \
+                     maximum is {limit:?})",
+                    self.profile.name()
+                );
+            }
+
+            let seconds = elapsed.as_secs() as usize;
+            if seconds > shown {
+                report::beat("chip erase", 0);
+                bar.inc(seconds - shown);
+                shown = seconds;
+            }
+            std::thread::sleep(Self::CHIP_ERASE_POLL);
+        }
+        bar.finish();
+
+        Ok(started.elapsed())
+    }
+
+    /// Issue a chip erase without waiting for it to complete.
+    pub fn start_chip_erase(&mut self) -> Result<()> {
+        self.write_enable()?;
+
+        self.select()?;
+        self.write(Self::CHIP_ERASE)?;
+        self.busy = Some(Busy::ChipErase);
+        self.deselect()
+    }
+
+    /// Opcodes that modify the flash, refused by [`FlashProgrammer::raw`] unless allowed.
+    pub const DESTRUCTIVE: [u8; 16] = [
+        Self::PROGRAM,
+        Erase::SECTOR.opcode,
+        Erase::HALF_BLOCK.opcode,
+        Erase::BLOCK.opcode,
+        0x60,
+        Self::CHIP_ERASE,
+        Self::PROGRAM_SECURITY,
+        Self::ERASE_SECURITY,
+        // The 4-byte address forms of page program and the erases
+        0x12,
+        0x21,
+        0x5C,
+        0xDC,
+        // The status register writes, including the 0x3E some parts keep their quad enable
+        // bit behind, which can set block protection or permanently lock the registers
+        0x01,
+        0x31,
+        0x11,
+        0x3E,
+    ];
+
+    /// Send an arbitrary command, clocking out `write` and then clocking in `read` bytes.
+    ///
+    /// Commands whose opcode is in [`FlashProgrammer::DESTRUCTIVE`] are refused unless
+    /// `allow_destructive` is set. With `write_enable`, a write enable is sent first.
+    pub fn raw(
+        &mut self,
+        write: &[u8],
+        read: usize,
+        write_enable: bool,
+        allow_destructive: bool,
+    ) -> Result<Vec<u8>> {
+        if let Some(&opcode) = write.first() {
+            if Self::DESTRUCTIVE.contains(&opcode) && !allow_destructive {
+                bail!(
+                    "Opcode {opcode:#04x} modifies the flash (pass --allow-destructive to send it)"
+                );
+            }
+        }
+
+        if write_enable {
+            self.write_enable()?;
+        }
+
+        self.select()?;
+        for byte in write {
+            self.write(*byte)?;
+        }
+        let response = (0..read).map(|_| self.read()).collect::<Result<_>>()?;
+        self.deselect()?;
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancel::Cancelled;
+    use crate::flash::Port;
+    use crate::mock::{MockFlash, Settings};
+    use crate::timing::Timing;
+    use crate::Error;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// The simulated flash, cancelling `token` once it's been sent `pages` page programs.
+    struct CancelAfter {
+        flash: MockFlash,
+        token: CancellationToken,
+        pages: usize,
+        first: bool,
+    }
+
+    impl Port for CancelAfter {
+        fn select(&mut self) -> anyhow::Result<()> {
+            self.first = true;
+            self.flash.select()
+        }
+
+        fn deselect(&mut self) -> anyhow::Result<()> {
+            self.flash.deselect()
+        }
+
+        fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+            if std::mem::take(&mut self.first) && byte == FlashProgrammer::PROGRAM {
+                self.pages -= 1;
+                if self.pages == 0 {
+                    self.token.cancel();
+                }
+            }
+            self.flash.write(byte)
+        }
+
+        fn read(&mut self) -> anyhow::Result<u8> {
+            self.flash.read()
+        }
+    }
+
+    #[test]
+    fn cancelling_stops_after_the_page_in_flight() {
+        let token = CancellationToken::new();
+        let port = CancelAfter {
+            flash: MockFlash::with_memory(vec![0xFF; 1 << 20]),
+            token: token.clone(),
+            pages: 3,
+            first: false,
+        };
+        let mut programmer =
+            FlashProgrammer::with_port(Box::new(port), &Timing::default(), None).unwrap();
+
+        let start = FlashAddress::new(0x1000).unwrap();
+        let data: Vec<u8> = (0..8 * 256).map(|i| i as u8).collect();
+        let error = programmer
+            .flash_images(&[(&data, start)], true, &Mask::EMPTY, &token)
+            .unwrap_err();
+        let Error::Cancelled(cancelled) = error else {
+            panic!("{error}");
+        };
+        assert_eq!(
+            cancelled,
+            Cancelled {
+                address: 0x1000 + 3 * 256
+            }
+        );
+
+        // The third page finished programming, and nothing was sent after it
+        let written = programmer.read_arbitrary(start, data.len()).unwrap();
+        assert_eq!(written[..3 * 256], data[..3 * 256]);
+        assert!(written[3 * 256..].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn images_sharing_a_block_both_survive() {
+        let mut programmer = FlashProgrammer::with_port(
+            Box::new(MockFlash::with_memory(vec![0x00; 1 << 20])),
+            &Timing::default(),
+            None,
+        )
+        .unwrap();
+        let first = vec![0xA5; 0x1000];
+        let second = vec![0x5A; 0x1800];
+        let images = [
+            (&first[..], FlashAddress::new(0x7E000).unwrap()),
+            (&second[..], FlashAddress::new(0x7F000).unwrap()),
+        ];
+        programmer
+            .flash_images(&images, true, &Mask::EMPTY, &CancellationToken::new())
+            .unwrap();
+
+        for (data, address) in images {
+            let cancel = CancellationToken::new();
+            programmer
+                .verify_data(data, address, &Mask::EMPTY, &cancel)
+                .unwrap();
+        }
+        // Erased only as far as the images reach
+        let around = programmer
+            .read_arbitrary(FlashAddress::new(0x7D000).unwrap(), 0x1000)
+            .unwrap();
+        assert!(around.iter().all(|b| *b == 0x00));
+    }
+
+    /// The simulated flash, counting the page programs it's sent.
+    struct Programs {
+        flash: MockFlash,
+        count: Rc<Cell<usize>>,
+        first: bool,
+    }
+
+    impl Port for Programs {
+        fn select(&mut self) -> anyhow::Result<()> {
+            self.first = true;
+            self.flash.select()
+        }
+
+        fn deselect(&mut self) -> anyhow::Result<()> {
+            self.flash.deselect()
+        }
+
+        fn write(&mut self, byte: u8) -> anyhow::Result<()> {
+            if std::mem::take(&mut self.first) && byte == FlashProgrammer::PROGRAM {
+                self.count.set(self.count.get() + 1);
+            }
+            self.flash.write(byte)
+        }
+
+        fn read(&mut self) -> anyhow::Result<u8> {
+            self.flash.read()
+        }
+    }
+
+    /// A programmer for a flash holding zeroes and ignoring every program and erase, as one
+    /// with SDO shorted to an old trace or its writes blocked seems to, and its page program
+    /// count.
+    fn read_only_flash(name: &str) -> (FlashProgrammer, Rc<Cell<usize>>) {
+        let image = std::env::temp_dir().join(format!(
+            "lattice-prog-canary-{name}-{}.bin",
+            std::process::id()
+        ));
+        std::fs::write(&image, vec![0x00; 1 << 20]).unwrap();
+        let flash = MockFlash::open(&Settings {
+            image: Some(image.clone()),
+            size: 1 << 20,
+            ignore_writes: true,
+            sector_erase_only: false,
+        })
+        .unwrap();
+        std::fs::remove_file(image).unwrap();
+
+        let count = Rc::new(Cell::new(0));
+        let port = Programs {
+            flash,
+            count: count.clone(),
+            first: false,
+        };
+        let programmer =
+            FlashProgrammer::with_port(Box::new(port), &Timing::default(), None).unwrap();
+        (programmer, count)
+    }
+
+    #[test]
+    fn an_ignored_erase_fails_before_programming() {
+        let (mut programmer, programs) = read_only_flash("ignored");
+        let data = vec![0xA5; 0x20000];
+        let error = programmer
+            .flash_images(
+                &[(&data, FlashAddress::new(0x10000).unwrap())],
+                true,
+                &Mask::EMPTY,
+                &CancellationToken::new(),
+            )
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with(
+                "Flash contents did not change after erase: 0x10000 still reads 0x00 after \
+                 erasing 0x10000 with opcode"
+            ),
+            "{error}"
+        );
+        assert_eq!(programs.get(), 0);
+    }
+
+    #[test]
+    fn assumed_blank_flash_skips_the_check() {
+        let (mut programmer, programs) = read_only_flash("assumed-blank");
+        let data = vec![0xA5; 0x1000];
+        programmer
+            .flash_images(
+                &[(&data, FlashAddress::ZERO)],
+                false,
+                &Mask::EMPTY,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+        assert_eq!(programs.get(), 0x1000 / 256);
+    }
+
+    #[test]
+    fn an_ignored_chip_erase_is_caught() {
+        let (mut programmer, _) = read_only_flash("chip");
+        programmer.chip_erase().unwrap();
+        let error = programmer.check_chip_erased().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Flash contents did not change after the chip erase: 0x0 still"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn a_working_erase_passes_the_check() {
+        let mut programmer = FlashProgrammer::with_port(
+            Box::new(MockFlash::with_memory(vec![0x00; 1 << 20])),
+            &Timing::default(),
+            None,
+        )
+        .unwrap();
+        let planned = programmer
+            .plan_erases(FlashAddress::new(0x20000).unwrap(), 0x10000)
+            .unwrap();
+        programmer.erase_planned(&planned).unwrap();
+        programmer.check_erased(planned[0].0, planned[0].1).unwrap();
+    }
+
+    /// A programmer for a flash implementing only 4 KiB sector erases, holding zeroes, with
+    /// `erases` overriding the ones read from its SFDP table.
+    fn sector_erase_only(erases: Option<Vec<Erase>>) -> FlashProgrammer {
+        let flash = MockFlash::open(&Settings {
+            image: None,
+            size: 1 << 20,
+            ignore_writes: false,
+            sector_erase_only: true,
+        })
+        .unwrap();
+        let timing = Timing {
+            erases,
+            ..Timing::default()
+        };
+        let mut programmer = FlashProgrammer::with_port(Box::new(flash), &timing, None).unwrap();
+        let zeroes = vec![0x00; 0x40000];
+        programmer
+            .flash_images(
+                &[(&zeroes, FlashAddress::ZERO)],
+                false,
+                &Mask::EMPTY,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+        programmer
+    }
+
+    #[test]
+    fn sector_erase_only_flash_is_erased_by_sector() {
+        let mut programmer = sector_erase_only(None);
+        assert_eq!(programmer.erases().unwrap(), [Erase::SECTOR]);
+        let planned = programmer
+            .plan_erases(FlashAddress::new(0x10000).unwrap(), 0x10000)
+            .unwrap();
+        assert_eq!(planned.len(), 16);
+        assert!(planned.iter().all(|(_, erase)| *erase == Erase::SECTOR));
+
+        // Over flash that isn't blank, so block erases the chip ignored would show up as
+        // AND-ed data
+        let cancel = CancellationToken::new();
+        let data: Vec<u8> = (0..0x28000).map(|i| (i * 7 + i / 256) as u8).collect();
+        let address = FlashAddress::new(0x8000).unwrap();
+        programmer
+            .flash_images(&[(&data, address)], true, &Mask::EMPTY, &cancel)
+            .unwrap();
+        programmer
+            .verify_data(&data, address, &Mask::EMPTY, &cancel)
+            .unwrap();
+    }
+
+    #[test]
+    fn overridden_block_erase_is_caught_on_a_sector_erase_only_flash() {
+        let mut programmer = sector_erase_only(Some(Erase::STANDARD.to_vec()));
+        let data = vec![0xA5; 0x10000];
+        let error = programmer
+            .flash_images(
+                &[(&data, FlashAddress::new(0x10000).unwrap())],
+                true,
+                &Mask::EMPTY,
+                &CancellationToken::new(),
+            )
+            .unwrap_err();
+        assert!(
+            error.to_string().starts_with(
+                "Flash contents did not change after erase: 0x10000 still reads 0x00 after \
+                 erasing 0x10000 with opcode 0xd8 (64 KiB)"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn erase_opcodes_override_the_sfdp_table() {
+        let mut programmer = FlashProgrammer::with_port(
+            Box::new(MockFlash::with_memory(vec![0xFF; 1 << 20])),
+            &Timing {
+                erases: Some(vec![Erase::SECTOR, Erase::HALF_BLOCK]),
+                ..Timing::default()
+            },
+            None,
+        )
+        .unwrap();
+        let planned = programmer.plan_erases(FlashAddress::ZERO, 0x10000).unwrap();
+        assert_eq!(
+            planned,
+            [
+                (FlashAddress::ZERO, Erase::HALF_BLOCK),
+                (FlashAddress::new(0x8000).unwrap(), Erase::HALF_BLOCK),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_status_register_writes_need_allowing() {
+        let mut programmer = FlashProgrammer::with_port(
+            Box::new(MockFlash::with_memory(vec![0xFF; 1 << 20])),
+            &Timing::default(),
+            None,
+        )
+        .unwrap();
+
+        for opcode in [0x01, 0x31, 0x11] {
+            let error = programmer.raw(&[opcode, 0x60], 0, true, false).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "Opcode {opcode:#04x} modifies the flash (pass --allow-destructive to send it)"
+                )
+            );
+        }
+        assert_eq!(programmer.read_register(0x15).unwrap(), 0);
+
+        programmer.raw(&[0x11, 0x60], 0, true, true).unwrap();
+        assert_eq!(programmer.read_register(0x15).unwrap(), 0x60);
+    }
+
+    #[test]
+    fn every_write_is_destructive() {
+        // The larger flash is sent the 4-byte address forms
+        for size in [1 << 20, 1 << 25] {
+            let mut programmer = FlashProgrammer::with_port(
+                Box::new(MockFlash::with_memory(vec![0xFF; size])),
+                &Timing::default(),
+                Some(crate::trace::Trace::new("flash", 0, 0)),
+            )
+            .unwrap();
+            let address = FlashAddress::new(0x10000).unwrap();
+            programmer.write_page(&[0x5A; 16], address).unwrap();
+            programmer.erase_sector(address).unwrap();
+            programmer.erase_block(address).unwrap();
+            // The half block erase has no 4-byte address form
+            if size == 1 << 20 {
+                programmer
+                    .erase_planned(&[(address, Erase::HALF_BLOCK)])
+                    .unwrap();
+            }
+            programmer.chip_erase().unwrap();
+            programmer.program_security(0x1000, &[0x5A; 16]).unwrap();
+            programmer.erase_security(0x1000).unwrap();
+            for opcode in [0x01, 0x31, 0x11, 0x3E] {
+                programmer.write_register(opcode, &[0]).unwrap();
+            }
+
+            // Whatever follows a write enable is a write
+            let transactions = programmer.take_trace().unwrap().transactions;
+            let writes: Vec<_> = transactions
+                .windows(2)
+                .filter(|pair| pair[0].write == [FlashProgrammer::WRITE_ENABLE])
+                .map(|pair| pair[1].write[0])
+                .collect();
+            assert_eq!(writes.len(), if size == 1 << 20 { 11 } else { 10 });
+            for opcode in writes {
+                assert!(
+                    FlashProgrammer::DESTRUCTIVE.contains(&opcode),
+                    "{opcode:#04x} isn't refused by raw"
+                );
+            }
+        }
+    }
+}
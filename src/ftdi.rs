@@ -0,0 +1,200 @@
+//! The `ftdi` backend, for programming from a desktop over an FT232H or FT2232H breakout: the
+//! MPSSE engine clocks SPI and drives the control lines, through libftdi.
+//!
+//! SCK, MOSI, and MISO are fixed on ADBUS0-2. The chip select, reset, and CDONE pins are set
+//! with `--ftdi-cs`, `--ftdi-reset`, and `--ftdi-cdone`, numbered 3-7 for ADBUS3-7 and 8-15
+//! for ACBUS0-7, defaulting to the iCEstick and iCEBreaker wiring that `iceprog` expects. The
+//! flash and the FPGA share the one chip select there, as on those boards. Polarities still
+//! come from the pin settings. The ports are the `embedded-hal` ones from [`crate::hal`], so
+//! the backend needs the `ftdi` feature.
+
+use crate::flash;
+use crate::pins::PinConfig;
+use crate::sram;
+use crate::timing::Timing;
+use anyhow::Result;
+
+/// The flash is clocked conservatively unless `--flash-clock` says otherwise.
+#[cfg(feature = "ftdi")]
+const FLASH_SPEED: u32 = 6_000_000;
+
+/// The adapter to open and its wiring.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The adapter's USB serial number, or `None` for the first one found.
+    pub serial: Option<String>,
+    pub cs: u8,
+    pub reset: u8,
+    pub cdone: u8,
+}
+
+impl Settings {
+    /// Fail unless every pin is a distinct GPIO the MPSSE engine leaves free.
+    pub fn check(&self) -> Result<()> {
+        let pins = [
+            ("--ftdi-cs", self.cs),
+            ("--ftdi-reset", self.reset),
+            ("--ftdi-cdone", self.cdone),
+        ];
+        for (i, (flag, pin)) in pins.iter().enumerate() {
+            if !(3..=15).contains(pin) {
+                anyhow::bail!(
+                    "{flag} {pin} isn't a free pin; ADBUS0-2 carry SPI, so use 3-7 for \
+                     ADBUS3-7 or 8-15 for ACBUS0-7"
+                );
+            }
+            if let Some((other, _)) = pins[..i].iter().find(|(_, other)| other == pin) {
+                anyhow::bail!("{other} and {flag} both use pin {pin}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "ftdi"))]
+fn unavailable<T>() -> Result<T> {
+    anyhow::bail!("This build doesn't include the ftdi backend; rebuild with --features ftdi")
+}
+
+#[cfg(feature = "ftdi")]
+mod adapter {
+    use super::Settings;
+    use anyhow::{Context, Result};
+    use ftdi_embedded_hal::ftdi::{self, Device, Interface};
+    use ftdi_embedded_hal::{FtHal, InputPin, OutputPin};
+
+    const VENDOR: u16 = 0x0403;
+    /// The FT232H's and FT2232H's product IDs.
+    const PRODUCTS: [u16; 2] = [0x6014, 0x6010];
+
+    /// Open the adapter with its MPSSE engine clocking SPI at `speed`.
+    pub fn open(settings: &Settings, speed: u32) -> Result<FtHal<Device>> {
+        settings.check()?;
+
+        let mut last = None;
+        for product in PRODUCTS {
+            let mut opener = ftdi::find_by_vid_pid(VENDOR, product).interface(Interface::A);
+            if let Some(serial) = &settings.serial {
+                opener = opener.serial(serial);
+            }
+            match opener.open() {
+                Ok(device) => {
+                    return FtHal::init_freq(device, speed)
+                        .context("Failed to start the FTDI adapter's MPSSE engine")
+                }
+                Err(e) => last = Some(e),
+            }
+        }
+
+        let what = match &settings.serial {
+            Some(serial) => format!("the FT232H or FT2232H with serial number {serial}"),
+            None => "an FT232H or FT2232H".into(),
+        };
+        Err(anyhow::Error::from(
+            last.expect("at least one product is tried"),
+        ))
+        .with_context(|| format!("Failed to open {what}"))
+    }
+
+    pub fn output(hal: &FtHal<Device>, pin: u8) -> Result<OutputPin<Device>> {
+        let result = match pin {
+            3 => hal.ad3(),
+            4 => hal.ad4(),
+            5 => hal.ad5(),
+            6 => hal.ad6(),
+            7 => hal.ad7(),
+            8 => hal.c0(),
+            9 => hal.c1(),
+            10 => hal.c2(),
+            11 => hal.c3(),
+            12 => hal.c4(),
+            13 => hal.c5(),
+            14 => hal.c6(),
+            15 => hal.c7(),
+            _ => anyhow::bail!("Pin {pin} isn't a free FTDI pin"),
+        };
+        result.with_context(|| format!("Failed to acquire FTDI pin {pin}"))
+    }
+
+    pub fn input(hal: &FtHal<Device>, pin: u8) -> Result<InputPin<Device>> {
+        let result = match pin {
+            3 => hal.adi3(),
+            4 => hal.adi4(),
+            5 => hal.adi5(),
+            6 => hal.adi6(),
+            7 => hal.adi7(),
+            8 => hal.ci0(),
+            9 => hal.ci1(),
+            10 => hal.ci2(),
+            11 => hal.ci3(),
+            12 => hal.ci4(),
+            13 => hal.ci5(),
+            14 => hal.ci6(),
+            15 => hal.ci7(),
+            _ => anyhow::bail!("Pin {pin} isn't a free FTDI pin"),
+        };
+        result.with_context(|| format!("Failed to acquire FTDI pin {pin}"))
+    }
+}
+
+/// Connect to the flash, holding the FPGA in reset until the port is dropped.
+#[cfg(feature = "ftdi")]
+pub fn flash(
+    settings: &Settings,
+    pins: &PinConfig,
+    timing: &Timing,
+) -> Result<Box<dyn flash::Port>> {
+    use anyhow::Context;
+
+    let hal = adapter::open(settings, timing.flash_clock.unwrap_or(FLASH_SPEED))?;
+    let reset = adapter::output(&hal, settings.reset)?;
+    let cs = adapter::output(&hal, settings.cs)?;
+    let bus = hal
+        .spi()
+        .context("Failed to start the FTDI adapter's SPI")?;
+    let port = crate::hal::HalFlash::new(bus, cs, Some(reset), pins)?;
+
+    // Let the FPGA reset and fail configuration, releasing the bus
+    spin_sleep::sleep(timing.settle);
+    spin_sleep::sleep(timing.reset_pulse);
+
+    Ok(Box::new(port))
+}
+
+#[cfg(not(feature = "ftdi"))]
+pub fn flash(
+    _settings: &Settings,
+    _pins: &PinConfig,
+    _timing: &Timing,
+) -> Result<Box<dyn flash::Port>> {
+    unavailable()
+}
+
+/// Connect to the FPGA's configuration port at `baud`, holding it in reset, with CDONE read
+/// from `--ftdi-cdone` for the preflight checks.
+#[cfg(feature = "ftdi")]
+pub fn fpga(settings: &Settings, pins: &PinConfig, baud: u32) -> Result<Box<dyn sram::Port>> {
+    use anyhow::Context;
+
+    let hal = adapter::open(settings, baud)?;
+    let reset = adapter::output(&hal, settings.reset)?;
+    let cs = adapter::output(&hal, settings.cs)?;
+    let cdone = adapter::input(&hal, settings.cdone)?;
+    let bus = hal
+        .spi()
+        .context("Failed to start the FTDI adapter's SPI")?;
+
+    Ok(Box::new(crate::hal::HalFpga::new(
+        bus,
+        cs,
+        reset,
+        Some(cdone),
+        pins,
+    )?))
+}
+
+#[cfg(not(feature = "ftdi"))]
+pub fn fpga(_settings: &Settings, _pins: &PinConfig, _baud: u32) -> Result<Box<dyn sram::Port>> {
+    unavailable()
+}
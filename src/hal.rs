@@ -0,0 +1,469 @@
+//! Ports over `embedded-hal` buses and pins, so both programmers run on any host with an
+//! `embedded-hal` implementation, such as `linux-embedded-hal` or an FTDI adapter.
+//!
+//! Both take a raw [`SpiBus`] and drive chip select as an ordinary [`OutputPin`], since the
+//! flash's transactions are framed byte by byte and the FPGA's chip select has to be held
+//! across a reset pulse, neither of which fits an `SpiDevice`. Pin polarities come from the
+//! [`PinConfig`], and its GPIO numbers are ignored.
+
+use crate::flash;
+use crate::pins::{driven_high, PinConfig};
+use crate::sram;
+use anyhow::Result;
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal::spi::SpiBus;
+
+/// Drive `pin` to the level for `asserted` under the given polarity.
+pub(crate) fn drive<P: OutputPin>(pin: &mut P, active_low: bool, asserted: bool) -> Result<()> {
+    let result = if driven_high(active_low, asserted) {
+        pin.set_high()
+    } else {
+        pin.set_low()
+    };
+    result.map_err(|e| anyhow::anyhow!("Failed to drive a pin: {e:?}"))
+}
+
+/// The flash on an `embedded-hal` bus, for a [`flash::FlashProgrammer`].
+///
+/// Dropping it releases the FPGA's reset so it boots from the flash, as the Pi's pins do
+/// when they return to inputs, unless it was handed off.
+pub struct HalFlash<B: SpiBus, C: OutputPin, R: OutputPin> {
+    bus: B,
+    cs: C,
+    /// Held asserted, keeping the FPGA off the bus, unless omitted.
+    fpga_reset: Option<R>,
+    pins: PinConfig,
+    handed_off: bool,
+}
+
+impl<B: SpiBus, C: OutputPin, R: OutputPin> HalFlash<B, C, R> {
+    /// Take over `bus` and the flash's chip select, holding the FPGA in reset with
+    /// `fpga_reset` when given.
+    pub fn new(bus: B, mut cs: C, mut fpga_reset: Option<R>, pins: &PinConfig) -> Result<Self> {
+        drive(&mut cs, pins.flash_cs_active_low, false)?;
+        if let Some(reset) = &mut fpga_reset {
+            drive(reset, pins.reset_active_low, true)?;
+        }
+
+        Ok(Self {
+            bus,
+            cs,
+            fpga_reset,
+            pins: pins.clone(),
+            handed_off: false,
+        })
+    }
+
+    /// The bus, for transfers `embedded-hal` can't express.
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+}
+
+impl<B: SpiBus, C: OutputPin, R: OutputPin> flash::Port for HalFlash<B, C, R> {
+    fn select(&mut self) -> Result<()> {
+        drive(&mut self.cs, self.pins.flash_cs_active_low, true)
+    }
+
+    fn deselect(&mut self) -> Result<()> {
+        self.bus
+            .flush()
+            .map_err(|e| anyhow::anyhow!("Failed to flush the SPI bus: {e:?}"))?;
+        drive(&mut self.cs, self.pins.flash_cs_active_low, false)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<()> {
+        self.bus
+            .write(&[byte])
+            .map_err(|e| anyhow::anyhow!("Failed to write to the SPI bus: {e:?}"))
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        let mut byte = [0xFF];
+        self.bus
+            .transfer_in_place(&mut byte)
+            .map_err(|e| anyhow::anyhow!("Failed to read from the SPI bus: {e:?}"))?;
+        Ok(byte[0])
+    }
+
+    fn hand_off(&mut self) {
+        let _ = drive(&mut self.cs, self.pins.flash_cs_active_low, false);
+        if let Some(reset) = &mut self.fpga_reset {
+            let _ = drive(reset, self.pins.reset_active_low, true);
+        }
+        self.handed_off = true;
+    }
+
+    fn read_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+        buffer.fill(0xFF);
+        self.bus
+            .transfer_in_place(buffer)
+            .map_err(|e| anyhow::anyhow!("Failed to read from the SPI bus: {e:?}"))
+    }
+}
+
+impl<B: SpiBus, C: OutputPin, R: OutputPin> Drop for HalFlash<B, C, R> {
+    fn drop(&mut self) {
+        if let Some(reset) = self.fpga_reset.as_mut().filter(|_| !self.handed_off) {
+            let _ = drive(reset, self.pins.reset_active_low, false);
+        }
+    }
+}
+
+/// The FPGA's configuration port on an `embedded-hal` bus, for an
+/// [`sram::SramProgrammer`].
+///
+/// Output pins can't be read back, so the preflight check of CRESET_B always passes.
+pub struct HalFpga<B, C, R, D> {
+    bus: B,
+    cs: C,
+    reset: R,
+    cdone: Option<D>,
+    pins: PinConfig,
+}
+
+impl<B: SpiBus, C: OutputPin, R: OutputPin, D: InputPin> HalFpga<B, C, R, D> {
+    /// Take over `bus` and the FPGA's chip select and reset, holding the FPGA in reset, with
+    /// `cdone` read for the CDONE checks when given.
+    pub fn new(
+        bus: B,
+        mut cs: C,
+        mut reset: R,
+        cdone: Option<D>,
+        pins: &PinConfig,
+    ) -> Result<Self> {
+        drive(&mut reset, pins.reset_active_low, true)?;
+        drive(&mut cs, pins.fpga_cs_active_low, false)?;
+
+        Ok(Self {
+            bus,
+            cs,
+            reset,
+            cdone,
+            pins: pins.clone(),
+        })
+    }
+}
+
+impl<B: SpiBus, C: OutputPin, R: OutputPin, D: InputPin> sram::Port for HalFpga<B, C, R, D> {
+    fn reset(&mut self, asserted: bool) -> Result<()> {
+        drive(&mut self.reset, self.pins.reset_active_low, asserted)
+    }
+
+    fn select(&mut self, asserted: bool) -> Result<()> {
+        drive(&mut self.cs, self.pins.fpga_cs_active_low, asserted)
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.bus
+            .write(data)
+            .and_then(|()| self.bus.flush())
+            .map_err(|e| std::io::Error::other(format!("{e:?}")))?;
+        Ok(data.len())
+    }
+
+    fn check_reset(&self, _asserted: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn cdone(&mut self, _pin: u8) -> Result<bool> {
+        match &mut self.cdone {
+            Some(cdone) => cdone
+                .is_high()
+                .map_err(|e| anyhow::anyhow!("Failed to read CDONE: {e:?}")),
+            None => anyhow::bail!("No CDONE pin was given to the embedded-hal port"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sram::Port as _;
+    use std::cell::RefCell;
+    use std::convert::Infallible;
+    use std::rc::Rc;
+
+    /// An output pin recording every level it's driven to.
+    #[derive(Clone, Default)]
+    struct Recorded(Rc<RefCell<Vec<bool>>>);
+
+    impl Recorded {
+        fn levels(&self) -> Vec<bool> {
+            self.0.borrow().clone()
+        }
+    }
+
+    impl embedded_hal::digital::ErrorType for Recorded {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Recorded {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.0.borrow_mut().push(true);
+            Ok(())
+        }
+    }
+
+    impl InputPin for Recorded {
+        fn is_high(&mut self) -> Result<bool, Infallible> {
+            Ok(self.0.borrow().last().copied().unwrap_or_default())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    /// A bus that accepts everything and reads 0xFF.
+    struct Bus;
+
+    impl embedded_hal::spi::ErrorType for Bus {
+        type Error = Infallible;
+    }
+
+    impl SpiBus for Bus {
+        fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+            words.fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, _: &[u8]) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn transfer(&mut self, read: &mut [u8], _: &[u8]) -> Result<(), Infallible> {
+            read.fill(0xFF);
+            Ok(())
+        }
+
+        fn transfer_in_place(&mut self, _: &mut [u8]) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    fn pins(active_low: bool) -> PinConfig {
+        PinConfig {
+            reset_active_low: active_low,
+            fpga_cs_active_low: active_low,
+            flash_cs_active_low: active_low,
+            ..PinConfig::default()
+        }
+    }
+
+    #[test]
+    fn flash_lines_follow_the_polarity() {
+        for active_low in [true, false] {
+            let (cs, reset) = (Recorded::default(), Recorded::default());
+            let mut flash =
+                HalFlash::new(Bus, cs.clone(), Some(reset.clone()), &pins(active_low)).unwrap();
+            flash::Port::select(&mut flash).unwrap();
+            flash::Port::deselect(&mut flash).unwrap();
+            drop(flash);
+
+            // Deselected, selected, deselected
+            assert_eq!(cs.levels(), [active_low, !active_low, active_low]);
+            // Held in reset, then released as the port's dropped
+            assert_eq!(reset.levels(), [!active_low, active_low]);
+        }
+    }
+
+    #[test]
+    fn fpga_lines_follow_the_polarity() {
+        for active_low in [true, false] {
+            let (cs, reset) = (Recorded::default(), Recorded::default());
+            let mut fpga = HalFpga::new(
+                Bus,
+                cs.clone(),
+                reset.clone(),
+                None::<Recorded>,
+                &pins(active_low),
+            )
+            .unwrap();
+            fpga.reset(false).unwrap();
+            fpga.select(true).unwrap();
+
+            assert_eq!(reset.levels(), [!active_low, active_low]);
+            assert_eq!(cs.levels(), [active_low, !active_low]);
+        }
+    }
+
+    /// What happened on the lines, in order.
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        /// A named line driven high or low.
+        Level(&'static str, bool),
+        /// A byte written on the bus.
+        Sent(u8),
+    }
+
+    type Log = Rc<RefCell<Vec<Event>>>;
+
+    /// An output pin logging its levels under its name.
+    struct Line(&'static str, Log);
+
+    impl embedded_hal::digital::ErrorType for Line {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for Line {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().push(Event::Level(self.0, false));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.1.borrow_mut().push(Event::Level(self.0, true));
+            Ok(())
+        }
+    }
+
+    /// A bus logging the bytes written, and reading 0xFF.
+    struct LoggedBus(Log);
+
+    impl embedded_hal::spi::ErrorType for LoggedBus {
+        type Error = Infallible;
+    }
+
+    impl SpiBus for LoggedBus {
+        fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+            words.fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            let mut log = self.0.borrow_mut();
+            log.extend(words.iter().map(|byte| Event::Sent(*byte)));
+            Ok(())
+        }
+
+        fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+            self.write(write)?;
+            read.fill(0xFF);
+            Ok(())
+        }
+
+        fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+            words.fill(0xFF);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reset_stays_asserted_until_the_flash_is_done() {
+        for active_low in [true, false] {
+            let log = Log::default();
+            let flash = HalFlash::new(
+                LoggedBus(log.clone()),
+                Recorded::default(),
+                Some(Line("reset", log.clone())),
+                &pins(active_low),
+            )
+            .unwrap();
+            let programmer = flash::FlashProgrammer::with_port(
+                Box::new(flash),
+                &crate::timing::Timing::default(),
+                None,
+            )
+            .unwrap();
+
+            // CRESET_B is asserted before the wake command opens the session, and never
+            // released while the programmer holds the bus
+            let asserted = Event::Level("reset", driven_high(active_low, true));
+            let released = Event::Level("reset", driven_high(active_low, false));
+            assert_eq!(log.borrow()[..2], [asserted, Event::Sent(0xAB)]);
+            assert!(!log.borrow().contains(&released));
+
+            drop(programmer);
+            assert_eq!(log.borrow().last(), Some(&released));
+        }
+    }
+
+    #[test]
+    fn handing_off_to_the_sram_programmer_never_overlaps() {
+        for active_low in [true, false] {
+            let log = Log::default();
+            let pins = pins(active_low);
+            let flash = HalFlash::new(
+                LoggedBus(log.clone()),
+                Line("flash cs", log.clone()),
+                Some(Line("reset", log.clone())),
+                &pins,
+            )
+            .unwrap();
+            let timing = crate::timing::Timing::default();
+            flash::FlashProgrammer::with_port(Box::new(flash), &timing, None)
+                .unwrap()
+                .hand_off();
+            let handed_off = log.borrow().len();
+
+            let fpga = HalFpga::new(
+                LoggedBus(log.clone()),
+                Line("fpga cs", log.clone()),
+                Line("reset", log.clone()),
+                None::<Recorded>,
+                &pins,
+            )
+            .unwrap();
+            let mut sram = sram::SramProgrammer::with_port(Box::new(fpga), &timing);
+            let preflight = sram::Preflight {
+                cdone: None,
+                no_preflight: true,
+            };
+            let spi = sram::SpiSettings {
+                baud: 1_000_000,
+                transfer: 4096,
+                retries: 0,
+                inter_chunk_delay_us: 0,
+                trailing_clocks: 0,
+            };
+            sram.begin(&preflight).unwrap();
+            sram.program_bytes(&[0x5A; 16], &spi, &crate::cancel::CancellationToken::new())
+                .unwrap();
+
+            let log = log.borrow();
+            let level = |name, asserted| {
+                let active_low = match name {
+                    "flash cs" => pins.flash_cs_active_low,
+                    "fpga cs" => pins.fpga_cs_active_low,
+                    _ => pins.reset_active_low,
+                };
+                Event::Level(name, driven_high(active_low, asserted))
+            };
+            let first = |event: &Event| log.iter().position(|e| e == event).unwrap();
+            let last = |event: &Event| log.iter().rposition(|e| e == event).unwrap();
+
+            // The flash is deselected by the hand-off and never selected again
+            assert!(last(&level("flash cs", true)) < handed_off);
+            assert_eq!(
+                log[..handed_off]
+                    .iter()
+                    .rfind(|e| matches!(e, Event::Level("flash cs", _))),
+                Some(&level("flash cs", false))
+            );
+
+            // CRESET_B is only released by the SRAM programmer, with the FPGA's CS asserted so
+            // it comes up in slave SPI mode, and before any of the bitstream is sent
+            let released = first(&level("reset", false));
+            assert!(released > handed_off);
+            assert!(first(&level("fpga cs", true)) < released);
+            assert!(!log[handed_off..released]
+                .iter()
+                .any(|e| matches!(e, Event::Sent(_))));
+            assert_eq!(log.last(), Some(&level("fpga cs", false)));
+        }
+    }
+}
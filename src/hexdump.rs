@@ -0,0 +1,227 @@
+//! Hexdumps of flash contents, annotated with layout partitions and bitstream landmarks.
+//!
+//! Every data row keeps the same `address: bytes  |ascii|` columns whether or not it's
+//! annotated. Annotations are printed as separate banner lines, starting with `----`, before
+//! the row they apply to.
+
+use crate::bitstream;
+use crate::layout::Layout;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The number of bytes in each row.
+const ROW: usize = 16;
+
+/// The banners to print before each row, keyed by the row's offset within the dump.
+///
+/// `landmarks` are `(address, description)` pairs at absolute flash addresses, and any outside
+/// the dump are ignored.
+pub fn banners(
+    address: usize,
+    length: usize,
+    layout: Option<&Layout>,
+    landmarks: &[(usize, String)],
+) -> BTreeMap<usize, Vec<String>> {
+    let end = address + length;
+    let row = |at: usize| (at - address) / ROW * ROW;
+    let mut banners: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+
+    let partitions = layout.map_or(&[][..], |layout| &layout.partitions);
+    for partition in partitions {
+        if (address..end).contains(&partition.offset) {
+            banners
+                .entry(row(partition.offset))
+                .or_default()
+                .push(format!(
+                    "---- partition: {} @ {:#x} ----",
+                    partition.name, partition.offset
+                ));
+        } else if partition.offset < address && address < partition.end() {
+            banners.entry(0).or_default().push(format!(
+                "---- partition: {} @ {:#x} (continued) ----",
+                partition.name, partition.offset
+            ));
+        }
+    }
+
+    for (at, description) in landmarks {
+        if (address..end).contains(at) {
+            banners
+                .entry(row(*at))
+                .or_default()
+                .push(format!("---- {description} @ {at:#x} ----"));
+        }
+    }
+
+    banners
+}
+
+/// The bitstream landmarks in a dump of `data` at `address`, found by parsing from the start
+/// of the dump and from the start of each partition within it.
+pub fn landmarks(address: usize, data: &[u8], layout: Option<&Layout>) -> Vec<(usize, String)> {
+    let mut starts = vec![address];
+    if let Some(layout) = layout {
+        starts.extend(
+            layout
+                .partitions
+                .iter()
+                .map(|p| p.offset)
+                .filter(|offset| (address + 1..address + data.len()).contains(offset)),
+        );
+    }
+
+    starts
+        .into_iter()
+        .flat_map(|start| {
+            bitstream::landmarks(&data[start - address..])
+                .into_iter()
+                .map(move |(offset, description)| (start + offset, description))
+        })
+        .collect()
+}
+
+/// Format `data` read from `address` as a hexdump, with `banners` from [`banners`].
+pub fn format(address: usize, data: &[u8], banners: &BTreeMap<usize, Vec<String>>) -> String {
+    let mut output = String::new();
+
+    for (index, row) in data.chunks(ROW).enumerate() {
+        let offset = index * ROW;
+        for banner in banners.get(&offset).into_iter().flatten() {
+            let _ = writeln!(output, "{banner}");
+        }
+
+        let _ = write!(output, "{:08x}: ", address + offset);
+        for i in 0..ROW {
+            match row.get(i) {
+                Some(byte) => {
+                    let _ = write!(output, "{byte:02x} ");
+                }
+                None => output.push_str("   "),
+            }
+        }
+
+        let ascii: String = row
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(output, " |{ascii}|");
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Partition;
+
+    fn layout() -> Layout {
+        let partition = |name: &str, offset, size| Partition {
+            name: name.into(),
+            offset,
+            size,
+            readonly: false,
+        };
+        Layout {
+            device: None,
+            partitions: vec![
+                partition("bitstream", 0, 0x1008),
+                partition("userdata", 0x1008, 0x0ff8),
+                partition("spare", 0x3000, 0x1000),
+            ],
+        }
+    }
+
+    #[test]
+    fn partition_banners_go_before_their_row() {
+        assert_eq!(
+            banners(0x1000, 0x100, Some(&layout()), &[]),
+            BTreeMap::from([(
+                0,
+                vec![
+                    "---- partition: bitstream @ 0x0 (continued) ----".to_string(),
+                    "---- partition: userdata @ 0x1008 ----".to_string(),
+                ]
+            )])
+        );
+
+        // A partition starting later is on the row holding its first byte
+        let later = banners(0xF00, 0x200, Some(&layout()), &[]);
+        assert_eq!(later[&0x100], ["---- partition: userdata @ 0x1008 ----"]);
+    }
+
+    #[test]
+    fn partitions_outside_the_dump_are_left_out() {
+        assert!(banners(0x1100, 0x100, Some(&layout()), &[])[&0]
+            .iter()
+            .all(|banner| banner.contains("userdata")));
+        assert!(banners(0x2000, 0x100, None, &[]).is_empty());
+    }
+
+    #[test]
+    fn landmarks_are_placed_at_their_address() {
+        let landmarks = [
+            (0x0FFF, "before".to_string()),
+            (0x1021, "inside".to_string()),
+            (0x1100, "after".to_string()),
+        ];
+        assert_eq!(
+            banners(0x1000, 0x100, None, &landmarks),
+            BTreeMap::from([(0x20, vec!["---- inside @ 0x1021 ----".to_string()])])
+        );
+    }
+
+    #[test]
+    fn landmarks_are_found_at_each_partition() {
+        let mut data = vec![0xFF; 0x3000];
+        let header = bitstream::warmboot_header(0x20000, &[]);
+        data[0x1008..0x1008 + header.len()].copy_from_slice(&header);
+
+        // Parsed from the start of the dump, there's no header, only a preamble further on
+        let landmarks = landmarks(0x0, &data, Some(&layout()));
+        assert_eq!(landmarks[0], (0x1008, "preamble".to_string()));
+
+        // Parsed from the start of the partition, each of the header's entries is found
+        let entries = &landmarks[1..];
+        assert_eq!(entries.len(), header.len() / 32);
+        assert_eq!(
+            entries[0],
+            (
+                0x1008,
+                "multiboot entry 0 (power-on): image at 0x20000".to_string()
+            )
+        );
+        assert_eq!(entries[1].0, 0x1028);
+    }
+
+    #[test]
+    fn banners_leave_the_rows_unchanged() {
+        let data: Vec<u8> = (0..0x123).map(|i| i as u8).collect();
+        let landmarks = [(0x1050, "landmark".to_string())];
+        let annotated = format(
+            0x1000,
+            &data,
+            &banners(0x1000, data.len(), Some(&layout()), &landmarks),
+        );
+        let plain = format(0x1000, &data, &BTreeMap::new());
+
+        let rows: Vec<_> = annotated
+            .lines()
+            .filter(|line| !line.starts_with("----"))
+            .collect();
+        assert_eq!(rows, plain.lines().collect::<Vec<_>>());
+        assert_eq!(annotated.lines().count(), rows.len() + 3);
+
+        // The ASCII column starts in the same place on every row, the short last one too
+        let column = "00001000: ".len() + ROW * 3 + " ".len();
+        for row in rows {
+            assert_eq!(row.find('|'), Some(column), "{row}");
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Reading the image to program, optionally from a window within a larger file, or from the
+//! segments of an Intel HEX or S-record file.
+//!
+//! What a file holds is told from its first bytes rather than its name, so a text file given
+//! by mistake is converted, or refused, instead of being programmed as raw bytes.
+
+use crate::address::FlashAddress;
+use crate::layout::{Layout, Region};
+use crate::records::{self, Segment};
+use anyhow::{Context, Result};
+use clap::Args;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Take};
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// How many bytes of the input are looked at to tell its format.
+const DETECT_SIZE: usize = 256;
+
+/// What an input file holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// The bytes to program, such as a binary bitstream
+    Raw,
+    /// Intel HEX records
+    Ihex,
+    /// Motorola S-records
+    Srec,
+    /// An ASCII iCE40 bitstream, converted with IceStorm's `icepack`
+    Asc,
+}
+
+impl Format {
+    /// Tell the format from the start of a file.
+    pub fn detect(start: &[u8]) -> Self {
+        let text = start.trim_ascii_start();
+        let line = text
+            .split(|byte| matches!(byte, b'\r' | b'\n'))
+            .next()
+            .unwrap_or_default()
+            .trim_ascii_end();
+        let hex = |digits: &[u8]| !digits.is_empty() && digits.iter().all(u8::is_ascii_hexdigit);
+
+        if text.starts_with(b".comment") || text.starts_with(b".device") {
+            Self::Asc
+        } else if line.first() == Some(&b':') && hex(&line[1..]) {
+            Self::Ihex
+        } else if line.len() > 2 && line[0] == b'S' && line[1].is_ascii_digit() && hex(&line[2..]) {
+            Self::Srec
+        } else {
+            Self::Raw
+        }
+    }
+
+    fn records(self) -> Option<records::Format> {
+        match self {
+            Self::Ihex => Some(records::Format::Ihex),
+            Self::Srec => Some(records::Format::Srec),
+            Self::Raw | Self::Asc => None,
+        }
+    }
+}
+
+/// The image to program.
+#[derive(Args, Clone, Debug)]
+pub struct Input {
+    /// Path to the input RTL
+    ///
+    /// May be a regular file or a raw block device. Intel HEX and S-record files are told
+    /// from their contents, with each record written at its address from the start of where
+    /// the image is written, and ASCII bitstreams are converted with `icepack`.
+    #[arg(value_name = "INPUT")]
+    pub path: PathBuf,
+
+    /// Start reading the image at this byte offset within the input
+    #[arg(long, default_value = "0", value_parser = parse_size)]
+    pub input_offset: usize,
+
+    /// Read only this many bytes of the input
+    ///
+    /// Defaults to the rest of the input after `--input-offset`.
+    #[arg(long, value_parser = parse_size)]
+    pub input_length: Option<usize>,
+
+    /// Read the input as this format rather than telling it from the contents
+    #[arg(long, value_enum)]
+    pub input_format: Option<Format>,
+}
+
+impl Input {
+    /// The input's format, as given by `--input-format` or told from its first bytes.
+    pub fn format(&self) -> Result<Format> {
+        if let Some(format) = self.input_format {
+            return Ok(format);
+        }
+
+        let path = self.path.display();
+        let file = File::open(&self.path).with_context(|| format!("Error opening {path}"))?;
+        let mut start = Vec::with_capacity(DETECT_SIZE);
+        file.take(DETECT_SIZE as u64)
+            .read_to_end(&mut start)
+            .with_context(|| format!("Error reading input file {path}"))?;
+
+        Ok(Format::detect(&start))
+    }
+
+    /// Read the windowed slice of the input.
+    ///
+    /// A record file reads as its bytes from its first address to its last, with any gaps
+    /// 0xFF, and an ASCII bitstream as the binary bitstream `icepack` makes of it.
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let format = self.format()?;
+        if let Some(records) = format.records() {
+            return Ok(records::flatten(&self.parse(records)?));
+        }
+        if format == Format::Asc {
+            return self.pack();
+        }
+
+        let (file, length) = self.window()?;
+        let mut data = vec![0; length];
+        file.take(length as u64)
+            .read_exact(&mut data)
+            .with_context(|| format!("Error reading input file {}", self.path.display()))?;
+
+        Ok(data)
+    }
+
+    /// A buffered reader over the windowed slice of a raw input, and its length, for comparing
+    /// against without holding it in memory. Inputs that are converted rather than read as is
+    /// give `None`.
+    pub fn reader(&self) -> Result<Option<(BufReader<Take<File>>, usize)>> {
+        let format = self.format()?;
+        if format.records().is_some() || format == Format::Asc {
+            return Ok(None);
+        }
+
+        let (file, length) = self.window()?;
+        Ok(Some((BufReader::new(file.take(length as u64)), length)))
+    }
+
+    /// The raw input, positioned at the start of its window, and the window's length.
+    fn window(&self) -> Result<(File, usize)> {
+        let path = self.path.display();
+        let mut file = File::open(&self.path).with_context(|| format!("Error opening {path}"))?;
+
+        // Block devices report a zero length in their metadata, so measure by seeking instead
+        let size =
+            file.seek(SeekFrom::End(0))
+                .with_context(|| format!("Error reading the size of {path}"))? as usize;
+
+        if self.input_offset > size {
+            anyhow::bail!(
+                "--input-offset {:#x} is beyond the end of {path} ({size:#x} bytes)",
+                self.input_offset
+            );
+        }
+
+        let available = size - self.input_offset;
+        let length = self.input_length.unwrap_or(available);
+        if length > available {
+            anyhow::bail!(
+                "The window {:#x}..{:#x} extends beyond the end of {path} ({size:#x} bytes)",
+                self.input_offset,
+                self.input_offset + length
+            );
+        }
+
+        file.seek(SeekFrom::Start(self.input_offset as u64))?;
+        Ok((file, length))
+    }
+
+    /// The contiguous runs of the input, each at its offset from the start of the image.
+    ///
+    /// A raw input is a single segment at offset zero, while a record file has one for each
+    /// region it fills.
+    pub fn segments(&self) -> Result<Vec<Segment>> {
+        match self.format()?.records() {
+            Some(format) => self.parse(format),
+            None => Ok(vec![Segment {
+                offset: 0,
+                data: self.read()?,
+            }]),
+        }
+    }
+
+    /// Fail when a window is given for an input that's converted rather than read as is.
+    fn unwindowed(&self, name: &str) -> Result<()> {
+        if self.input_offset != 0 || self.input_length.is_some() {
+            anyhow::bail!(
+                "--input-offset and --input-length only apply to raw images, not {name} like {}",
+                self.path.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn parse(&self, format: records::Format) -> Result<Vec<Segment>> {
+        self.unwindowed(format.name())?;
+        let path = self.path.display();
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Error reading input file {path}"))?;
+        format
+            .parse(&text)
+            .with_context(|| format!("Error parsing {path} as {}", format.name()))
+    }
+
+    /// Convert an ASCII bitstream to a binary one with `icepack`.
+    fn pack(&self) -> Result<Vec<u8>> {
+        self.unwindowed("an ASCII bitstream")?;
+        let path = self.path.display();
+
+        let output = match Command::new("icepack").arg(&self.path).output() {
+            Ok(output) => output,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(
+                "{path} is an ASCII bitstream, and converting it needs icepack from Project \
+                 IceStorm on PATH"
+            ),
+            Err(e) => return Err(e).with_context(|| "Failed to run icepack"),
+        };
+        if !output.status.success() {
+            anyhow::bail!(
+                "icepack failed to convert {path}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// The holes within the `length` byte window of a sparse input, relative to the window's
+    /// start, or `None` when its filesystem can't report them.
+    ///
+    /// Holes are extents never written, which read as zeros but hold no data. A filesystem
+    /// without hole support reports the whole file as data, so there are simply none.
+    pub fn holes(&self, length: usize) -> Result<Option<Vec<Range<usize>>>> {
+        // Only a raw file's holes line up with the image; a converted one has none
+        if self.format()? != Format::Raw {
+            return Ok(Some(Vec::new()));
+        }
+        let file = File::open(&self.path)
+            .with_context(|| format!("Error opening {}", self.path.display()))?;
+        let start = self.input_offset;
+        let end = start + length;
+
+        let seek = |offset: usize, whence| {
+            // SAFETY: lseek only moves the offset of a descriptor the file keeps open
+            let result = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+            match result {
+                -1 => Err(std::io::Error::last_os_error()),
+                offset => Ok(offset as usize),
+            }
+        };
+
+        let mut holes = Vec::new();
+        let mut position = start;
+        while position < end {
+            let data = match seek(position, libc::SEEK_DATA) {
+                Ok(data) => data.min(end),
+                // No data after `position`, so the rest of the window is a hole
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => end,
+                Err(_) => return Ok(None),
+            };
+            if data > position {
+                holes.push(position - start..data - start);
+            }
+            if data == end {
+                break;
+            }
+
+            position = match seek(data, libc::SEEK_HOLE) {
+                Ok(hole) => hole,
+                Err(_) => return Ok(None),
+            };
+        }
+
+        Ok(Some(holes))
+    }
+}
+
+/// Parse a byte count or offset, given in decimal or as `0x`-prefixed hex.
+pub fn parse_size(text: &str) -> Result<usize> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+
+    parsed.with_context(|| format!("Invalid size {text:?}"))
+}
+
+/// Parse a single byte, given in decimal or as `0x`-prefixed hex.
+pub fn parse_byte(text: &str) -> Result<u8> {
+    u8::try_from(parse_size(text)?).with_context(|| format!("{text:?} doesn't fit in a byte"))
+}
+
+/// An additional image and where to write it, parsed from `<path>@<offset>` or
+/// `<path>@<partition>`.
+#[derive(Clone, Debug)]
+pub struct Placement {
+    pub path: PathBuf,
+    pub region: Region,
+}
+
+impl Placement {
+    pub fn parse(text: &str) -> Result<Self> {
+        let (path, at) = text
+            .rsplit_once('@')
+            .with_context(|| format!("Expected <path>@<offset or partition>, got {text:?}"))?;
+
+        let region = match parse_size(at) {
+            Ok(address) => Region {
+                address: Some(FlashAddress::new(address)?),
+                ..Default::default()
+            },
+            Err(_) => Region {
+                partition: Some(at.into()),
+                ..Default::default()
+            },
+        };
+
+        Ok(Self {
+            path: path.into(),
+            region,
+        })
+    }
+
+    /// Read the image, returning it along with its resolved flash address.
+    pub fn read(&self, layout: Option<&Layout>) -> Result<(Vec<u8>, FlashAddress)> {
+        let data = std::fs::read(&self.path)
+            .with_context(|| format!("Error reading {}", self.path.display()))?;
+        let (address, _) = Region {
+            length: Some(data.len()),
+            ..self.region.clone()
+        }
+        .resolve(layout, data.len(), true)?;
+
+        Ok((data, address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    const BLOCK: usize = 0x10000;
+
+    /// A sparse file of `size` bytes, unique to the test, holding only `extents`.
+    struct Sparse(PathBuf);
+
+    impl Sparse {
+        fn new(name: &str, size: usize, extents: &[(usize, &[u8])]) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "lattice-prog-sparse-{name}-{}.bin",
+                std::process::id()
+            ));
+            let file = File::create(&path).unwrap();
+            file.set_len(size as u64).unwrap();
+            for (offset, data) in extents {
+                file.write_all_at(data, *offset as u64).unwrap();
+            }
+            Self(path)
+        }
+
+        fn input(&self) -> Input {
+            Input {
+                path: self.0.clone(),
+                input_offset: 0,
+                input_length: None,
+                input_format: None,
+            }
+        }
+    }
+
+    impl Drop for Sparse {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Whether the temporary directory's filesystem reports holes, without which only the
+    /// fallback can be checked.
+    fn reports_holes() -> bool {
+        let file = Sparse::new("probe", 4 * BLOCK, &[(BLOCK, &[1])]);
+        let holes = file.input().holes(4 * BLOCK).unwrap();
+        holes.is_some_and(|holes| !holes.is_empty())
+    }
+
+    #[test]
+    fn holes_lie_between_the_data_extents() {
+        if !reports_holes() {
+            return;
+        }
+        let data = [0xA5; 0x1000];
+        let file = Sparse::new(
+            "between",
+            4 * BLOCK,
+            &[(BLOCK, &data), (2 * BLOCK + 0x8000, &data)],
+        );
+        assert_eq!(
+            file.input().holes(4 * BLOCK).unwrap().unwrap(),
+            [
+                0..BLOCK,
+                BLOCK + 0x1000..2 * BLOCK + 0x8000,
+                2 * BLOCK + 0x9000..4 * BLOCK
+            ]
+        );
+    }
+
+    #[test]
+    fn written_zeros_are_data() {
+        if !reports_holes() {
+            return;
+        }
+        let zeros = [0x00; 0x1000];
+        let file = Sparse::new("zeros", 2 * BLOCK, &[(0, &zeros), (BLOCK, &zeros)]);
+        assert_eq!(
+            file.input().holes(2 * BLOCK).unwrap().unwrap(),
+            [0x1000..BLOCK, BLOCK + 0x1000..2 * BLOCK]
+        );
+    }
+
+    #[test]
+    fn files_without_holes_have_none() {
+        let data = vec![0x00; 2 * BLOCK];
+        let file = Sparse::new("dense", 2 * BLOCK, &[(0, &data)]);
+        assert_eq!(file.input().holes(2 * BLOCK).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn holes_are_relative_to_the_window() {
+        if !reports_holes() {
+            return;
+        }
+        let data = [0x5A; 0x1000];
+        let file = Sparse::new("window", 4 * BLOCK, &[(2 * BLOCK, &data)]);
+        let input = Input {
+            input_offset: BLOCK + 0x8000,
+            ..file.input()
+        };
+        // The window ends in the middle of a hole, and starts in the middle of another
+        assert_eq!(
+            input.holes(BLOCK).unwrap().unwrap(),
+            [0..0x8000, 0x9000..BLOCK]
+        );
+        // A window ending in data stops there
+        let holes = input.holes(0x8800).unwrap().unwrap();
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes[0], 0..0x8000);
+    }
+
+    #[test]
+    fn a_trailing_hole_reaches_the_window_end() {
+        if !reports_holes() {
+            return;
+        }
+        let data = [0x5A; 0x1000];
+        let file = Sparse::new("trailing", 4 * BLOCK, &[(0, &data)]);
+        let holes = file.input().holes(4 * BLOCK).unwrap().unwrap();
+        assert_eq!(holes.len(), 1);
+        assert_eq!(holes[0], 0x1000..4 * BLOCK);
+    }
+
+    #[test]
+    fn converted_inputs_have_no_holes() {
+        let record = b":0400000001020304F2\n:00000001FF\n";
+        let file = Sparse::new("ihex", BLOCK, &[(BLOCK - record.len(), record)]);
+        let input = Input {
+            input_format: Some(Format::Ihex),
+            ..file.input()
+        };
+        assert_eq!(input.holes(BLOCK).unwrap(), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,222 @@
+//! A journal of the blocks a flash has written, so a run cut short by Ctrl-C or a dropped SSH
+//! session can pick up where it stopped rather than starting a large image over.
+//!
+//! The journal is TOML, keyed by the image's SHA-256, the address it's written at, and the
+//! flash chip (as in the wear file), with the address of each 64 KiB block written and read
+//! back so far:
+//!
+//! ```toml
+//! sha256 = "5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef"
+//! address = 0
+//! chip = "ef4018-d26358b7cb4f2a2c"
+//! blocks = [0, 65536, 131072]
+//! ```
+//!
+//! A journal for another image, address, or chip is started over. It's rewritten after every
+//! block, by replacing the file with an updated copy, and removed once the whole image is
+//! written.
+
+use crate::address::FlashAddress;
+use crate::cancel::CancellationToken;
+use crate::error;
+use crate::flash::FlashProgrammer;
+use crate::mask::Mask;
+use crate::plan;
+use crate::progress::{self, Progress};
+use crate::{status, warning};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Journal {
+    sha256: String,
+    address: usize,
+    chip: String,
+    #[serde(default)]
+    blocks: BTreeSet<usize>,
+}
+
+impl Journal {
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .map(Some)
+                .with_context(|| format!("Invalid journal {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Error reading {}", path.display())),
+        }
+    }
+
+    /// Replace the file in one step, so it's never left partly written.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        std::fs::write(&temporary, toml::to_string(self)?)
+            .with_context(|| format!("Error writing {}", temporary.display()))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("Error replacing {}", path.display()))
+    }
+
+    fn same_flash(&self, other: &Self) -> bool {
+        self.sha256 == other.sha256 && self.address == other.address && self.chip == other.chip
+    }
+}
+
+/// The `(address, range of the image)` of each 64 KiB block `length` bytes at `address` cover.
+fn blocks(address: FlashAddress, length: usize) -> Result<Vec<(FlashAddress, Range<usize>)>> {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < length {
+        let current = address.offset(offset)?;
+        let end = (current.align_down(plan::BLOCK_SIZE).get() + plan::BLOCK_SIZE - address.get())
+            .min(length);
+        blocks.push((current, offset..end));
+        offset = end;
+    }
+
+    Ok(blocks)
+}
+
+/// Whether the flash at `address` holds the `range` of `data`, outside the mask.
+fn holds(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    address: FlashAddress,
+    range: &Range<usize>,
+    mask: &Mask,
+) -> Result<bool> {
+    programmer.await_ready()?;
+    let actual = programmer.read_arbitrary(address, range.len())?;
+    Ok(mask
+        .mismatch(range.start, &data[range.clone()], &actual)
+        .is_none())
+}
+
+/// Write `data` at `address` a block at a time, reading each block back and recording it in the
+/// journal at `path`, and skipping the blocks a journal for the same image already records.
+///
+/// The recorded blocks are read back again before they're skipped, in case the flash changed
+/// in between. Since every block is compared as it's written, there's no separate verify.
+pub fn flash(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    address: FlashAddress,
+    mask: &Mask,
+    path: &Path,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let fresh = Journal {
+        sha256: crate::backup::hex(&Sha256::digest(data)),
+        address: address.get(),
+        chip: crate::wear::chip(programmer)?,
+        blocks: BTreeSet::new(),
+    };
+    let mut journal = match Journal::load(path)? {
+        Some(saved) if saved.same_flash(&fresh) => saved,
+        Some(_) => {
+            warning!(
+                "{} is the journal of another image or flash, so it's started over",
+                path.display()
+            );
+            fresh
+        }
+        None => fresh,
+    };
+
+    let blocks = blocks(address, data.len())?;
+    if !journal.blocks.is_empty() {
+        status!(
+            "Resuming from {}: checking the {} blocks already written...",
+            path.display(),
+            journal.blocks.len()
+        );
+        let mut changed = 0;
+        for (start, range) in &blocks {
+            if journal.blocks.contains(&start.get())
+                && !holds(programmer, data, *start, range, mask)?
+            {
+                journal.blocks.remove(&start.get());
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            warning!(
+                "{changed} of those blocks no longer hold the image, so they're written again"
+            );
+        }
+    }
+    journal.save(path)?;
+
+    let pending: Vec<_> = blocks
+        .iter()
+        .filter(|(start, _)| !journal.blocks.contains(&start.get()))
+        .collect();
+    status!(
+        "Flashing data: {} of {} blocks already written, {} to go...",
+        blocks.len() - pending.len(),
+        blocks.len(),
+        pending.len()
+    );
+
+    let result = write(programmer, data, mask, path, &mut journal, &pending, cancel);
+    if result
+        .as_ref()
+        .is_err_and(|e| error::cancellation(e).is_some())
+    {
+        status!(
+            "{} records the blocks written so far; flash the same image with it again to resume",
+            path.display()
+        );
+    }
+    result?;
+
+    programmer.report_latency();
+    progress::report_mask(mask, data.len());
+    std::fs::remove_file(path).with_context(|| format!("Error removing {}", path.display()))
+}
+
+/// Erase, write, and read back each of the `pending` blocks, recording each in the journal.
+fn write(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    mask: &Mask,
+    path: &Path,
+    journal: &mut Journal,
+    pending: &[&(FlashAddress, Range<usize>)],
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let mut bar = Progress::bytes("program", pending.iter().map(|(_, r)| r.len()).sum());
+    let page_size = programmer.page_size()?;
+    for (i, &(start, range)) in pending.iter().enumerate() {
+        programmer.check_cancelled(cancel, start.get())?;
+        crate::watchdog::beat("erase", start.get());
+        programmer.await_ready()?;
+        let erases = programmer.plan_erases(*start, range.len())?;
+        programmer.erase_planned(&erases)?;
+        if i == 0 {
+            programmer.check_erased(erases[0].0, erases[0].1)?;
+        }
+
+        for (address, page) in plan::pages(&data[range.clone()], *start, page_size)? {
+            programmer.check_cancelled(cancel, address.get())?;
+            crate::watchdog::beat("program", address.get());
+            programmer.await_ready()?;
+            programmer.write_page(page, address)?;
+            bar.inc(page.len());
+        }
+
+        if !holds(programmer, data, *start, range, mask)? {
+            anyhow::bail!("The block at {start:#x} doesn't read back as it was written");
+        }
+        journal.blocks.insert(start.get());
+        journal.save(path)?;
+    }
+
+    Ok(())
+}
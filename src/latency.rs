@@ -0,0 +1,98 @@
+//! How long each block erase and page program kept the flash busy, for spotting blocks that
+//! are slowing down as they wear.
+//!
+//! Worn NOR takes progressively longer to erase before it fails outright, so a block far
+//! slower than the session's median is flagged while it still works.
+
+use crate::address::FlashAddress;
+use crate::plan::BLOCK_SIZE;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Busy times below this are never flagged, since they're timing noise rather than the flash.
+const NOISE_FLOOR: Duration = Duration::from_millis(1);
+
+/// The busy times measured during a session.
+#[derive(Debug, Default)]
+pub struct Latency {
+    /// Each block erase, by the block's address.
+    erases: Vec<(FlashAddress, Duration)>,
+    /// Each page program, by the page's address.
+    programs: Vec<(FlashAddress, Duration)>,
+}
+
+fn median(durations: impl Iterator<Item = Duration>) -> Option<Duration> {
+    let mut durations: Vec<_> = durations.collect();
+    durations.sort();
+    durations.get(durations.len() / 2).copied()
+}
+
+impl Latency {
+    pub fn erase(&mut self, block: FlashAddress, duration: Duration) {
+        self.erases.push((block, duration));
+    }
+
+    pub fn program(&mut self, page: FlashAddress, duration: Duration) {
+        self.programs.push((page, duration));
+    }
+
+    /// The slowest page program of each block, by the block's address.
+    fn slowest_programs(&self) -> BTreeMap<FlashAddress, Duration> {
+        let mut slowest = BTreeMap::new();
+        for (page, duration) in &self.programs {
+            let block = slowest.entry(page.align_down(BLOCK_SIZE)).or_default();
+            *block = (*duration).max(*block);
+        }
+
+        slowest
+    }
+
+    /// Describe every block whose erase, or slowest page program, took more than `factor`
+    /// times the session's median.
+    pub fn outliers(&self, factor: u32) -> Vec<String> {
+        let mut outliers = Vec::new();
+
+        if let Some(median) = median(self.erases.iter().map(|(_, duration)| *duration)) {
+            for (block, duration) in &self.erases {
+                if *duration > median * factor && *duration > NOISE_FLOOR {
+                    outliers.push(format!(
+                        "block {block:#08x} erased in {duration:.1?} vs median {median:.1?} \
+                         \u{2014} possible wear"
+                    ));
+                }
+            }
+        }
+
+        if let Some(median) = median(self.programs.iter().map(|(_, duration)| *duration)) {
+            for (block, duration) in self.slowest_programs() {
+                if duration > median * factor && duration > NOISE_FLOOR {
+                    outliers.push(format!(
+                        "block {block:#08x} had a page program take {duration:.1?} vs median \
+                         {median:.1?} \u{2014} possible wear"
+                    ));
+                }
+            }
+        }
+
+        outliers
+    }
+
+    /// The medians measured, for `-v`.
+    pub fn describe(&self) -> Option<String> {
+        let erases = median(self.erases.iter().map(|(_, duration)| *duration));
+        let programs = median(self.programs.iter().map(|(_, duration)| *duration));
+        if erases.is_none() && programs.is_none() {
+            return None;
+        }
+
+        let describe = |median: Option<Duration>, count: usize| match median {
+            Some(median) => format!("median {median:.1?} over {count}"),
+            None => "none".into(),
+        };
+        Some(format!(
+            "Block erases: {}; page programs: {}",
+            describe(erases, self.erases.len()),
+            describe(programs, self.programs.len())
+        ))
+    }
+}
@@ -0,0 +1,496 @@
+//! Named flash partitions.
+//!
+//! A layout is a TOML file listing the partitions of the flash, and optionally the board's
+//! FPGA, which bitstreams are checked against when no `--device` is given:
+//!
+//! ```toml
+//! device = "up5k"
+//!
+//! [[partition]]
+//! name = "bitstream"
+//! offset = 0x0
+//! size = 0x80000
+//! readonly = true
+//!
+//! [[partition]]
+//! name = "userdata"
+//! offset = 0x100000
+//! size = 0x100000
+//! ```
+
+use crate::address::FlashAddress;
+use crate::device::Device;
+use crate::plan;
+use anyhow::{Context, Result};
+use clap::Args;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Layout formats of other tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// flashrom's `start:end name` regions, with inclusive hex addresses
+    Flashrom,
+    /// A JSON object with a `partitions` array
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Partition {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+impl Partition {
+    pub fn end(&self) -> usize {
+        self.offset + self.size
+    }
+
+    fn overlaps(&self, address: usize, length: usize) -> bool {
+        address < self.end() && self.offset < address + length
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct Layout {
+    #[serde(default)]
+    pub device: Option<Device>,
+    #[serde(default, rename = "partition")]
+    pub partitions: Vec<Partition>,
+}
+
+impl Layout {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading layout from {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid layout in {}", path.display()))
+    }
+
+    pub fn partition(&self, name: &str) -> Result<&Partition> {
+        self.partitions
+            .iter()
+            .find(|p| p.name == name)
+            .with_context(|| format!("No partition named {name:?} in the layout"))
+    }
+
+    /// The partition `address` falls within, if any.
+    pub fn containing(&self, address: usize) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.overlaps(address, 1))
+    }
+
+    /// Convert the layout to another tool's format.
+    pub fn export(&self, format: Format) -> Result<String> {
+        match format {
+            Format::Flashrom => self.flashrom(),
+            Format::Json => Ok(self.json()),
+        }
+    }
+
+    /// The layout as flashrom regions, failing on partitions flashrom can't express.
+    ///
+    /// Gaps between partitions are fine, but regions can't overlap or be empty, and their names
+    /// can't contain whitespace. The `readonly` flags have no flashrom equivalent and are left
+    /// out.
+    fn flashrom(&self) -> Result<String> {
+        let mut problems = Vec::new();
+        for (i, partition) in self.partitions.iter().enumerate() {
+            if partition.size == 0 {
+                problems.push(format!("{:?} is empty", partition.name));
+            }
+            if partition.name.is_empty() || partition.name.contains(char::is_whitespace) {
+                problems.push(format!(
+                    "{:?} has a name flashrom can't parse",
+                    partition.name
+                ));
+            }
+            for other in &self.partitions[i + 1..] {
+                if partition.overlaps(other.offset, other.size) {
+                    problems.push(format!("{:?} overlaps {:?}", partition.name, other.name));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "The layout can't be expressed in flashrom's format:\n  {}",
+                problems.join("\n  ")
+            );
+        }
+
+        let mut output = String::new();
+        for partition in &self.partitions {
+            writeln!(
+                output,
+                "{:08x}:{:08x} {}",
+                partition.offset,
+                partition.end() - 1,
+                partition.name
+            )?;
+        }
+
+        Ok(output)
+    }
+
+    fn json(&self) -> String {
+        let partitions: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|p| {
+                format!(
+                    r#"{{"name":{:?},"offset":{},"size":{},"readonly":{}}}"#,
+                    p.name, p.offset, p.size, p.readonly
+                )
+            })
+            .collect();
+
+        format!("{{\"partitions\":[{}]}}\n", partitions.join(","))
+    }
+
+    /// Parse flashrom's `start:end name` regions, ignoring blank lines and `#` comments.
+    pub fn parse_flashrom(text: &str) -> Result<Self> {
+        let mut partitions = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let context = || {
+                format!(
+                    "Line {}: expected `start:end name`, got {line:?}",
+                    number + 1
+                )
+            };
+            let (range, name) = line.split_once(char::is_whitespace).with_context(context)?;
+            let (start, end) = range.split_once(':').with_context(context)?;
+            let start = usize::from_str_radix(start, 16).with_context(context)?;
+            let end = usize::from_str_radix(end, 16).with_context(context)?;
+            if end < start {
+                anyhow::bail!("Line {}: the region ends before it starts", number + 1);
+            }
+
+            partitions.push(Partition {
+                name: name.trim().into(),
+                offset: start,
+                size: end - start + 1,
+                readonly: false,
+            });
+        }
+
+        Ok(Self {
+            partitions,
+            ..Default::default()
+        })
+    }
+
+    /// The layout as a layout file.
+    pub fn toml(&self) -> String {
+        let mut output = String::new();
+
+        for (i, partition) in self.partitions.iter().enumerate() {
+            if i > 0 {
+                output.push('\n');
+            }
+            let _ = writeln!(output, "[[partition]]");
+            let _ = writeln!(output, "name = {:?}", partition.name);
+            let _ = writeln!(output, "offset = {:#x}", partition.offset);
+            let _ = writeln!(output, "size = {:#x}", partition.size);
+            if partition.readonly {
+                let _ = writeln!(output, "readonly = true");
+            }
+        }
+
+        output
+    }
+}
+
+/// A flash range given either as a raw address or as a layout partition.
+#[derive(Args, Clone, Debug, Default)]
+pub struct Region {
+    /// The flash address to start at
+    #[arg(short, long, conflicts_with = "partition", value_parser = FlashAddress::parse)]
+    pub address: Option<FlashAddress>,
+
+    /// The number of bytes
+    ///
+    /// Defaults to the partition's size when `--partition` is given.
+    #[arg(short, long)]
+    pub length: Option<usize>,
+
+    /// Start at the named layout partition instead of a raw address
+    #[arg(long)]
+    pub partition: Option<String>,
+
+    /// Allow the length to extend beyond the end of the partition
+    #[arg(long)]
+    pub allow_cross_partition: bool,
+}
+
+impl Region {
+    /// Resolve the region to an address and length.
+    ///
+    /// Without a partition, the address defaults to zero and the length to `default_length`.
+    /// Write-type operations are refused on read-only partitions, whether addressed by name or
+    /// by a range whose erases would reach them. Either way, the range must fit in the address
+    /// space.
+    pub fn resolve(
+        &self,
+        layout: Option<&Layout>,
+        default_length: usize,
+        write: bool,
+    ) -> Result<(FlashAddress, usize)> {
+        let (address, length) = match &self.partition {
+            None => (
+                self.address.unwrap_or_default(),
+                self.length.unwrap_or(default_length),
+            ),
+            Some(name) => {
+                let layout =
+                    layout.with_context(|| "--partition requires a layout (see --layout)")?;
+                let partition = layout.partition(name)?;
+
+                if write && partition.readonly {
+                    anyhow::bail!("The partition {name:?} is read-only");
+                }
+
+                let length = self.length.unwrap_or(partition.size);
+                if length > partition.size && !self.allow_cross_partition {
+                    anyhow::bail!(
+                        "{length} bytes exceeds the {} byte partition {name:?} (pass \
+                         --allow-cross-partition to allow this)",
+                        partition.size
+                    );
+                }
+
+                (FlashAddress::new(partition.offset)?, length)
+            }
+        };
+        let end = address.end(length)?;
+
+        if let Some(layout) = layout.filter(|_| write && length > 0) {
+            check_erase_footprint(layout, address, end)?;
+        }
+
+        Ok((address, length))
+    }
+}
+
+/// Refuse a write to `address..end` if the erases before it could reach a read-only partition.
+///
+/// Erases are widened to the flash's smallest erase, which isn't known until the flash is
+/// probed, so the range is rounded out to whole 64 KiB blocks, the largest that can be.
+fn check_erase_footprint(layout: &Layout, address: FlashAddress, end: usize) -> Result<()> {
+    let start = address.align_down(plan::BLOCK_SIZE).get();
+    let erased_end = end.div_ceil(plan::BLOCK_SIZE) * plan::BLOCK_SIZE;
+
+    let Some(partition) = layout
+        .partitions
+        .iter()
+        .find(|p| p.readonly && p.overlaps(start, erased_end - start))
+    else {
+        return Ok(());
+    };
+    if partition.overlaps(address.get(), end - address.get()) {
+        anyhow::bail!(
+            "The range {address:#x}..{end:#x} overlaps the read-only partition {:?}",
+            partition.name
+        );
+    }
+    anyhow::bail!(
+        "Erasing for the range {address:#x}..{end:#x} could reach the read-only partition {:?} \
+         at {:#x}..{:#x}, which shares a 64 KiB block with it",
+        partition.name,
+        partition.offset,
+        partition.end()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> Layout {
+        Layout {
+            device: None,
+            partitions: vec![
+                Partition {
+                    name: "bitstream".into(),
+                    offset: 0,
+                    size: 0x21000,
+                    readonly: true,
+                },
+                Partition {
+                    name: "userdata".into(),
+                    offset: 0x21000,
+                    size: 0x1f000,
+                    readonly: false,
+                },
+                Partition {
+                    name: "assets".into(),
+                    offset: 0x40000,
+                    size: 0x40000,
+                    readonly: false,
+                },
+            ],
+        }
+    }
+
+    fn region(address: Option<usize>, length: usize, partition: Option<&str>) -> Region {
+        Region {
+            address: address.map(|a| FlashAddress::new(a).unwrap()),
+            length: Some(length),
+            partition: partition.map(Into::into),
+            allow_cross_partition: true,
+        }
+    }
+
+    #[test]
+    fn refuses_writes_overlapping_read_only_partitions() {
+        let layout = layout();
+        assert!(region(Some(0x20000), 0x100, None)
+            .resolve(Some(&layout), 0, true)
+            .is_err());
+        assert!(region(Some(0x20000), 0x100, None)
+            .resolve(Some(&layout), 0, false)
+            .is_ok());
+    }
+
+    #[test]
+    fn refuses_writes_whose_erases_reach_read_only_partitions() {
+        let layout = layout();
+        // Past the read-only partition, but within the block its end shares
+        assert!(region(Some(0x22000), 0x100, None)
+            .resolve(Some(&layout), 0, true)
+            .is_err());
+        assert!(region(None, 0x100, Some("userdata"))
+            .resolve(Some(&layout), 0, true)
+            .is_err());
+        assert_eq!(
+            region(None, 0x100, Some("assets"))
+                .resolve(Some(&layout), 0, true)
+                .unwrap(),
+            (FlashAddress::new(0x40000).unwrap(), 0x100)
+        );
+    }
+
+    #[test]
+    fn refuses_cross_partition_writes_into_read_only_partitions() {
+        let mut layout = layout();
+        layout.partitions[0].offset = 0x80000;
+        layout.partitions[0].size = 0x10000;
+        assert!(region(None, 0x50000, Some("assets"))
+            .resolve(Some(&layout), 0, true)
+            .is_err());
+        assert!(region(None, 0x40000, Some("assets"))
+            .resolve(Some(&layout), 0, true)
+            .is_ok());
+    }
+
+    #[test]
+    fn finds_the_partition_containing_an_address() {
+        let layout = layout();
+        assert_eq!(layout.containing(0x21000).unwrap().name, "userdata");
+        assert_eq!(layout.containing(0x3ffff).unwrap().name, "userdata");
+        assert_eq!(layout.containing(0x50000).unwrap().name, "assets");
+        assert!(layout.containing(0x80000).is_none());
+    }
+
+    #[test]
+    fn exports_flashrom_regions() {
+        assert_eq!(
+            layout().export(Format::Flashrom).unwrap(),
+            "00000000:00020fff bitstream\n00021000:0003ffff userdata\n00040000:0007ffff assets\n"
+        );
+    }
+
+    #[test]
+    fn exports_json() {
+        assert_eq!(
+            layout().export(Format::Json).unwrap(),
+            concat!(
+                r#"{"partitions":["#,
+                r#"{"name":"bitstream","offset":0,"size":135168,"readonly":true},"#,
+                r#"{"name":"userdata","offset":135168,"size":126976,"readonly":false},"#,
+                r#"{"name":"assets","offset":262144,"size":262144,"readonly":false}"#,
+                "]}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn flashrom_regions_round_trip() {
+        let mut layout = layout();
+        // Gaps are fine
+        layout.partitions[2].offset = 0x50000;
+
+        let imported = Layout::parse_flashrom(&layout.export(Format::Flashrom).unwrap()).unwrap();
+        // flashrom has no read-only flag
+        layout.partitions[0].readonly = false;
+        assert_eq!(imported, layout);
+    }
+
+    #[test]
+    fn layout_files_round_trip() {
+        let layout = layout();
+        let parsed: Layout = toml::from_str(&layout.toml()).unwrap();
+        assert_eq!(parsed, layout);
+    }
+
+    #[test]
+    fn flashrom_export_flags_what_it_cant_express() {
+        let mut layout = layout();
+        layout.partitions[1].size = 0x20000;
+        layout.partitions[2].name = "my assets".into();
+        layout.partitions.push(Partition {
+            name: "empty".into(),
+            offset: 0x90000,
+            size: 0,
+            readonly: false,
+        });
+
+        let error = layout.export(Format::Flashrom).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "The layout can't be expressed in flashrom's format:\n  \
+             \"userdata\" overlaps \"my assets\"\n  \
+             \"my assets\" has a name flashrom can't parse\n  \
+             \"empty\" is empty"
+        );
+        // JSON can express all of it
+        assert!(layout.export(Format::Json).is_ok());
+    }
+
+    #[test]
+    fn parses_flashrom_comments_and_blank_lines() {
+        let layout = Layout::parse_flashrom(
+            "# The boot image\n00000000:0000ffff boot\n\n00010000:0001ffff data # logs\n",
+        )
+        .unwrap();
+        let names: Vec<_> = layout.partitions.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["boot", "data"]);
+        assert_eq!(layout.partitions[1].size, 0x10000);
+    }
+
+    #[test]
+    fn rejects_malformed_flashrom_regions() {
+        for (text, message) in [
+            ("00000000:0000ffff", "Line 1: expected `start:end name`"),
+            ("\n0000:zz boot", "Line 2: expected `start:end name`"),
+            (
+                "00001000-00002000 boot",
+                "Line 1: expected `start:end name`",
+            ),
+            (
+                "00002000:00001000 boot",
+                "Line 1: the region ends before it starts",
+            ),
+        ] {
+            let error = Layout::parse_flashrom(text).unwrap_err().to_string();
+            assert!(error.starts_with(message), "{error}");
+        }
+    }
+}
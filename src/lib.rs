@@ -0,0 +1,57 @@
+//! The programmers behind `lattice-prog`, for build scripts, test rigs, and daemons that
+//! configure lattice FPGAs from a Raspberry Pi without shelling out to the CLI.
+//!
+//! [`sram::SramProgrammer`] configures the FPGA's SRAM over hardware SPI, and
+//! [`flash::FlashProgrammer`] programs its configuration flash over a bit-banged bus, or over
+//! [`mock::MockFlash`] for running without hardware. Both take the board's wiring as a
+//! [`pins::PinConfig`] and its delays as a [`timing::Timing`], whose defaults match the CLI's.
+//!
+//! With the `embedded-hal` feature, [`hal`] provides ports over `embedded-hal` buses and pins
+//! for hosts other than a Pi, and with the `linux` feature, [`linux`] opens them over the
+//! GPIO character device and spidev. With the `ftdi` feature, [`ftdi`] opens them on an FT232H
+//! or FT2232H USB adapter. With the `ch341a` feature, [`ch341a`] reaches the flash alone
+//! through a CH341A USB programmer.
+//!
+//! The programmers fail with an [`Error`], which separates a cancellation and an SRAM write
+//! that needs configuration restarted from everything else, which carries a readable chain of
+//! context. Nothing is printed: messages and progress go to the [`report::Reporter`] installed
+//! with [`report::set_reporter`], if any.
+//!
+//! Building with the `read-only` feature leaves out every primitive that erases or programs the
+//! flash.
+
+// The helpers shared between the write paths and the reads are unused without the writes
+#![cfg_attr(feature = "read-only", allow(dead_code))]
+
+pub use error::{Error, Result};
+pub(crate) use report::{status, verbose, warning};
+
+pub mod address;
+pub mod bitstream;
+pub mod board;
+pub mod boot;
+pub mod cancel;
+pub mod ch341a;
+pub mod chip;
+pub mod device;
+pub mod error;
+pub mod flash;
+pub mod ftdi;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
+pub mod input;
+pub mod latency;
+pub mod layout;
+pub mod linux;
+pub mod mask;
+pub mod mock;
+pub mod parts;
+pub mod pins;
+pub mod plan;
+pub mod records;
+pub mod report;
+pub mod sample;
+pub mod sfdp;
+pub mod sram;
+pub mod timing;
+pub mod trace;
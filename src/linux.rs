@@ -0,0 +1,319 @@
+//! The `linux` backend, for boards other than a Pi: control lines through the kernel's GPIO
+//! character device and both buses through spidev.
+//!
+//! The pin settings' GPIO numbers are taken as line offsets on `--gpiochip`. The flash and the
+//! FPGA share the one spidev bus, with the flash CS and FPGA CS driven as GPIO lines, so the
+//! spidev's own chip select, asserted on every transfer, should be left unconnected. The ports
+//! are the `embedded-hal` ones from [`crate::hal`], so the backend needs the `linux` feature.
+//!
+//! Where the SPI controller can take data on two or four lines, the flash's reads use them,
+//! once they've read back the same as single reads. Quad reads need the flash's WP# and HOLD#
+//! wired to the controller's IO2 and IO3.
+
+use crate::flash;
+use crate::pins::PinConfig;
+use crate::sram;
+use crate::timing::Timing;
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// The flash is clocked conservatively unless `--flash-clock` says otherwise.
+#[cfg(feature = "linux")]
+const FLASH_SPEED: u32 = 1_000_000;
+
+/// The devices the backend opens.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub gpiochip: PathBuf,
+    pub spidev: PathBuf,
+}
+
+impl Settings {
+    /// Accept a bare device name such as `gpiochip0` for a node in `/dev`.
+    pub fn new(gpiochip: PathBuf, spidev: PathBuf) -> Self {
+        let in_dev = |path: PathBuf| {
+            if path.components().count() == 1 {
+                PathBuf::from("/dev").join(path)
+            } else {
+                path
+            }
+        };
+
+        Self {
+            gpiochip: in_dev(gpiochip),
+            spidev: in_dev(spidev),
+        }
+    }
+}
+
+#[cfg(not(feature = "linux"))]
+fn unavailable<T>() -> Result<T> {
+    anyhow::bail!("This build doesn't include the linux backend; rebuild with --features linux")
+}
+
+#[cfg(feature = "linux")]
+mod devices {
+    use super::Settings;
+    use crate::flash::ReadWidth;
+    use crate::pins::driven_high;
+    use anyhow::{Context, Result};
+    use linux_embedded_hal::gpio_cdev::{Chip, LineRequestFlags};
+    use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+    use linux_embedded_hal::{CdevPin, SpidevBus};
+
+    const CONSUMER: &str = "lattice-prog";
+
+    pub fn chip(settings: &Settings) -> Result<Chip> {
+        Chip::new(&settings.gpiochip)
+            .with_context(|| format!("Failed to open {}", settings.gpiochip.display()))
+    }
+
+    /// Request `line` as an output, starting at the level for `asserted`.
+    pub fn output(
+        chip: &mut Chip,
+        line: u8,
+        name: &str,
+        active_low: bool,
+        asserted: bool,
+    ) -> Result<CdevPin> {
+        let handle = chip
+            .get_line(line.into())
+            .and_then(|line| {
+                line.request(
+                    LineRequestFlags::OUTPUT,
+                    driven_high(active_low, asserted) as u8,
+                    CONSUMER,
+                )
+            })
+            .with_context(|| format!("Failed to acquire the {name} line {line}"))?;
+        Ok(CdevPin::new(handle)?)
+    }
+
+    pub fn input(chip: &mut Chip, line: u8, name: &str) -> Result<CdevPin> {
+        let handle = chip
+            .get_line(line.into())
+            .and_then(|line| line.request(LineRequestFlags::INPUT, 0, CONSUMER))
+            .with_context(|| format!("Failed to acquire the {name} line {line}"))?;
+        Ok(CdevPin::new(handle)?)
+    }
+
+    pub fn bus(settings: &Settings, speed: u32) -> Result<SpidevBus> {
+        let mut bus = SpidevBus::open(&settings.spidev)
+            .with_context(|| format!("Failed to open {}", settings.spidev.display()))?;
+        bus.configure(
+            &SpidevOptions::new()
+                .bits_per_word(8)
+                .max_speed_hz(speed)
+                .mode(SpiModeFlags::SPI_MODE_0)
+                .build(),
+        )
+        .with_context(|| format!("Failed to configure {}", settings.spidev.display()))?;
+        Ok(bus)
+    }
+
+    /// Open the bus for the flash, along with the widest read the controller accepts, found
+    /// by asking for each in turn.
+    pub fn flash_bus(settings: &Settings, speed: u32) -> Result<(SpidevBus, ReadWidth)> {
+        let mut bus = bus(settings, speed)?;
+        for (width, lines) in [
+            (ReadWidth::Quad, SpiModeFlags::SPI_RX_QUAD),
+            (ReadWidth::Dual, SpiModeFlags::SPI_RX_DUAL),
+        ] {
+            let options = SpidevOptions::new()
+                .mode(SpiModeFlags::SPI_MODE_0 | lines)
+                .build();
+            if bus.configure(&options).is_ok() {
+                return Ok((bus, width));
+            }
+        }
+        Ok((bus, ReadWidth::Single))
+    }
+}
+
+/// Connect to the flash, holding the FPGA in reset until the port is dropped.
+#[cfg(feature = "linux")]
+pub fn flash(
+    settings: &Settings,
+    pins: &PinConfig,
+    timing: &Timing,
+) -> Result<Box<dyn flash::Port>> {
+    let mut chip = devices::chip(settings)?;
+    // Acquired already asserted, so an FPGA held in reset by an earlier run is never let go
+    let reset = devices::output(
+        &mut chip,
+        pins.reset,
+        "FPGA reset",
+        pins.reset_active_low,
+        true,
+    )?;
+    let cs = devices::output(
+        &mut chip,
+        pins.flash_cs,
+        "flash CS",
+        pins.flash_cs_active_low,
+        false,
+    )?;
+    let (bus, width) = devices::flash_bus(settings, timing.flash_clock.unwrap_or(FLASH_SPEED))?;
+    let port = Flash {
+        port: crate::hal::HalFlash::new(bus, cs, Some(reset), pins)?,
+        width,
+    };
+
+    // Let the FPGA reset and fail configuration, releasing the bus
+    spin_sleep::sleep(timing.settle);
+    spin_sleep::sleep(timing.reset_pulse);
+
+    Ok(Box::new(port))
+}
+
+#[cfg(not(feature = "linux"))]
+pub fn flash(
+    _settings: &Settings,
+    _pins: &PinConfig,
+    _timing: &Timing,
+) -> Result<Box<dyn flash::Port>> {
+    unavailable()
+}
+
+/// Connect to the FPGA's configuration port at `baud`, holding it in reset, with `cdone` read
+/// for the preflight checks when given.
+#[cfg(feature = "linux")]
+pub fn fpga(
+    settings: &Settings,
+    pins: &PinConfig,
+    baud: u32,
+    cdone: Option<u8>,
+) -> Result<Box<dyn sram::Port>> {
+    let mut chip = devices::chip(settings)?;
+    let reset = devices::output(
+        &mut chip,
+        pins.reset,
+        "FPGA reset",
+        pins.reset_active_low,
+        true,
+    )?;
+    let cs = devices::output(
+        &mut chip,
+        pins.fpga_cs,
+        "FPGA CS",
+        pins.fpga_cs_active_low,
+        false,
+    )?;
+    // Held deselected, so the flash stays off the bus
+    let flash_cs = devices::output(
+        &mut chip,
+        pins.flash_cs,
+        "flash CS",
+        pins.flash_cs_active_low,
+        false,
+    )?;
+    let cdone = cdone
+        .map(|line| devices::input(&mut chip, line, "CDONE"))
+        .transpose()?;
+    let bus = devices::bus(settings, baud)?;
+
+    let port = crate::hal::HalFpga::new(bus, cs, reset, cdone, pins)?;
+
+    Ok(Box::new(Fpga {
+        port,
+        _flash_cs: flash_cs,
+    }))
+}
+
+/// The flash's port, reading on several lines at once where the controller can.
+#[cfg(feature = "linux")]
+struct Flash {
+    port: crate::hal::HalFlash<
+        linux_embedded_hal::SpidevBus,
+        linux_embedded_hal::CdevPin,
+        linux_embedded_hal::CdevPin,
+    >,
+    width: flash::ReadWidth,
+}
+
+#[cfg(feature = "linux")]
+impl flash::Port for Flash {
+    fn select(&mut self) -> Result<()> {
+        self.port.select()
+    }
+
+    fn deselect(&mut self) -> Result<()> {
+        self.port.deselect()
+    }
+
+    fn write(&mut self, byte: u8) -> Result<()> {
+        self.port.write(byte)
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        self.port.read()
+    }
+
+    fn read_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+        self.port.read_into(buffer)
+    }
+
+    fn read_width(&self) -> flash::ReadWidth {
+        self.width
+    }
+
+    fn read_wide(&mut self, width: flash::ReadWidth, buffer: &mut [u8]) -> Result<()> {
+        let lines = match width {
+            flash::ReadWidth::Single => return self.port.read_into(buffer),
+            flash::ReadWidth::Dual => 2,
+            flash::ReadWidth::Quad => 4,
+        };
+
+        let mut transfer = linux_embedded_hal::spidev::SpidevTransfer::read(buffer);
+        // spidev names the bytes holding tx_nbits and rx_nbits as padding
+        transfer.pad = u32::from_ne_bytes([0, lines, 0, 0]);
+        self.port
+            .bus()
+            .transfer(&mut transfer)
+            .map_err(|e| anyhow::anyhow!("Failed to read from the SPI bus: {e}"))
+    }
+
+    fn hand_off(&mut self) {
+        self.port.hand_off()
+    }
+}
+
+/// The FPGA's port, holding the flash's chip select deselected for as long as it's open.
+#[cfg(feature = "linux")]
+struct Fpga<P> {
+    port: P,
+    _flash_cs: linux_embedded_hal::CdevPin,
+}
+
+#[cfg(feature = "linux")]
+impl<P: sram::Port> sram::Port for Fpga<P> {
+    fn reset(&mut self, asserted: bool) -> Result<()> {
+        self.port.reset(asserted)
+    }
+
+    fn select(&mut self, asserted: bool) -> Result<()> {
+        self.port.select(asserted)
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.port.write(data)
+    }
+
+    fn check_reset(&self, asserted: bool) -> Result<()> {
+        self.port.check_reset(asserted)
+    }
+
+    fn cdone(&mut self, pin: u8) -> Result<bool> {
+        self.port.cdone(pin)
+    }
+}
+
+#[cfg(not(feature = "linux"))]
+pub fn fpga(
+    _settings: &Settings,
+    _pins: &PinConfig,
+    _baud: u32,
+    _cdone: Option<u8>,
+) -> Result<Box<dyn sram::Port>> {
+    unavailable()
+}
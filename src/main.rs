@@ -6,242 +6,3805 @@
 //! architecture may vary model-to-model).
 //! To build, simply run `cross build --release --target armv7-unknown-linux-musleabihf`, or
 //! whatever the correct target may be for the intended device.
+//!
+//! Building with the `read-only` feature leaves out every command and primitive that erases or
+//! programs the flash, for a binary that can only probe, read, and verify.
+
+// The helpers shared between the write paths and the reads are unused without the writes
+#![cfg_attr(feature = "read-only", allow(dead_code))]
 
+#[cfg(not(feature = "read-only"))]
+use lattice_prog::error;
+use lattice_prog::{
+    address, bitstream, board, boot, cancel, ch341a, chip, device, flash, ftdi, input, layout,
+    linux, mask, mock, parts, pins, plan, report, sample, sram, timing, trace,
+};
+
+use address::FlashAddress;
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use flash::FlashProgrammer;
-use rppal::gpio::{Gpio, OutputPin};
-use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
-use std::path::PathBuf;
+use cancel::CancellationToken;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use device::Device;
+use flash::{FlashProgrammer, Verification, VerifyMode};
+use input::Input;
+use layout::{Layout, Region};
+use mask::{Mask, MaskArgs};
+use pins::{Claims, PinConfig, Pulse};
+use plan::Plan;
+use progress::ProgressMode;
+use sample::Sample;
+use sram::{Preflight, SpiSettings, SramProgrammer};
+use std::path::{Path, PathBuf};
+use timing::Timing;
+use trace::{Replay, Trace};
+
+mod backup;
+mod burnin;
+mod config;
+mod confirm;
+mod counter;
+mod describe;
+mod diagnose;
+mod examples;
+mod export;
+mod hexdump;
+#[cfg(not(feature = "read-only"))]
+mod journal;
+#[cfg(not(feature = "read-only"))]
+mod manifest;
+mod otp;
+mod pinout;
+mod progress;
+mod protect;
+mod reliability;
+mod remote;
+#[cfg(not(feature = "read-only"))]
+mod restore;
+#[cfg(not(feature = "read-only"))]
+mod script;
+mod slots;
+mod systemd;
+mod vcd;
+mod watchdog;
+mod wear;
 
-mod flash;
+/// The version, marking read-only builds so they can be told apart.
+const VERSION: &str = if cfg!(feature = "read-only") {
+    concat!(
+        env!("CARGO_PKG_VERSION"),
+        " (read-only: cannot erase or program the flash)"
+    )
+} else {
+    env!("CARGO_PKG_VERSION")
+};
 
 /// Program a lattice FPGA with the provided synthesized design.
 ///
 /// Documentation: https://www.latticesemi.com/view_document?document_id=46502
 ///
-/// This assumes the following pins are connected:
+/// The FPGA is configured over an SPI peripheral (`--spi-bus`, SPI 0 by default: MOSI on
+/// GPIO 10, MISO on GPIO 9, SCK on GPIO 11). The other pins are set with flags, defaulting to:
 ///
-/// SPI 0:
-/// - MISO: GPIO 9
-/// - MOSI: GPIO 10
-/// - SCK: GPIO 11
+/// - FPGA CS (`--fpga-cs-gpio`): GPIO 13
+/// - Flash CS (`--flash-cs-gpio`): GPIO 5
+/// - FPGA Reset (`--reset-gpio`): GPIO 6
+/// - Flash SDI (`--flash-sdi-gpio`): GPIO 9
+/// - Flash SCK (`--flash-sck-gpio`): GPIO 11
+/// - Flash SDO (`--flash-sdo-gpio`): GPIO 10
 ///
-/// GPIO:
-/// - FPGA CS: GPIO 13
-/// - Flash CS: GPIO 5
-/// - FPGA Reset: GPIO 6
+/// `pinout` shows the wiring these settings expect.
 ///
 /// You may need to enable access to SPI and GPIO peripherals in the Pi's configuration, accessible
 /// either through `raspi-config` or /boot/config.txt
 #[derive(Parser)]
-#[command(author, version, long_about, verbatim_doc_comment)]
+#[command(author, version = VERSION, long_about, verbatim_doc_comment)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+
+    /// Print a JSON description of this build's subcommands, flags, features, and backends,
+    /// and of the configuration resolved from the other flags, without touching the hardware
+    ///
+    /// The document's `schema` field changes whenever a field is removed or changes meaning.
+    #[arg(long)]
+    describe: bool,
+
+    #[command(flatten)]
+    pins: PinConfig,
+
+    /// A TOML file describing the flash partitions
+    #[arg(long, global = true)]
+    layout: Option<PathBuf>,
+
+    /// A TOML file of named board profiles
+    ///
+    /// Defaults to `$XDG_CONFIG_HOME/lattice-prog/config.toml`, or `~/.config` when that's unset.
+    #[arg(long, global = true, requires = "profile")]
+    config: Option<PathBuf>,
+
+    /// The board profile whose pins, SPI bus, baud rate, transfer size, and flash size fill in
+    /// any of those flags not given
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Override a reset, wake, or settling delay, e.g. `--timing post_reset_wait=20ms`
+    ///
+    /// Available keys are settle, reset_pulse, post_reset_wait, wake_delay, cs_setup, cs_hold,
+    /// cdone_delay, cdone_poll, cdone_timeout, and release_settle. Values below the datasheet
+    /// minimums are clamped. The delays in use are printed with `-v`.
+    #[arg(long = "timing", global = true, value_parser = timing::parse_override)]
+    timings: Vec<(String, std::time::Duration)>,
+
+    /// Wait this long after releasing CRESET_B before polling CDONE, for boards whose power
+    /// supervisor holds the FPGA in reset a while longer
+    ///
+    /// Shorthand for `--timing cdone_delay=<DURATION>`.
+    #[arg(long, global = true, value_parser = timing::parse_duration)]
+    post_flash_delay: Option<std::time::Duration>,
+
+    /// The flash chip family whose datasheet busy limits apply
+    ///
+    /// Detected from the flash's JEDEC manufacturer ID when omitted.
+    #[arg(long, global = true, value_enum)]
+    chip_profile: Option<chip::ChipProfile>,
+
+    /// The erase opcodes the flash implements, as a comma-separated list of 0x20 (4 KiB), 0x52
+    /// (32 KiB), and 0xd8 (64 KiB)
+    ///
+    /// Read from the flash's SFDP table when omitted, falling back to all three for chips
+    /// without one. Each 64 KiB block is erased with the largest available.
+    #[arg(long, global = true, value_delimiter = ',', value_parser = chip::Erase::parse)]
+    erase_opcodes: Vec<chip::Erase>,
+
+    /// Flag blocks whose erase, or slowest page program, takes this many times the session's
+    /// median or longer, as a sign of wear
+    #[arg(long, global = true, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+    slow_block_factor: u32,
+
+    /// Clock the flash at this many Hz over the Pi's SPI peripheral or the linux or ftdi
+    /// backends
+    ///
+    /// Defaults to 8 MHz on the Pi, 1 MHz on linux, and 6 MHz on ftdi. Above 50 MHz, the most
+    /// plain reads (0x03) are specified for, reads switch to Fast Read with 8 dummy clocks
+    /// unless `--fast-read-dummy` says otherwise.
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    flash_clock: Option<u32>,
+
+    /// Read with Fast Read (0x0B), sending this many dummy clocks after the address
+    ///
+    /// Most parts take 8, but some, such as Micron's, can be configured for more at high
+    /// clocks. Must be a multiple of 8. Dual and quad output reads use the same count.
+    #[arg(long, global = true, value_parser = timing::parse_dummy_clocks)]
+    fast_read_dummy: Option<u8>,
+
+    /// How to report progress during long operations
+    ///
+    /// With `json`, one event per line is written to stderr, always ending with a
+    /// `{"phase":"done","ok":...}` event.
+    #[arg(long, global = true, value_enum, default_value = "bars")]
+    progress: ProgressMode,
+
+    /// Abort if the hardware makes no progress for this many seconds (0 to disable)
+    ///
+    /// When the watchdog fires, the pins are released, a failure report written, and the
+    /// process exits with code 3.
+    #[arg(long, global = true, default_value = "600")]
+    watchdog_seconds: u64,
+
+    /// Where to write the failure report, a JSON object of the last phase, address, and time
+    /// since progress, when work is abandoned
+    ///
+    /// Defaults to `lattice-prog-watchdog.json` in the temporary directory.
+    #[arg(long, global = true)]
+    watchdog_report: Option<PathBuf>,
+
+    /// How long to let work stop at its next page after SIGTERM before abandoning it
+    ///
+    /// An operation abandoned mid-way is logged, the pins released, a failure report written
+    /// as for the watchdog, and the process exits with code 143.
+    #[arg(long, global = true, default_value = "30s", value_parser = timing::parse_duration)]
+    term_grace: std::time::Duration,
+
+    /// Skip confirmation prompts before destructive operations
+    ///
+    /// Required for those operations when stdin isn't a terminal.
+    #[arg(long, global = true)]
+    yes: bool,
+
+    /// Describe what's being tried while probing and recovering the hardware
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Count erases of each 64 KiB block in this file, keyed by the flash's ID
+    ///
+    /// Shown with the `wear` subcommand. The counts are only recorded, never acted on.
+    #[arg(long, global = true)]
+    wear_file: Option<PathBuf>,
+
+    /// The hardware to drive
+    ///
+    /// The mock backend, also accepted as `sim`, simulates the flash and an FPGA that
+    /// configures from any valid bitstream, for practice, demos, and CI without a Pi. `burnin` and `--verify-after-boot`
+    /// need real hardware.
+    ///
+    /// The linux backend drives boards other than a Pi, such as a BeagleBone or an Orange Pi,
+    /// through `--gpiochip` and `--spidev`, taking the GPIO flags as line offsets on that chip.
+    /// It needs a build with the `linux` feature.
+    ///
+    /// The ftdi backend drives an FT232H or FT2232H USB adapter from a desktop, wired as set
+    /// with `--ftdi-cs`, `--ftdi-reset`, and `--ftdi-cdone`. It needs a build with the `ftdi`
+    /// feature.
+    ///
+    /// The ch341a backend reaches only the flash, through a CH341A USB programmer, for
+    /// recovering a board from any PC. Nothing drives CRESET_B, so hold the FPGA in reset or
+    /// leave the board unpowered. It needs a build with the `ch341a` feature.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    backend: mock::Backend,
+
+    /// The GPIO character device the linux backend drives the control lines through
+    ///
+    /// A bare name such as `gpiochip0` is looked up in /dev.
+    #[arg(long, global = true, default_value = "/dev/gpiochip0")]
+    gpiochip: PathBuf,
+
+    /// The spidev device the linux backend drives the flash and FPGA through
+    ///
+    /// Its own chip select should be left unconnected, since both are selected with the
+    /// GPIO flags.
+    #[arg(long, global = true, default_value = "/dev/spidev0.0")]
+    spidev: PathBuf,
+
+    /// The USB serial number of the adapter the ftdi backend opens, when several are plugged in
+    #[arg(long, global = true)]
+    ftdi_serial: Option<String>,
+
+    /// The adapter pin selecting the flash and the FPGA, numbered 3-7 for ADBUS3-7 and 8-15
+    /// for ACBUS0-7
+    #[arg(long, global = true, default_value = "4")]
+    ftdi_cs: u8,
+
+    /// The adapter pin driving CRESET_B
+    #[arg(long, global = true, default_value = "7")]
+    ftdi_reset: u8,
+
+    /// The adapter pin reading CDONE, checked with `--cdone`
+    #[arg(long, global = true, default_value = "6")]
+    ftdi_cdone: u8,
+
+    /// The CH341A the ch341a backend opens, as `<bus>:<address>` from `lsusb`, when several
+    /// are plugged in
+    #[arg(long, global = true, value_parser = ch341a::parse_device)]
+    ch341a_device: Option<ch341a::UsbAddress>,
+
+    /// Keep the simulated flash in this file, so its contents persist between runs
+    ///
+    /// Created blank when missing. Without it, the simulated flash starts blank every run.
+    #[arg(long, global = true)]
+    mock_image: Option<PathBuf>,
+
+    /// The capacity of a new simulated flash
+    #[arg(long, global = true, default_value = "0x400000", value_parser = input::parse_size)]
+    mock_size: usize,
+
+    /// Make the simulated flash silently ignore programs and erases, as a flash whose writes
+    /// never take effect does
+    #[arg(long, global = true)]
+    mock_ignore_writes: bool,
+
+    /// Make the simulated flash implement only 4 KiB sector erases, listing just those in its
+    /// SFDP table and ignoring block erases
+    #[arg(long, global = true)]
+    mock_sector_erase_only: bool,
 }
 
+// Parsed once per run, so the size of the largest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Program the FPGA's internal flash
+    ///
+    /// With `--sequence`, configures the FPGA with several bitstreams in turn, as for designs
+    /// using dynamic reconfiguration.
     Sram {
-        /// Path to the input RTL
-        input: PathBuf,
+        #[command(flatten)]
+        input: Option<Input>,
+
+        #[command(flatten)]
+        sequence: Sequence,
+
+        #[command(flatten)]
+        reliability: reliability::Options,
+
+        #[command(flatten)]
+        spi: SpiSettings,
+
+        #[command(flatten)]
+        preflight: Preflight,
 
-        /// SPI baud rate
+        /// The target device, checked against the die the bitstream was built for
         ///
-        /// Values that are too low or too high seem to corrupt the bitstream.
-        #[arg(short, long, default_value = "10000000")]
-        baud: u32,
+        /// Defaults to the layout's `device`, then the board profile's. When none is given,
+        /// the device is inferred from the bitstream header where possible.
+        #[arg(long, value_enum)]
+        device: Option<Device>,
 
-        /// SPI transfer buffer size
+        /// Program the bitstream even if it doesn't look like one, was built for another
+        /// device, or its size doesn't match
+        #[arg(long)]
+        force: bool,
+
+        /// After successful configuration, drive a GPIO high for some milliseconds
         ///
-        /// The maximum possible value is 65536, but any value above 4096 must be set in the Pi's
-        /// boot configuration (by inserting spidev.bufsiz=<desired value> in /boot/cmdline.txt).
-        #[arg(short, long, default_value = "16384")]
-        transfer: usize,
+        /// Given as `<gpio>:<millis>`, and may be repeated to pulse several pins in order.
+        #[arg(long, value_parser = Pulse::parse)]
+        post_program_pulse: Vec<Pulse>,
     },
+    #[cfg(not(feature = "read-only"))]
     /// Program the flash chip
+    ///
+    /// Ctrl-C while writing or verifying stops after the current page, once the flash is no
+    /// longer busy, and reports how far the write got.
     Flash {
-        /// Path to the input RTL
-        input: PathBuf,
+        #[command(flatten)]
+        input: Input,
+
+        /// Record every flash transaction to this file
+        #[arg(long)]
+        trace: Option<PathBuf>,
+
+        /// Skip the pre-check that leaves the flash untouched if it already holds the image
+        ///
+        /// The pre-check hashes the target range and compares it against the input, which
+        /// costs a full read of the range.
+        #[arg(long)]
+        no_precheck: bool,
+
+        /// The flash address to write the image to
+        #[arg(short, long, value_parser = FlashAddress::parse, conflicts_with = "partition")]
+        offset: Option<FlashAddress>,
+
+        /// Write the image to the start of the named layout partition
+        #[arg(long)]
+        partition: Option<String>,
+
+        /// Allow the image to extend beyond the end of the partition
+        #[arg(long)]
+        allow_cross_partition: bool,
+
+        /// Save the blocks about to be erased to a timestamped file in this directory
+        #[arg(long)]
+        backup: Option<PathBuf>,
+
+        #[command(flatten)]
+        verification: Verification,
+
+        /// Warn when what's written fills at least this percentage of the image's partition,
+        /// or of the flash outside a layout
+        #[arg(long, default_value = "90", value_parser = clap::value_parser!(u8).range(0..=100))]
+        utilization_warning: u8,
+
+        #[command(flatten)]
+        mask: MaskArgs,
+
+        /// Also write another image in the same session, given as `<path>@<offset>` or
+        /// `<path>@<partition>` (may be repeated)
+        ///
+        /// Erases are planned across all images together, so images sharing an erase block
+        /// don't wipe one another.
+        #[arg(long = "image", value_parser = input::Placement::parse, conflicts_with = "trace")]
+        images: Vec<input::Placement>,
+
+        /// Flash a bitstream away from offset 0 even though no multiboot header points at it
+        #[arg(long)]
+        allow_unbootable: bool,
+
+        /// The target device, checked against the die the bitstream was built for
+        ///
+        /// Defaults to the layout's `device`, then the board profile's. When none is given,
+        /// the device is inferred from the bitstream header where possible.
+        #[arg(long, value_enum)]
+        device: Option<Device>,
+
+        /// Flash an image to offset 0 even though it doesn't look like a bitstream or a
+        /// multiboot header, or a bitstream built for another device
+        #[arg(long)]
+        force: bool,
+
+        /// Write a multiboot header at offset 0 pointing at the image, when none already does
+        ///
+        /// Every entry of the header points at the image, so it's configured both at power-on
+        /// and on warm boots.
+        #[arg(long, conflicts_with_all = ["trace", "allow_unbootable"])]
+        write_boot_header: bool,
+
+        /// Skip the erases, pre-check, and overwrite confirmation for a factory-fresh flash
+        /// that's known to be blank
+        ///
+        /// The written image is still verified in full, which catches a flash that wasn't
+        /// actually blank.
+        #[arg(long, conflicts_with_all = ["backup", "trace"])]
+        assume_blank: bool,
+
+        /// Leave the holes of a sparse input file erased, rather than programming the zeros
+        /// they read as
+        ///
+        /// Holes are found with SEEK_HOLE, so zeros actually written to the file are still
+        /// programmed. On filesystems that can't report holes, the whole image is programmed.
+        #[arg(long)]
+        skip_zero_blocks: bool,
+
+        /// Read the flash back first and only erase and write the sectors that differ from
+        /// the image
+        ///
+        /// Sectors are the flash's smallest erase, usually 4 KiB. Saves time and wear when
+        /// only part of a bitstream changes between builds. Only a single image is supported.
+        #[arg(long, conflicts_with_all = ["assume_blank", "skip_zero_blocks", "images"])]
+        incremental: bool,
+
+        /// Write a 64 KiB block at a time, recording each block in PATH once it reads back,
+        /// and on a later run of the same image, skip the blocks PATH records
+        ///
+        /// Resumes a flash cut short by Ctrl-C or a dropped connection. PATH is removed once
+        /// the whole image is written. Every block is compared as it's written, in place of
+        /// the usual verify. Only a single image is supported.
+        #[arg(long, value_name = "PATH", conflicts_with_all = [
+            "assume_blank", "skip_zero_blocks", "incremental", "images",
+        ])]
+        journal: Option<PathBuf>,
+
+        /// Restore a full-chip dump exactly: erase everything it covers, program only the pages
+        /// that aren't blank, and check the flash's SHA-256 against the dump's
+        ///
+        /// The dump is written from offset 0. A JEDEC ID recorded in a `--backup` sidecar next
+        /// to it is compared against the flash's.
+        #[arg(long, conflicts_with_all = [
+            "offset", "partition", "images", "write_boot_header", "assume_blank",
+            "skip_zero_blocks", "journal", "trace", "and_load",
+        ])]
+        from_dump: bool,
+
+        /// Restore a dump smaller than the flash, leaving the rest of the flash as it is
+        #[arg(long, requires = "from_dump")]
+        allow_size_mismatch: bool,
+
+        /// After flashing, also configure the FPGA's SRAM with the image over hardware SPI,
+        /// so it runs without a power cycle
+        #[arg(long, conflicts_with = "verify_after_boot")]
+        and_load: bool,
+
+        #[command(flatten)]
+        spi: SpiSettings,
+
+        #[command(flatten)]
+        preflight: Preflight,
+
+        #[command(flatten)]
+        boot_check: boot::BootCheck,
+
+        /// The image's version, checked against the version counter and written to it once
+        /// the image is verified
+        #[arg(long = "version")]
+        image_version: Option<u32>,
+
+        /// Also refuse images whose `--version` is below this, whatever the counter holds
+        #[arg(long, requires = "image_version")]
+        require_version_ge: Option<u32>,
+
+        #[command(flatten)]
+        counter: counter::Location,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Flash up to four bitstreams with a multiboot header at offset 0 that points the
+    /// FPGA's power-on and warm boots at them
+    ///
+    /// A warm boot through `SB_WARMBOOT` configures the bitstream its S1:S0 select, counting
+    /// from 0 in the order given. Warm boots past the last bitstream configure the power-on
+    /// one. Only the header and the bitstreams are written; the flash between them is left as
+    /// it is.
+    Multiboot {
+        /// A bitstream and where to write it, as `<path>@<offset>` or `<path>@<partition>`
+        #[arg(
+            required = true,
+            num_args = 1..=bitstream::WARMBOOT_IMAGES,
+            value_parser = input::Placement::parse,
+        )]
+        images: Vec<input::Placement>,
+
+        /// The bitstream configured at power-on, counting from 0
+        #[arg(long, default_value = "0")]
+        power_on: usize,
+
+        /// Write the combined image to this file, with 0xFF between the bitstreams, rather
+        /// than flashing it
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// The target device, checked against the die each bitstream was built for
+        ///
+        /// Defaults to the layout's `device`, then the board profile's.
+        #[arg(long, value_enum)]
+        device: Option<Device>,
+
+        /// Write images that don't look like bitstreams, or that were built for another device
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        verification: Verification,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Write every region a manifest lists, such as a bitstream, its soft CPU's firmware, and
+    /// data, erasing, writing, and verifying them together
+    ///
+    /// The manifest is TOML, with a `[[region]]` table for each region giving its `file` and
+    /// either an `offset` or a layout `partition`, and optionally a `name` for messages. Files
+    /// are relative to the manifest. Regions that already hold their file are skipped.
+    FlashManifest {
+        /// Path to the manifest
+        manifest: PathBuf,
+
+        /// Skip the pre-check that leaves regions already holding their files untouched
+        #[arg(long)]
+        no_precheck: bool,
+
+        /// The target device, checked against the die any bitstream was built for
+        ///
+        /// Defaults to the layout's `device`, then the board profile's.
+        #[arg(long, value_enum)]
+        device: Option<Device>,
+
+        /// Write a region at offset 0 that doesn't look like a bitstream or a multiboot header,
+        /// or a bitstream built for another device
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        verification: Verification,
     },
     /// Dump the flash
+    ///
+    /// Without `--length`, 256 bytes are dumped (or the whole partition with `--partition`).
     Dump {
-        /// The address to dump
-        #[arg(short, long, default_value = "0")]
-        address: usize,
-
-        /// The amount of bytes to dump
-        #[arg(short, long, default_value = "256")]
-        length: usize,
-    },
-}
-
-#[allow(dead_code)]
-struct SramProgrammer {
-    spi: Spi,
-    fpga_reset: OutputPin,
-    fpga_cs: OutputPin,
-    flash_cs: OutputPin,
-}
-
-impl SramProgrammer {
-    pub fn new(baud: u32) -> Result<Self> {
-        let mut spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, baud, Mode::Mode0)
-            .with_context(|| "Failed to acquire SPI")?;
-
-        let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
-        let mut fpga_reset = gpio
-            .get(6)
-            .with_context(|| "Failed to acquire FPGA reset pin")?
-            .into_output_high();
-        let mut fpga_cs = gpio
-            .get(13)
-            .with_context(|| "Failed to acquire FPGA CS pin")?
-            .into_output_high();
-        let flash_cs = gpio
-            .get(5)
-            .with_context(|| "Failed to acquire flash CS pin")?
-            .into_output_high();
-
-        sleep(1);
-        // Set CRESET_B low for at least 200 ns, ensuring the FPGA's CS is low when reset is
-        // released
-        fpga_reset.set_low();
-        fpga_cs.set_low();
-        sleep(1);
-        // Wait for at least 1200 us as the FPGA clears configuration memory
-        fpga_reset.set_high();
-        sleep(10);
-
-        // Set CS high and clock in 8 dummy bits
-        fpga_cs.set_high();
-        spi.write(&[0u8])?;
-        fpga_cs.set_low();
-
-        // Device ready for configuration
-        Ok(Self {
-            spi,
-            fpga_reset,
-            fpga_cs,
-            flash_cs,
-        })
-    }
+        #[command(flatten)]
+        region: Region,
 
-    pub fn program_bytes(mut self, mut data: Vec<u8>, transfer: usize) -> Result<()> {
-        if transfer > 65536 {
-            return Err(anyhow::Error::msg(format!(
-                "SPI transfer buffer (set to {transfer}) must be less than 65536"
-            )));
-        }
+        /// Record every flash transaction to this file
+        #[arg(long)]
+        trace: Option<PathBuf>,
+
+        /// How to write the dumped bytes
+        ///
+        /// In a hexdump, banner lines starting with `----` mark layout partitions and recognized
+        /// bitstream structures, leaving the data rows' columns unchanged.
+        #[arg(long, value_enum, default_value_t)]
+        format: export::Format,
 
-        // The transaction requires 49 dummy bits after waiting a maximum of 100 clocks
-        data.extend([0u8; 18]);
-        let bar = indicatif::ProgressBar::new(data.len() as u64);
-        bar.tick();
+        /// Leave the banners out of a hexdump
+        #[arg(long)]
+        no_annotate: bool,
 
-        for block in data.chunks(transfer) {
-            self.spi
-                .write(block)
-                .with_context(|| "Error writing to SPI bus")?;
-            bar.inc(block.len() as u64);
-        }
+        /// The symbol name for the array formats
+        #[arg(long, default_value = "flash_data")]
+        symbol: String,
 
-        sleep(1);
-        self.fpga_cs.set_high();
-        sleep(1);
+        /// Write the dump to this file rather than stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
 
-        Ok(())
-    }
+        /// Read while the FPGA keeps running, without touching CRESET_B
+        ///
+        /// Only safe on boards where the running design tri-states its flash pins: if the
+        /// design drives the bus, both it and the read may see corrupted data. The read is
+        /// refused if the FPGA's CS is seen active, and nothing is written.
+        #[arg(long)]
+        no_fpga_reset: bool,
+    },
+    /// Compare the flash against an image without writing anything
+    ///
+    /// For re-checking a board against a known-good image, such as in CI or manufacturing.
+    /// `--verify-mode sample` reads only a seeded random subset of pages, for a quick check of
+    /// a large image; pass the printed `--seed` again to repeat exactly the same check.
+    Verify {
+        #[command(flatten)]
+        input: Input,
 
-    pub fn reset() -> Result<()> {
-        let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+        /// The flash address of the image, also accepted as `--address`
+        #[arg(
+            short,
+            long,
+            visible_alias = "address",
+            value_parser = FlashAddress::parse,
+            conflicts_with = "partition"
+        )]
+        offset: Option<FlashAddress>,
 
-        gpio.get(6)?.into_input().set_reset_on_drop(false);
-        gpio.get(13)?.into_input().set_reset_on_drop(false);
-        gpio.get(5)?.into_input().set_reset_on_drop(false);
+        /// Compare against the start of the named layout partition
+        #[arg(long)]
+        partition: Option<String>,
 
-        Ok(())
-    }
+        /// Allow the image to extend beyond the end of the partition
+        #[arg(long)]
+        allow_cross_partition: bool,
+
+        #[command(flatten)]
+        verification: Verification,
+
+        #[command(flatten)]
+        mask: MaskArgs,
+
+        /// Print a sample verification's result, seed, coverage, and page addresses as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Read while the FPGA keeps running, without touching CRESET_B
+        ///
+        /// Only safe on boards where the running design tri-states its flash pins: if the
+        /// design drives the bus, both it and the read may see corrupted data. The read is
+        /// refused if the FPGA's CS is seen active, and nothing is written.
+        #[arg(long)]
+        no_fpga_reset: bool,
+    },
+    /// Print the SHA-256 of a flash range
+    ///
+    /// Without `--length`, the checksum covers the rest of the flash (or the partition with
+    /// `--partition`).
+    Checksum {
+        #[command(flatten)]
+        region: Region,
+    },
+    /// Check that a flash range is erased, reading all 0xFF
+    ///
+    /// Without `--length`, the check runs to the end of the flash (or of the partition with
+    /// `--partition`). A range that isn't blank fails, reporting its first non-blank address.
+    BlankCheck {
+        #[command(flatten)]
+        region: Region,
+    },
+    /// Show which flash blocks writing an image would modify
+    ///
+    /// Each block the image covers is read back and classified, and the resulting plan is
+    /// printed with an estimated duration. With `--execute`, exactly the printed plan is carried
+    /// out and the result verified.
+    Plan {
+        #[command(flatten)]
+        input: Input,
+
+        /// The flash address to write the image to
+        #[arg(short, long, value_parser = FlashAddress::parse, conflicts_with = "partition")]
+        offset: Option<FlashAddress>,
+
+        /// Write the image to the start of the named layout partition
+        #[arg(long)]
+        partition: Option<String>,
+
+        /// Allow the image to extend beyond the end of the partition
+        #[arg(long)]
+        allow_cross_partition: bool,
+
+        /// Print the plan as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Carry out the printed plan
+        #[cfg(not(feature = "read-only"))]
+        #[arg(long)]
+        execute: bool,
+
+        /// Before executing, save the blocks about to be erased to this directory
+        #[cfg(not(feature = "read-only"))]
+        #[arg(long, requires = "execute")]
+        backup: Option<PathBuf>,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Erase the entire flash with a chip erase, or a range of it
+    ///
+    /// The chip erase (0xC7) is sent after a write enable and the status polled until it
+    /// completes, which takes anywhere from seconds to minutes depending on the chip. The
+    /// start of the flash is then read back to check it's blank.
+    ///
+    /// A range, given by `--address` and `--length` or by `--partition`, must lie on the
+    /// flash's erase boundaries, and can't include a read-only partition.
+    Erase {
+        /// Erase the whole chip
+        #[arg(
+            long,
+            required_unless_present_any = ["address", "partition"],
+            conflicts_with_all = ["address", "length", "partition"]
+        )]
+        all: bool,
+
+        #[command(flatten)]
+        region: Region,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Attempt to recover an unresponsive flash chip
+    ///
+    /// This is the option of last resort, for chips whose status register reads garbage so
+    /// that every normal operation hangs waiting for the chip to become ready. The chip is
+    /// woken and sent a chip erase without any status polling, then left alone for a fixed
+    /// worst-case time before its JEDEC ID is read to see whether it came back.
+    ///
+    /// This erases the entire flash.
+    Recover {
+        /// Erase the whole chip without polling its status
+        #[arg(long, required = true)]
+        blind_chip_erase: bool,
+
+        /// How long to wait for the chip erase to complete
+        #[arg(long, default_value = "200s", value_parser = timing::parse_duration)]
+        erase_wait: std::time::Duration,
+    },
+    /// Repeatedly reconfigure the FPGA from flash, timing each configuration
+    ///
+    /// Each cycle pulses CRESET_B and waits for CDONE to rise. With `--verify-image`, random
+    /// flash pages are also checked against the image every `--verify-every` cycles. Ctrl-C
+    /// stops after the current cycle with a partial summary.
+    Burnin {
+        /// The number of reconfiguration cycles
+        #[arg(long, default_value = "50")]
+        cycles: usize,
+
+        /// The GPIO connected to the FPGA's CDONE output
+        #[arg(long)]
+        cdone: u8,
+
+        /// How long to wait for CDONE after releasing reset before counting a failure, instead
+        /// of the `cdone_timeout` timing
+        #[arg(long, value_parser = timing::parse_duration)]
+        done_timeout: Option<std::time::Duration>,
+
+        /// The image expected in flash, enabling spot-verifies
+        #[arg(long)]
+        verify_image: Option<PathBuf>,
+
+        /// The flash address of the image
+        #[arg(long, default_value = "0", value_parser = FlashAddress::parse, requires = "verify_image")]
+        offset: FlashAddress,
+
+        /// Spot-verify every this many cycles
+        #[arg(
+            long,
+            default_value = "10",
+            requires = "verify_image",
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        verify_every: usize,
+
+        /// Write a record of each cycle to this file, as JSON lines if it ends in `.json` and
+        /// CSV otherwise
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Send an arbitrary command to the flash and print the response in hex
+    ///
+    /// Flash CS is asserted, the `--write` bytes clocked out, `--read` bytes clocked in, and CS
+    /// deasserted. Program, erase, and status register write opcodes are refused unless
+    /// `--allow-destructive` is given.
+    RawCmd {
+        /// The bytes to write, starting with the opcode, e.g. `--write 0x35` or `--write 0x03,0,0,0`
+        #[arg(long, value_delimiter = ',', value_parser = input::parse_byte)]
+        write: Vec<u8>,
+
+        /// The number of bytes to read after writing
+        #[arg(long, default_value = "0", value_parser = input::parse_size)]
+        read: usize,
+
+        /// Send a write enable (0x06) before the command
+        #[arg(long)]
+        keep_write_enable: bool,
+
+        /// Allow program, erase, and status register write opcodes
+        #[arg(long)]
+        allow_destructive: bool,
+
+        /// Record every flash transaction to this file
+        #[arg(long)]
+        trace: Option<PathBuf>,
+    },
+    /// Identify the flash from its JEDEC ID
+    ///
+    /// The ID is looked up in the built-in database of flash parts, and the capacity, page size,
+    /// and block erase the flash will be programmed with are printed.
+    Detect,
+    /// Print the parameters the flash describes itself with in its SFDP table
+    ///
+    /// The address width, capacity, fast reads, erase commands, and page size are read from the
+    /// basic flash parameter table. Flash that isn't in the built-in database is programmed with
+    /// these.
+    FlashInfo,
+    /// Print the flash's factory-programmed 64-bit unique ID in hex
+    ///
+    /// The ID is read with 0x4B, for telling boards apart when tracking them. Flash without
+    /// one reads all ones or all zeros, which is an error.
+    UniqueId,
+    /// Put the flash into deep power-down (0xB9), to save power on battery-powered boards
+    ///
+    /// The flash then ignores everything until it's woken with 0xAB, which `wake` and every
+    /// other subcommand send first. An iCE40 sends it too whenever it configures from the flash.
+    PowerDown,
+    /// Wake the flash from deep power-down (0xAB) and print its JEDEC ID
+    Wake,
+    /// Print ready-to-run commands for common tasks on this setup
+    ///
+    /// The commands carry over the active layout, pin polarity, and chip profile, detecting
+    /// the flash when the hardware is reachable.
+    Examples,
+    /// Release all programming pins to inputs
+    Release {
+        /// Leave CRESET_B untouched, as after reading with `--no-fpga-reset`
+        #[arg(long)]
+        no_fpga_reset: bool,
+    },
+    /// Find which GPIO selects the flash, for boards with swapped or miswired chip selects
+    ///
+    /// Each candidate line is tried as the flash chip select while the others are held high
+    /// and the FPGA is held in reset, and the pin settings that match the wiring are printed.
+    DiagnoseCs {
+        /// Also try this GPIO as the flash chip select (may be repeated)
+        #[arg(long = "candidate-pin")]
+        candidate_pins: Vec<u8>,
+    },
+    /// Replay a recorded trace, checking the current logic against it
+    ///
+    /// The recorded responses are fed back into the same flash and verify logic, failing at the
+    /// first transaction where the logic's behavior diverges from the recording.
+    Replay {
+        /// Path to the recorded trace
+        trace: PathBuf,
+
+        /// Path to the input RTL, required for replaying a flash
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+    },
+    /// Convert a recorded trace into a pin-level waveform for a waveform viewer
+    ///
+    /// Works offline, without touching the hardware. Each bit is drawn one period of
+    /// `--clock-khz` long, with CS framed by the `cs_setup` and `cs_hold` timings.
+    ExportTrace {
+        /// Path to the recorded trace
+        trace: PathBuf,
+
+        /// The format to write
+        #[arg(long, value_enum, default_value_t)]
+        format: trace::Format,
+
+        /// The SCK frequency to draw the bits at
+        #[arg(long, default_value = "500")]
+        clock_khz: u32,
+
+        /// Where to write the waveform
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Convert the layout to and from the formats of other flash tools
+    ///
+    /// Works offline, without touching the hardware.
+    Layout {
+        #[command(subcommand)]
+        action: LayoutAction,
+    },
+    /// Read or raise the rollback-protection version counter
+    Counter {
+        #[command(subcommand)]
+        action: CounterAction,
+    },
+    /// Read, program, erase, or lock the flash's one-time programmable security registers
+    ///
+    /// Supported on Winbond and GigaDevice flash, whose three 256-byte registers keep data
+    /// such as serial numbers and calibration through every reflash and chip erase.
+    Otp {
+        #[command(subcommand)]
+        action: OtpAction,
+    },
+    /// Update A/B slots beside a golden image, moving the multiboot header's power-on entry
+    /// only once the new slot verifies
+    ///
+    /// The slots are the layout's `golden`, `slot-a`, and `slot-b` partitions. Warm boot 0
+    /// always configures the golden image, and warm boots 1 and 2 slots A and B.
+    Slot {
+        #[command(subcommand)]
+        action: SlotAction,
+    },
+    /// Protect a range of the flash against writes through its status registers
+    ///
+    /// The block protect bits covering the range are worked out for the detected chip family,
+    /// written non-volatilely, and read back to confirm. With `--status`, the currently
+    /// protected range is decoded instead.
+    Lockdown {
+        /// The range to protect, as `<start>:<end>` with an exclusive end, e.g. `0x0:0x80000`
+        ///
+        /// Chips can only protect ranges of certain sizes at the top or bottom of the flash.
+        #[cfg(not(feature = "read-only"))]
+        #[arg(long, value_parser = protect::parse_range, required_unless_present = "status")]
+        protect_range: Option<std::ops::Range<usize>>,
+
+        /// Also set status register protect, locking the registers while WP# is held low
+        ///
+        /// With WP# strapped low, the protection can't be changed without reworking the board.
+        #[cfg(not(feature = "read-only"))]
+        #[arg(long, requires = "protect_range")]
+        srp: bool,
+
+        /// Print the currently protected range instead of changing it
+        #[cfg_attr(
+            not(feature = "read-only"),
+            arg(long, conflicts_with = "protect_range")
+        )]
+        #[cfg_attr(feature = "read-only", arg(long, required = true))]
+        status: bool,
+    },
+    /// Lock the flash against erases and programs through its block protect bits
+    ///
+    /// The whole flash is protected unless `--range` narrows it. The bits are written
+    /// non-volatilely and read back to confirm; `unprotect` clears them before reflashing.
+    #[cfg(not(feature = "read-only"))]
+    Protect {
+        /// Protect only this range, as `<start>:<end>` with an exclusive end
+        ///
+        /// Chips can only protect ranges of certain sizes at the top or bottom of the flash.
+        #[arg(long, value_parser = protect::parse_range)]
+        range: Option<std::ops::Range<usize>>,
+
+        /// Also set status register protect, locking the registers while WP# is held low
+        ///
+        /// With WP# strapped low, the protection can't be changed without reworking the board.
+        #[arg(long)]
+        srp: bool,
+    },
+    /// Clear the block protect bits and status register protect, so the flash can be written
+    ///
+    /// Fails when SRP is set and WP# is held low, since the registers are locked then.
+    #[cfg(not(feature = "read-only"))]
+    Unprotect,
+    /// Print the wiring diagram for the active pin configuration
+    ///
+    /// Lists each signal's GPIO and physical pin on the 40-pin header, for both programmers,
+    /// flagging GPIOs claimed twice and those not broken out on the header.
+    Pinout {
+        #[arg(long, value_enum, default_value_t)]
+        format: pinout::Format,
+    },
+    /// Show the erase counts recorded with `--wear-file`
+    Wear {
+        /// Mark blocks erased at least this many times
+        #[arg(long, default_value = "50000")]
+        threshold: u64,
+    },
+    /// Accept commands from `remote` clients over TCP, running them on this machine's hardware
+    ///
+    /// Jobs run one at a time. Anyone who can reach the address can program the attached
+    /// hardware, so only listen on trusted networks.
+    Serve {
+        /// The address and port to listen on
+        #[arg(long, default_value = "0.0.0.0:8976")]
+        listen: String,
+    },
+    /// Run a command on the hardware of a machine running `serve`
+    ///
+    /// The input file of `flash`, `sram`, and `verify` is uploaded with the command, and progress is
+    /// relayed as it happens. Any other paths refer to the remote machine. The exit code is the
+    /// remote command's, or 4 if the connection is lost before it finishes.
+    ///
+    /// Example: `lattice-prog remote --host pi4.lab:8976 flash design.bin --offset 0`
+    Remote {
+        /// The server's address and port
+        #[arg(long)]
+        host: String,
+
+        /// The command to run remotely
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        args: Vec<String>,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Run the steps listed in a TOML script within one flash session
+    ///
+    /// Steps run in order and the session stops at the first failure, other than of steps
+    /// marked `allow_failure`. The available operations are flash, verify, erase, dump, and raw.
+    Run {
+        /// Path to the script
+        script: PathBuf,
+
+        /// Substitute `value` for `${name}` in the script's parameters, given as `name=value`
+        /// (may be repeated)
+        #[arg(long = "var", value_parser = script::parse_var)]
+        vars: Vec<(String, String)>,
+    },
 }
 
-fn sleep(milliseconds: u64) {
-    std::thread::sleep(std::time::Duration::from_millis(milliseconds));
+#[derive(Subcommand)]
+enum LayoutAction {
+    /// Write the `--layout` file in another tool's format
+    Export {
+        #[arg(long, value_enum)]
+        format: layout::Format,
+
+        /// Write to this file rather than stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Convert a flashrom layout into a layout file
+    ///
+    /// flashrom has no read-only regions, so mark any in the result by hand.
+    Import {
+        /// Path to the flashrom layout
+        path: PathBuf,
+
+        /// Write to this file rather than stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
-fn program(filepath: PathBuf, baud: u32, transfer: usize) -> Result<()> {
-    let data = std::fs::read(filepath).with_context(|| "Error reading input file")?;
-    let programmer = SramProgrammer::new(baud)?;
-    programmer.program_bytes(data, transfer)?;
+#[derive(Subcommand)]
+enum CounterAction {
+    /// Print the counter's value
+    Read {
+        #[command(flatten)]
+        location: counter::Location,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Raise the counter, which can never be lowered again
+    Bump {
+        #[command(flatten)]
+        location: counter::Location,
 
-    Ok(())
+        /// The new value, defaulting to one above the current value
+        #[arg(long)]
+        to: Option<u32>,
+    },
 }
 
-fn flash(filepath: PathBuf) -> Result<()> {
-    let data = std::fs::read(filepath).with_context(|| "Error reading input file")?;
-    let mut programmer = FlashProgrammer::new()?;
-    println!("Flashing data...");
-    programmer.flash_data(&data, 0)?;
-    println!("Verifying data...");
-    programmer.verify_data(&data, 0)?;
+#[derive(Subcommand, Clone, Debug)]
+enum OtpAction {
+    /// Print a security register as a hexdump, or save it to a file
+    Read {
+        /// The register, from 1 to 3
+        #[arg(long, value_parser = otp::parse_register)]
+        register: u8,
 
-    Ok(())
+        /// Save the register's 256 bytes here instead of printing them
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Program a file's bytes into a security register, which must be erased where they go
+    Program {
+        /// The register, from 1 to 3
+        #[arg(long, value_parser = otp::parse_register)]
+        register: u8,
+
+        /// The offset within the register to program at
+        #[arg(long, default_value = "0", value_parser = input::parse_size)]
+        offset: usize,
+
+        input: PathBuf,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Erase a security register back to 0xff
+    Erase {
+        /// The register, from 1 to 3
+        #[arg(long, value_parser = otp::parse_register)]
+        register: u8,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Set a security register's lock bit, making it read-only for good
+    Lock {
+        /// The register, from 1 to 3
+        #[arg(long, value_parser = otp::parse_register)]
+        register: u8,
+    },
 }
 
-fn dump(address: usize, length: usize) -> Result<Vec<u8>> {
-    let mut programmer = FlashProgrammer::new()?;
+#[derive(Subcommand)]
+enum SlotAction {
+    /// Show which slot boots at power-on and what each holds
+    Status,
+    #[cfg(not(feature = "read-only"))]
+    /// Write a bitstream to the slot that isn't booting, verify it, then boot it at power-on
+    Update {
+        #[command(flatten)]
+        input: Input,
 
-    Ok(programmer.read_arbitrary(address, length))
+        /// The target device, checked against the die the bitstream was built for
+        ///
+        /// Defaults to the layout's `device`, then the board profile's.
+        #[arg(long, value_enum)]
+        device: Option<Device>,
+    },
+    #[cfg(not(feature = "read-only"))]
+    /// Boot a slot that already holds a bitstream at power-on, such as to roll back an update
+    Select {
+        #[arg(value_enum)]
+        slot: slots::Slot,
+    },
 }
 
-fn main() {
-    let args = Cli::parse();
-    use std::io::Write;
+/// Several bitstreams loaded back-to-back by `sram --sequence`.
+#[derive(clap::Args, Clone, Debug)]
+struct Sequence {
+    /// Configure the FPGA with each of these bitstreams in turn, instead of a single INPUT
+    #[arg(long, num_args = 1.., value_name = "INPUT", conflicts_with = "path")]
+    sequence: Vec<PathBuf>,
 
-    let message = match args.command {
-        Commands::Sram {
-            input,
-            baud,
-            transfer,
-        } => {
-            let result = program(input, baud, transfer);
-            let reset = SramProgrammer::reset();
+    /// How long to leave each image running before loading the next
+    #[arg(long, default_value = "1s", value_parser = timing::parse_duration)]
+    dwell: std::time::Duration,
 
-            match (result, reset) {
-                (Ok(_), Ok(_)) => "Succesfully programmed device!".into(),
-                (Err(e), Ok(_)) => format!("Failed to program device: {e}"),
-                (Ok(_), Err(r)) => {
-                    format!("Succesfully programmed device, but failed to reset: {r}")
-                }
-                (Err(e), Err(r)) => {
-                    format!("Failed to program device: {e}\nAnd failed to reset: {r}")
-                }
+    /// Wait for Enter instead of the dwell time before loading the next image
+    #[arg(long, requires = "sequence")]
+    interactive: bool,
+
+    /// Stop at the first image that fails to configure
+    #[arg(long, requires = "sequence")]
+    fail_fast: bool,
+}
+
+/// Settings shared by every subcommand.
+struct Setup {
+    pins: PinConfig,
+    timing: Timing,
+    layout: Option<Layout>,
+    layout_path: Option<PathBuf>,
+    yes: bool,
+    wear_file: Option<PathBuf>,
+    backend: mock::Backend,
+    /// The simulated flash, when the mock backend is selected.
+    mock: Option<mock::Settings>,
+    /// The devices to open, when the linux backend is selected.
+    linux: Option<linux::Settings>,
+    /// The adapter to open, when the ftdi backend is selected.
+    ftdi: Option<ftdi::Settings>,
+    /// The programmer to open, when the ch341a backend is selected.
+    ch341a: Option<ch341a::Settings>,
+    /// The capacity the board profile expects the flash to report.
+    flash_size: Option<usize>,
+    /// The FPGA the board profile declares.
+    device: Option<Device>,
+}
+
+impl Setup {
+    /// Connect to the flash, simulated or real.
+    fn flash(&self, trace: Option<Trace>) -> Result<FlashProgrammer> {
+        self.open_flash(trace, false)
+    }
+
+    /// Connect to the flash over the configured backend, refusing every write when `read_only`.
+    fn open_flash(&self, trace: Option<Trace>, read_only: bool) -> Result<FlashProgrammer> {
+        let port: Box<dyn flash::Port> = if let Some(settings) = &self.mock {
+            verbose!("Using {}", mock::describe(settings));
+            Box::new(mock::MockFlash::open(settings)?)
+        } else if let Some(settings) = &self.linux {
+            linux::flash(settings, &self.pins, &self.timing)?
+        } else if let Some(settings) = &self.ftdi {
+            ftdi::flash(settings, &self.pins, &self.timing)?
+        } else if let Some(settings) = &self.ch341a {
+            ch341a::flash(settings)?
+        } else {
+            let mut programmer = FlashProgrammer::new(&self.pins, &self.timing, trace)?;
+            self.check_size(&mut programmer)?;
+            return Ok(programmer);
+        };
+        let mut programmer = if read_only {
+            FlashProgrammer::with_port_read_only(port, &self.timing, trace)?
+        } else {
+            FlashProgrammer::with_port(port, &self.timing, trace)?
+        };
+        self.check_size(&mut programmer)?;
+
+        Ok(programmer)
+    }
+
+    /// Fail when the flash doesn't report the capacity the board profile expects.
+    fn check_size(&self, programmer: &mut FlashProgrammer) -> Result<()> {
+        let Some(expected) = self.flash_size else {
+            return Ok(());
+        };
+
+        match programmer.info()?.capacity() {
+            Some(capacity) if capacity != expected => anyhow::bail!(
+                "The flash reports {capacity:#x} bytes, but the board profile expects {expected:#x}"
+            ),
+            Some(_) => Ok(()),
+            None => {
+                warning!("The flash doesn't report its capacity, so the profile's isn't checked");
+                Ok(())
+            }
+        }
+    }
+
+    /// Connect to the flash without touching CRESET_B, refusing every write.
+    fn flash_live(&self, trace: Option<Trace>) -> Result<FlashProgrammer> {
+        watchdog::keep_fpga_reset();
+        match self.backend {
+            mock::Backend::Linux | mock::Backend::Ftdi => anyhow::bail!(
+                "--no-fpga-reset can't run with --backend {}, which drives the shared bus",
+                self.backend.name()
+            ),
+            // Neither touches CRESET_B anyway
+            mock::Backend::Mock | mock::Backend::Ch341a => self.open_flash(trace, true),
+            mock::Backend::Pi => {
+                let mut programmer = FlashProgrammer::live(&self.pins, &self.timing, trace)?;
+                self.check_size(&mut programmer)?;
+                Ok(programmer)
             }
         }
-        Commands::Flash { input } => {
-            FlashProgrammer::reset().expect("Error releasing pins");
+    }
+
+    /// Connect to the flash, live with `no_fpga_reset`.
+    fn flash_reader(&self, trace: Option<Trace>, no_fpga_reset: bool) -> Result<FlashProgrammer> {
+        if no_fpga_reset {
+            self.flash_live(trace)
+        } else {
+            self.flash(trace)
+        }
+    }
 
-            match flash(input) {
-                Ok(_) => "Succesfully flashed device!".into(),
-                Err(e) => format!("Failed to flash device: {e}"),
+    /// Connect to the FPGA to configure its SRAM, simulated or real, returning the post-program
+    /// pulses the backend can fire.
+    fn sram<'a>(
+        &self,
+        spi: &SpiSettings,
+        preflight: &Preflight,
+        pulses: &'a [Pulse],
+    ) -> Result<(SramProgrammer, &'a [Pulse])> {
+        if self.mock.is_some() {
+            if !pulses.is_empty() {
+                warning!(
+                    "Skipping the post-program pulses, which the mock backend doesn't simulate"
+                );
+            }
+            let port = Box::new(mock::MockFpga::default());
+            return Ok((SramProgrammer::with_port(port, &self.timing), &[]));
+        }
+        if self.ch341a.is_some() {
+            anyhow::bail!(
+                "--backend ch341a only reaches the flash; configure the SRAM with another backend"
+            );
+        }
+        let port = if let Some(settings) = &self.linux {
+            Some(linux::fpga(
+                settings,
+                &self.pins,
+                spi.baud,
+                preflight.cdone,
+            )?)
+        } else if let Some(settings) = &self.ftdi {
+            Some(ftdi::fpga(settings, &self.pins, spi.baud)?)
+        } else {
+            None
+        };
+        if let Some(port) = port {
+            if !pulses.is_empty() {
+                warning!("Skipping the post-program pulses, which need the pi backend");
             }
+            return Ok((SramProgrammer::with_port(port, &self.timing), &[]));
         }
-        Commands::Dump { address, length } => {
-            FlashProgrammer::reset().expect("Error releasing pins");
 
-            match dump(address, length) {
-                Ok(data) => {
-                    std::io::stdout().write_all(&data).unwrap();
-                    return;
-                }
-                Err(e) => {
-                    eprintln!("Error dumping data: {e}");
-                    return;
+        check_sram_claims(self, preflight, pulses)?;
+        let programmer = SramProgrammer::new(spi.baud, &self.pins, &self.timing)?;
+        Ok((programmer, pulses))
+    }
+
+    /// Release the SRAM programmer's pins, which only the pi backend holds past the programmer.
+    fn release_sram(&self, extra: &[u8]) -> Result<()> {
+        if self.backend != mock::Backend::Pi {
+            return Ok(());
+        }
+
+        sleep(self.timing.release_settle);
+        Ok(SramProgrammer::reset(&self.pins, extra)?)
+    }
+
+    /// The target device given with `--device`, or declared in the layout.
+    fn device(&self, device: Option<Device>) -> Option<Device> {
+        device
+            .or(self.layout.as_ref().and_then(|layout| layout.device))
+            .or(self.device)
+    }
+
+    /// Fail for operations the mock backend can't simulate, or that only the pi backend drives.
+    fn require_hardware(&self, operation: &str) -> Result<()> {
+        match self.backend {
+            mock::Backend::Pi => Ok(()),
+            mock::Backend::Mock => {
+                anyhow::bail!("{operation} needs real hardware and can't run with --backend mock")
+            }
+            backend => anyhow::bail!(
+                "{operation} needs the pi backend and can't run with --backend {}",
+                backend.name()
+            ),
+        }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    /// Count the programmer's erases in the wear file, if one was given.
+    fn track_wear(&self, programmer: &mut FlashProgrammer) -> Result<()> {
+        match &self.wear_file {
+            Some(path) => wear::track(programmer, path),
+            None => Ok(()),
+        }
+    }
+}
+
+fn sleep(duration: std::time::Duration) {
+    spin_sleep::sleep(duration);
+}
+
+fn program(
+    setup: &Setup,
+    input: &Input,
+    spi: &SpiSettings,
+    preflight: &Preflight,
+    device: Option<Device>,
+    force: bool,
+    pulses: &[Pulse],
+) -> Result<()> {
+    let data = input.read()?;
+    check_device(setup, &data, device, force)?;
+
+    load(setup, &data, spi, preflight, pulses)
+}
+
+/// Check the bitstream is one, and against the target device, unless `force` is given.
+fn check_device(setup: &Setup, data: &[u8], device: Option<Device>, force: bool) -> Result<()> {
+    check_bitstream(data, force)?;
+    check_target(setup, data, device, force)?;
+    if !force {
+        if let Some(device) = setup.device(device).or_else(|| Device::infer(data)) {
+            device.check_size(data.len())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Refuse an image that doesn't parse as a bitstream, only warning with `force`.
+fn check_bitstream(data: &[u8], force: bool) -> Result<()> {
+    match bitstream::check(data) {
+        Ok(()) => {
+            if let Some(comment) = bitstream::comment(data) {
+                verbose!("The bitstream's comment: {comment}");
+            }
+            Ok(())
+        }
+        Err(e) if force => {
+            warning!("{e:#}");
+            Ok(())
+        }
+        Err(e) => anyhow::bail!("{e:#}; pass --force to program it anyway"),
+    }
+}
+
+/// Check the die a bitstream was built for against the declared target device, only warning
+/// with `force`, and report the inferred target when none was declared.
+fn check_target(setup: &Setup, data: &[u8], device: Option<Device>, force: bool) -> Result<()> {
+    match (setup.device(device), Device::infer(data)) {
+        (Some(device), _) => {
+            if let Err(e) = device.check_target(data) {
+                if !force {
+                    anyhow::bail!("{e:#}; pass --force to program it anyway");
                 }
+                warning!("{e:#}");
             }
         }
-    };
+        (None, Some(inferred)) => status!("The bitstream targets an {}", inferred.die_names()),
+        (None, None) => {}
+    }
 
-    println!("{message}");
+    Ok(())
+}
+
+/// Configure the FPGA's SRAM with `data` over hardware SPI, retrying after write errors.
+fn load(
+    setup: &Setup,
+    data: &[u8],
+    spi: &SpiSettings,
+    preflight: &Preflight,
+    pulses: &[Pulse],
+) -> Result<()> {
+    let (mut programmer, pulses) = setup.sram(spi, preflight, pulses)?;
+    configure(
+        &mut programmer,
+        data,
+        spi,
+        preflight,
+        pulses,
+        &cancel::on_interrupt(),
+    )
+}
+
+/// Check that none of the pins used to configure the FPGA clash.
+fn check_sram_claims(setup: &Setup, preflight: &Preflight, pulses: &[Pulse]) -> Result<()> {
+    let claims = pulses
+        .iter()
+        .fold(Claims::sram(&setup.pins), |claims, pulse| {
+            claims.pin("pulse", pulse.pin)
+        });
+    match preflight.cdone {
+        Some(cdone) => claims.pin("CDONE", cdone),
+        None => claims,
+    }
+    .check()
+}
+
+/// Run one full configuration cycle, from the reset pulse to CDONE and the pulses.
+fn configure(
+    programmer: &mut SramProgrammer,
+    data: &[u8],
+    spi: &SpiSettings,
+    preflight: &Preflight,
+    pulses: &[Pulse],
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        programmer.begin(preflight)?;
+        match programmer.program_bytes(data, spi, cancel) {
+            Ok(()) => break,
+            Err(e) if e.is_corrupted() && attempt < spi.retries => {
+                attempt += 1;
+                warning!(
+                    "{e}; restarting configuration (retry {attempt} of {})",
+                    spi.retries
+                );
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    programmer.check_done(preflight)?;
+    Pulse::fire(pulses)?;
+
+    Ok(())
+}
+
+/// Configure the FPGA with each image in `sequence` in turn, reusing one programmer, and
+/// summarize how each went.
+///
+/// A failed image is reported and skipped unless `--fail-fast` is given.
+fn sequence(
+    setup: &Setup,
+    sequence: &Sequence,
+    spi: &SpiSettings,
+    preflight: &Preflight,
+    device: Option<Device>,
+    force: bool,
+    pulses: &[Pulse],
+) -> Result<String> {
+    let (mut programmer, pulses) = setup.sram(spi, preflight, pulses)?;
+
+    let cancel = cancel::on_interrupt();
+    let count = sequence.sequence.len();
+    let mut report = Vec::new();
+    let mut failed = 0;
+
+    for (index, path) in sequence.sequence.iter().enumerate() {
+        let number = index + 1;
+        if index > 0 {
+            dwell(sequence, &cancel)?;
+        }
+
+        status!("Image {number} of {count} ({})", path.display());
+        let start = std::time::Instant::now();
+        let result = std::fs::read(path)
+            .with_context(|| format!("Error reading {}", path.display()))
+            .and_then(|data| {
+                check_device(setup, &data, device, force)?;
+                configure(&mut programmer, &data, spi, preflight, pulses, &cancel)
+            });
+        let elapsed = start.elapsed();
+
+        match result {
+            Ok(()) => report.push(format!("  {}: configured in {elapsed:.2?}", path.display())),
+            Err(e) => {
+                failed += 1;
+                report.push(format!(
+                    "  {}: failed after {elapsed:.2?}: {e:#}",
+                    path.display()
+                ));
+                if cancel.is_cancelled() {
+                    anyhow::bail!("Stopped at image {number} of {count}: {e:#}");
+                }
+                if sequence.fail_fast {
+                    anyhow::bail!(
+                        "Image {number} of {count} failed, skipping the remaining {}: {e:#}",
+                        count - number
+                    );
+                }
+                warning!("Image {number} of {count} failed, continuing: {e:#}");
+            }
+        }
+    }
+
+    let summary = format!(
+        "Configured {} of {count} images:\n{}",
+        count - failed,
+        report.join("\n")
+    );
+    if failed > 0 {
+        anyhow::bail!(summary);
+    }
+
+    Ok(summary)
+}
+
+/// Program `input` repeatedly at each baud rate, returning a table or JSON comparing how often
+/// CDONE rose and how long configuration took.
+fn reliability_test(
+    setup: &Setup,
+    input: &Input,
+    options: &reliability::Options,
+    spi: &SpiSettings,
+    preflight: &Preflight,
+    device: Option<Device>,
+    force: bool,
+) -> Result<String> {
+    setup.require_hardware("--reliability-test")?;
+    let data = input.read()?;
+    check_device(setup, &data, device, force)?;
+    check_sram_claims(setup, preflight, &[])?;
+
+    let bauds = if options.baud_list.is_empty() {
+        vec![spi.baud]
+    } else {
+        options.baud_list.clone()
+    };
+    let cancel = cancel::on_interrupt();
+    let mut tallies = Vec::new();
+
+    for baud in bauds {
+        status!("Programming {} times at {baud} baud", options.iterations);
+        let spi = SpiSettings {
+            baud,
+            ..spi.clone()
+        };
+        // A fresh programmer for each rate, since the bus's clock is set when it's acquired
+        let mut programmer = SramProgrammer::new(baud, &setup.pins, &setup.timing)?;
+        let mut tally = reliability::Tally::new(baud);
+
+        for iteration in 1..=options.iterations {
+            let start = std::time::Instant::now();
+            match configure(&mut programmer, &data, &spi, preflight, &[], &cancel) {
+                Ok(()) => {
+                    let elapsed = start.elapsed();
+                    verbose!("Iteration {iteration} configured in {elapsed:.2?}");
+                    tally.pass(elapsed);
+                }
+                Err(e) if cancel.is_cancelled() => return Err(e),
+                Err(e) => {
+                    warning!("Iteration {iteration} at {baud} baud failed: {e:#}");
+                    tally.fail();
+                }
+            }
+        }
+
+        status!(
+            "{}/{} configured at {baud} baud",
+            tally.passed(),
+            tally.iterations()
+        );
+        tallies.push(tally);
+    }
+
+    Ok(if options.json {
+        reliability::json(&tallies)
+    } else {
+        reliability::table(&tallies)
+    })
+}
+
+/// Leave the current image running for the dwell time, or until Enter with `--interactive`.
+fn dwell(sequence: &Sequence, cancel: &CancellationToken) -> Result<()> {
+    if sequence.interactive {
+        return confirm::pause("Press Enter to load the next image.");
+    }
+
+    // Waiting isn't a stall
+    watchdog::pause();
+    let end = std::time::Instant::now() + sequence.dwell;
+    while let Some(remaining) = end.checked_duration_since(std::time::Instant::now()) {
+        cancel.check(0)?;
+        sleep(remaining.min(std::time::Duration::from_millis(100)));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Options for the `flash` subcommand beyond the image and its destination.
+struct FlashOptions {
+    trace: Option<PathBuf>,
+    precheck: bool,
+    backup: Option<PathBuf>,
+    verification: Verification,
+    utilization_warning: u8,
+    mask: Mask,
+    images: Vec<input::Placement>,
+    allow_unbootable: bool,
+    device: Option<Device>,
+    force: bool,
+    write_boot_header: bool,
+    assume_blank: bool,
+    skip_zero_blocks: bool,
+    incremental: bool,
+    journal: Option<PathBuf>,
+    and_load: bool,
+    spi: SpiSettings,
+    preflight: Preflight,
+    boot_check: boot::BootCheck,
+    image_version: Option<u32>,
+    require_version_ge: Option<u32>,
+    counter: Option<FlashAddress>,
+}
+
+#[cfg(not(feature = "read-only"))]
+fn flash(setup: &Setup, input: &Input, region: Region, mut options: FlashOptions) -> Result<bool> {
+    let segments = input.segments()?;
+    let extent = segments.last().map_or(0, |segment| segment.end());
+    if segments.len() > 1 {
+        verbose!(
+            "{} holds {} separate regions, each written at its own address",
+            input.path.display(),
+            segments.len()
+        );
+    }
+    let mut segments = segments.into_iter();
+    let first = segments.next().context("The input holds no data")?;
+    let data = first.data;
+    let holes = if options.skip_zero_blocks {
+        input.holes(data.len())?.map(Mask::new).unwrap_or_else(|| {
+            warning!("The input's filesystem can't report holes, so it's programmed in full");
+            Mask::EMPTY
+        })
+    } else {
+        Mask::EMPTY
+    };
+    options.mask = options.mask.union(&holes);
+    let partition = region.partition.clone();
+    let (base, _) = Region {
+        length: Some(extent),
+        ..region
+    }
+    .resolve(setup.layout.as_ref(), extent, true)?;
+    let address = base.offset(first.offset)?;
+
+    check_target(setup, &data, options.device, options.force)?;
+
+    let mut images = vec![(data, address)];
+    for segment in segments {
+        images.push((segment.data, base.offset(segment.offset)?));
+    }
+    for placement in &options.images {
+        images.push(placement.read(setup.layout.as_ref())?);
+    }
+
+    // The FPGA configures from offset 0, so whatever's written there has to be a bitstream or
+    // a multiboot header pointing at one
+    if let Some((data, _)) = images.iter().find(|(_, a)| *a == FlashAddress::ZERO) {
+        if bitstream::boot_addresses(data).is_empty() {
+            check_bitstream(data, options.force)?;
+        }
+    }
+
+    let trace = options
+        .trace
+        .as_ref()
+        .map(|_| Trace::new("flash", address.get(), images[0].0.len()));
+    let mut programmer = setup.flash(trace)?;
+
+    // Only the flash itself is traced, since that's all a replay reproduces
+    let trace = programmer.take_trace();
+    setup.track_wear(&mut programmer)?;
+    if let Some(header) = boot_header(&mut programmer, &images, &options)? {
+        images.push((header, FlashAddress::ZERO));
+    }
+    if (options.incremental || options.journal.is_some()) && images.len() > 1 {
+        anyhow::bail!(
+            "--{} writes a single image, but there are {}, from a multiboot header or the \
+             separate regions of a record file",
+            if options.incremental {
+                "incremental"
+            } else {
+                "journal"
+            },
+            images.len()
+        );
+    }
+    let stored_version = check_version(&mut programmer, &images, &options)?;
+    let result = (|| {
+        check_utilization(
+            &mut programmer,
+            setup.layout.as_ref(),
+            partition.as_deref(),
+            &images,
+            &options,
+        )?;
+
+        let images: Vec<_> = images.iter().map(|(d, a)| (&d[..], *a)).collect();
+
+        if options.assume_blank {
+            if options.verification.verify_mode == Some(VerifyMode::Sample) {
+                anyhow::bail!(
+                    "--assume-blank needs a full verification, so it can't be combined with \
+                     --verify-mode sample"
+                );
+            }
+            warning!(
+                "Assuming the flash is blank: skipping the pre-check, confirmation, and erases"
+            );
+            programmer.resume_trace(trace);
+            flash_images(
+                &mut programmer,
+                &images,
+                Some(input),
+                &options.verification,
+                &options.mask,
+                &holes,
+                true,
+                &cancel::on_interrupt(),
+            )?;
+            return Ok(true);
+        }
+
+        if options.precheck {
+            let mut flashed = true;
+            for (i, (data, address)) in images.iter().enumerate() {
+                let mask = image_mask(&options.mask, i);
+                flashed &= already_flashed(&mut programmer, data, *address, mask)?;
+            }
+            if flashed {
+                return Ok(false);
+            }
+        }
+
+        for (data, address) in &images {
+            confirm::overwrite(&mut programmer, data, *address, setup.yes)?;
+        }
+
+        if let Some(directory) = &options.backup {
+            for (data, address) in &images {
+                let (start, length) = backup::erase_range(*address, data.len());
+                backup::backup(&mut programmer, directory, start, length)?;
+            }
+        }
+
+        programmer.resume_trace(trace);
+        if options.incremental {
+            let (data, address) = images[0];
+            flash_incremental(
+                &mut programmer,
+                data,
+                Some(input),
+                address,
+                &options.verification,
+                &options.mask,
+                &cancel::on_interrupt(),
+            )?;
+            return Ok(true);
+        }
+        if let Some(path) = &options.journal {
+            let (data, address) = images[0];
+            journal::flash(
+                &mut programmer,
+                data,
+                address,
+                &options.mask,
+                path,
+                &cancel::on_interrupt(),
+            )?;
+            return Ok(true);
+        }
+        flash_images(
+            &mut programmer,
+            &images,
+            Some(input),
+            &options.verification,
+            &options.mask,
+            &holes,
+            false,
+            &cancel::on_interrupt(),
+        )?;
+        Ok(true)
+    })();
+
+    save_trace(&mut programmer, options.trace)?;
+    let flashed = result?;
+
+    if let (Some(address), Some(version), Some(stored)) =
+        (options.counter, options.image_version, stored_version)
+    {
+        if version > stored {
+            counter::bump(&mut programmer, address, version)?;
+            status!("Raised the version counter to {version}");
+        }
+    }
+
+    if options.and_load {
+        status!("Loading the image into the FPGA...");
+        programmer.hand_off();
+        let loaded = load(setup, &images[0].0, &options.spi, &options.preflight, &[]);
+        let reset = setup.release_sram(&[]);
+        loaded?;
+        reset?;
+    } else if options.boot_check.verify_after_boot {
+        setup.require_hardware("--verify-after-boot")?;
+        // Keeps the FPGA in reset until the boot check pulses it
+        programmer.hand_off();
+        let cdone = options
+            .preflight
+            .cdone
+            .context("--verify-after-boot needs --cdone")?;
+        boot::verify(&setup.pins, &setup.timing, cdone, &options.boot_check)?;
+    }
+
+    Ok(flashed)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Check that the FPGA's configuration engine will find the first image, returning a
+/// multiboot header to write at offset 0 when it won't and `--write-boot-header` was given.
+///
+/// The FPGA configures from offset 0, or from the images named by a multiboot header there, so
+/// a bitstream anywhere else is only found through the header. Images that don't look like
+/// bitstreams aren't checked.
+fn boot_header(
+    programmer: &mut FlashProgrammer,
+    images: &[(Vec<u8>, FlashAddress)],
+    options: &FlashOptions,
+) -> Result<Option<Vec<u8>>> {
+    let (data, address) = &images[0];
+    if images
+        .iter()
+        .any(|(_, address)| *address == FlashAddress::ZERO)
+        || !bitstream::is_bitstream(data)
+    {
+        return Ok(None);
+    }
+
+    let head = programmer.read_arbitrary(FlashAddress::ZERO, bitstream::MULTIBOOT_HEADER_SIZE)?;
+    let targets = bitstream::boot_addresses(&head);
+    if targets.contains(&address.get()) {
+        return Ok(None);
+    }
+
+    let found = if targets.is_empty() {
+        "there's no multiboot header at offset 0".to_string()
+    } else {
+        let targets: Vec<_> = targets.iter().map(|t| format!("{t:#x}")).collect();
+        format!(
+            "the multiboot header at offset 0 only points at {}",
+            targets.join(", ")
+        )
+    };
+
+    if options.write_boot_header {
+        status!("Writing a multiboot header at offset 0 pointing at {address:#x}, since {found}");
+        return Ok(Some(bitstream::multiboot_header(address.get())));
+    }
+
+    let message = format!(
+        "The FPGA configures from offset 0 or the multiboot header there, but {found}, so it \
+         won't find the image at {address:#x}"
+    );
+    if options.allow_unbootable {
+        warning!("Warning: {message}");
+        Ok(None)
+    } else {
+        anyhow::bail!(
+            "{message}. Pass --write-boot-header to write a header pointing at the image, or \
+             --allow-unbootable to flash it anyway"
+        )
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Refuse images older than the version counter or `--require-version-ge`, returning the
+/// counter's value when there is one.
+fn check_version(
+    programmer: &mut FlashProgrammer,
+    images: &[(Vec<u8>, FlashAddress)],
+    options: &FlashOptions,
+) -> Result<Option<u32>> {
+    let Some(version) = options.image_version else {
+        if options.counter.is_some() {
+            anyhow::bail!("Checking the version counter needs the image's --version");
+        }
+        return Ok(None);
+    };
+
+    if let Some(minimum) = options.require_version_ge.filter(|m| version < *m) {
+        anyhow::bail!("Refusing to flash version {version}, below --require-version-ge {minimum}");
+    }
+
+    let Some(address) = options.counter else {
+        return Ok(None);
+    };
+    for (data, image) in images {
+        let (start, length) = backup::erase_range(*image, data.len());
+        if start.get() < address.end(counter::SECTOR_SIZE)? && address.get() < start.end(length)? {
+            anyhow::bail!(
+                "Flashing the image at {image:#x} would erase the version counter at {address:#x}"
+            );
+        }
+    }
+
+    let stored = counter::read(programmer, address)?.current();
+    status!("The version counter at {address:#x} is at {stored}");
+    if version < stored {
+        anyhow::bail!(
+            "Refusing to flash version {version}, which would roll back past the version counter \
+             at {stored}"
+        );
+    }
+
+    Ok(Some(stored))
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Report how full the first image's partition will be, or the flash outside a layout.
+///
+/// The partition is the one named, or else the one the image starts in. Everything written
+/// into it counts: the other images, a multiboot header, and the version counter's sector.
+fn check_utilization(
+    programmer: &mut FlashProgrammer,
+    layout: Option<&Layout>,
+    partition: Option<&str>,
+    images: &[(Vec<u8>, FlashAddress)],
+    options: &FlashOptions,
+) -> Result<()> {
+    let partition = match (partition, layout) {
+        (Some(name), Some(layout)) => Some(layout.partition(name)?),
+        (None, Some(layout)) => layout.containing(images[0].1.get()),
+        _ => None,
+    };
+    let (start, capacity, name) = match partition {
+        Some(partition) => (
+            partition.offset,
+            partition.size,
+            format!("partition {:?}", partition.name),
+        ),
+        None => match programmer.info()?.capacity() {
+            Some(capacity) => (0, capacity, "the flash".to_string()),
+            None => return Ok(()),
+        },
+    };
+
+    let mut written: Vec<_> = images
+        .iter()
+        .map(|(data, address)| (address.get(), data.len()))
+        .collect();
+    if let Some(counter) = options.counter.filter(|_| options.image_version.is_some()) {
+        written.push((counter.get(), counter::SECTOR_SIZE));
+    }
+    let end = start + capacity;
+    let used = written
+        .iter()
+        .map(|(address, length)| {
+            (address + length)
+                .min(end)
+                .saturating_sub(*address.max(&start))
+        })
+        .sum();
+
+    utilization(used, capacity, &name, options.utilization_warning);
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Print how much of `capacity` is used, warning when it reaches `threshold` percent.
+fn utilization(used: usize, capacity: usize, name: &str, threshold: u8) {
+    if capacity == 0 {
+        warning!("Warning: {name} is empty, so there's no utilization to report");
+        return;
+    }
+
+    let percent = used as f64 / capacity as f64 * 100.0;
+    status!("The image fills {percent:.1}% of {name} ({used} of {capacity} bytes)");
+
+    if percent >= threshold as f64 {
+        warning!(
+            "Warning: the image nearly fills {name}, with only {} bytes of headroom remaining",
+            capacity.saturating_sub(used)
+        );
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Check whether the flash already holds the image by comparing hashes of both.
+///
+/// Masked bytes are left out of both hashes.
+fn already_flashed(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    address: FlashAddress,
+    mask: &Mask,
+) -> Result<bool> {
+    status!("Checking existing flash contents...");
+    let expected = mask.digest(data.len(), |offset, window| {
+        Ok(data[offset..offset + window].to_vec())
+    })?;
+    let start = std::time::Instant::now();
+    let existing = programmer.hash_range(address, data.len(), mask)?;
+    status!("Read back {} bytes in {:.2?}", data.len(), start.elapsed());
+    progress::report_mask(mask, data.len());
+
+    Ok(existing == expected)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// The mask for the `index`th image of a session, since `--ignore-range` and `--mask` are
+/// relative to the first.
+fn image_mask(mask: &Mask, index: usize) -> &Mask {
+    static EMPTY: Mask = Mask::EMPTY;
+    if index == 0 {
+        mask
+    } else {
+        &EMPTY
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Write the regions listed in the manifest at `path` in one session, skipping those the flash
+/// already holds when `precheck` is set.
+fn flash_manifest(
+    setup: &Setup,
+    path: &Path,
+    precheck: bool,
+    device: Option<Device>,
+    force: bool,
+    verification: &Verification,
+) -> Result<String> {
+    let mut images = manifest::Manifest::load(path)?.images(setup.layout.as_ref())?;
+
+    for image in &images {
+        if image.address == FlashAddress::ZERO && bitstream::boot_addresses(&image.data).is_empty()
+        {
+            check_bitstream(&image.data, force)
+                .with_context(|| format!("The {:?} region at offset 0", image.name))?;
+        }
+        if bitstream::is_bitstream(&image.data) {
+            check_target(setup, &image.data, device, force)
+                .with_context(|| format!("The {:?} region", image.name))?;
+        }
+    }
+
+    let mut programmer = setup.flash(None)?;
+    setup.track_wear(&mut programmer)?;
+    let regions = |images: &[manifest::Image]| {
+        let names: std::collections::BTreeSet<_> = images.iter().map(|i| &i.name).collect();
+        names.len()
+    };
+    let total = regions(&images);
+    if precheck {
+        let mut pending = Vec::new();
+        for image in images {
+            if already_flashed(&mut programmer, &image.data, image.address, &Mask::EMPTY)? {
+                status!("{:?} already holds its file, skipping it", image.name);
+            } else {
+                pending.push(image);
+            }
+        }
+        images = pending;
+        if images.is_empty() {
+            return Ok("Flash already contains every region, nothing to do".into());
+        }
+    }
+
+    for image in &images {
+        status!(
+            "{:?}: {} bytes at {:#x}",
+            image.name,
+            image.data.len(),
+            image.address
+        );
+        confirm::overwrite(&mut programmer, &image.data, image.address, setup.yes)?;
+    }
+    let pieces: Vec<_> = images
+        .iter()
+        .map(|image| (&image.data[..], image.address))
+        .collect();
+    flash_images(
+        &mut programmer,
+        &pieces,
+        None,
+        verification,
+        &Mask::EMPTY,
+        &Mask::EMPTY,
+        false,
+        &cancel::on_interrupt(),
+    )?;
+
+    Ok(format!(
+        "Succesfully flashed {} of {total} regions!",
+        regions(&images)
+    ))
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Write `placements` of bitstreams behind a multiboot header at offset 0, with the one at
+/// `power_on` configured at power-on and the rest selected by warm boots in order, or save the
+/// combined image to `output`.
+fn multiboot(
+    setup: &Setup,
+    placements: &[input::Placement],
+    power_on: usize,
+    output: Option<&Path>,
+    device: Option<Device>,
+    force: bool,
+    verification: &Verification,
+) -> Result<String> {
+    if power_on >= placements.len() {
+        anyhow::bail!(
+            "--power-on {power_on} names no bitstream, since there are {}",
+            placements.len()
+        );
+    }
+
+    let mut images = Vec::new();
+    for placement in placements {
+        let (data, address) = placement.read(setup.layout.as_ref())?;
+        let name = placement.path.display();
+        check_bitstream(&data, force).with_context(|| format!("{name} isn't a bitstream"))?;
+        check_target(setup, &data, device, force).with_context(|| name.to_string())?;
+        if address.get() < bitstream::MULTIBOOT_HEADER_SIZE {
+            anyhow::bail!(
+                "{name} at {address:#x} would overlap the multiboot header in the first {} bytes",
+                bitstream::MULTIBOOT_HEADER_SIZE
+            );
+        }
+        images.push((data, address));
+    }
+
+    let addresses: Vec<_> = images.iter().map(|(_, address)| address.get()).collect();
+    let header = bitstream::warmboot_header(addresses[power_on], &addresses);
+    images.insert(0, (header, FlashAddress::ZERO));
+    // Also rejects bitstreams that overlap one another
+    plan::erase_spans(
+        &images
+            .iter()
+            .map(|(data, address)| (*address, data.len()))
+            .collect::<Vec<_>>(),
+    )?;
+
+    for (i, address) in addresses.iter().enumerate() {
+        let boots = if i == power_on {
+            "Power-on and warm boot"
+        } else {
+            "Warm boot"
+        };
+        status!(
+            "{boots} {i}: {} at {address:#x}",
+            placements[i].path.display()
+        );
+    }
+
+    if let Some(path) = output {
+        let end = images
+            .iter()
+            .map(|(data, address)| address.get() + data.len());
+        let mut combined = vec![0xFF; end.max().unwrap_or_default()];
+        for (data, address) in &images {
+            combined[address.get()..address.get() + data.len()].copy_from_slice(data);
+        }
+        std::fs::write(path, &combined)
+            .with_context(|| format!("Error writing {}", path.display()))?;
+        return Ok(format!(
+            "Wrote the {} byte multiboot image to {}",
+            combined.len(),
+            path.display()
+        ));
+    }
+
+    let mut programmer = setup.flash(None)?;
+    setup.track_wear(&mut programmer)?;
+    for (data, address) in &images {
+        confirm::overwrite(&mut programmer, data, *address, setup.yes)?;
+    }
+    let images: Vec<_> = images.iter().map(|(d, a)| (&d[..], *a)).collect();
+    flash_images(
+        &mut programmer,
+        &images,
+        None,
+        verification,
+        &Mask::EMPTY,
+        &Mask::EMPTY,
+        false,
+        &cancel::on_interrupt(),
+    )?;
+
+    Ok(format!(
+        "Succesfully flashed {} bitstreams behind a multiboot header!",
+        placements.len()
+    ))
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Restore a full-chip dump, confirming first if the flash holds other data.
+fn restore(setup: &Setup, input: &Input, allow_size_mismatch: bool) -> Result<String> {
+    let data = input.read()?;
+    let jedec = restore::recorded_jedec(&input.path)?;
+
+    let mut programmer = setup.flash(None)?;
+    setup.track_wear(&mut programmer)?;
+    confirm::overwrite(&mut programmer, &data, FlashAddress::ZERO, setup.yes)?;
+
+    restore::restore(
+        &mut programmer,
+        &data,
+        jedec,
+        allow_size_mismatch,
+        &cancel::on_interrupt(),
+    )
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Write and verify the images, skipping the erases when `assume_blank` is set.
+///
+/// Pages of the first image within `holes` are left unprogrammed, so `mask` should cover them
+/// too. `source` is the input the first image was read from, if any, for [`verify`].
+#[allow(clippy::too_many_arguments)]
+fn flash_images(
+    programmer: &mut FlashProgrammer,
+    images: &[(&[u8], FlashAddress)],
+    source: Option<&Input>,
+    verification: &Verification,
+    mask: &Mask,
+    holes: &Mask,
+    assume_blank: bool,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if assume_blank {
+        status!("Flashing data without erasing...");
+    } else {
+        status!("Flashing data...");
+    }
+    let skipped = programmer.flash_images(images, !assume_blank, holes, cancel)?;
+    if skipped > 0 {
+        status!("Skipped {skipped} bytes in holes of the sparse input");
+    }
+
+    status!("Verifying data...");
+    for (i, (data, address)) in images.iter().enumerate() {
+        let mask = image_mask(mask, i);
+        let source = source.filter(|_| i == 0);
+        let result = verify(
+            programmer,
+            data,
+            source,
+            *address,
+            verification,
+            mask,
+            cancel,
+        );
+        if assume_blank
+            && result
+                .as_ref()
+                .is_err_and(|e| error::cancellation(e).is_none())
+        {
+            return result.context(
+                "The flash was assumed blank with --assume-blank, so this may be left over from \
+                 earlier contents; try again without it",
+            );
+        }
+        result?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Write only the sectors where the flash differs from the image, then verify it.
+fn flash_incremental(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    source: Option<&Input>,
+    address: FlashAddress,
+    verification: &Verification,
+    mask: &Mask,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let sector = programmer
+        .erases()?
+        .iter()
+        .map(|erase| erase.size)
+        .min()
+        .unwrap_or(plan::BLOCK_SIZE);
+    status!(
+        "Comparing the flash against the image in {} KiB sectors...",
+        sector / 1024
+    );
+    let plan = Plan::build(programmer, data, address, sector)?;
+    status!(
+        "Flashing data: {} of {} sectors already match, {} to write, {} to erase and write...",
+        plan.count(plan::Action::Skip),
+        plan.blocks.len(),
+        plan.count(plan::Action::Write),
+        plan.count(plan::Action::EraseWrite)
+    );
+    plan.execute(programmer, data, cancel)?;
+
+    status!("Verifying data...");
+    verify(
+        programmer,
+        data,
+        source,
+        address,
+        verification,
+        mask,
+        cancel,
+    )
+}
+
+/// Compare the flash against `data`. A windowed verification reads the expected bytes from
+/// `source`, the input `data` was read from, when it's a raw file.
+fn verify(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    source: Option<&Input>,
+    address: FlashAddress,
+    verification: &Verification,
+    mask: &Mask,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    match VerifyMode::resolve(verification.verify_mode, data.len()) {
+        VerifyMode::Full => programmer.verify_data(data, address, mask, cancel)?,
+        VerifyMode::Windowed => match source.map(Input::reader).transpose()?.flatten() {
+            Some((mut reader, _)) => {
+                programmer.verify_windowed(&mut reader, data.len(), address, mask, cancel)?
+            }
+            None => {
+                programmer.verify_windowed(&mut &data[..], data.len(), address, mask, cancel)?
+            }
+        },
+        VerifyMode::Sample => {
+            let sample = verification.sample(data.len());
+            verify_sample(programmer, data, address, &sample, mask, cancel)?
+        }
+    }
+
+    progress::report_mask(mask, data.len());
+    Ok(())
+}
+
+/// Compare only the sampled pages, logging the seed so a failure can be reproduced.
+fn verify_sample(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    address: FlashAddress,
+    sample: &Sample,
+    mask: &Mask,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    status!(
+        "Sampling {} pages ({:.1}% of the image) with --seed {}",
+        sample.pages.len(),
+        sample.coverage(),
+        sample.seed
+    );
+
+    programmer
+        .verify_pages(data, address, &sample.pages, mask, cancel)
+        .with_context(|| {
+            format!(
+                "Sample verification failed; reproduce it with --seed {}",
+                sample.seed
+            )
+        })
+}
+
+/// Compare the flash against an image without writing anything, returning a summary.
+///
+/// With `json`, a sample verification's report is printed instead, whether or not it passed.
+fn verify_only(
+    setup: &Setup,
+    input: &Input,
+    region: Region,
+    verification: &Verification,
+    mask: &Mask,
+    json: bool,
+    no_fpga_reset: bool,
+) -> Result<String> {
+    // A windowed verification of a raw input streams it, so it's never all in memory
+    if let Some((mut reader, length)) = input.reader()?.filter(|(_, length)| {
+        VerifyMode::resolve(verification.verify_mode, *length) == VerifyMode::Windowed
+    }) {
+        let (address, _) = Region {
+            length: Some(length),
+            ..region
+        }
+        .resolve(setup.layout.as_ref(), length, false)?;
+        let mut programmer = setup.flash_reader(None, no_fpga_reset)?;
+        let cancel = cancel::on_interrupt();
+        programmer.verify_windowed(&mut reader, length, address, mask, &cancel)?;
+        progress::report_mask(mask, length);
+        return Ok(format!("Flash at {address:#x} matches the image"));
+    }
+
+    let data = input.read()?;
+    let (address, _) = Region {
+        length: Some(data.len()),
+        ..region
+    }
+    .resolve(setup.layout.as_ref(), data.len(), false)?;
+    let mut programmer = setup.flash_reader(None, no_fpga_reset)?;
+    let cancel = cancel::on_interrupt();
+
+    if VerifyMode::resolve(verification.verify_mode, data.len()) != VerifyMode::Sample {
+        verify(
+            &mut programmer,
+            &data,
+            None,
+            address,
+            verification,
+            mask,
+            &cancel,
+        )?;
+        return Ok(format!("Flash at {address:#x} matches the image"));
+    }
+
+    let sample = verification.sample(data.len());
+    let result = verify_sample(&mut programmer, &data, address, &sample, mask, &cancel);
+    if json {
+        let error = result.as_ref().err().map(|e| format!("{e:#}"));
+        status!("{}", sample.json(address.get(), error.as_deref()));
+    }
+    result?;
+    progress::report_mask(mask, data.len());
+
+    Ok(format!(
+        "Sampled flash at {address:#x} matches the image ({:.1}% coverage, --seed {})",
+        sample.coverage(),
+        sample.seed
+    ))
+}
+
+/// Read a flash region, returning its address along with the data.
+fn dump(
+    setup: &Setup,
+    region: Region,
+    trace_path: Option<PathBuf>,
+    no_fpga_reset: bool,
+) -> Result<(FlashAddress, Vec<u8>)> {
+    let (address, length) = region.resolve(setup.layout.as_ref(), 256, false)?;
+    let trace = trace_path
+        .as_ref()
+        .map(|_| Trace::new("dump", address.get(), length));
+    let mut programmer = setup.flash_reader(trace, no_fpga_reset)?;
+    let result = programmer.read_arbitrary(address, length);
+
+    save_trace(&mut programmer, trace_path)?;
+    Ok((address, result?))
+}
+
+/// Read a flash range through, failing when any of it isn't 0xFF.
+/// Resolve a region read from the flash, running to the end of the flash without a length.
+fn resolve_read(
+    setup: &Setup,
+    programmer: &mut FlashProgrammer,
+    region: &Region,
+) -> Result<(FlashAddress, usize)> {
+    let start = region.address.unwrap_or_default().get();
+    let remaining = match programmer.info()?.capacity() {
+        Some(capacity) => capacity.saturating_sub(start),
+        None if region.length.is_some() || region.partition.is_some() => 0,
+        None => anyhow::bail!("The flash's capacity is unknown, so give --length"),
+    };
+    region.resolve(setup.layout.as_ref(), remaining, false)
+}
+
+fn checksum(setup: &Setup, region: Region) -> Result<String> {
+    use sha2::{Digest, Sha256};
+
+    let mut programmer = setup.flash(None)?;
+    let (address, length) = resolve_read(setup, &mut programmer, &region)?;
+
+    let mut hasher = Sha256::new();
+    programmer.stream(address, length, |chunk| {
+        hasher.update(chunk);
+        Ok(())
+    })?;
+
+    let end = address.get() + length;
+    Ok(format!(
+        "{}  {address:#x}..{end:#x}",
+        backup::hex(&hasher.finalize())
+    ))
+}
+
+fn blank_check(setup: &Setup, region: Region) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    let (address, length) = resolve_read(setup, &mut programmer, &region)?;
+
+    let mut offset = 0;
+    let mut first = None;
+    let mut dirty = 0;
+    programmer.stream(address, length, |chunk| {
+        for (i, byte) in chunk.iter().enumerate() {
+            if *byte != 0xFF {
+                first.get_or_insert(offset + i);
+                dirty += 1;
+            }
+        }
+        offset += chunk.len();
+        Ok(())
+    })?;
+
+    let end = address.get() + length;
+    match first {
+        Some(first) => anyhow::bail!(
+            "{address:#x}..{end:#x} isn't blank: {dirty} bytes aren't 0xFF, the first at {:#x}",
+            address.get() + first
+        ),
+        None => Ok(format!("{address:#x}..{end:#x} is blank ({length} bytes)")),
+    }
+}
+
+/// Print the plan for writing an image, carrying it out when `execute` holds the directory to
+/// back the erased blocks up to, if any.
+fn plan(
+    setup: &Setup,
+    input: &Input,
+    region: Region,
+    json: bool,
+    execute: Option<Option<PathBuf>>,
+) -> Result<()> {
+    let data = input.read()?;
+    let (offset, _) = Region {
+        length: Some(data.len()),
+        ..region
+    }
+    .resolve(setup.layout.as_ref(), data.len(), execute.is_some())?;
+    let mut programmer = setup.flash(None)?;
+    #[cfg(not(feature = "read-only"))]
+    setup.track_wear(&mut programmer)?;
+    let plan = Plan::build(&mut programmer, &data, offset, plan::BLOCK_SIZE)?;
+
+    if json {
+        status!("{}", plan.json());
+    } else {
+        status!("{}", plan.table());
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    if let Some(backup) = execute {
+        confirm::overwrite(&mut programmer, &data, offset, setup.yes)?;
+
+        if let Some(directory) = &backup {
+            let (start, length) = backup::erase_range(offset, data.len());
+            backup::backup(&mut programmer, directory, start, length)?;
+        }
+
+        let cancel = cancel::on_interrupt();
+        status!("Executing plan...");
+        plan.execute(&mut programmer, &data, &cancel)?;
+        status!("Verifying data...");
+        verify(
+            &mut programmer,
+            &data,
+            None,
+            offset,
+            &Verification::default(),
+            &Mask::default(),
+            &cancel,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn recover(setup: &Setup, erase_wait: std::time::Duration) -> Result<()> {
+    if !setup.yes {
+        confirm::prompt(
+            "This will erase the entire flash without checking its status.",
+            "erase",
+        )?;
+    }
+
+    let mut programmer = setup.flash(None)?;
+    programmer.start_chip_erase()?;
+
+    status!("Waiting {erase_wait:?} for the chip erase to complete...");
+    let mut bar = progress::Progress::count("chip erase", erase_wait.as_secs() as usize);
+    for _ in 0..erase_wait.as_secs() {
+        watchdog::beat("chip erase", 0);
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        bar.inc(1);
+    }
+    std::thread::sleep(erase_wait - std::time::Duration::from_secs(erase_wait.as_secs()));
+    bar.finish();
+
+    // Reconnect so the JEDEC ID is read afresh rather than from before the erase
+    drop(programmer);
+    let info = setup.flash(None)?.info()?;
+    let id = info.jedec;
+    if info.unresponsive() {
+        anyhow::bail!(
+            "The chip still isn't responding (JEDEC ID {:02x} {:02x} {:02x})",
+            id[0],
+            id[1],
+            id[2]
+        );
+    }
+
+    status!("JEDEC ID: {:02x} {:02x} {:02x}", id[0], id[1], id[2]);
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn erase_all(setup: &Setup) -> Result<String> {
+    if !setup.yes {
+        confirm::prompt("This will erase the entire flash.", "erase")?;
+    }
+
+    let mut programmer = setup.flash(None)?;
+    status!("Erasing the flash...");
+    let elapsed = programmer.chip_erase()?;
+    programmer.check_chip_erased()?;
+
+    Ok(format!("Erased the flash in {:.1}s", elapsed.as_secs_f64()))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn erase_region(setup: &Setup, region: Region) -> Result<String> {
+    if region.partition.is_none() && region.length.is_none() {
+        anyhow::bail!("Give the number of bytes to erase with --length");
+    }
+    let (address, length) = region.resolve(setup.layout.as_ref(), 0, true)?;
+    let end = address.end(length)?;
+    if !setup.yes {
+        confirm::prompt(&format!("This will erase {address:#x}..{end:#x}."), "erase")?;
+    }
+
+    let mut programmer = setup.flash(None)?;
+    status!("Erasing {address:#x}..{end:#x}...");
+    programmer.erase_range(address, length, &cancel::on_interrupt())?;
+
+    Ok(format!("Erased {address:#x}..{end:#x} ({length} bytes)"))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn raw_cmd(
+    setup: &Setup,
+    write: &[u8],
+    read: usize,
+    write_enable: bool,
+    allow_destructive: bool,
+    trace_path: Option<PathBuf>,
+) -> Result<Vec<u8>> {
+    let trace = trace_path.as_ref().map(|_| Trace::new("raw", 0, read));
+    let mut programmer = setup.flash(trace)?;
+    let result = programmer.raw(write, read, write_enable, allow_destructive);
+
+    save_trace(&mut programmer, trace_path)?;
+    Ok(result?)
+}
+
+fn convert_layout(setup: &Setup, action: LayoutAction) -> Result<()> {
+    use std::io::Write;
+
+    let (text, output) = match action {
+        LayoutAction::Export { format, output } => {
+            let layout = setup
+                .layout
+                .as_ref()
+                .context("Pass the layout to export with --layout")?;
+            (layout.export(format)?, output)
+        }
+        LayoutAction::Import { path, output } => {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Error reading {}", path.display()))?;
+            let layout = Layout::parse_flashrom(&text)
+                .with_context(|| format!("Invalid flashrom layout in {}", path.display()))?;
+            (layout.toml(), output)
+        }
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(&path, text).with_context(|| format!("Error writing {}", path.display()))
+        }
+        None => Ok(std::io::stdout().write_all(text.as_bytes())?),
+    }
+}
+
+fn flash_info(setup: &Setup) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    let jedec = programmer.info()?.jedec;
+    let id = format!("{:02x} {:02x} {:02x}", jedec[0], jedec[1], jedec[2]);
+
+    Ok(match programmer.sfdp()? {
+        Some(parameters) => format!("JEDEC ID:      {id}\n{parameters}"),
+        None => format!("The flash with JEDEC ID {id} has no SFDP table"),
+    })
+}
+
+fn unique_id(setup: &Setup) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    match programmer.unique_id()? {
+        Some(id) => Ok(backup::hex(&id)),
+        None => anyhow::bail!("The flash has no unique ID; it read all ones or all zeros"),
+    }
+}
+
+fn counter_read(setup: &Setup, location: &counter::Location) -> Result<String> {
+    let address = location.require(setup.layout.as_ref())?;
+    let mut programmer = setup.flash(None)?;
+
+    Ok(match counter::read(&mut programmer, address)?.value {
+        Some(value) => format!("Version counter: {value}"),
+        None => "The version counter has never been written".into(),
+    })
+}
+
+#[cfg(not(feature = "read-only"))]
+fn counter_bump(setup: &Setup, location: &counter::Location, to: Option<u32>) -> Result<String> {
+    let address = location.require(setup.layout.as_ref())?;
+    let mut programmer = setup.flash(None)?;
+    let value = match to {
+        Some(value) => value,
+        None => counter::read(&mut programmer, address)?
+            .current()
+            .checked_add(1)
+            .context("The version counter is at its maximum")?,
+    };
+
+    counter::bump(&mut programmer, address, value)?;
+    Ok(format!("Raised the version counter to {value}"))
+}
+
+fn otp_read(setup: &Setup, register: u8, output: Option<&Path>) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    let data = otp::read(&mut programmer, register)?;
+    let state = if otp::locked(&mut programmer, register)? {
+        "locked"
+    } else {
+        "unlocked"
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, &data)
+                .with_context(|| format!("Error writing {}", path.display()))?;
+            Ok(format!(
+                "Saved security register {register} ({state}) to {}",
+                path.display()
+            ))
+        }
+        None => Ok(format!(
+            "Security register {register}, {state}:\n{}",
+            hexdump::format(0, &data, &Default::default()).trim_end()
+        )),
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+fn otp_program(setup: &Setup, register: u8, offset: usize, input: &Path) -> Result<String> {
+    let data =
+        std::fs::read(input).with_context(|| format!("Error reading {}", input.display()))?;
+    let mut programmer = setup.flash(None)?;
+    otp::program(&mut programmer, register, offset, &data)?;
+    Ok(format!(
+        "Programmed {} bytes into security register {register} at {offset:#x}",
+        data.len()
+    ))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn slot_update(setup: &Setup, input: &Input, device: Option<Device>) -> Result<String> {
+    let data = input.read()?;
+    let mut programmer = setup.flash(None)?;
+    setup.track_wear(&mut programmer)?;
+    let slot = slots::update(
+        &mut programmer,
+        setup.layout.as_ref(),
+        &data,
+        setup.device(device),
+        setup.yes,
+        &cancel::on_interrupt(),
+    )?;
+    Ok(format!(
+        "Updated {} and booting it at power-on",
+        slot.name()
+    ))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn slot_select(setup: &Setup, slot: slots::Slot) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    slots::select(
+        &mut programmer,
+        setup.layout.as_ref(),
+        slot,
+        &cancel::on_interrupt(),
+    )?;
+    Ok(format!("Booting {} at power-on", slot.name()))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn otp_erase(setup: &Setup, register: u8) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    if !setup.yes {
+        confirm::prompt(
+            &format!("Erasing security register {register} loses whatever was provisioned in it."),
+            "erase",
+        )?;
+    }
+    otp::erase(&mut programmer, register)?;
+    Ok(format!("Erased security register {register}"))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn otp_lock(setup: &Setup, register: u8) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    if !setup.yes {
+        confirm::prompt(
+            &format!("Locking security register {register} can never be undone."),
+            "lock",
+        )?;
+    }
+    otp::lock(&mut programmer, register)?;
+    Ok(format!("Locked security register {register}"))
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Protect `range`, or the whole flash, through the status registers, confirming first when
+/// setting SRP.
+fn lockdown(setup: &Setup, range: Option<&std::ops::Range<usize>>, srp: bool) -> Result<String> {
+    let mut programmer = setup.flash(None)?;
+    if srp && !setup.yes {
+        confirm::prompt(
+            "Setting SRP locks the status registers while WP# is held low, which may make the \
+             protection permanent.",
+            "lock",
+        )?;
+    }
+
+    protect::lock(&mut programmer, range, srp)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Run a script's steps against a single flash session.
+fn run_script(setup: &Setup, script: &script::Script) -> Result<usize> {
+    use script::Step;
+
+    let mut programmer = setup.flash(None)?;
+    setup.track_wear(&mut programmer)?;
+    let layout = setup.layout.as_ref();
+    let cancel = cancel::on_interrupt();
+
+    script.run(|step| match step {
+        Step::Flash { path, text, region } => {
+            let data = match (path, text) {
+                (Some(path), None) => std::fs::read(path)
+                    .with_context(|| format!("Error reading {}", path.display()))?,
+                (None, Some(text)) => text.clone().into_bytes(),
+                _ => anyhow::bail!("A flash step needs exactly one of path and text"),
+            };
+            let (address, _) = Region {
+                length: Some(data.len()),
+                ..region.into()
+            }
+            .resolve(layout, data.len(), true)?;
+
+            confirm::overwrite(&mut programmer, &data, address, setup.yes)?;
+            flash_images(
+                &mut programmer,
+                &[(&data, address)],
+                None,
+                &Verification::default(),
+                &Mask::EMPTY,
+                &Mask::EMPTY,
+                false,
+                &cancel,
+            )
+        }
+        Step::Verify { path, region } => {
+            let data =
+                std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))?;
+            let (address, _) = Region {
+                length: Some(data.len()),
+                ..region.into()
+            }
+            .resolve(layout, data.len(), false)?;
+
+            let verification = Verification::default();
+            verify(
+                &mut programmer,
+                &data,
+                None,
+                address,
+                &verification,
+                &Mask::EMPTY,
+                &cancel,
+            )?;
+            Ok(())
+        }
+        Step::Erase { region } => {
+            let (address, length) = Region::from(region).resolve(layout, 0, true)?;
+            if length == 0 {
+                anyhow::bail!("An erase step needs a length or a partition");
+            }
+
+            let spans = plan::erase_spans(&[(address, length)])?;
+            status!("Erasing {length:#x} bytes from {address:#x}");
+            for (address, length) in spans {
+                cancel.check(address.get())?;
+                watchdog::beat("erase", address.get());
+                programmer.await_ready()?;
+                let erases = programmer.plan_erases(address, length)?;
+                programmer.erase_planned(&erases)?;
+            }
+            Ok(programmer.await_ready()?)
+        }
+        Step::Dump { output, region } => {
+            let (address, length) = Region::from(region).resolve(layout, 256, false)?;
+            let data = programmer.read_arbitrary(address, length)?;
+
+            std::fs::write(output, data)
+                .with_context(|| format!("Error writing {}", output.display()))
+        }
+        Step::Raw {
+            write,
+            read,
+            keep_write_enable,
+            allow_destructive,
+        } => {
+            let response = programmer.raw(write, *read, *keep_write_enable, *allow_destructive)?;
+            if !response.is_empty() {
+                status!("{}", backup::hex(&response));
+            }
+            Ok(())
+        }
+    })
+}
+
+fn save_trace(programmer: &mut FlashProgrammer, trace_path: Option<PathBuf>) -> Result<()> {
+    match (trace_path, programmer.take_trace()) {
+        (Some(path), Some(trace)) => trace.save(&path),
+        _ => Ok(()),
+    }
+}
+
+fn replay(trace_path: PathBuf, input: Option<PathBuf>) -> Result<usize> {
+    let trace = Trace::load(&trace_path)?;
+    let expected = trace.transactions.len();
+    let recording = Trace::new(&trace.operation, trace.address, trace.length);
+    let mut programmer = FlashProgrammer::with_port(
+        Box::new(Replay::new(trace.clone())),
+        &Timing {
+            fast_read: trace.fast_read(),
+            ..Timing::default()
+        },
+        Some(recording),
+    )?;
+
+    let address = FlashAddress::new(trace.address)?;
+    match trace.operation.as_str() {
+        #[cfg(feature = "read-only")]
+        "flash" => {
+            let _ = input;
+            anyhow::bail!("This build is read-only, so it can't replay a flash")
+        }
+        #[cfg(not(feature = "read-only"))]
+        "flash" => {
+            let input = input.with_context(|| "Replaying a flash requires the input file")?;
+            let data = std::fs::read(input).with_context(|| "Error reading input file")?;
+            if data.len() != trace.length {
+                anyhow::bail!(
+                    "The input is {} bytes, but the traced flash wrote {} bytes",
+                    data.len(),
+                    trace.length
+                );
+            }
+            flash_images(
+                &mut programmer,
+                &[(&data, address)],
+                None,
+                &Verification::default(),
+                &Mask::EMPTY,
+                &Mask::EMPTY,
+                false,
+                &cancel::on_interrupt(),
+            )?;
+        }
+        "dump" => {
+            programmer.read_arbitrary(address, trace.length)?;
+        }
+        operation => anyhow::bail!("Unknown operation {operation:?} in trace"),
+    }
+
+    let replayed = programmer.take_trace().map_or(0, |t| t.transactions.len());
+    if replayed != expected {
+        anyhow::bail!(
+            "Replay finished after {replayed} transactions, but the trace recorded {expected}"
+        );
+    }
+
+    Ok(replayed)
+}
+
+/// Run a subcommand, returning the message to report on success or failure.
+fn run(command: Commands, setup: &Setup) -> Result<Option<String>, String> {
+    use std::io::Write;
+
+    let message = match command {
+        Commands::Sram {
+            input,
+            sequence: images,
+            reliability,
+            spi,
+            preflight,
+            device,
+            force,
+            post_program_pulse,
+        } => {
+            let result = match &input {
+                Some(input) if reliability.reliability_test => {
+                    reliability_test(setup, input, &reliability, &spi, &preflight, device, force)
+                }
+                Some(input) => program(
+                    setup,
+                    input,
+                    &spi,
+                    &preflight,
+                    device,
+                    force,
+                    &post_program_pulse,
+                )
+                .map(|_| "Succesfully programmed device!".to_string()),
+                None if !images.sequence.is_empty() => sequence(
+                    setup,
+                    &images,
+                    &spi,
+                    &preflight,
+                    device,
+                    force,
+                    &post_program_pulse,
+                ),
+                None => return Err("Pass an INPUT, or several with --sequence".into()),
+            };
+            let pulse_pins: Vec<_> = post_program_pulse.iter().map(|p| p.pin).collect();
+            let reset = setup.release_sram(&pulse_pins);
+
+            match (result, reset) {
+                (Ok(message), Ok(_)) => message,
+                (Err(e), Ok(_)) => return Err(format!("Failed to program device: {e}")),
+                (Ok(_), Err(r)) => {
+                    return Err(format!(
+                        "Succesfully programmed device, but failed to reset: {r}"
+                    ))
+                }
+                (Err(e), Err(r)) => {
+                    return Err(format!(
+                        "Failed to program device: {e}\nAnd failed to reset: {r}"
+                    ))
+                }
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::Flash {
+            input,
+            trace,
+            no_precheck,
+            offset,
+            partition,
+            allow_cross_partition,
+            backup,
+            verification,
+            utilization_warning,
+            mask,
+            images,
+            allow_unbootable,
+            device,
+            force,
+            write_boot_header,
+            assume_blank,
+            skip_zero_blocks,
+            incremental,
+            journal,
+            from_dump,
+            allow_size_mismatch,
+            and_load,
+            spi,
+            preflight,
+            boot_check,
+            image_version,
+            require_version_ge,
+            counter,
+        } => {
+            if from_dump {
+                return match restore(setup, &input, allow_size_mismatch) {
+                    Ok(report) => Ok(Some(report)),
+                    Err(e) => Err(format!("Failed to restore the dump: {e:#}")),
+                };
+            }
+
+            let region = Region {
+                address: offset,
+                partition,
+                allow_cross_partition,
+                ..Default::default()
+            };
+            let options = FlashOptions {
+                trace,
+                precheck: !no_precheck,
+                backup,
+                verification,
+                utilization_warning,
+                mask: match mask.resolve() {
+                    Ok(mask) => mask,
+                    Err(e) => return Err(format!("Failed to flash device: {e:#}")),
+                },
+                images,
+                allow_unbootable,
+                device,
+                force,
+                write_boot_header,
+                assume_blank,
+                skip_zero_blocks,
+                incremental,
+                journal,
+                and_load,
+                spi,
+                preflight,
+                boot_check,
+                image_version,
+                require_version_ge,
+                counter: match counter.resolve(setup.layout.as_ref()) {
+                    Ok(address) => address,
+                    Err(e) => return Err(format!("Failed to flash device: {e:#}")),
+                },
+            };
+            match flash(setup, &input, region, options) {
+                Ok(true) if assume_blank => "Succesfully flashed device!\n\
+                    NOTE: NO BLOCKS WERE ERASED. The flash was assumed blank (--assume-blank), \
+                    and verification confirmed the written image."
+                    .into(),
+                Ok(true) if and_load => "Succesfully flashed and loaded device!".into(),
+                Ok(true) => "Succesfully flashed device!".into(),
+                Ok(false) if and_load => {
+                    "Flash already contains this image; loaded it into the FPGA".into()
+                }
+                Ok(false) => "Flash already contains this image, nothing to do".into(),
+                Err(e) => return Err(format!("Failed to flash device: {e:#}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::FlashManifest {
+            manifest,
+            no_precheck,
+            device,
+            force,
+            verification,
+        } => match flash_manifest(setup, &manifest, !no_precheck, device, force, &verification) {
+            Ok(message) => message,
+            Err(e) => return Err(format!("Failed to flash the manifest: {e:#}")),
+        },
+        #[cfg(not(feature = "read-only"))]
+        Commands::Multiboot {
+            images,
+            power_on,
+            output,
+            device,
+            force,
+            verification,
+        } => match multiboot(
+            setup,
+            &images,
+            power_on,
+            output.as_deref(),
+            device,
+            force,
+            &verification,
+        ) {
+            Ok(message) => message,
+            Err(e) => return Err(format!("Failed to build the multiboot image: {e:#}")),
+        },
+        Commands::Dump {
+            region,
+            trace,
+            format,
+            no_annotate,
+            symbol,
+            output,
+            no_fpga_reset,
+        } => match dump(setup, region, trace, no_fpga_reset) {
+            Ok((address, data)) => {
+                let address = address.get();
+                let bytes = match format {
+                    export::Format::Raw => data,
+                    export::Format::Hex => {
+                        let layout = setup.layout.as_ref();
+                        let banners = if no_annotate {
+                            Default::default()
+                        } else {
+                            let landmarks = hexdump::landmarks(address, &data, layout);
+                            hexdump::banners(address, data.len(), layout, &landmarks)
+                        };
+                        hexdump::format(address, &data, &banners).into_bytes()
+                    }
+                    export::Format::CArray => export::c_array(&symbol, address, &data).into_bytes(),
+                    export::Format::RustArray => {
+                        export::rust_array(&symbol, address, &data).into_bytes()
+                    }
+                    export::Format::Ihex => export::ihex(address, &data).into_bytes(),
+                    export::Format::Srec => export::srec(address, &data).into_bytes(),
+                };
+
+                let written = match &output {
+                    Some(path) => std::fs::write(path, &bytes),
+                    None => std::io::stdout().write_all(&bytes),
+                };
+                match written {
+                    Ok(_) => return Ok(None),
+                    Err(e) => return Err(format!("Error writing the dump: {e}")),
+                }
+            }
+            Err(e) => return Err(format!("Error dumping data: {e}")),
+        },
+        Commands::Verify {
+            input,
+            offset,
+            partition,
+            allow_cross_partition,
+            verification,
+            mask,
+            json,
+            no_fpga_reset,
+        } => {
+            let region = Region {
+                address: offset,
+                partition,
+                allow_cross_partition,
+                ..Default::default()
+            };
+            let result = mask.resolve().and_then(|mask| {
+                verify_only(
+                    setup,
+                    &input,
+                    region,
+                    &verification,
+                    &mask,
+                    json,
+                    no_fpga_reset,
+                )
+            });
+            match result {
+                Ok(_) if json => return Ok(None),
+                Ok(summary) => summary,
+                Err(e) => return Err(format!("Failed to verify: {e:#}")),
+            }
+        }
+        Commands::Checksum { region } => match checksum(setup, region) {
+            Ok(summary) => summary,
+            Err(e) => return Err(format!("Failed to checksum the flash: {e:#}")),
+        },
+        Commands::BlankCheck { region } => match blank_check(setup, region) {
+            Ok(summary) => summary,
+            Err(e) => return Err(format!("Failed to blank check: {e:#}")),
+        },
+        Commands::Plan {
+            input,
+            offset,
+            partition,
+            allow_cross_partition,
+            json,
+            #[cfg(not(feature = "read-only"))]
+            execute,
+            #[cfg(not(feature = "read-only"))]
+            backup,
+        } => {
+            let region = Region {
+                address: offset,
+                partition,
+                allow_cross_partition,
+                ..Default::default()
+            };
+            #[cfg(feature = "read-only")]
+            let (execute, backup) = (false, None);
+            match plan(setup, &input, region, json, execute.then_some(backup)) {
+                Ok(_) if execute => "Succesfully executed plan!".into(),
+                Ok(_) => return Ok(None),
+                Err(e) => return Err(format!("Failed to plan: {e}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::Erase { all, region } => {
+            let result = if all {
+                erase_all(setup)
+            } else {
+                erase_region(setup, region)
+            };
+            match result {
+                Ok(message) => message,
+                Err(e) => return Err(format!("Failed to erase the flash: {e:#}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::Recover {
+            blind_chip_erase: _,
+            erase_wait,
+        } => match recover(setup, erase_wait) {
+            Ok(_) => "Chip recovered!".into(),
+            Err(e) => return Err(format!("Failed to recover chip: {e}")),
+        },
+        Commands::Layout { action } => match convert_layout(setup, action) {
+            Ok(()) => return Ok(None),
+            Err(e) => return Err(format!("Failed to convert the layout: {e:#}")),
+        },
+        Commands::Counter { action } => {
+            let result = match &action {
+                CounterAction::Read { location } => counter_read(setup, location),
+                #[cfg(not(feature = "read-only"))]
+                CounterAction::Bump { location, to } => counter_bump(setup, location, *to),
+            };
+            match result {
+                Ok(message) => message,
+                Err(e) => return Err(format!("Failed to access the version counter: {e:#}")),
+            }
+        }
+        Commands::Otp { action } => {
+            let result = match &action {
+                OtpAction::Read { register, output } => {
+                    otp_read(setup, *register, output.as_deref())
+                }
+                #[cfg(not(feature = "read-only"))]
+                OtpAction::Program {
+                    register,
+                    offset,
+                    input,
+                } => otp_program(setup, *register, *offset, input),
+                #[cfg(not(feature = "read-only"))]
+                OtpAction::Erase { register } => otp_erase(setup, *register),
+                #[cfg(not(feature = "read-only"))]
+                OtpAction::Lock { register } => otp_lock(setup, *register),
+            };
+            match result {
+                Ok(message) => message,
+                Err(e) => return Err(format!("Failed to access the security registers: {e:#}")),
+            }
+        }
+        Commands::Slot { action } => {
+            let result = match &action {
+                SlotAction::Status => setup.flash(None).and_then(|mut programmer| {
+                    slots::status(&mut programmer, setup.layout.as_ref())
+                }),
+                #[cfg(not(feature = "read-only"))]
+                SlotAction::Update { input, device } => slot_update(setup, input, *device),
+                #[cfg(not(feature = "read-only"))]
+                SlotAction::Select { slot } => slot_select(setup, *slot),
+            };
+            match result {
+                Ok(message) => message,
+                Err(e) => return Err(format!("Failed to access the slots: {e:#}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::Lockdown {
+            protect_range,
+            srp,
+            status: _,
+        } => {
+            let result = match &protect_range {
+                Some(range) => lockdown(setup, Some(range), srp),
+                None => setup
+                    .flash(None)
+                    .and_then(|mut programmer| protect::status(&mut programmer)),
+            };
+            match result {
+                Ok(message) => message,
+                Err(e) => return Err(format!("Failed to configure block protection: {e:#}")),
+            }
+        }
+        #[cfg(feature = "read-only")]
+        Commands::Lockdown { status: _ } => {
+            match setup
+                .flash(None)
+                .and_then(|mut programmer| protect::status(&mut programmer))
+            {
+                Ok(message) => message,
+                Err(e) => return Err(format!("Failed to read block protection: {e:#}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::Protect { range, srp } => match lockdown(setup, range.as_ref(), srp) {
+            Ok(message) => message,
+            Err(e) => return Err(format!("Failed to protect the flash: {e:#}")),
+        },
+        #[cfg(not(feature = "read-only"))]
+        Commands::Unprotect => match setup
+            .flash(None)
+            .and_then(|mut programmer| protect::unlock(&mut programmer))
+        {
+            Ok(message) => message,
+            Err(e) => return Err(format!("Failed to unprotect the flash: {e:#}")),
+        },
+        Commands::Pinout { format } => pinout::render(&setup.pins, board::Board::detect(), format),
+        Commands::Wear { threshold } => {
+            let Some(path) = &setup.wear_file else {
+                return Err("The wear subcommand requires --wear-file".into());
+            };
+            match wear::report(path, threshold) {
+                Ok(report) => report,
+                Err(e) => return Err(format!("Failed to read the wear file: {e:#}")),
+            }
+        }
+        Commands::Serve { listen } => match remote::serve(&listen) {
+            Ok(()) => "Stopped serving".into(),
+            Err(e) => return Err(format!("Failed to serve: {e:#}")),
+        },
+        Commands::Remote { host, args } => {
+            let name = Cli::command().get_name().to_string();
+            let upload = match Cli::try_parse_from(std::iter::once(&name).chain(&args)) {
+                Ok(cli) => match cli.command {
+                    #[cfg(not(feature = "read-only"))]
+                    Some(Commands::Flash { input, .. }) => Some(input.path),
+                    Some(Commands::Verify { input, .. }) => Some(input.path),
+                    Some(Commands::Sram {
+                        input: Some(input), ..
+                    }) => Some(input.path),
+                    Some(Commands::Sram { .. }) => {
+                        return Err("Only one image can be uploaded, so --sequence can't run \
+                                    remotely"
+                            .into())
+                    }
+                    Some(Commands::Serve { .. } | Commands::Remote { .. }) => {
+                        return Err("Only hardware commands can be run remotely".into())
+                    }
+                    _ => None,
+                },
+                Err(e) => return Err(format!("Invalid remote command: {e}")),
+            };
+
+            let mut args = match &upload {
+                Some(path) => remote::mark_upload(&args, path),
+                None => args,
+            };
+            if progress::mode() == ProgressMode::Json {
+                args.extend(["--progress".into(), "json".into()]);
+            }
+
+            match remote::client(&host, &args, upload.as_deref()) {
+                Ok(()) => return Ok(None),
+                Err(e) => return Err(format!("Remote command failed: {e:#}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::Run { script, vars } => {
+            let result =
+                script::Script::load(&script, &vars).and_then(|script| run_script(setup, &script));
+            match result {
+                Ok(0) => "Completed every step".into(),
+                Ok(failed) => format!("Completed the script, with {failed} allowed failures"),
+                Err(e) => return Err(format!("Script failed: {e:#}")),
+            }
+        }
+        Commands::ExportTrace {
+            trace,
+            format: trace::Format::Vcd,
+            clock_khz,
+            output,
+        } => {
+            let result = Trace::load(&trace).and_then(|trace| {
+                let vcd = vcd::export(
+                    &trace,
+                    &setup.timing,
+                    clock_khz,
+                    setup.pins.flash_cs_active_low,
+                );
+                std::fs::write(&output, vcd)
+                    .with_context(|| format!("Error writing {}", output.display()))?;
+                Ok(trace.transactions.len())
+            });
+            match result {
+                Ok(count) => format!("Exported {count} transactions to {}", output.display()),
+                Err(e) => return Err(format!("Failed to export the trace: {e:#}")),
+            }
+        }
+        Commands::Replay { trace, input } => match replay(trace, input) {
+            Ok(count) => format!("Replayed all {count} transactions without divergence"),
+            Err(e) => return Err(format!("Replay diverged: {e}")),
+        },
+        Commands::Burnin {
+            cycles,
+            cdone,
+            done_timeout,
+            verify_image,
+            offset,
+            verify_every,
+            report,
+        } => {
+            let image = match verify_image.map(std::fs::read).transpose() {
+                Ok(Some(image)) if image.is_empty() => {
+                    return Err("The verify image is empty, so there's nothing to verify".into())
+                }
+                Ok(image) => image.map(|image| (image, offset)),
+                Err(e) => return Err(format!("Error reading the verify image: {e}")),
+            };
+            let options = burnin::Options {
+                cycles,
+                cdone,
+                done_timeout,
+                image,
+                verify_every,
+                report,
+            };
+
+            let result = setup
+                .require_hardware("Burn-in")
+                .and_then(|_| burnin::run(&setup.pins, &setup.timing, &options));
+            match result {
+                Ok(summary) if summary.failures() == 0 => summary.to_string(),
+                Ok(summary) => return Err(summary.to_string()),
+                Err(e) => return Err(format!("Burn-in failed: {e}")),
+            }
+        }
+        #[cfg(not(feature = "read-only"))]
+        Commands::RawCmd {
+            write,
+            read,
+            keep_write_enable,
+            allow_destructive,
+            trace,
+        } => {
+            match raw_cmd(
+                setup,
+                &write,
+                read,
+                keep_write_enable,
+                allow_destructive,
+                trace,
+            ) {
+                Ok(response) => backup::hex(&response),
+                Err(e) => return Err(format!("Failed to send command: {e}")),
+            }
+        }
+        Commands::Detect => match setup
+            .flash(None)
+            .and_then(|mut programmer| parts::report(&mut programmer))
+        {
+            Ok(report) => report,
+            Err(e) => return Err(format!("Failed to detect the flash: {e:#}")),
+        },
+        Commands::FlashInfo => match flash_info(setup) {
+            Ok(report) => report,
+            Err(e) => return Err(format!("Failed to read the SFDP table: {e:#}")),
+        },
+        Commands::UniqueId => match unique_id(setup) {
+            Ok(id) => id,
+            Err(e) => return Err(format!("Failed to read the unique ID: {e:#}")),
+        },
+        Commands::PowerDown => match setup
+            .flash(None)
+            .and_then(|mut programmer| Ok(programmer.power_down()?))
+        {
+            Ok(()) => "Put the flash into deep power-down".into(),
+            Err(e) => return Err(format!("Failed to power down the flash: {e:#}")),
+        },
+        Commands::Wake => match setup
+            .flash(None)
+            .and_then(|mut programmer| Ok(programmer.info()?))
+        {
+            Ok(info) if info.unresponsive() => {
+                return Err(format!(
+                    "Failed to wake the flash: it still reads JEDEC ID {:02x?}",
+                    info.jedec
+                ))
+            }
+            Ok(info) => format!("Woke the flash with JEDEC ID {:02x?}", info.jedec),
+            Err(e) => return Err(format!("Failed to wake the flash: {e:#}")),
+        },
+        Commands::Examples => {
+            let active = examples::Active {
+                pins: &setup.pins,
+                timing: &setup.timing,
+                layout: setup.layout_path.as_deref().zip(setup.layout.as_ref()),
+            };
+
+            match examples::generate(&Cli::command(), &active) {
+                Ok(output) => output,
+                Err(e) => return Err(format!("Failed to generate examples: {e}")),
+            }
+        }
+        Commands::DiagnoseCs { candidate_pins } => {
+            let result = setup
+                .require_hardware("Diagnosing chip selects")
+                .and_then(|_| diagnose::chip_selects(&setup.pins, &setup.timing, &candidate_pins));
+            match result {
+                Ok(report) => report,
+                Err(e) => return Err(format!("Failed to diagnose chip selects: {e:#}")),
+            }
+        }
+        Commands::Release { .. } if setup.backend != mock::Backend::Pi => {
+            format!("Nothing to release with --backend {}", setup.backend.name())
+        }
+        Commands::Release { no_fpga_reset } => {
+            sleep(setup.timing.release_settle);
+            match FlashProgrammer::reset(&setup.pins, !no_fpga_reset) {
+                Ok(_) => "Released pins".into(),
+                Err(e) => return Err(format!("Failed to release pins: {e}")),
+            }
+        }
+    };
+
+    Ok(Some(message))
+}
+
+impl Commands {
+    /// The SRAM programmer's SPI settings, for the subcommands that take them.
+    fn spi_mut(&mut self) -> Option<&mut SpiSettings> {
+        match self {
+            Commands::Sram { spi, .. } => Some(spi),
+            #[cfg(not(feature = "read-only"))]
+            Commands::Flash { spi, .. } => Some(spi),
+            _ => None,
+        }
+    }
+}
+
+fn main() {
+    let matches = Cli::command().get_matches();
+    let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    progress::set_mode(args.progress);
+    progress::set_verbose(args.verbose);
+    report::set_reporter(Box::new(progress::Terminal));
+
+    let profile = match args.profile.as_deref() {
+        Some(name) => match config::load(args.config.as_deref(), name) {
+            Ok((path, profile)) => {
+                verbose!("Using board profile {name:?} from {}", path.display());
+                Some(profile)
+            }
+            Err(e) => {
+                warning!("{e:#}");
+                progress::done(false);
+                return;
+            }
+        },
+        None => None,
+    };
+    if let Some(profile) = &profile {
+        profile.apply_pins(&mut args.pins, &matches);
+        if let Some(size) = profile
+            .flash_size
+            .filter(|_| config::defaulted(&matches, "mock_size"))
+        {
+            args.mock_size = size;
+        }
+        if let (Some(spi), Some((_, matches))) = (
+            args.command.as_mut().and_then(Commands::spi_mut),
+            matches.subcommand(),
+        ) {
+            if let Some(baud) = profile.baud.filter(|_| config::defaulted(matches, "baud")) {
+                spi.baud = baud;
+            }
+            if let Some(transfer) = profile
+                .transfer
+                .filter(|_| config::defaulted(matches, "transfer"))
+            {
+                spi.transfer = transfer;
+            }
+        }
+    }
+
+    let mut timings = args.timings.clone();
+    if let Some(delay) = args.post_flash_delay {
+        timings.push(("cdone_delay".into(), delay));
+    }
+    let timing = match Timing::with_overrides(&timings) {
+        Ok(timing) => Timing {
+            chip_profile: args.chip_profile,
+            erases: (!args.erase_opcodes.is_empty()).then_some(args.erase_opcodes),
+            slow_factor: args.slow_block_factor,
+            flash_clock: args.flash_clock,
+            fast_read: args.fast_read_dummy,
+            ..timing
+        },
+        Err(e) => {
+            warning!("Invalid timing: {e}");
+            progress::done(false);
+            return;
+        }
+    };
+
+    verbose!("Timing: {}", timing.describe());
+    if args.backend == mock::Backend::Pi {
+        match board::Board::detect() {
+            Some(board) => verbose!(
+                "Running on a {} ({} GPIO)",
+                board.model,
+                board.controller.name()
+            ),
+            None => verbose!("No Raspberry Pi model in the device tree"),
+        }
+    }
+
+    let layout = match args.layout.as_deref().map(Layout::load).transpose() {
+        Ok(layout) => layout,
+        Err(e) => {
+            warning!("{e:#}");
+            progress::done(false);
+            return;
+        }
+    };
+
+    let setup = Setup {
+        pins: args.pins,
+        timing,
+        layout,
+        layout_path: args.layout,
+        yes: args.yes,
+        wear_file: args.wear_file,
+        backend: args.backend,
+        mock: (args.backend == mock::Backend::Mock).then_some(mock::Settings {
+            image: args.mock_image,
+            size: args.mock_size,
+            ignore_writes: args.mock_ignore_writes,
+            sector_erase_only: args.mock_sector_erase_only,
+        }),
+        linux: (args.backend == mock::Backend::Linux)
+            .then(|| linux::Settings::new(args.gpiochip, args.spidev)),
+        ftdi: (args.backend == mock::Backend::Ftdi).then_some(ftdi::Settings {
+            serial: args.ftdi_serial,
+            cs: args.ftdi_cs,
+            reset: args.ftdi_reset,
+            cdone: args.ftdi_cdone,
+        }),
+        ch341a: (args.backend == mock::Backend::Ch341a).then_some(ch341a::Settings {
+            device: args.ch341a_device,
+        }),
+        flash_size: profile.as_ref().and_then(|profile| profile.flash_size),
+        device: profile.and_then(|profile| profile.device),
+    };
+
+    let command = match (args.command, args.describe) {
+        (None, true) => {
+            let active = describe::Active {
+                pins: &setup.pins,
+                timing: &setup.timing,
+                layout: setup.layout_path.as_deref().zip(setup.layout.as_ref()),
+                mock: setup.mock.as_ref(),
+                linux: setup.linux.as_ref(),
+                ftdi: setup.ftdi.as_ref(),
+                ch341a: setup.ch341a.as_ref(),
+                wear_file: setup.wear_file.as_deref(),
+            };
+            status!("{}", describe::describe(&Cli::command(), &active));
+            progress::done(true);
+            return;
+        }
+        (Some(_), true) => Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--describe can't be combined with a subcommand",
+            )
+            .exit(),
+        (None, false) => Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "A subcommand is required unless --describe is given",
+            )
+            .exit(),
+        (Some(command), false) => command,
+    };
+
+    let watchdog = std::time::Duration::from_secs(args.watchdog_seconds);
+    // After the profile's pins are applied, so they're released rather than the defaults
+    watchdog::set_pins(&setup.pins);
+    watchdog::set_report(
+        args.watchdog_report
+            .unwrap_or_else(|| std::env::temp_dir().join("lattice-prog-watchdog.json")),
+    );
+    if setup.backend != mock::Backend::Pi {
+        watchdog::set_backend(setup.backend.name());
+    }
+    let result = watchdog::supervise(watchdog, args.term_grace, move || run(command, &setup));
+
+    match &result {
+        Ok(Some(message)) => status!("{message}"),
+        Ok(None) => {}
+        Err(message) => warning!("{message}"),
+    }
+    progress::done(result.is_ok());
 }
@@ -0,0 +1,131 @@
+//! Manifests of the regions a product's flash holds, for writing a bitstream along with its
+//! soft CPU's firmware and data in one `flash-manifest` run.
+//!
+//! A manifest is a TOML file listing each region's file and where it goes, by address or by
+//! layout partition. Files are relative to the manifest, and are read like `flash` inputs, so
+//! Intel HEX and S-record files are written at their records' addresses from the region's start:
+//!
+//! ```toml
+//! [[region]]
+//! name = "bitstream"
+//! file = "build/top.bin"
+//! offset = 0x0
+//!
+//! [[region]]
+//! name = "riscv firmware"
+//! file = "build/firmware.hex"
+//! offset = 0x100000
+//!
+//! [[region]]
+//! name = "assets"
+//! file = "assets.bin"
+//! partition = "assets"
+//! ```
+
+use crate::address::FlashAddress;
+use crate::input::Input;
+use crate::layout::{Layout, Region};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Entry {
+    name: Option<String>,
+    file: PathBuf,
+    offset: Option<usize>,
+    partition: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default, rename = "region")]
+    entries: Vec<Entry>,
+    /// The directory the files are relative to.
+    #[serde(skip)]
+    directory: PathBuf,
+}
+
+/// A piece of a region's file, read and placed on the flash.
+pub struct Image {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub address: FlashAddress,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading manifest from {}", path.display()))?;
+        let mut manifest: Self = toml::from_str(&text)
+            .with_context(|| format!("Invalid manifest in {}", path.display()))?;
+        if manifest.entries.is_empty() {
+            anyhow::bail!("{} lists no regions", path.display());
+        }
+        manifest.directory = path.parent().unwrap_or(Path::new("")).to_owned();
+
+        Ok(manifest)
+    }
+
+    /// Read every region's file and resolve where it's written, refusing writes to read-only
+    /// partitions. A record file with gaps gives an image for each of its segments.
+    pub fn images(&self, layout: Option<&Layout>) -> Result<Vec<Image>> {
+        let mut images = Vec::new();
+        for entry in &self.entries {
+            let name = entry
+                .name
+                .clone()
+                .unwrap_or_else(|| entry.file.display().to_string());
+            let segments = entry
+                .input(&self.directory)
+                .segments()
+                .with_context(|| format!("Error reading the {name:?} region"))?;
+            let extent = segments.last().map_or(0, |segment| segment.end());
+            let (base, _) = entry
+                .region(&name, extent)?
+                .resolve(layout, extent, true)
+                .with_context(|| format!("Error placing the {name:?} region"))?;
+
+            for segment in segments {
+                images.push(Image {
+                    name: name.clone(),
+                    address: base.offset(segment.offset)?,
+                    data: segment.data,
+                });
+            }
+        }
+
+        Ok(images)
+    }
+}
+
+impl Entry {
+    fn input(&self, directory: &Path) -> Input {
+        Input {
+            path: directory.join(&self.file),
+            input_offset: 0,
+            input_length: None,
+            input_format: None,
+        }
+    }
+
+    fn region(&self, name: &str, length: usize) -> Result<Region> {
+        let address = match (self.offset, &self.partition) {
+            (Some(offset), None) => Some(FlashAddress::new(offset)?),
+            (None, Some(_)) => None,
+            (Some(_), Some(_)) => {
+                anyhow::bail!("The {name:?} region has both an offset and a partition")
+            }
+            (None, None) => anyhow::bail!("The {name:?} region needs an offset or a partition"),
+        };
+
+        Ok(Region {
+            address,
+            length: Some(length),
+            partition: self.partition.clone(),
+            allow_cross_partition: false,
+        })
+    }
+}
@@ -0,0 +1,177 @@
+//! Don't-care regions excluded when comparing the flash against an image.
+//!
+//! Ranges are given relative to the start of the image as `offset:length`, either with
+//! `--ignore-range` or one per line in a mask file (where `#` starts a comment).
+
+use crate::input::parse_size;
+use anyhow::{Context, Result};
+use clap::Args;
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// The size of the windows hashed by [`Mask::digest`].
+const HASH_WINDOW: usize = 4096;
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct MaskArgs {
+    /// Exclude `offset:length` bytes of the image from comparison (may be repeated)
+    #[arg(long, value_parser = parse_range)]
+    pub ignore_range: Vec<Range<usize>>,
+
+    /// Exclude the ranges listed in this file, one `offset:length` per line
+    #[arg(long)]
+    pub mask: Option<PathBuf>,
+}
+
+impl MaskArgs {
+    pub fn resolve(&self) -> Result<Mask> {
+        let mut ranges = self.ignore_range.clone();
+
+        if let Some(path) = &self.mask {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Error reading mask from {}", path.display()))?;
+
+            for (number, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if !line.is_empty() {
+                    ranges.push(parse_range(line).with_context(|| {
+                        format!(
+                            "Invalid mask range on line {} of {}",
+                            number + 1,
+                            path.display()
+                        )
+                    })?);
+                }
+            }
+        }
+
+        Ok(Mask::new(ranges))
+    }
+}
+
+/// Parse an `offset:length` range.
+pub fn parse_range(text: &str) -> Result<Range<usize>> {
+    let (offset, length) = text
+        .split_once(':')
+        .with_context(|| format!("Expected offset:length, got {text:?}"))?;
+    let offset = parse_size(offset)?;
+    let end = offset
+        .checked_add(parse_size(length)?)
+        .with_context(|| format!("The range {text:?} runs past the end of the address space"))?;
+
+    Ok(offset..end)
+}
+
+/// A set of masked ranges, sorted and merged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Mask {
+    ranges: Vec<Range<usize>>,
+}
+
+impl Mask {
+    pub const EMPTY: Mask = Mask { ranges: Vec::new() };
+
+    pub fn new(mut ranges: Vec<Range<usize>>) -> Self {
+        ranges.retain(|range| !range.is_empty());
+        ranges.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    /// The ranges masked by either `self` or `other`.
+    pub fn union(&self, other: &Mask) -> Mask {
+        Mask::new(self.ranges.iter().chain(&other.ranges).cloned().collect())
+    }
+
+    pub fn contains(&self, offset: usize) -> bool {
+        self.ranges.iter().any(|range| range.contains(&offset))
+    }
+
+    /// The number of masked bytes within `offset..offset + length`.
+    pub fn overlap(&self, offset: usize, length: usize) -> usize {
+        let end = offset + length;
+        self.ranges
+            .iter()
+            .map(|range| range.end.min(end).saturating_sub(range.start.max(offset)))
+            .sum()
+    }
+
+    /// Whether every byte of `offset..offset + length` is masked.
+    pub fn covers(&self, offset: usize, length: usize) -> bool {
+        self.overlap(offset, length) == length
+    }
+
+    /// Zero the masked bytes of `bytes`, which starts at `offset` within the image.
+    pub fn clear(&self, offset: usize, bytes: &mut [u8]) {
+        let end = offset + bytes.len();
+        for range in &self.ranges {
+            let start = range.start.max(offset);
+            let stop = range.end.min(end);
+            if start < stop {
+                bytes[start - offset..stop - offset].fill(0);
+            }
+        }
+    }
+
+    /// Hash `length` bytes with the masked bytes zeroed, reading each window with `read`.
+    ///
+    /// Windows that are entirely masked are skipped without being read, so hashing the flash
+    /// doesn't spend time on regions that don't matter.
+    pub fn digest(
+        &self,
+        length: usize,
+        mut read: impl FnMut(usize, usize) -> Result<Vec<u8>>,
+    ) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+
+        for (offset, window) in crate::flash::windows(length, HASH_WINDOW) {
+            if self.covers(offset, window) {
+                continue;
+            }
+
+            let mut bytes = read(offset, window)?;
+            self.clear(offset, &mut bytes);
+            hasher.update(&bytes);
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// The first offset at which `expected` and `actual` differ outside the mask, where both
+    /// start at `offset` within the image.
+    pub fn mismatch(&self, offset: usize, expected: &[u8], actual: &[u8]) -> Option<usize> {
+        expected
+            .iter()
+            .zip(actual)
+            .enumerate()
+            .map(|(i, pair)| (offset + i, pair))
+            .find(|(at, (expected, actual))| expected != actual && !self.contains(*at))
+            .map(|(at, _)| at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("0x100:0x20").unwrap(), 0x100..0x120);
+        assert!(parse_range("0x100").is_err());
+    }
+
+    #[test]
+    fn rejects_ranges_that_overflow() {
+        let text = format!("{}:2", usize::MAX - 1);
+        assert!(parse_range(&text).is_err());
+    }
+}
@@ -0,0 +1,666 @@
+//! A simulated flash and FPGA, selected with `--backend mock`, for trying commands without a
+//! Pi.
+//!
+//! The flash answers the same SPI commands as a Winbond W25Q part, held in memory and
+//! optionally backed by a file so its contents persist between runs. Programs and erases keep
+//! it busy for a few status polls, during which it ignores every other command, as the real
+//! part does, so a missing wait shows up as a failure rather than passing. Its status registers hold what's written to them,
+//! but aren't saved, and don't protect anything, except that the lock bits keep the security
+//! registers, which aren't saved either, from changing. Its SFDP table lists the erase commands it
+//! implements, which can be limited to 4 KiB sector erases. The simulated FPGA raises CDONE when it's sent
+//! anything containing a valid bitstream preamble.
+
+use crate::bitstream;
+use crate::chip::Erase;
+use crate::flash::{Port, ReadWidth};
+use crate::sfdp;
+use crate::sram;
+use crate::status;
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+/// Which hardware the commands drive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// The Pi's GPIO and SPI peripherals
+    #[default]
+    Pi,
+    /// A simulated flash and FPGA
+    #[value(alias = "sim")]
+    Mock,
+    /// The GPIO character device and spidev, on boards other than a Pi
+    Linux,
+    /// An FT232H or FT2232H USB adapter
+    Ftdi,
+    /// A CH341A USB programmer clipped onto the flash
+    Ch341a,
+}
+
+impl Backend {
+    /// The name given to `--backend`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Pi => "pi",
+            Self::Mock => "mock",
+            Self::Linux => "linux",
+            Self::Ftdi => "ftdi",
+            Self::Ch341a => "ch341a",
+        }
+    }
+}
+
+/// Settings for the simulated flash.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The file holding the flash contents, or `None` to start blank every run.
+    pub image: Option<PathBuf>,
+    /// The capacity of a flash not loaded from an existing file.
+    pub size: usize,
+    /// Accept programs and erases without carrying them out.
+    pub ignore_writes: bool,
+    /// Implement only 4 KiB sector erases, ignoring 32 and 64 KiB block erases.
+    pub sector_erase_only: bool,
+}
+
+/// Winbond, as the manufacturer of the simulated part.
+const MANUFACTURER: u8 = 0xEF;
+const MEMORY_TYPE: u8 = 0x40;
+const UNIQUE_ID: [u8; 8] = *b"MOCKID01";
+/// The status register's write enable latch.
+const WEL: u8 = 0x02;
+/// The status register's busy bit.
+const WIP: u8 = 0x01;
+
+pub struct MockFlash {
+    memory: Vec<u8>,
+    path: Option<PathBuf>,
+    /// The bytes written since CS was asserted.
+    command: Vec<u8>,
+    /// The bytes read since CS was asserted.
+    read: usize,
+    write_enabled: bool,
+    ignore_writes: bool,
+    sector_erase_only: bool,
+    /// The SFDP header and basic flash parameter table.
+    sfdp: Vec<u8>,
+    /// Status registers 1 to 3, without the busy and write enable bits.
+    registers: [u8; 3],
+    /// The three 256-byte security registers.
+    security: [[u8; 256]; 3],
+    modified: bool,
+    /// In deep power-down, ignoring everything but the wake (0xAB).
+    powered_down: bool,
+    /// The status reads left before the program or erase in progress completes.
+    busy: usize,
+}
+
+/// The status reads a page program keeps the flash busy for.
+const PROGRAM_POLLS: usize = 1;
+/// The status reads a sector or block erase keeps the flash busy for.
+const ERASE_POLLS: usize = 3;
+/// The status reads a chip erase keeps the flash busy for.
+const CHIP_ERASE_POLLS: usize = 8;
+
+impl MockFlash {
+    /// Open the flash in `settings`, loading its file when it exists.
+    pub fn open(settings: &Settings) -> Result<Self> {
+        let memory = match &settings.image {
+            Some(path) if path.exists() => {
+                let memory = std::fs::read(path)
+                    .with_context(|| format!("Error reading {}", path.display()))?;
+                if !memory.len().is_power_of_two() || memory.len() < 65536 {
+                    anyhow::bail!(
+                        "{} holds {} bytes, but a simulated flash must be a power of two of at \
+                         least 64 KiB",
+                        path.display(),
+                        memory.len()
+                    );
+                }
+                memory
+            }
+            _ => {
+                if !settings.size.is_power_of_two() || settings.size < 65536 {
+                    anyhow::bail!(
+                        "The simulated flash size must be a power of two of at least 64 KiB"
+                    );
+                }
+                vec![0xFF; settings.size]
+            }
+        };
+
+        let sfdp = sfdp::encode(
+            if settings.sector_erase_only {
+                &[Erase::SECTOR]
+            } else {
+                &Erase::STANDARD
+            },
+            memory.len(),
+        );
+
+        Ok(Self {
+            memory,
+            path: settings.image.clone(),
+            command: Vec::new(),
+            read: 0,
+            write_enabled: false,
+            ignore_writes: settings.ignore_writes,
+            sector_erase_only: settings.sector_erase_only,
+            sfdp,
+            registers: [0; 3],
+            security: [[0xFF; 256]; 3],
+            busy: 0,
+            modified: settings.image.as_deref().is_some_and(|p| !p.exists()),
+            powered_down: false,
+        })
+    }
+
+    #[cfg(test)]
+    /// A flash holding `memory`, which isn't saved anywhere.
+    pub(crate) fn with_memory(memory: Vec<u8>) -> Self {
+        let settings = Settings {
+            image: None,
+            size: memory.len(),
+            ignore_writes: false,
+            sector_erase_only: false,
+        };
+        let mut flash = Self::open(&settings).expect("the simulated flash size is valid");
+        flash.memory = memory;
+        flash
+    }
+
+    /// The length of the command's opcode and address, with 4-byte addresses for the opcodes
+    /// taking them.
+    fn header(&self) -> usize {
+        match self.command.first() {
+            Some(0x13 | 0x0C | 0x3C | 0x6C | 0x12 | 0x21 | 0x5C | 0xDC) => 5,
+            _ => 4,
+        }
+    }
+
+    fn address(&self) -> usize {
+        self.command[1..self.header()]
+            .iter()
+            .fold(0, |address, byte| address << 8 | *byte as usize)
+            % self.memory.len()
+    }
+
+    /// The index of the security register the command's address is in, when it's unlocked or
+    /// `locked_too`.
+    fn security_register(&self, locked_too: bool) -> Option<usize> {
+        let register = (self.address() >> 12) & 0x03;
+        let unlocked = self.registers[1] & 1 << (register + 2) == 0;
+        (register > 0 && (unlocked || locked_too)).then(|| register - 1)
+    }
+
+    /// The response to the current command, for the `index`th byte read.
+    fn respond(&self, index: usize) -> u8 {
+        let jedec = [
+            MANUFACTURER,
+            MEMORY_TYPE,
+            self.memory.len().trailing_zeros() as u8,
+        ];
+
+        match (self.command.first(), self.command.len()) {
+            (Some(0x05), 1) if self.busy > 0 => self.registers[0] | WEL | WIP,
+            // Everything else is ignored until the flash is ready
+            _ if self.busy > 0 => 0xFF,
+            (Some(0xAB), 4) => jedec[2] - 1,
+            _ if self.powered_down => 0xFF,
+            (Some(0x9F), 1) => jedec.get(index).copied().unwrap_or(0xFF),
+            (Some(0x05), 1) => {
+                if self.write_enabled {
+                    self.registers[0] | WEL
+                } else {
+                    self.registers[0]
+                }
+            }
+            (Some(0x35), 1) => self.registers[1],
+            (Some(0x15), 1) => self.registers[2],
+            (Some(0x03), 4)
+            | (Some(0x0B | 0x3B | 0x6B | 0x13), 5)
+            | (Some(0x0C | 0x3C | 0x6C), 6) => {
+                self.memory[(self.address() + index) % self.memory.len()]
+            }
+            (Some(0x5A), 5) => self
+                .sfdp
+                .get(self.address() + index)
+                .copied()
+                .unwrap_or(0xFF),
+            (Some(0x4B), 5) => UNIQUE_ID.get(index).copied().unwrap_or(0xFF),
+            (Some(0x48), 5) => match self.security_register(true) {
+                Some(register) => self.security[register][(self.address() + index) & 0xFF],
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    /// Carry out a program or erase once CS is released, as the real part does.
+    fn execute(&mut self) {
+        let Some(&opcode) = self.command.first() else {
+            return;
+        };
+        if self.busy > 0 {
+            return;
+        }
+
+        match opcode {
+            0xAB => self.powered_down = false,
+            _ if self.powered_down => {}
+            0xB9 => self.powered_down = true,
+            0x06 | 0x50 => self.write_enabled = true,
+            0x04 | 0x66 | 0x99 => self.write_enabled = false,
+            0x02 | 0x12 | 0x20 | 0x21 | 0x52 | 0x5C | 0xD8 | 0xDC | 0x60 | 0xC7
+                if self.ignore_writes =>
+            {
+                self.write_enabled = false
+            }
+            0x52 | 0x5C | 0xD8 | 0xDC if self.sector_erase_only => self.write_enabled = false,
+            0x02 | 0x12 if self.write_enabled && self.command.len() > self.header() => {
+                let address = self.address();
+                let page = address & !0xFF;
+                for (i, byte) in self.command[self.header()..].iter().enumerate() {
+                    // Programming wraps within the page and can only clear bits
+                    self.memory[page + (address + i) % 256] &= byte;
+                }
+                self.write_enabled = false;
+                self.modified = true;
+                self.busy = PROGRAM_POLLS;
+            }
+            0x20 | 0x21 | 0x52 | 0x5C | 0xD8 | 0xDC
+                if self.write_enabled && self.command.len() >= self.header() =>
+            {
+                let size = match opcode {
+                    0x20 | 0x21 => 4096,
+                    0x52 | 0x5C => 32768,
+                    _ => 65536,
+                };
+                let start = self.address() & !(size - 1);
+                self.memory[start..start + size].fill(0xFF);
+                self.write_enabled = false;
+                self.modified = true;
+                self.busy = ERASE_POLLS;
+            }
+            0x01 | 0x31 | 0x11 if self.write_enabled && self.command.len() > 1 => {
+                let first = match opcode {
+                    0x01 => 0,
+                    0x31 => 1,
+                    _ => 2,
+                };
+                for (register, value) in self.registers[first..].iter_mut().zip(&self.command[1..])
+                {
+                    *register = *value;
+                }
+                self.registers[0] &= !(WEL | 0x01);
+                self.write_enabled = false;
+            }
+            0x42 if self.write_enabled && self.command.len() > 4 => {
+                let address = self.address();
+                if let Some(register) = self.security_register(false) {
+                    for (i, byte) in self.command[4..].iter().enumerate() {
+                        self.security[register][(address + i) & 0xFF] &= byte;
+                    }
+                }
+                self.write_enabled = false;
+                self.busy = PROGRAM_POLLS;
+            }
+            0x44 if self.write_enabled && self.command.len() >= 4 => {
+                if let Some(register) = self.security_register(false) {
+                    self.security[register].fill(0xFF);
+                }
+                self.write_enabled = false;
+                self.busy = ERASE_POLLS;
+            }
+            0x60 | 0xC7 if self.write_enabled => {
+                self.memory.fill(0xFF);
+                self.write_enabled = false;
+                self.modified = true;
+                self.busy = CHIP_ERASE_POLLS;
+            }
+            _ => {}
+        }
+    }
+
+    fn save(&mut self) -> Result<()> {
+        if let Some(path) = self.path.as_ref().filter(|_| self.modified) {
+            std::fs::write(path, &self.memory)
+                .with_context(|| format!("Error writing {}", path.display()))?;
+        }
+        self.modified = false;
+
+        Ok(())
+    }
+}
+
+impl Port for MockFlash {
+    fn select(&mut self) -> Result<()> {
+        self.command.clear();
+        self.read = 0;
+        Ok(())
+    }
+
+    fn deselect(&mut self) -> Result<()> {
+        if self.read == 0 {
+            self.execute();
+        }
+        self.command.clear();
+        Ok(())
+    }
+
+    fn write(&mut self, byte: u8) -> Result<()> {
+        self.command.push(byte);
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        let value = self.respond(self.read);
+        self.read += 1;
+        if self.command == [0x05] {
+            self.busy = self.busy.saturating_sub(1);
+        }
+        Ok(value)
+    }
+
+    fn read_width(&self) -> ReadWidth {
+        ReadWidth::Quad
+    }
+
+    fn read_wide(&mut self, _width: ReadWidth, buffer: &mut [u8]) -> Result<()> {
+        self.read_into(buffer)
+    }
+}
+
+impl Drop for MockFlash {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            crate::warning!("Failed to save the simulated flash: {e:#}");
+        }
+    }
+}
+
+/// A simulated FPGA in slave SPI configuration mode.
+#[derive(Debug, Default)]
+pub struct MockFpga {
+    in_reset: bool,
+    selected: bool,
+    /// The bytes sent since the FPGA was last selected.
+    received: Vec<u8>,
+    configured: bool,
+}
+
+impl sram::Port for MockFpga {
+    fn reset(&mut self, asserted: bool) -> Result<()> {
+        self.in_reset = asserted;
+        if asserted {
+            self.configured = false;
+        }
+        Ok(())
+    }
+
+    // The FPGA checks what it was sent once it's deselected after the stream
+    fn select(&mut self, asserted: bool) -> Result<()> {
+        let sent = std::mem::take(&mut self.received);
+        self.selected = asserted;
+        if !asserted && !sent.is_empty() {
+            configure(&sent)?;
+            self.configured = true;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.selected && !self.in_reset {
+            self.received.extend_from_slice(data);
+        }
+        Ok(data.len())
+    }
+
+    fn check_reset(&self, _asserted: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn cdone(&mut self, _pin: u8) -> Result<bool> {
+        Ok(self.configured)
+    }
+}
+
+/// Configure the simulated FPGA with `data`, which succeeds when it holds a bitstream.
+fn configure(data: &[u8]) -> Result<()> {
+    if !bitstream::is_bitstream(data) {
+        anyhow::bail!(
+            "The simulated FPGA found no bitstream preamble in the {} bytes sent, so CDONE stayed \
+             low",
+            data.len()
+        );
+    }
+
+    status!("The simulated FPGA configured and raised CDONE");
+    Ok(())
+}
+
+/// The name of the flash's backing, for messages.
+pub fn describe(settings: &Settings) -> String {
+    match &settings.image {
+        Some(path) => format!("the simulated flash in {}", path.display()),
+        None => "an in-memory simulated flash".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sram::Port as _;
+    use clap::ValueEnum;
+
+    fn settings(image: Option<PathBuf>) -> Settings {
+        Settings {
+            image,
+            size: 65536,
+            ignore_writes: false,
+            sector_erase_only: false,
+        }
+    }
+
+    /// Send `write` in one chip select, returning `read` bytes clocked in after it.
+    fn transact(flash: &mut MockFlash, write: &[u8], read: usize) -> Vec<u8> {
+        flash.select().unwrap();
+        for byte in write {
+            flash.write(*byte).unwrap();
+        }
+        let response = (0..read).map(|_| flash.read().unwrap()).collect();
+        flash.deselect().unwrap();
+        response
+    }
+
+    /// Poll the status register until the flash is ready, returning the number of busy polls.
+    fn await_ready(flash: &mut MockFlash) -> usize {
+        (0..)
+            .find(|_| transact(flash, &[0x05], 1)[0] & WIP == 0)
+            .unwrap()
+    }
+
+    fn program(flash: &mut MockFlash, address: usize, data: &[u8]) {
+        transact(flash, &[0x06], 0);
+        let mut command = vec![
+            0x02,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+        ];
+        command.extend(data);
+        transact(flash, &command, 0);
+        await_ready(flash);
+    }
+
+    fn erase(flash: &mut MockFlash, opcode: u8, address: usize) -> usize {
+        transact(flash, &[0x06], 0);
+        let command = [
+            opcode,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+        ];
+        transact(flash, &command, 0);
+        await_ready(flash)
+    }
+
+    fn read(flash: &mut MockFlash, address: usize, length: usize) -> Vec<u8> {
+        let command = [
+            0x03,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+        ];
+        transact(flash, &command, length)
+    }
+
+    #[test]
+    fn identifies_as_a_winbond_part() {
+        let mut flash = MockFlash::with_memory(vec![0xFF; 1 << 20]);
+        assert_eq!(transact(&mut flash, &[0x9F], 3), [0xEF, 0x40, 20]);
+        assert_eq!(transact(&mut flash, &[0x4B, 0, 0, 0, 0], 8), UNIQUE_ID);
+    }
+
+    #[test]
+    fn programs_only_clear_bits_and_wrap_within_the_page() {
+        let mut flash = MockFlash::with_memory(vec![0xFF; 65536]);
+        program(&mut flash, 0x1FE, &[0x0F, 0xF0, 0x55, 0xAA]);
+        assert_eq!(read(&mut flash, 0x1FE, 2), [0x0F, 0xF0]);
+        assert_eq!(read(&mut flash, 0x100, 2), [0x55, 0xAA]);
+
+        program(&mut flash, 0x1FE, &[0xF0]);
+        assert_eq!(read(&mut flash, 0x1FE, 1), [0x00]);
+    }
+
+    #[test]
+    fn writes_need_write_enable() {
+        let mut flash = MockFlash::with_memory(vec![0xFF; 65536]);
+        transact(&mut flash, &[0x02, 0, 0, 0, 0x00], 0);
+        assert_eq!(read(&mut flash, 0, 1), [0xFF]);
+        assert_eq!(transact(&mut flash, &[0x05], 1), [0x00]);
+    }
+
+    #[test]
+    fn erases_cover_their_aligned_range() {
+        for (opcode, size) in [(0x20, 4096), (0x52, 32768), (0xD8, 65536)] {
+            let mut flash = MockFlash::with_memory(vec![0x00; 1 << 18]);
+            assert_eq!(
+                erase(&mut flash, opcode, 0x10000 + size / 2 + 1),
+                ERASE_POLLS
+            );
+
+            let memory = read(&mut flash, 0, 1 << 18);
+            let erased: Vec<_> = (0..memory.len()).filter(|i| memory[*i] == 0xFF).collect();
+            assert_eq!(erased.first(), Some(&0x10000), "{opcode:#04x}");
+            assert_eq!(erased.len(), size, "{opcode:#04x}");
+        }
+    }
+
+    #[test]
+    fn busy_flash_ignores_commands() {
+        let mut flash = MockFlash::with_memory(vec![0x00; 65536]);
+        transact(&mut flash, &[0x06], 0);
+        transact(&mut flash, &[0x20, 0, 0, 0], 0);
+
+        // Neither the read nor a second erase is seen until the erase finishes
+        assert_eq!(read(&mut flash, 0x1000, 1), [0xFF]);
+        transact(&mut flash, &[0x06], 0);
+        transact(&mut flash, &[0x20, 0, 0x10, 0], 0);
+        assert_eq!(await_ready(&mut flash), ERASE_POLLS);
+        assert_eq!(read(&mut flash, 0x1000, 1), [0x00]);
+    }
+
+    #[test]
+    fn limited_flash_ignores_block_erases() {
+        let mut flash = MockFlash::open(&Settings {
+            sector_erase_only: true,
+            ..settings(None)
+        })
+        .unwrap();
+        program(&mut flash, 0x8000, &[0x00]);
+        assert_eq!(erase(&mut flash, 0xD8, 0), 0);
+        assert_eq!(read(&mut flash, 0x8000, 1), [0x00]);
+        assert_eq!(erase(&mut flash, 0x20, 0x8000), ERASE_POLLS);
+        assert_eq!(read(&mut flash, 0x8000, 1), [0xFF]);
+    }
+
+    #[test]
+    fn ignored_writes_change_nothing() {
+        let mut flash = MockFlash::open(&Settings {
+            ignore_writes: true,
+            ..settings(None)
+        })
+        .unwrap();
+        program(&mut flash, 0, &[0x00]);
+        assert_eq!(read(&mut flash, 0, 1), [0xFF]);
+    }
+
+    #[test]
+    fn file_backed_flash_persists() {
+        let path =
+            std::env::temp_dir().join(format!("lattice-prog-mock-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut flash = MockFlash::open(&settings(Some(path.clone()))).unwrap();
+        program(&mut flash, 0x100, b"persist");
+        drop(flash);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 65536);
+
+        let mut flash = MockFlash::open(&settings(Some(path.clone()))).unwrap();
+        assert_eq!(read(&mut flash, 0x100, 7), b"persist");
+        drop(flash);
+
+        std::fs::write(&path, [0xFF; 1000]).unwrap();
+        assert!(MockFlash::open(&settings(Some(path.clone()))).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn sizes_must_be_a_power_of_two() {
+        for size in [0, 32768, 65537, 3 << 16] {
+            assert!(MockFlash::open(&Settings {
+                size,
+                ..settings(None)
+            })
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn fpga_configures_from_a_bitstream() {
+        let mut fpga = MockFpga::default();
+        for (data, configured) in [
+            (&[0xFF, 0x00, 0x7E, 0xAA, 0x99, 0x7E, 0x01][..], true),
+            (&[0x00; 16][..], false),
+        ] {
+            fpga.reset(true).unwrap();
+            fpga.select(true).unwrap();
+            fpga.reset(false).unwrap();
+            fpga.write(data).unwrap();
+            assert_eq!(fpga.select(false).is_ok(), configured);
+            assert_eq!(fpga.cdone(0).unwrap(), configured);
+        }
+    }
+
+    #[test]
+    fn fpga_ignores_data_sent_in_reset() {
+        let mut fpga = MockFpga::default();
+        fpga.reset(true).unwrap();
+        fpga.select(true).unwrap();
+        fpga.write(&[0x7E, 0xAA, 0x99, 0x7E]).unwrap();
+        fpga.select(false).unwrap();
+        assert!(!fpga.cdone(0).unwrap());
+    }
+
+    #[test]
+    fn sim_names_the_mock_backend() {
+        assert_eq!(Backend::from_str("sim", false), Ok(Backend::Mock));
+        for backend in Backend::value_variants() {
+            assert_eq!(
+                Backend::from_str(backend.name(), false).as_ref(),
+                Ok(backend)
+            );
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! The flash's one-time programmable security registers, for data provisioned during
+//! manufacturing, such as board serial numbers and calibration, that has to survive every
+//! reflash and chip erase.
+//!
+//! Winbond and GigaDevice parts have three 256-byte registers, read with 0x48, programmed with
+//! 0x42, and erased with 0x44, at addresses whose bits 12-13 pick the register. Setting a
+//! register's lock bit, LB1-LB3 in status register 2, makes it read-only for good.
+
+use crate::flash::FlashProgrammer;
+use anyhow::{Context, Result};
+
+pub const REGISTERS: u8 = 3;
+pub const REGISTER_SIZE: usize = 256;
+
+const READ_STATUS_2: u8 = 0x35;
+#[cfg(not(feature = "read-only"))]
+const WRITE_STATUS_2: u8 = 0x31;
+
+/// The manufacturers whose parts lay out their security registers as above.
+const SUPPORTED: [(u8, &str); 2] = [(0xEF, "Winbond"), (0xC8, "GigaDevice")];
+
+/// Parse a security register number, from 1 to 3.
+pub fn parse_register(text: &str) -> Result<u8> {
+    let register: u8 = text
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid security register {text:?}"))?;
+    if !(1..=REGISTERS).contains(&register) {
+        anyhow::bail!("Security registers are numbered 1 to {REGISTERS}");
+    }
+
+    Ok(register)
+}
+
+fn address(register: u8) -> usize {
+    (register as usize) << 12
+}
+
+fn lock_bit(register: u8) -> u8 {
+    1 << (register + 2)
+}
+
+fn check(programmer: &mut FlashProgrammer) -> Result<()> {
+    let jedec = programmer.info()?.jedec;
+    if !SUPPORTED.iter().any(|(id, _)| *id == jedec[0]) {
+        let names: Vec<_> = SUPPORTED.iter().map(|(_, name)| *name).collect();
+        anyhow::bail!(
+            "Security registers are only supported on {} flash, not JEDEC ID {}",
+            names.join(" and "),
+            crate::backup::hex(&jedec)
+        );
+    }
+
+    Ok(())
+}
+
+/// Whether `register`'s lock bit is set.
+pub fn locked(programmer: &mut FlashProgrammer, register: u8) -> Result<bool> {
+    Ok(programmer.read_register(READ_STATUS_2)? & lock_bit(register) != 0)
+}
+
+/// Read the whole of `register`.
+pub fn read(programmer: &mut FlashProgrammer, register: u8) -> Result<Vec<u8>> {
+    check(programmer)?;
+    Ok(programmer.read_security(address(register), REGISTER_SIZE)?)
+}
+
+#[cfg(not(feature = "read-only"))]
+fn check_unlocked(programmer: &mut FlashProgrammer, register: u8) -> Result<()> {
+    check(programmer)?;
+    if locked(programmer, register)? {
+        anyhow::bail!("Security register {register} is locked, so it can't be changed");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Program `data` into `register` at `offset`, where it must only clear bits, and confirm by
+/// reading back.
+pub fn program(
+    programmer: &mut FlashProgrammer,
+    register: u8,
+    offset: usize,
+    data: &[u8],
+) -> Result<()> {
+    check_unlocked(programmer, register)?;
+    if offset + data.len() > REGISTER_SIZE {
+        anyhow::bail!(
+            "{:#x} bytes at {offset:#x} extend beyond the {REGISTER_SIZE}-byte security register",
+            data.len()
+        );
+    }
+
+    let current = read(programmer, register)?;
+    let current = &current[offset..offset + data.len()];
+    if let Some(i) = (0..data.len()).find(|i| current[*i] & data[*i] != data[*i]) {
+        anyhow::bail!(
+            "Byte {:#x} of security register {register} reads {:#04x}, and programming can only \
+             clear bits to reach {:#04x}; erase the register first",
+            offset + i,
+            current[i],
+            data[i]
+        );
+    }
+
+    programmer.await_ready()?;
+    programmer.program_security(address(register) + offset, data)?;
+    programmer.await_ready()?;
+
+    let written = read(programmer, register)?;
+    if written[offset..offset + data.len()] != *data {
+        anyhow::bail!("Security register {register} doesn't read back what was programmed");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Erase `register` back to 0xff, and confirm by reading back.
+pub fn erase(programmer: &mut FlashProgrammer, register: u8) -> Result<()> {
+    check_unlocked(programmer, register)?;
+
+    programmer.await_ready()?;
+    programmer.erase_security(address(register))?;
+    programmer.await_ready()?;
+
+    if let Some(offset) = read(programmer, register)?.iter().position(|b| *b != 0xFF) {
+        anyhow::bail!("Byte {offset:#x} of security register {register} isn't blank after erasing");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Set `register`'s lock bit, and confirm by reading back.
+pub fn lock(programmer: &mut FlashProgrammer, register: u8) -> Result<()> {
+    check(programmer)?;
+    let status = programmer.read_register(READ_STATUS_2)?;
+    if status & lock_bit(register) != 0 {
+        return Ok(());
+    }
+
+    programmer.write_register(WRITE_STATUS_2, &[status | lock_bit(register)])?;
+    programmer.await_ready()?;
+
+    if !locked(programmer, register)? {
+        anyhow::bail!(
+            "Status register 2 reads {:#04x} after setting LB{register}; the flash may not take \
+             writes to it with {WRITE_STATUS_2:#04x}",
+            programmer.read_register(READ_STATUS_2)?
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+//! A database of SPI flash parts found on iCE40 boards, looked up by JEDEC ID.
+//!
+//! A known part's entry gives its capacity, page size, and erase commands, so none of them
+//! has to be guessed from the ID. Parts that aren't listed fall back to the capacity encoded in
+//! the ID, 256-byte pages, and the erases from their SFDP table.
+
+use crate::chip::Erase;
+use crate::flash::FlashProgrammer;
+use crate::plan;
+use anyhow::Result;
+use std::fmt::Write;
+
+/// One flash part.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Part {
+    pub jedec: [u8; 3],
+    pub name: &'static str,
+    pub capacity: usize,
+    /// The most bytes one page program writes.
+    pub page_size: usize,
+    /// The erase commands the part implements, assumed when it has no SFDP table.
+    pub erases: &'static [Erase],
+}
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+
+/// Parts whose only erase is the 64 KiB block erase.
+const BLOCK_ONLY: &[Erase] = &[Erase::BLOCK];
+
+const fn part(jedec: [u8; 3], name: &'static str, capacity: usize) -> Part {
+    Part {
+        jedec,
+        name,
+        capacity,
+        page_size: plan::PAGE_SIZE,
+        erases: &Erase::STANDARD,
+    }
+}
+
+const PARTS: &[Part] = &[
+    part([0xEF, 0x40, 0x14], "Winbond W25Q80", MIB),
+    part([0xEF, 0x40, 0x15], "Winbond W25Q16", 2 * MIB),
+    part([0xEF, 0x40, 0x16], "Winbond W25Q32", 4 * MIB),
+    part([0xEF, 0x40, 0x17], "Winbond W25Q64", 8 * MIB),
+    part([0xEF, 0x40, 0x18], "Winbond W25Q128", 16 * MIB),
+    part([0xEF, 0x40, 0x19], "Winbond W25Q256", 32 * MIB),
+    part([0xEF, 0x40, 0x20], "Winbond W25Q512", 64 * MIB),
+    part([0xEF, 0x70, 0x15], "Winbond W25Q16JV-IM", 2 * MIB),
+    part([0xEF, 0x70, 0x16], "Winbond W25Q32JV-IM", 4 * MIB),
+    part([0xC2, 0x20, 0x15], "Macronix MX25L1606E", 2 * MIB),
+    part([0xC2, 0x20, 0x16], "Macronix MX25L3233F", 4 * MIB),
+    part([0xC2, 0x20, 0x19], "Macronix MX25L25645G", 32 * MIB),
+    part([0xC2, 0x28, 0x15], "Macronix MX25R1635F", 2 * MIB),
+    part([0xC2, 0x28, 0x16], "Macronix MX25R3235F", 4 * MIB),
+    part([0xC8, 0x40, 0x15], "GigaDevice GD25Q16", 2 * MIB),
+    part([0xC8, 0x40, 0x16], "GigaDevice GD25Q32", 4 * MIB),
+    part([0xC8, 0x40, 0x19], "GigaDevice GD25Q256", 32 * MIB),
+    part([0x9D, 0x60, 0x15], "ISSI IS25LP016", 2 * MIB),
+    part([0x9D, 0x60, 0x16], "ISSI IS25LP032", 4 * MIB),
+    part([0x9D, 0x60, 0x19], "ISSI IS25LP256", 32 * MIB),
+    part([0x20, 0xBA, 0x16], "Micron N25Q032", 4 * MIB),
+    part([0x20, 0xBA, 0x18], "Micron N25Q128", 16 * MIB),
+    part([0x20, 0xBA, 0x19], "Micron N25Q256", 32 * MIB),
+    Part {
+        erases: BLOCK_ONLY,
+        ..part([0x20, 0x20, 0x14], "Micron M25P80", MIB)
+    },
+    Part {
+        erases: BLOCK_ONLY,
+        ..part([0x20, 0x20, 0x15], "Micron M25P16", 2 * MIB)
+    },
+    // Adesto's IDs don't encode the capacity
+    part([0x1F, 0x84, 0x01], "Adesto AT25SF041", 512 * KIB),
+    part([0x1F, 0x85, 0x01], "Adesto AT25SF081", MIB),
+    part([0x1F, 0x86, 0x01], "Adesto AT25SF161", 2 * MIB),
+];
+
+/// JEDEC manufacturer IDs, for naming parts that aren't in the database.
+const MANUFACTURERS: &[(u8, &str)] = &[
+    (0x01, "Cypress"),
+    (0x0B, "XTX"),
+    (0x1F, "Adesto"),
+    (0x20, "Micron"),
+    (0x68, "Boya"),
+    (0x85, "Puya"),
+    (0x9D, "ISSI"),
+    (0xBF, "Microchip"),
+    (0xC2, "Macronix"),
+    (0xC8, "GigaDevice"),
+    (0xEF, "Winbond"),
+];
+
+impl Part {
+    /// The part with `jedec`, or `None` when it isn't in the database.
+    pub fn lookup(jedec: [u8; 3]) -> Option<&'static Self> {
+        PARTS.iter().find(|part| part.jedec == jedec)
+    }
+
+    /// The largest erase the part implements within a 64 KiB block, the one images are
+    /// erased with.
+    pub fn block_erase(&self) -> Option<Erase> {
+        Erase::largest_within(self.erases, plan::BLOCK_SIZE)
+    }
+}
+
+/// The manufacturer's name for the first byte of a JEDEC ID.
+pub fn manufacturer(id: u8) -> Option<&'static str> {
+    MANUFACTURERS
+        .iter()
+        .find(|(known, _)| *known == id)
+        .map(|(_, name)| *name)
+}
+
+/// Identify the flash and describe the geometry it'll be programmed with.
+pub fn report(programmer: &mut FlashProgrammer) -> Result<String> {
+    let info = programmer.info()?;
+    if info.unresponsive() {
+        anyhow::bail!(
+            "The flash reads JEDEC ID {:02x?}, so it isn't responding; check the wiring",
+            info.jedec
+        );
+    }
+
+    let mut output = format!(
+        "JEDEC ID:      {:02x} {:02x} {:02x}",
+        info.jedec[0], info.jedec[1], info.jedec[2]
+    );
+    write!(
+        output,
+        "\nManufacturer:  {}",
+        manufacturer(info.jedec[0]).unwrap_or("unknown")
+    )?;
+    let part = info.part();
+    write!(
+        output,
+        "\nPart:          {}",
+        part.map_or("not in the database", |part| part.name)
+    )?;
+
+    match info.capacity() {
+        Some(capacity) => write!(
+            output,
+            "\nCapacity:      {} KiB ({capacity:#x} bytes){}",
+            capacity / 1024,
+            if part.is_some() { "" } else { ", from the ID" }
+        )?,
+        None => output.push_str("\nCapacity:      unknown"),
+    }
+    write!(
+        output,
+        "\nPage size:     {} bytes{}",
+        programmer.page_size()?,
+        if part.is_some() { "" } else { ", assumed" }
+    )?;
+    write!(output, "\nBlock erase:   {}", programmer.block_erase()?)?;
+
+    Ok(output)
+}
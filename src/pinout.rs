@@ -0,0 +1,321 @@
+//! The wiring diagram for the active pin configuration, for building or checking a harness.
+//!
+//! Every GPIO either programmer claims is listed with its physical pin on the 40-pin header.
+//! The two programmers never run at once, so a GPIO is only a conflict when one programmer
+//! claims it for two roles, the same check made before acquiring the hardware.
+
+use crate::board::Board;
+use crate::pins::{header_pin, Claims, PinConfig};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// An aligned table
+    #[default]
+    Ascii,
+    /// One row per signal, with a header row
+    Csv,
+    /// A JSON object
+    Json,
+}
+
+/// A signal on one GPIO.
+struct Row {
+    signal: String,
+    gpio: u8,
+    header: Option<u8>,
+    used_by: Vec<&'static str>,
+}
+
+fn rows(pins: &PinConfig, has_header: bool) -> Vec<Row> {
+    let mut rows: Vec<Row> = Vec::new();
+    for (programmer, claims) in [("flash", Claims::flash(pins)), ("sram", Claims::sram(pins))] {
+        for (signal, gpio) in claims.roles() {
+            match rows
+                .iter_mut()
+                .find(|row| row.signal == *signal && row.gpio == *gpio)
+            {
+                Some(row) => row.used_by.push(programmer),
+                None => rows.push(Row {
+                    signal: signal.clone(),
+                    gpio: *gpio,
+                    header: header_pin(*gpio).filter(|_| has_header),
+                    used_by: vec![programmer],
+                }),
+            }
+        }
+    }
+
+    rows
+}
+
+/// A GPIO claimed for several roles by one programmer.
+type Conflict = (&'static str, u8, Vec<String>);
+
+impl Row {
+    fn conflicts(&self, conflicts: &[Conflict]) -> bool {
+        conflicts
+            .iter()
+            .any(|(programmer, gpio, _)| *gpio == self.gpio && self.used_by.contains(programmer))
+    }
+}
+
+/// Every GPIO claimed twice by one programmer.
+fn conflicts(pins: &PinConfig) -> Vec<Conflict> {
+    let mut conflicts = Vec::new();
+    for (programmer, claims) in [("flash", Claims::flash(pins)), ("sram", Claims::sram(pins))] {
+        for (gpio, roles) in claims.conflicts() {
+            let roles = roles.into_iter().map(String::from).collect();
+            conflicts.push((programmer, gpio, roles));
+        }
+    }
+
+    conflicts
+}
+
+/// Render the wiring diagram for `pins` on `board`, or an unknown model, in `format`.
+pub fn render(pins: &PinConfig, board: Option<Board>, format: Format) -> String {
+    let has_header = !board.as_ref().is_some_and(Board::compute_module);
+    let model = board.map(|board| board.model);
+    let rows = rows(pins, has_header);
+    let conflicts = conflicts(pins);
+
+    match format {
+        Format::Ascii => ascii(model.as_deref(), has_header, &rows, &conflicts),
+        Format::Csv => {
+            let mut output = String::from("signal,gpio,header_pin,used_by,conflict\n");
+            for row in &rows {
+                let _ = writeln!(
+                    output,
+                    "{},{},{},{},{}",
+                    row.signal,
+                    row.gpio,
+                    row.header.map(|pin| pin.to_string()).unwrap_or_default(),
+                    row.used_by.join(" "),
+                    row.conflicts(&conflicts)
+                );
+            }
+            output.trim_end().to_string()
+        }
+        Format::Json => {
+            let rows: Vec<_> = rows
+                .iter()
+                .map(|row| {
+                    format!(
+                        r#"{{"signal":{:?},"gpio":{},"header_pin":{},"used_by":[{}]}}"#,
+                        row.signal,
+                        row.gpio,
+                        row.header
+                            .map(|pin| pin.to_string())
+                            .unwrap_or_else(|| "null".into()),
+                        quoted(row.used_by.iter()),
+                    )
+                })
+                .collect();
+            let conflicts: Vec<_> = conflicts
+                .iter()
+                .map(|(programmer, gpio, roles)| {
+                    format!(
+                        r#"{{"programmer":{programmer:?},"gpio":{gpio},"roles":[{}]}}"#,
+                        quoted(roles.iter())
+                    )
+                })
+                .collect();
+
+            format!(
+                r#"{{"model":{},"header":{has_header},"pins":[{}],"conflicts":[{}]}}"#,
+                model
+                    .map(|model| format!("{model:?}"))
+                    .unwrap_or_else(|| "null".into()),
+                rows.join(","),
+                conflicts.join(",")
+            )
+        }
+    }
+}
+
+fn quoted<T: std::fmt::Debug>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| format!("{item:?}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn ascii(model: Option<&str>, has_header: bool, rows: &[Row], conflicts: &[Conflict]) -> String {
+    let mut output = format!(
+        "{}\n",
+        model.unwrap_or("Unknown model, assuming a 40-pin header")
+    );
+    if !has_header {
+        output.push_str("No 40-pin header; trace each GPIO on the carrier board\n");
+    }
+
+    let width = rows.iter().map(|row| row.signal.len()).max().unwrap_or(0);
+    let _ = writeln!(output, "\n{:width$}  GPIO  Pin  Used by", "Signal");
+    for row in rows {
+        let pin = match row.header {
+            Some(pin) => pin.to_string(),
+            None => "-".into(),
+        };
+        let _ = write!(
+            output,
+            "{:width$}  {:>4}  {pin:>3}  {}",
+            row.signal,
+            row.gpio,
+            row.used_by.join(", ")
+        );
+        if has_header && row.header.is_none() {
+            output.push_str("  (not on the header)");
+        }
+        if row.conflicts(conflicts) {
+            output.push_str("  (CONFLICT)");
+        }
+        output.push('\n');
+    }
+
+    if !conflicts.is_empty() {
+        output.push_str("\nConflicts:\n");
+        for (programmer, gpio, roles) in conflicts {
+            let _ = writeln!(
+                output,
+                "  GPIO {gpio:>2} ({programmer}): {}",
+                roles.join(", ")
+            );
+        }
+    }
+
+    output.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pins::SpiBus;
+
+    fn board(model: &str) -> Option<Board> {
+        Some(Board::from_model(model).unwrap())
+    }
+
+    #[test]
+    fn default_wiring_on_a_pi_4() {
+        let output = render(
+            &PinConfig::default(),
+            board("Raspberry Pi 4 Model B Rev 1.4"),
+            Format::Ascii,
+        );
+        assert_eq!(
+            output,
+            "Raspberry Pi 4 Model B Rev 1.4
+
+Signal      GPIO  Pin  Used by
+FPGA reset     6   31  flash, sram
+FPGA CS       13   33  flash, sram
+flash CS       5   29  flash, sram
+flash SDI      9   21  flash
+flash SCK     11   23  flash
+flash SDO     10   19  flash
+SPI0 CE1       7   26  sram
+SPI0 CE0       8   24  sram
+SPI0 MISO      9   21  sram
+SPI0 MOSI     10   19  sram
+SPI0 SCLK     11   23  sram"
+        );
+    }
+
+    #[test]
+    fn spi1_wiring_as_csv() {
+        let pins = PinConfig {
+            spi_bus: SpiBus::Spi1,
+            ..PinConfig::default()
+        };
+        let output = render(&pins, board("Raspberry Pi 3 Model B Rev 1.2"), Format::Csv);
+        assert_eq!(
+            output,
+            "signal,gpio,header_pin,used_by,conflict
+FPGA reset,6,31,flash sram,false
+FPGA CS,13,33,flash sram,false
+flash CS,5,29,flash sram,false
+flash SDI,9,21,flash,false
+flash SCK,11,23,flash,false
+flash SDO,10,19,flash,false
+SPI1 CE2,16,36,sram,false
+SPI1 CE1,17,11,sram,false
+SPI1 CE0,18,12,sram,false
+SPI1 MISO,19,35,sram,false
+SPI1 MOSI,20,38,sram,false
+SPI1 SCLK,21,40,sram,false"
+        );
+    }
+
+    #[test]
+    fn conflicts_are_flagged_for_their_programmer_only() {
+        let pins = PinConfig {
+            spi_bus: SpiBus::Spi1,
+            flash_cs: 18,
+            ..PinConfig::default()
+        };
+        let output = render(&pins, None, Format::Ascii);
+        assert!(output.starts_with("Unknown model, assuming a 40-pin header\n"));
+        assert!(output.contains("flash CS      18   12  flash, sram  (CONFLICT)\n"));
+        assert!(output.contains("SPI1 CE0      18   12  sram  (CONFLICT)\n"));
+        assert!(output.ends_with("Conflicts:\n  GPIO 18 (sram): SPI1 CE0, flash CS"));
+        // The flash programmer doesn't use SPI1, so its own pins are fine
+        assert!(!output.contains("(flash)"));
+
+        let json = render(&pins, None, Format::Json);
+        assert!(json.starts_with(r#"{"model":null,"header":true,"#));
+        assert!(json.ends_with(
+            r#""conflicts":[{"programmer":"sram","gpio":18,"roles":["SPI1 CE0","flash CS"]}]}"#
+        ));
+    }
+
+    #[test]
+    fn pins_off_the_header_are_flagged() {
+        let pins = PinConfig {
+            reset: 30,
+            ..PinConfig::default()
+        };
+        let output = render(
+            &pins,
+            board("Raspberry Pi 4 Model B Rev 1.4"),
+            Format::Ascii,
+        );
+        assert!(
+            output.contains("FPGA reset    30    -  flash, sram  (not on the header)\n"),
+            "{output}"
+        );
+        let json = render(&pins, None, Format::Json);
+        assert!(json.contains(
+            r#"{"signal":"FPGA reset","gpio":30,"header_pin":null,"used_by":["flash","sram"]}"#
+        ));
+    }
+
+    #[test]
+    fn compute_modules_have_no_header() {
+        let model = "Raspberry Pi Compute Module 4 Rev 1.0";
+        let output = render(&PinConfig::default(), board(model), Format::Ascii);
+        assert!(output.starts_with(&format!(
+            "{model}\nNo 40-pin header; trace each GPIO on the carrier board\n"
+        )));
+        assert!(
+            output.contains("FPGA reset     6    -  flash, sram\n"),
+            "{output}"
+        );
+        assert!(!output.contains("not on the header"));
+
+        let json = render(&PinConfig::default(), board(model), Format::Json);
+        assert!(json.starts_with(&format!(r#"{{"model":"{model}","header":false,"#)));
+        assert!(!json.contains(r#""header_pin":3"#) && json.contains(r#""header_pin":null"#));
+
+        let csv = render(&PinConfig::default(), board(model), Format::Csv);
+        assert!(csv.contains("\nFPGA reset,6,,flash sram,false\n"), "{csv}");
+    }
+
+    #[test]
+    fn pi_5_shares_the_header_mapping() {
+        let pins = PinConfig::default();
+        let pi_4 = render(&pins, board("Raspberry Pi 4 Model B Rev 1.4"), Format::Csv);
+        let pi_5 = render(&pins, board("Raspberry Pi 5 Model B Rev 1.0"), Format::Csv);
+        assert_eq!(pi_4, pi_5);
+    }
+}
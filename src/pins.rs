@@ -0,0 +1,780 @@
+use crate::board::Board;
+use anyhow::{Context, Result};
+use clap::{ArgAction, Args, ValueEnum};
+use rppal::gpio::{Gpio, OutputPin, Pin};
+use std::time::Duration;
+
+pub const FPGA_RESET: u8 = 6;
+pub const FPGA_CS: u8 = 13;
+pub const FLASH_CS: u8 = 5;
+pub const FLASH_SDI: u8 = 9;
+pub const FLASH_SCK: u8 = 11;
+pub const FLASH_SDO: u8 = 10;
+
+/// Board-specific pin settings shared by both programmers.
+#[derive(Args, Clone, Debug)]
+pub struct PinConfig {
+    /// Drive CRESET_B high, rather than low, to hold the FPGA in reset
+    ///
+    /// For boards with an inverter between the Pi and the FPGA's reset line.
+    #[arg(long = "reset-active-high", global = true, action = ArgAction::SetFalse)]
+    pub reset_active_low: bool,
+
+    /// Drive the FPGA CS high, rather than low, to select it
+    #[arg(long = "fpga-cs-active-high", global = true, action = ArgAction::SetFalse)]
+    pub fpga_cs_active_low: bool,
+
+    /// Drive the flash CS high, rather than low, to select it
+    #[arg(long = "flash-cs-active-high", global = true, action = ArgAction::SetFalse)]
+    pub flash_cs_active_low: bool,
+
+    /// The GPIO wired to the FPGA's CRESET_B reset line
+    #[arg(long = "reset-gpio", global = true, default_value_t = FPGA_RESET)]
+    pub reset: u8,
+
+    /// The GPIO wired to the FPGA's SPI_SS_B chip select
+    #[arg(long = "fpga-cs-gpio", global = true, default_value_t = FPGA_CS)]
+    pub fpga_cs: u8,
+
+    /// The GPIO wired to the flash's chip select
+    ///
+    /// `diagnose-cs` finds the right value for a board whose chip selects are miswired.
+    #[arg(long = "flash-cs-gpio", global = true, default_value_t = FLASH_CS)]
+    pub flash_cs: u8,
+
+    /// The GPIO bit-banged as the flash programmer's SDI, driving the flash's data input
+    ///
+    /// The SRAM programmer uses its SPI bus's hardware pins, which can't be moved.
+    #[arg(long = "flash-sdi-gpio", global = true, default_value_t = FLASH_SDI)]
+    pub flash_sdi: u8,
+
+    /// The GPIO bit-banged as the flash programmer's SCK
+    #[arg(long = "flash-sck-gpio", global = true, default_value_t = FLASH_SCK)]
+    pub flash_sck: u8,
+
+    /// The GPIO bit-banged as the flash programmer's SDO, reading the flash's data output
+    #[arg(long = "flash-sdo-gpio", global = true, default_value_t = FLASH_SDO)]
+    pub flash_sdo: u8,
+
+    /// The SPI peripheral the SRAM programmer configures the FPGA over
+    ///
+    /// SPI1 must be enabled in the Pi's boot configuration, e.g. with `dtoverlay=spi1-3cs`.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub spi_bus: SpiBus,
+
+    /// How the flash programmer drives the flash's data lines
+    ///
+    /// The `--spi-bus` peripheral is far faster than bit-banging, but needs the flash's SDI
+    /// on its MOSI, SDO on its MISO, and SCK on its SCLK. The default pins have SDI and SDO
+    /// the other way round, as the FPGA sees them, so they're bit-banged.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub flash_bus: FlashBus,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        Self {
+            reset_active_low: true,
+            fpga_cs_active_low: true,
+            flash_cs_active_low: true,
+            reset: FPGA_RESET,
+            fpga_cs: FPGA_CS,
+            flash_cs: FLASH_CS,
+            flash_sdi: FLASH_SDI,
+            flash_sck: FLASH_SCK,
+            flash_sdo: FLASH_SDO,
+            spi_bus: SpiBus::Spi0,
+            flash_bus: FlashBus::Auto,
+        }
+    }
+}
+
+impl PinConfig {
+    /// The flags that reproduce this configuration in another invocation.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (flag, active_low) in [
+            ("--reset-active-high", self.reset_active_low),
+            ("--fpga-cs-active-high", self.fpga_cs_active_low),
+            ("--flash-cs-active-high", self.flash_cs_active_low),
+        ] {
+            if !active_low {
+                args.push(flag.to_string());
+            }
+        }
+        for (flag, gpio) in [
+            ("--reset-gpio", self.reset),
+            ("--fpga-cs-gpio", self.fpga_cs),
+            ("--flash-cs-gpio", self.flash_cs),
+            ("--flash-sdi-gpio", self.flash_sdi),
+            ("--flash-sck-gpio", self.flash_sck),
+            ("--flash-sdo-gpio", self.flash_sdo),
+        ] {
+            args.extend([flag.to_string(), gpio.to_string()]);
+        }
+        for (flag, value) in [
+            ("--spi-bus", self.spi_bus.to_possible_value()),
+            ("--flash-bus", self.flash_bus.to_possible_value()),
+        ] {
+            if let Some(value) = value {
+                args.extend([flag.to_string(), value.get_name().to_string()]);
+            }
+        }
+
+        args
+    }
+
+    /// Whether the flash programmer drives the flash over the SPI peripheral, failing when
+    /// that was required but the flash pins aren't the peripheral's.
+    pub fn flash_hardware_spi(&self) -> Result<bool> {
+        let (mosi, miso, sclk) = self.spi_bus.data_pins();
+        let wired = self.flash_sdi == mosi && self.flash_sdo == miso && self.flash_sck == sclk;
+
+        match self.flash_bus {
+            FlashBus::Bitbang => Ok(false),
+            FlashBus::Auto => Ok(wired),
+            FlashBus::Spi if wired => Ok(true),
+            FlashBus::Spi => anyhow::bail!(
+                "--flash-bus spi needs the flash's SDI, SDO, and SCK on GPIO {mosi}, {miso}, and \
+                 {sclk} (MOSI, MISO, and SCLK), but they're on GPIO {}, {}, and {}",
+                self.flash_sdi,
+                self.flash_sdo,
+                self.flash_sck
+            ),
+        }
+    }
+}
+
+/// How the flash programmer reaches the flash's data lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlashBus {
+    /// The SPI peripheral when the flash pins are its data pins, bit-banging otherwise
+    #[default]
+    Auto,
+    /// The SPI peripheral, failing unless the flash pins are its data pins
+    Spi,
+    /// Bit-banged GPIO, whatever the wiring
+    Bitbang,
+}
+
+/// An output with a configurable active level.
+///
+/// All control line transitions go through `assert` and `release` so no caller needs to
+/// know the board's polarity.
+pub struct ActivePin {
+    pin: OutputPin,
+    active_low: bool,
+}
+
+impl ActivePin {
+    /// Configure `pin` as an output, starting in the given logical state.
+    pub fn new(pin: Pin, active_low: bool, asserted: bool) -> Self {
+        let pin = if driven_high(active_low, asserted) {
+            pin.into_output_high()
+        } else {
+            pin.into_output_low()
+        };
+
+        Self { pin, active_low }
+    }
+
+    pub fn assert(&mut self) {
+        self.drive(true);
+    }
+
+    pub fn release(&mut self) {
+        self.drive(false);
+    }
+
+    fn drive(&mut self, asserted: bool) {
+        if driven_high(self.active_low, asserted) {
+            self.pin.set_high();
+        } else {
+            self.pin.set_low();
+        }
+    }
+
+    /// Keep driving the current level after this pin is dropped, rather than restoring the
+    /// pin's original mode, so the line holds steady until its next owner acquires it.
+    pub fn hold(&mut self) {
+        self.pin.set_reset_on_drop(false);
+    }
+
+    /// Check that the line itself reads back at the level driven for `asserted`, which fails
+    /// when it's shorted or held by something else on the board.
+    pub fn check(&self, name: &str, asserted: bool) -> Result<()> {
+        let driven = driven_high(self.active_low, asserted);
+        let observed = self.pin.is_set_high();
+
+        if observed != driven {
+            anyhow::bail!(
+                "{name} (GPIO {}) reads {} while driven {}; the line may be shorted or \
+                 disconnected",
+                self.pin.pin(),
+                level(observed),
+                level(driven)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a line with the given polarity is driven high for `asserted`.
+pub fn driven_high(active_low: bool, asserted: bool) -> bool {
+    asserted != active_low
+}
+
+/// The name of a logic level.
+pub fn level(high: bool) -> &'static str {
+    if high {
+        "high"
+    } else {
+        "low"
+    }
+}
+
+/// A GPIO driven high for a fixed time after successful configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pulse {
+    pub pin: u8,
+    pub duration: Duration,
+}
+
+impl Pulse {
+    /// Parse a `<gpio>:<millis>` pulse.
+    pub fn parse(text: &str) -> Result<Self> {
+        let (pin, millis) = text
+            .split_once(':')
+            .with_context(|| format!("Expected <gpio>:<millis>, got {text:?}"))?;
+        let pin = pin
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid GPIO {pin:?}"))?;
+        let millis = millis
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid duration {millis:?}"))?;
+
+        Ok(Self {
+            pin,
+            duration: Duration::from_millis(millis),
+        })
+    }
+
+    /// Drive each pin high for its duration in order, releasing it afterwards.
+    pub fn fire(pulses: &[Self]) -> Result<()> {
+        if pulses.is_empty() {
+            return Ok(());
+        }
+
+        let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+
+        for pulse in pulses {
+            let mut pin = gpio
+                .get(pulse.pin)
+                .with_context(|| format!("Failed to acquire GPIO {}", pulse.pin))?
+                .into_output_low();
+            pin.set_high();
+            spin_sleep::sleep(pulse.duration);
+            pin.set_low();
+            drop(pin);
+
+            gpio.get(pulse.pin)?.into_input().set_reset_on_drop(false);
+        }
+
+        Ok(())
+    }
+}
+
+/// A Raspberry Pi SPI peripheral.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpiBus {
+    #[default]
+    Spi0,
+    Spi1,
+}
+
+impl SpiBus {
+    /// The peripheral's MOSI, MISO, and SCLK GPIOs.
+    pub fn data_pins(self) -> (u8, u8, u8) {
+        match self {
+            SpiBus::Spi0 => (10, 9, 11),
+            SpiBus::Spi1 => (20, 19, 21),
+        }
+    }
+
+    /// Every GPIO the peripheral claims while enabled, including its hardware chip selects.
+    pub fn pins(self) -> &'static [(&'static str, u8)] {
+        match self {
+            SpiBus::Spi0 => &[
+                ("SPI0 CE1", 7),
+                ("SPI0 CE0", 8),
+                ("SPI0 MISO", 9),
+                ("SPI0 MOSI", 10),
+                ("SPI0 SCLK", 11),
+            ],
+            SpiBus::Spi1 => &[
+                ("SPI1 CE2", 16),
+                ("SPI1 CE1", 17),
+                ("SPI1 CE0", 18),
+                ("SPI1 MISO", 19),
+                ("SPI1 MOSI", 20),
+                ("SPI1 SCLK", 21),
+            ],
+        }
+    }
+}
+
+/// The GPIOs an invocation will use, each with its role.
+///
+/// Checked before any hardware is acquired, so that two roles never fight over one pin.
+#[derive(Clone, Debug, Default)]
+pub struct Claims {
+    claims: Vec<(String, u8)>,
+}
+
+impl Claims {
+    /// The pins used by the SRAM programmer: its SPI bus and the control lines.
+    pub fn sram(pins: &PinConfig) -> Self {
+        Self::default().spi(pins.spi_bus).control(pins)
+    }
+
+    /// The pins used by the flash programmer: its bus, bit-banged or the SPI peripheral, and
+    /// the control lines.
+    pub fn flash(pins: &PinConfig) -> Self {
+        let claims = Self::default().control(pins);
+        if pins.flash_hardware_spi().unwrap_or(false) {
+            claims.spi(pins.spi_bus)
+        } else {
+            claims.bus(pins)
+        }
+    }
+
+    /// The bit-banged flash bus.
+    pub fn bus(self, pins: &PinConfig) -> Self {
+        self.pin("flash SDI", pins.flash_sdi)
+            .pin("flash SCK", pins.flash_sck)
+            .pin("flash SDO", pins.flash_sdo)
+    }
+
+    fn control(self, pins: &PinConfig) -> Self {
+        self.pin("FPGA reset", pins.reset)
+            .pin("FPGA CS", pins.fpga_cs)
+            .pin("flash CS", pins.flash_cs)
+    }
+
+    pub fn spi(mut self, bus: SpiBus) -> Self {
+        for (role, gpio) in bus.pins() {
+            self = self.pin(*role, *gpio);
+        }
+        self
+    }
+
+    pub fn pin(mut self, role: impl Into<String>, gpio: u8) -> Self {
+        self.claims.push((role.into(), gpio));
+        self
+    }
+
+    /// Every claim, as its role and GPIO, in the order claimed.
+    pub fn roles(&self) -> &[(String, u8)] {
+        &self.claims
+    }
+
+    /// Every GPIO claimed by more than one role, with those roles.
+    pub fn conflicts(&self) -> Vec<(u8, Vec<&str>)> {
+        let mut gpios: Vec<_> = self.claims.iter().map(|(_, gpio)| *gpio).collect();
+        gpios.sort_unstable();
+        gpios.dedup();
+
+        gpios
+            .into_iter()
+            .filter_map(|gpio| {
+                let roles: Vec<_> = self
+                    .claims
+                    .iter()
+                    .filter(|(_, g)| *g == gpio)
+                    .map(|(role, _)| role.as_str())
+                    .collect();
+                (roles.len() > 1).then_some((gpio, roles))
+            })
+            .collect()
+    }
+
+    /// Fail with a table of every GPIO claimed by more than one role, or on a GPIO the Pi
+    /// this runs on doesn't bring out.
+    pub fn check(&self) -> Result<()> {
+        self.check_on(Board::detect().as_ref())
+    }
+
+    /// Like [`check`](Self::check), for `board`, or an unknown one.
+    pub fn check_on(&self, board: Option<&Board>) -> Result<()> {
+        let conflicts: Vec<_> = self
+            .conflicts()
+            .into_iter()
+            .map(|(gpio, roles)| format!("  GPIO {gpio:>2}: {}", roles.join(", ")))
+            .collect();
+
+        if !conflicts.is_empty() {
+            anyhow::bail!("Conflicting pin assignments:\n{}", conflicts.join("\n"));
+        }
+
+        if let Some(board) = board {
+            if let Some(last) = board.controller.last_gpio() {
+                if let Some((role, gpio)) = self.roles().iter().find(|(_, gpio)| *gpio > last) {
+                    anyhow::bail!(
+                        "The {role} pin, GPIO {gpio}, isn't available on the {}, which brings \
+                         out GPIO 0-{last}",
+                        board.model
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The physical pin of each GPIO broken out on the 40-pin header, the same on every Pi with one.
+const HEADER: [(u8, u8); 28] = [
+    (0, 27),
+    (1, 28),
+    (2, 3),
+    (3, 5),
+    (4, 7),
+    (5, 29),
+    (6, 31),
+    (7, 26),
+    (8, 24),
+    (9, 21),
+    (10, 19),
+    (11, 23),
+    (12, 32),
+    (13, 33),
+    (14, 8),
+    (15, 10),
+    (16, 36),
+    (17, 11),
+    (18, 12),
+    (19, 35),
+    (20, 38),
+    (21, 40),
+    (22, 15),
+    (23, 16),
+    (24, 18),
+    (25, 22),
+    (26, 37),
+    (27, 13),
+];
+
+/// The 40-pin header's physical pin for `gpio`, or `None` when it isn't broken out there.
+pub fn header_pin(gpio: u8) -> Option<u8> {
+    HEADER
+        .iter()
+        .find(|(g, _)| *g == gpio)
+        .map(|(_, physical)| *physical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::Controller;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        pins: PinConfig,
+    }
+
+    fn parse(args: &[String]) -> PinConfig {
+        Cli::try_parse_from(std::iter::once("lattice-prog".to_string()).chain(args.iter().cloned()))
+            .unwrap()
+            .pins
+    }
+
+    #[test]
+    fn drives_both_polarities() {
+        assert!(!driven_high(true, true));
+        assert!(driven_high(true, false));
+        assert!(driven_high(false, true));
+        assert!(!driven_high(false, false));
+    }
+
+    #[test]
+    fn defaults_to_active_low() {
+        let pins = parse(&[]);
+        assert!(pins.reset_active_low && pins.fpga_cs_active_low && pins.flash_cs_active_low);
+    }
+
+    #[test]
+    fn args_reproduce_the_configuration() {
+        let pins = PinConfig {
+            reset_active_low: false,
+            flash_cs_active_low: false,
+            reset: 22,
+            flash_cs: 8,
+            ..PinConfig::default()
+        };
+        let parsed = parse(&pins.args());
+        assert!(!parsed.reset_active_low);
+        assert!(parsed.fpga_cs_active_low);
+        assert!(!parsed.flash_cs_active_low);
+        assert_eq!((parsed.reset, parsed.flash_cs), (22, 8));
+        assert_eq!(parsed.args(), pins.args());
+    }
+
+    #[test]
+    fn default_pins_dont_conflict() {
+        let pins = PinConfig::default();
+        assert!(Claims::flash(&pins).conflicts().is_empty());
+        assert!(Claims::sram(&pins).conflicts().is_empty());
+        Claims::sram(&pins).check().unwrap();
+    }
+
+    #[test]
+    fn bit_banged_bus_conflicts_with_spi0() {
+        let pins = PinConfig::default();
+        let claims = Claims::default().spi(SpiBus::Spi0).bus(&pins);
+        assert_eq!(
+            claims.conflicts(),
+            [
+                (9, vec!["SPI0 MISO", "flash SDI"]),
+                (10, vec!["SPI0 MOSI", "flash SDO"]),
+                (11, vec!["SPI0 SCLK", "flash SCK"]),
+            ]
+        );
+        assert!(Claims::default()
+            .spi(SpiBus::Spi1)
+            .bus(&pins)
+            .conflicts()
+            .is_empty());
+    }
+
+    #[test]
+    fn control_pins_conflict_with_the_sram_bus() {
+        let pins = PinConfig {
+            flash_cs: 8,
+            fpga_cs: 18,
+            ..PinConfig::default()
+        };
+        assert_eq!(
+            Claims::sram(&pins).conflicts(),
+            [(8, vec!["SPI0 CE0", "flash CS"])]
+        );
+
+        let pins = PinConfig {
+            spi_bus: SpiBus::Spi1,
+            ..pins
+        };
+        assert_eq!(
+            Claims::sram(&pins).conflicts(),
+            [(18, vec!["SPI1 CE0", "FPGA CS"])]
+        );
+    }
+
+    #[test]
+    fn hardware_flash_bus_claims_the_peripheral() {
+        let pins = PinConfig {
+            flash_sdi: 10,
+            flash_sdo: 9,
+            flash_bus: FlashBus::Spi,
+            ..PinConfig::default()
+        };
+        assert!(pins.flash_hardware_spi().unwrap());
+        let claims = Claims::flash(&pins);
+        assert!(claims.roles().iter().any(|(role, _)| role == "SPI0 CE0"));
+        assert!(claims.conflicts().is_empty());
+
+        let pins = PinConfig {
+            spi_bus: SpiBus::Spi1,
+            ..pins
+        };
+        assert!(pins.flash_hardware_spi().is_err());
+    }
+
+    #[test]
+    fn check_lists_every_conflict() {
+        let pins = PinConfig {
+            reset: 7,
+            flash_cs: 8,
+            ..PinConfig::default()
+        };
+        let error = Claims::sram(&pins).check().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Conflicting pin assignments:\n  GPIO  7: SPI0 CE1, FPGA reset\n  GPIO  8: SPI0 CE0, \
+             flash CS"
+        );
+    }
+
+    #[test]
+    fn header_pins_are_signal_pins() {
+        // The power and ground pins of the 40-pin header
+        let supplies = [1, 2, 4, 6, 9, 14, 17, 20, 25, 30, 34, 39];
+        let mut physical: Vec<_> = HEADER.iter().map(|(_, pin)| *pin).collect();
+        physical.sort_unstable();
+        physical.dedup();
+        assert_eq!(physical.len(), HEADER.len());
+        assert!(physical
+            .iter()
+            .all(|pin| (1..=40).contains(pin) && !supplies.contains(pin)));
+        assert_eq!(HEADER.len() + supplies.len(), 40);
+
+        for (gpio, (listed, _)) in HEADER.iter().enumerate() {
+            assert_eq!(usize::from(*listed), gpio);
+        }
+        assert_eq!(header_pin(2), Some(3));
+        assert_eq!(header_pin(21), Some(40));
+        assert_eq!(header_pin(28), None);
+    }
+
+    #[test]
+    fn spi_peripherals_land_on_their_header_pins() {
+        let physical = |bus: SpiBus| -> Vec<_> {
+            bus.pins()
+                .iter()
+                .map(|(role, gpio)| (*role, header_pin(*gpio)))
+                .collect()
+        };
+        assert_eq!(
+            physical(SpiBus::Spi0),
+            [
+                ("SPI0 CE1", Some(26)),
+                ("SPI0 CE0", Some(24)),
+                ("SPI0 MISO", Some(21)),
+                ("SPI0 MOSI", Some(19)),
+                ("SPI0 SCLK", Some(23)),
+            ]
+        );
+        assert_eq!(
+            physical(SpiBus::Spi1),
+            [
+                ("SPI1 CE2", Some(36)),
+                ("SPI1 CE1", Some(11)),
+                ("SPI1 CE0", Some(12)),
+                ("SPI1 MISO", Some(35)),
+                ("SPI1 MOSI", Some(38)),
+                ("SPI1 SCLK", Some(40)),
+            ]
+        );
+
+        for bus in [SpiBus::Spi0, SpiBus::Spi1] {
+            let (mosi, miso, sclk) = bus.data_pins();
+            let gpio = |suffix: &str| {
+                bus.pins()
+                    .iter()
+                    .find(|(role, _)| role.ends_with(suffix))
+                    .map(|(_, gpio)| *gpio)
+            };
+            assert_eq!(
+                (Some(mosi), Some(miso), Some(sclk)),
+                (gpio("MOSI"), gpio("MISO"), gpio("SCLK"))
+            );
+        }
+    }
+
+    #[test]
+    fn spi1_sram_claims_with_the_default_pins() {
+        let pins = PinConfig {
+            spi_bus: SpiBus::Spi1,
+            ..PinConfig::default()
+        };
+        let claims = Claims::sram(&pins);
+        assert_eq!(claims.roles().len(), 9);
+        assert!(claims.conflicts().is_empty());
+        // The flash programmer keeps bit-banging its own pins
+        assert!(!Claims::flash(&pins)
+            .roles()
+            .iter()
+            .any(|(role, _)| role.starts_with("SPI1")));
+    }
+
+    /// Both programmers' claims for each board and wiring: whatever works on the older Pis
+    /// works unchanged on the Pi 5, except GPIOs past its bank 0.
+    #[test]
+    fn claims_on_every_board() {
+        let boards = [
+            ("Raspberry Pi 3 Model B Rev 1.2", Controller::Bcm),
+            ("Raspberry Pi 4 Model B Rev 1.4", Controller::Bcm),
+            ("Raspberry Pi Compute Module 4 Rev 1.0", Controller::Bcm),
+            ("Raspberry Pi 5 Model B Rev 1.0", Controller::Rp1),
+            ("Raspberry Pi 500 Rev 1.0", Controller::Rp1),
+            ("Raspberry Pi Compute Module 5 Rev 1.0", Controller::Rp1),
+        ];
+        let wirings = [
+            ("default", PinConfig::default(), true),
+            (
+                "SPI1",
+                PinConfig {
+                    spi_bus: SpiBus::Spi1,
+                    ..PinConfig::default()
+                },
+                true,
+            ),
+            (
+                "flash on SPI0",
+                PinConfig {
+                    flash_sdi: 10,
+                    flash_sdo: 9,
+                    flash_bus: FlashBus::Spi,
+                    ..PinConfig::default()
+                },
+                true,
+            ),
+            (
+                "reset past bank 0",
+                PinConfig {
+                    reset: 30,
+                    ..PinConfig::default()
+                },
+                false,
+            ),
+        ];
+
+        for (model, controller) in boards {
+            let board = Board::from_model(model).unwrap();
+            assert_eq!(board.controller, controller, "{model}");
+            for (wiring, pins, everywhere) in &wirings {
+                for claims in [Claims::flash(pins), Claims::sram(pins)] {
+                    let result = claims.check_on(Some(&board));
+                    assert_eq!(
+                        result.is_ok(),
+                        *everywhere || controller == Controller::Bcm,
+                        "{wiring} on {model}: {result:?}"
+                    );
+                }
+            }
+        }
+
+        let pins = PinConfig {
+            reset: 30,
+            ..PinConfig::default()
+        };
+        let board = Board::from_model("Raspberry Pi 5 Model B Rev 1.0");
+        let error = Claims::flash(&pins).check_on(board.as_ref()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The FPGA reset pin, GPIO 30, isn't available on the Raspberry Pi 5 Model B Rev 1.0, \
+             which brings out GPIO 0-27"
+        );
+        // Conflicts are reported before the board's limits
+        let pins = PinConfig {
+            flash_cs: 30,
+            ..pins
+        };
+        let error = Claims::flash(&pins).check_on(board.as_ref()).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Conflicting pin assignments:"));
+        // Off a Pi there's nothing to limit the GPIOs by
+        Claims::sram(&PinConfig {
+            reset: 30,
+            ..PinConfig::default()
+        })
+        .check_on(None)
+        .unwrap();
+    }
+}
@@ -0,0 +1,588 @@
+//! Planning of flash writes.
+//!
+//! A plan compares the image against the current flash contents block by block, deciding which
+//! blocks can be skipped and which need erasing. The blocks are 64 KiB for `plan`, and the
+//! flash's smallest erase for `flash --incremental`. The plan is deterministic for a given image
+//! and flash state, so a reviewed plan can be executed exactly as printed.
+//!
+//! Only the part of a block the image covers is erased, widened to the flash's smallest erase,
+//! with the fewest erases that cover it. A small update near the end of an image then costs a
+//! few 4 KiB sector erases rather than a whole 64 KiB block, and data beyond the image within
+//! the block survives.
+
+use crate::address::FlashAddress;
+#[cfg(not(feature = "read-only"))]
+use crate::cancel::CancellationToken;
+use crate::chip::Erase;
+use crate::flash::FlashProgrammer;
+use crate::report::Progress;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::time::Duration;
+
+pub const BLOCK_SIZE: usize = 65536;
+/// The page size of nearly every SPI flash, assumed for parts that aren't in the database.
+pub const PAGE_SIZE: usize = 256;
+
+/// Rough timings for the duration estimate, based on typical datasheet figures and the
+/// bit-banged transfer rate.
+const SECTOR_ERASE_TIME: Duration = Duration::from_millis(45);
+const HALF_BLOCK_ERASE_TIME: Duration = Duration::from_millis(120);
+const BLOCK_ERASE_TIME: Duration = Duration::from_millis(150);
+const PAGE_PROGRAM_TIME: Duration = Duration::from_micros(700);
+const BYTE_TRANSFER_TIME: Duration = Duration::from_micros(30);
+
+#[cfg(not(feature = "read-only"))]
+/// The `(address, length)` span of each erase block written to by the `(address, length)`
+/// ranges of one session, in order.
+///
+/// Planning the erases for all images together means a block shared by two adjacent images is
+/// erased exactly once, before either is written, rather than the second image's erase wiping
+/// the first image's tail.
+pub fn erase_spans(ranges: &[(FlashAddress, usize)]) -> Result<Vec<(FlashAddress, usize)>> {
+    let mut sorted: Vec<_> = ranges.iter().filter(|(_, length)| *length > 0).collect();
+    sorted.sort();
+
+    let mut spans: Vec<(FlashAddress, usize)> = Vec::new();
+    for (i, (address, length)) in sorted.iter().enumerate() {
+        let end = address.end(*length)?;
+        if let Some((next, _)) = sorted.get(i + 1).filter(|(next, _)| end > next.get()) {
+            anyhow::bail!("The images at {address:#x}..{end:#x} and {next:#x} overlap");
+        }
+
+        let mut start = address.get();
+        while start < end {
+            let block = FlashAddress::new(start)?.align_down(BLOCK_SIZE);
+            let span_end = end.min(block.get() + BLOCK_SIZE);
+
+            match spans.last_mut() {
+                // Extended across the gap to an earlier image in the same block
+                Some((previous, length)) if previous.align_down(BLOCK_SIZE) == block => {
+                    *length = span_end - previous.get();
+                }
+                _ => spans.push((FlashAddress::new(start)?, span_end - start)),
+            }
+            start = span_end;
+        }
+    }
+
+    Ok(spans)
+}
+
+/// The fewest erases from `erases` covering `length` bytes at `address`, each aligned to its
+/// own size.
+///
+/// The range is first widened to the smallest erase the flash implements, then covered from the
+/// start with the largest erase that's aligned there and doesn't run past the end. With sizes
+/// that are powers of two, that's the cover with the fewest erases, and so the quickest.
+pub fn erases(
+    erases: &[Erase],
+    address: FlashAddress,
+    length: usize,
+) -> Result<Vec<(FlashAddress, Erase)>> {
+    let smallest = erases
+        .iter()
+        .filter(|e| e.size.is_power_of_two() && e.size <= BLOCK_SIZE)
+        .map(|e| e.size)
+        .min()
+        .with_context(|| {
+            format!(
+                "The flash implements no erase of {} KiB or less",
+                BLOCK_SIZE / 1024
+            )
+        })?;
+
+    let mut current = address.align_down(smallest).get();
+    let end = address.end(length)?.div_ceil(smallest) * smallest;
+
+    let mut cover = Vec::new();
+    while current < end {
+        let erase = erases
+            .iter()
+            .filter(|e| {
+                e.size.is_power_of_two()
+                    && e.size <= BLOCK_SIZE
+                    && current.is_multiple_of(e.size)
+                    && current + e.size <= end
+            })
+            .max_by_key(|e| e.size)
+            .copied()
+            .expect("the smallest erase always fits");
+        cover.push((FlashAddress::new(current)?, erase));
+        current += erase.size;
+    }
+
+    Ok(cover)
+}
+
+/// A rough time for one erase, by its size.
+fn erase_time(erase: &Erase) -> Duration {
+    if erase.size <= Erase::SECTOR.size {
+        SECTOR_ERASE_TIME
+    } else if erase.size <= Erase::HALF_BLOCK.size {
+        HALF_BLOCK_ERASE_TIME
+    } else {
+        BLOCK_ERASE_TIME
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Split `data`, written at `address`, into `(address, bytes)` pieces that don't cross a
+/// `page_size` page boundary, since a page program wraps around within its page.
+///
+/// Fails up front if `data` would run past the end of the address space.
+pub fn pages(
+    data: &[u8],
+    address: FlashAddress,
+    page_size: usize,
+) -> Result<impl Iterator<Item = (FlashAddress, &[u8])>> {
+    address.end(data.len())?;
+
+    let mut offset = 0;
+    Ok(std::iter::from_fn(move || {
+        if offset == data.len() {
+            return None;
+        }
+
+        let current = address
+            .offset(offset)
+            .expect("the whole image was checked to fit");
+        let length = (page_size - current.get() % page_size).min(data.len() - offset);
+        offset += length;
+        Some((current, &data[offset - length..offset]))
+    }))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockState {
+    /// The covered range is entirely erased.
+    Blank,
+    /// The covered range already holds the image.
+    Matches,
+    /// The covered range holds other data.
+    Differs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Skip,
+    /// Program without erasing, since the range is already blank.
+    Write,
+    EraseWrite,
+}
+
+impl BlockState {
+    pub fn name(self) -> &'static str {
+        match self {
+            BlockState::Blank => "blank",
+            BlockState::Matches => "matches",
+            BlockState::Differs => "differs",
+        }
+    }
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Skip => "skip",
+            Action::Write => "write",
+            Action::EraseWrite => "erase+write",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockPlan {
+    /// The aligned address of the block.
+    pub block: FlashAddress,
+    /// The start of the image data within this block.
+    pub address: FlashAddress,
+    /// The offset of this block's data within the image.
+    pub offset: usize,
+    /// The number of image bytes covered by this block.
+    pub length: usize,
+    pub state: BlockState,
+    pub action: Action,
+    /// The erases issued before writing, for [`Action::EraseWrite`].
+    pub erases: Vec<(FlashAddress, Erase)>,
+}
+
+impl BlockPlan {
+    /// The number of flash bytes the action modifies.
+    ///
+    /// An erase affects everything it covers, which may extend beyond the image's data.
+    pub fn affected(&self) -> usize {
+        match self.action {
+            Action::Skip => 0,
+            Action::Write => self.length,
+            Action::EraseWrite => self.erases.iter().map(|(_, erase)| erase.size).sum(),
+        }
+    }
+
+    /// The erases as a count of each size, such as `2x4K+1x32K`.
+    fn erase_summary(&self) -> String {
+        let mut counts: Vec<(usize, usize)> = Vec::new();
+        for (_, erase) in &self.erases {
+            match counts.iter_mut().find(|(size, _)| *size == erase.size) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((erase.size, 1)),
+            }
+        }
+        counts.sort();
+
+        counts
+            .iter()
+            .map(|(size, count)| format!("{count}x{}K", size / 1024))
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub address: FlashAddress,
+    pub length: usize,
+    pub blocks: Vec<BlockPlan>,
+}
+
+impl Plan {
+    /// Build a plan by reading back every `block_size` block the image covers.
+    pub fn build(
+        programmer: &mut FlashProgrammer,
+        data: &[u8],
+        address: FlashAddress,
+        block_size: usize,
+    ) -> Result<Self> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        address.end(data.len())?;
+
+        let mut bar = Progress::bytes("plan", data.len());
+        programmer.await_ready()?;
+
+        while offset < data.len() {
+            let current = address.offset(offset)?;
+            let block = current.align_down(block_size);
+            let length = (block.get() + block_size - current.get()).min(data.len() - offset);
+
+            let expected = &data[offset..offset + length];
+            let existing = programmer.read_arbitrary(current, length)?;
+            let state = if existing == expected {
+                BlockState::Matches
+            } else if existing.iter().all(|b| *b == 0xFF) {
+                BlockState::Blank
+            } else {
+                BlockState::Differs
+            };
+            let action = match state {
+                BlockState::Matches => Action::Skip,
+                BlockState::Blank => Action::Write,
+                BlockState::Differs => Action::EraseWrite,
+            };
+            let erases = if action == Action::EraseWrite {
+                programmer.plan_erases(current, length)?
+            } else {
+                Vec::new()
+            };
+
+            blocks.push(BlockPlan {
+                block,
+                address: current,
+                offset,
+                length,
+                state,
+                action,
+                erases,
+            });
+
+            offset += length;
+            bar.inc(length);
+        }
+
+        Ok(Self {
+            address,
+            length: data.len(),
+            blocks,
+        })
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    /// Carry out the plan, touching only the blocks it marks for writing. `cancel` is checked
+    /// before each erase and page program.
+    pub fn execute(
+        &self,
+        programmer: &mut FlashProgrammer,
+        data: &[u8],
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        if data.len() != self.length {
+            anyhow::bail!(
+                "The plan covers {} bytes, but the image is {} bytes",
+                self.length,
+                data.len()
+            );
+        }
+
+        let mut bar = Progress::bytes("program", self.written());
+        let mut erases = Progress::events("erase", self.count(Action::EraseWrite));
+
+        let page_size = programmer.page_size()?;
+        let mut checked = false;
+        for block in &self.blocks {
+            if block.action == Action::EraseWrite {
+                programmer.check_cancelled(cancel, block.address.get())?;
+                crate::report::beat("erase", block.block.get());
+                programmer.await_ready()?;
+                programmer.erase_planned(&block.erases)?;
+                if let Some((address, erase)) = block.erases.first().filter(|_| !checked) {
+                    programmer.check_erased(*address, *erase)?;
+                    checked = true;
+                }
+                erases.inc(1);
+            }
+
+            if block.action != Action::Skip {
+                let data = &data[block.offset..block.offset + block.length];
+                for (address, page) in pages(data, block.address, page_size)? {
+                    programmer.check_cancelled(cancel, address.get())?;
+                    crate::report::beat("program", address.get());
+                    programmer.await_ready()?;
+                    programmer.write_page(page, address)?;
+                    bar.inc(page.len());
+                }
+            }
+        }
+
+        programmer.await_ready()?;
+        programmer.report_latency();
+        Ok(())
+    }
+
+    pub fn count(&self, action: Action) -> usize {
+        self.blocks.iter().filter(|b| b.action == action).count()
+    }
+
+    /// The number of image bytes the plan programs.
+    pub fn written(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| b.action != Action::Skip)
+            .map(|b| b.length)
+            .sum()
+    }
+
+    pub fn affected(&self) -> usize {
+        self.blocks.iter().map(BlockPlan::affected).sum()
+    }
+
+    /// A rough estimate of the time to execute the plan and verify the result.
+    pub fn estimated_duration(&self) -> Duration {
+        let written = self.written();
+        let pages = self
+            .blocks
+            .iter()
+            .filter(|b| b.action != Action::Skip)
+            .map(|b| b.length.div_ceil(PAGE_SIZE))
+            .sum::<usize>();
+
+        let erases = self
+            .blocks
+            .iter()
+            .flat_map(|b| &b.erases)
+            .map(|(_, erase)| erase_time(erase))
+            .sum::<Duration>();
+
+        erases
+            + PAGE_PROGRAM_TIME * pages as u32
+            + BYTE_TRANSFER_TIME * (written + self.length) as u32
+    }
+
+    pub fn table(&self) -> String {
+        let mut output = String::new();
+
+        writeln!(
+            output,
+            "{:<10} {:<8} {:<12} {:>8}  erases",
+            "block", "state", "action", "bytes"
+        )
+        .unwrap();
+        for block in &self.blocks {
+            writeln!(
+                output,
+                "{:<#10x} {:<8} {:<12} {:>8}  {}",
+                block.block,
+                block.state.name(),
+                block.action.name(),
+                block.affected(),
+                block.erase_summary()
+            )
+            .unwrap();
+        }
+
+        writeln!(output).unwrap();
+        writeln!(
+            output,
+            "{} blocks: {} skipped, {} written, {} erased and written",
+            self.blocks.len(),
+            self.count(Action::Skip),
+            self.count(Action::Write),
+            self.count(Action::EraseWrite)
+        )
+        .unwrap();
+        writeln!(output, "{} bytes affected", self.affected()).unwrap();
+        write!(
+            output,
+            "Estimated duration: {:.1}s",
+            self.estimated_duration().as_secs_f64()
+        )
+        .unwrap();
+
+        output
+    }
+
+    pub fn json(&self) -> String {
+        let blocks = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let erases = block
+                    .erases
+                    .iter()
+                    .map(|(address, erase)| {
+                        format!(
+                            r#"{{"address":{},"opcode":{},"size":{}}}"#,
+                            address.get(),
+                            erase.opcode,
+                            erase.size
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                format!(
+                    r#"{{"block":{},"address":{},"length":{},"state":"{}","action":"{}","bytes":{},"erases":[{erases}]}}"#,
+                    block.block.get(),
+                    block.address.get(),
+                    block.length,
+                    block.state.name(),
+                    block.action.name(),
+                    block.affected()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            r#"{{"address":{},"length":{},"blocks":[{}],"totals":{{"skip":{},"write":{},"erase_write":{},"bytes":{}}},"estimated_seconds":{:.1}}}"#,
+            self.address.get(),
+            self.length,
+            blocks,
+            self.count(Action::Skip),
+            self.count(Action::Write),
+            self.count(Action::EraseWrite),
+            self.affected(),
+            self.estimated_duration().as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: usize) -> FlashAddress {
+        FlashAddress::new(address).unwrap()
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn images_sharing_a_block_erase_it_once() {
+        // The bitstream ends and the data starts partway through the block at 0x70000
+        let spans =
+            erase_spans(&[(address(0x7F000), 0x2000), (address(0x40000), 0x3F000)]).unwrap();
+        assert_eq!(
+            spans,
+            [
+                (address(0x40000), 0x10000),
+                (address(0x50000), 0x10000),
+                (address(0x60000), 0x10000),
+                (address(0x70000), 0x10000),
+                (address(0x80000), 0x1000),
+            ]
+        );
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn spans_reach_across_gaps_within_a_block() {
+        let spans = erase_spans(&[
+            (address(0x100), 0x100),
+            (address(0x8000), 0x100),
+            (address(0x20000), 0),
+        ])
+        .unwrap();
+        assert_eq!(spans, [(address(0x100), 0x8000)]);
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn overlapping_images_are_refused() {
+        let error = erase_spans(&[(address(0), 0x1001), (address(0x1000), 0x100)]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The images at 0x0..0x1001 and 0x1000 overlap"
+        );
+    }
+
+    #[test]
+    fn erases_use_the_largest_fitting_command() {
+        let cover = erases(&Erase::STANDARD, address(0x7000), 0x1A000).unwrap();
+        assert_eq!(
+            cover,
+            [
+                (address(0x7000), Erase::SECTOR),
+                (address(0x8000), Erase::HALF_BLOCK),
+                (address(0x10000), Erase::BLOCK),
+                (address(0x20000), Erase::SECTOR),
+            ]
+        );
+    }
+
+    #[test]
+    fn sector_only_chips_fall_back_to_sectors() {
+        let cover = erases(&[Erase::SECTOR], address(0x10000), 0x20000).unwrap();
+        assert_eq!(cover.len(), 32);
+        for (i, (start, erase)) in cover.iter().enumerate() {
+            assert_eq!(start.get(), 0x10000 + i * 4096);
+            assert_eq!(*erase, Erase::SECTOR);
+        }
+    }
+
+    #[test]
+    fn erases_cover_partial_sectors_at_both_ends() {
+        let cover = erases(&[Erase::SECTOR], address(0x1800), 0x1000).unwrap();
+        assert_eq!(
+            cover,
+            [
+                (address(0x1000), Erase::SECTOR),
+                (address(0x2000), Erase::SECTOR)
+            ]
+        );
+        // With only larger erases, the smallest one available sets the alignment
+        let cover = erases(&[Erase::HALF_BLOCK, Erase::BLOCK], address(0x9000), 0x100).unwrap();
+        assert_eq!(cover, [(address(0x8000), Erase::HALF_BLOCK)]);
+    }
+
+    #[test]
+    fn chips_without_a_small_erase_are_refused() {
+        let huge = Erase {
+            opcode: 0xDC,
+            size: 1 << 18,
+        };
+        let error = erases(&[huge], address(0), 0x1000).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The flash implements no erase of 64 KiB or less"
+        );
+        assert!(erases(&[], address(0), 0x1000).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! Progress reporting for long operations.
+//!
+//! Progress is drawn as terminal bars by default. With `--progress json`, each update is instead
+//! written to stderr as a single-line JSON event for external tools to render, ending with a
+//! terminal `{"phase":"done","ok":...}` event. When stderr isn't a terminal, or drawing a bar
+//! fails, progress falls back to plain lines.
+//!
+//! The library's reports are drawn the same way through [`Terminal`].
+//!
+//! Output must never abort programming halfway, so nothing here panics when a stream is closed,
+//! and status messages elsewhere go through [`status!`](crate::status) and
+//! [`warning!`](crate::warning) rather than `println!`, which would.
+
+use crate::mask::Mask;
+use crate::report::{Level, Measure, Phase, Reporter};
+use crate::watchdog;
+use std::fmt::Arguments;
+use std::io::{IsTerminal, Write};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// Draw progress bars on the terminal
+    #[default]
+    Bars,
+    /// Write one JSON event per line to stderr
+    Json,
+}
+
+static MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// The minimum time between JSON events for the same bar.
+const EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+pub fn set_mode(mode: ProgressMode) {
+    let _ = MODE.set(mode);
+}
+
+/// Show [`verbose!`](crate::verbose) messages.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn mode() -> ProgressMode {
+    MODE.get().copied().unwrap_or_default()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Unit {
+    Bytes,
+    Count,
+}
+
+enum Inner {
+    Bar(indicatif::ProgressBar),
+    Json {
+        start: Instant,
+        last: Option<Instant>,
+        done: u64,
+    },
+    /// A line at each tenth of the total, for when a bar can't be drawn.
+    Lines {
+        done: u64,
+        reported: u64,
+    },
+    Hidden,
+}
+
+/// Progress through a single phase of an operation.
+pub struct Progress {
+    phase: &'static str,
+    total: u64,
+    unit: Unit,
+    inner: Inner,
+}
+
+impl Progress {
+    fn new(phase: &'static str, total: u64, unit: Unit, visible: bool) -> Self {
+        let inner = match mode() {
+            ProgressMode::Json => Inner::Json {
+                start: Instant::now(),
+                last: None,
+                done: 0,
+            },
+            ProgressMode::Bars if visible && std::io::stderr().is_terminal() => {
+                std::panic::catch_unwind(|| indicatif::ProgressBar::new(total)).map_or(
+                    Inner::Lines {
+                        done: 0,
+                        reported: 0,
+                    },
+                    Inner::Bar,
+                )
+            }
+            ProgressMode::Bars if visible => Inner::Lines {
+                done: 0,
+                reported: 0,
+            },
+            ProgressMode::Bars => Inner::Hidden,
+        };
+
+        Self {
+            phase,
+            total,
+            unit,
+            inner,
+        }
+    }
+
+    /// Track a number of bytes, drawn as a bar.
+    pub fn bytes(phase: &'static str, total: usize) -> Self {
+        Self::new(phase, total as u64, Unit::Bytes, true)
+    }
+
+    /// Track a count of operations, drawn as a bar.
+    pub fn count(phase: &'static str, total: usize) -> Self {
+        Self::new(phase, total as u64, Unit::Count, true)
+    }
+
+    /// Track a count of operations that only appears in JSON events, for phases interleaved
+    /// with another bar.
+    pub fn events(phase: &'static str, total: usize) -> Self {
+        Self::new(phase, total as u64, Unit::Count, false)
+    }
+
+    pub fn inc(&mut self, amount: usize) {
+        let (phase, total, unit) = (self.phase, self.total, self.unit);
+
+        match &mut self.inner {
+            Inner::Bar(bar) => {
+                let bar = bar.clone();
+                if std::panic::catch_unwind(AssertUnwindSafe(|| bar.inc(amount as u64))).is_err() {
+                    self.inner = Inner::Lines {
+                        done: bar.position(),
+                        reported: 0,
+                    };
+                }
+            }
+            Inner::Lines { done, reported } => {
+                *done += amount as u64;
+
+                let tenths = (*done * 10).checked_div(total).unwrap_or(10);
+                if tenths > *reported {
+                    *reported = tenths;
+                    emit(&format!("{phase}: {}% ({done} of {total})", tenths * 10));
+                }
+            }
+            Inner::Json { start, last, done } => {
+                *done += amount as u64;
+
+                let now = Instant::now();
+                if *done < total && last.is_some_and(|last| now - last < EVENT_INTERVAL) {
+                    return;
+                }
+                *last = Some(now);
+
+                match unit {
+                    Unit::Bytes => {
+                        let elapsed = (now - *start).as_secs_f64();
+                        let rate = if elapsed > 0.0 {
+                            (*done as f64 / elapsed) as u64
+                        } else {
+                            0
+                        };
+                        emit(&format!(
+                            r#"{{"phase":"{phase}","bytes":{done},"total":{total},"rate_bps":{rate}}}"#
+                        ));
+                    }
+                    Unit::Count => emit(&format!(
+                        r#"{{"phase":"{phase}","done":{done},"total":{total}}}"#
+                    )),
+                }
+            }
+            Inner::Hidden => {}
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Inner::Bar(bar) = &self.inner {
+            bar.finish();
+        }
+    }
+}
+
+impl Phase for Progress {
+    fn inc(&mut self, amount: usize) {
+        Progress::inc(self, amount);
+    }
+
+    fn finish(&self) {
+        Progress::finish(self);
+    }
+}
+
+/// The library's reporter: messages and progress as the binary draws its own, and beats
+/// passed on to the watchdog.
+pub struct Terminal;
+
+impl Reporter for Terminal {
+    fn message(&self, level: Level, message: Arguments) {
+        match level {
+            Level::Status => status(message),
+            Level::Warning => warning(message),
+            Level::Verbose => verbose(message),
+        }
+    }
+
+    fn progress(&self, phase: &'static str, total: usize, measure: Measure) -> Box<dyn Phase> {
+        Box::new(match measure {
+            Measure::Bytes => Progress::bytes(phase, total),
+            Measure::Count => Progress::count(phase, total),
+            Measure::Events => Progress::events(phase, total),
+        })
+    }
+
+    fn beat(&self, phase: &'static str, address: usize) {
+        watchdog::beat(phase, address);
+    }
+}
+
+fn emit(event: &str) {
+    let mut stderr = std::io::stderr().lock();
+    let _ = writeln!(stderr, "{event}");
+    let _ = stderr.flush();
+}
+
+/// Write a line to stdout, ignoring failures such as a closed pipe.
+pub fn status(args: Arguments) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = stdout.write_fmt(args);
+    let _ = writeln!(stdout);
+}
+
+/// Write a line to stderr, ignoring failures.
+pub fn warning(args: Arguments) {
+    let mut stderr = std::io::stderr().lock();
+    let _ = stderr.write_fmt(args);
+    let _ = writeln!(stderr);
+}
+
+/// Write a line to stderr with `--verbose`, ignoring failures.
+pub fn verbose(args: Arguments) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        warning(args);
+    }
+}
+
+/// Like `println!`, but never panics on a closed stdout.
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::progress::status(format_args!($($arg)*))
+    };
+}
+
+/// Like `eprintln!`, but never panics on a closed stderr.
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::progress::warning(format_args!($($arg)*))
+    };
+}
+
+/// Like [`warning!`](crate::warning), but only shown with `--verbose`.
+#[macro_export]
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::progress::verbose(format_args!($($arg)*))
+    };
+}
+
+/// Report how many bytes of a `length` byte image `mask` excluded, if any.
+pub fn report_mask(mask: &Mask, length: usize) {
+    let skipped = mask.overlap(0, length);
+    if skipped > 0 {
+        status!("Skipped {skipped} masked bytes");
+    }
+}
+
+/// Report the end of the run, which in JSON mode emits the terminal event.
+pub fn done(ok: bool) {
+    if mode() == ProgressMode::Json {
+        emit(&format!(r#"{{"phase":"done","ok":{ok}}}"#));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(total: u64) -> Progress {
+        Progress {
+            phase: "test",
+            total,
+            unit: Unit::Bytes,
+            inner: Inner::Lines {
+                done: 0,
+                reported: 0,
+            },
+        }
+    }
+
+    fn reported(progress: &Progress) -> u64 {
+        match progress.inner {
+            Inner::Lines { reported, .. } => reported,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn lines_report_each_tenth_once() {
+        let mut progress = lines(1000);
+        progress.inc(50);
+        assert_eq!(reported(&progress), 0);
+        progress.inc(60);
+        assert_eq!(reported(&progress), 1);
+        progress.inc(500);
+        assert_eq!(reported(&progress), 6);
+        progress.inc(390);
+        assert_eq!(reported(&progress), 10);
+        progress.finish();
+    }
+
+    #[test]
+    fn empty_total_is_done_at_once() {
+        let mut progress = lines(0);
+        progress.inc(0);
+        assert_eq!(reported(&progress), 10);
+    }
+}
@@ -0,0 +1,768 @@
+//! Block protection through the flash's status registers, for locking down a provisioned image.
+//!
+//! The block protect bits select a range at the top or bottom of the flash, but vendors encode
+//! them differently. Each family decodes its registers into the address range they protect,
+//! and a requested range is encoded by searching the family's settings for one that decodes to
+//! exactly that range.
+
+use crate::chip::ChipProfile;
+use crate::flash::FlashProgrammer;
+use anyhow::{Context, Result};
+use std::fmt::{self, Write};
+use std::ops::Range;
+
+#[cfg(not(feature = "read-only"))]
+const WRITE_STATUS_1: u8 = 0x01;
+const READ_STATUS_1: u8 = 0x05;
+#[cfg(not(feature = "read-only"))]
+const WRITE_STATUS_2: u8 = 0x31;
+const READ_STATUS_2: u8 = 0x35;
+const READ_CONFIG: u8 = 0x15;
+
+/// Status register protect, which locks the status registers while WP# is held low.
+const SRP: u8 = 0x80;
+/// The read-only busy and write enable latch bits.
+const VOLATILE: u8 = 0x03;
+
+/// Winbond's sector, top/bottom, and complement bits.
+const W25Q_SEC: u8 = 0x40;
+const W25Q_TB: u8 = 0x20;
+const W25Q_CMP: u8 = 0x40;
+
+/// Macronix's top/bottom bit, in the configuration register.
+const MX25_TB: u8 = 0x08;
+
+/// A chip's protection settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub status: u8,
+    /// Winbond's status register 2, or Macronix's configuration register.
+    pub extra: u8,
+}
+
+/// A vendor's encoding of the block protect bits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    /// BP2..BP0 select a power-of-two fraction of the chip, or 4 to 32 KiB with SEC, at the
+    /// top or bottom by TB, and CMP in status register 2 inverts the range.
+    ///
+    /// Parts over 16 MiB use a different encoding, with a fourth BP bit.
+    Winbond,
+    /// BP3..BP0 select a power-of-two number of 64 KiB blocks, at the top or bottom by the
+    /// one-time programmable TB bit in the configuration register.
+    Macronix,
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Family::Winbond => "Winbond",
+            Family::Macronix => "Macronix",
+        })
+    }
+}
+
+impl Family {
+    pub fn detect(jedec: [u8; 3]) -> Result<Self> {
+        match ChipProfile::detect(jedec) {
+            ChipProfile::W25q => Ok(Family::Winbond),
+            ChipProfile::Mx25 => Ok(Family::Macronix),
+            ChipProfile::Generic => anyhow::bail!(
+                "Block protection is only supported on Winbond and Macronix flash, not JEDEC ID \
+                 {}",
+                crate::backup::hex(&jedec)
+            ),
+        }
+    }
+
+    fn extra_name(self) -> &'static str {
+        match self {
+            Family::Winbond => "status register 2",
+            Family::Macronix => "configuration register",
+        }
+    }
+
+    /// The range protected by `registers` on a chip of `capacity` bytes.
+    pub fn decode(self, registers: Registers, capacity: usize) -> Range<usize> {
+        match self {
+            Family::Winbond => {
+                let bp = (registers.status >> 2) & 0x07;
+                let length = match (bp, registers.status & W25Q_SEC != 0) {
+                    (0, _) => 0,
+                    (7, _) => capacity,
+                    // A 64th of the chip doubling with each step, but at least a 64 KiB block
+                    (bp, false) => ((capacity / 64).max(65536) << (bp - 1)).min(capacity),
+                    (bp, true) => 4096 << (bp.min(4) - 1),
+                };
+                let range = edge(length, registers.status & W25Q_TB == 0, capacity);
+
+                if registers.extra & W25Q_CMP != 0 {
+                    complement(range, capacity)
+                } else {
+                    range
+                }
+            }
+            Family::Macronix => {
+                let bp = (registers.status >> 2) & 0x0F;
+                let length = match bp {
+                    0 => 0,
+                    bp => (65536usize << (bp - 1)).min(capacity),
+                };
+
+                edge(length, registers.extra & MX25_TB == 0, capacity)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    /// Every setting the protect bits can take, keeping the other bits of `current`, simplest
+    /// first.
+    fn candidates(self, current: Registers) -> Vec<Registers> {
+        match self {
+            Family::Winbond => {
+                let mut candidates = Vec::new();
+                for cmp in [0, W25Q_CMP] {
+                    for sec in [0, W25Q_SEC] {
+                        for tb in [0, W25Q_TB] {
+                            for bp in 0..8 {
+                                candidates.push(Registers {
+                                    status: current.status & !0x7C | sec | tb | bp << 2,
+                                    extra: current.extra & !W25Q_CMP | cmp,
+                                });
+                            }
+                        }
+                    }
+                }
+                candidates
+            }
+            // TB is one-time programmable, so it's never changed here
+            Family::Macronix => (0..16)
+                .map(|bp| Registers {
+                    status: current.status & !0x3C | bp << 2,
+                    extra: current.extra,
+                })
+                .collect(),
+        }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    /// The settings protecting exactly `range`, keeping the other bits of `current`.
+    pub fn encode(
+        self,
+        current: Registers,
+        capacity: usize,
+        range: &Range<usize>,
+    ) -> Result<Registers> {
+        let candidates = self.candidates(current);
+        if let Some(registers) = candidates
+            .iter()
+            .find(|registers| self.decode(**registers, capacity) == *range)
+        {
+            return Ok(*registers);
+        }
+
+        let covering = candidates
+            .iter()
+            .map(|registers| self.decode(*registers, capacity))
+            .filter(|protected| protected.start <= range.start && protected.end >= range.end)
+            .min_by_key(|protected| protected.len());
+        let mut message = format!(
+            "No {self} block protection setting covers exactly {}",
+            describe(range)
+        );
+        if let Some(covering) = covering {
+            let _ = write!(
+                message,
+                "; the smallest covering it is {}",
+                describe(&covering)
+            );
+        }
+        if self == Family::Macronix {
+            let side = if current.extra & MX25_TB == 0 {
+                "top"
+            } else {
+                "bottom"
+            };
+            let _ = write!(
+                message,
+                " (this chip's one-time programmable TB bit protects from the {side})"
+            );
+        }
+
+        anyhow::bail!(message)
+    }
+
+    fn read(self, programmer: &mut FlashProgrammer) -> Result<Registers> {
+        let extra = match self {
+            Family::Winbond => READ_STATUS_2,
+            Family::Macronix => READ_CONFIG,
+        };
+
+        Ok(Registers {
+            status: programmer.read_register(READ_STATUS_1)? & !VOLATILE,
+            extra: programmer.read_register(extra)?,
+        })
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    /// Write `registers` non-volatilely, with SRP last so it can't lock out the other writes.
+    fn write(self, programmer: &mut FlashProgrammer, registers: Registers) -> Result<()> {
+        if self == Family::Winbond {
+            programmer.write_register(WRITE_STATUS_2, &[registers.extra])?;
+            programmer.await_ready()?;
+        }
+        programmer.write_register(WRITE_STATUS_1, &[registers.status])?;
+        Ok(programmer.await_ready()?)
+    }
+}
+
+/// `length` bytes at the top or bottom of the flash.
+fn edge(length: usize, top: bool, capacity: usize) -> Range<usize> {
+    match (length, top) {
+        (0, _) => 0..0,
+        (length, true) => capacity - length..capacity,
+        (length, false) => 0..length,
+    }
+}
+
+/// The rest of the flash outside a range at its top or bottom.
+fn complement(range: Range<usize>, capacity: usize) -> Range<usize> {
+    if range.is_empty() {
+        0..capacity
+    } else if range.start == 0 {
+        edge(capacity - range.end, true, capacity)
+    } else {
+        edge(range.start, false, capacity)
+    }
+}
+
+fn describe(range: &Range<usize>) -> String {
+    if range.is_empty() {
+        return "nothing".into();
+    }
+
+    format!(
+        "{:#08x}..{:#08x} ({} KiB)",
+        range.start,
+        range.end,
+        range.len() / 1024
+    )
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Parse a protected range, given as `<start>:<end>` with an exclusive end.
+pub fn parse_range(text: &str) -> Result<Range<usize>> {
+    let (start, end) = text
+        .split_once(':')
+        .with_context(|| format!("Expected <start>:<end>, got {text:?}"))?;
+    let range = crate::input::parse_size(start)?..crate::input::parse_size(end)?;
+    if range.is_empty() {
+        anyhow::bail!("The range {text:?} is empty");
+    }
+
+    Ok(range)
+}
+
+/// Detect the chip's family and capacity.
+fn detect(programmer: &mut FlashProgrammer) -> Result<(Family, usize)> {
+    let info = programmer.info()?;
+    let capacity = info
+        .capacity()
+        .context("The flash doesn't report its capacity in its JEDEC ID")?;
+
+    let family = Family::detect(info.jedec)?;
+    if family == Family::Winbond && capacity > 1 << 24 {
+        anyhow::bail!("Block protection isn't supported on Winbond parts over 16 MiB");
+    }
+
+    Ok((family, capacity))
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Protect exactly `range`, or the whole flash without one, also setting SRP with `srp`, and
+/// confirm by reading back.
+pub fn lock(
+    programmer: &mut FlashProgrammer,
+    range: Option<&Range<usize>>,
+    srp: bool,
+) -> Result<String> {
+    let (family, capacity) = detect(programmer)?;
+    let range = &range.cloned().unwrap_or(0..capacity);
+    if range.end > capacity {
+        anyhow::bail!(
+            "The range {} extends beyond the end of the {capacity:#x} byte flash",
+            describe(range)
+        );
+    }
+
+    let current = family.read(programmer)?;
+    if current.status & SRP != 0 {
+        crate::warning!("SRP is already set, so the write fails if WP# is held low");
+    }
+
+    let mut target = family.encode(current, capacity, range)?;
+    if srp {
+        target.status |= SRP;
+    }
+    family.write(programmer, target)?;
+
+    let written = family.read(programmer)?;
+    if family.decode(written, capacity) != *range || (written.status & SRP != 0) != srp {
+        anyhow::bail!(
+            "The registers read {written:02x?} after writing {target:02x?}; they may be locked by \
+             SRP with WP# held low"
+        );
+    }
+
+    status(programmer)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Clear the block protect bits and SRP, and confirm by reading back.
+pub fn unlock(programmer: &mut FlashProgrammer) -> Result<String> {
+    let (family, capacity) = detect(programmer)?;
+    let current = family.read(programmer)?;
+    let mut target = family.encode(current, capacity, &(0..0))?;
+    target.status &= !SRP;
+
+    // Skip the non-volatile write when there's nothing to clear
+    if target != current {
+        family.write(programmer, target)?;
+        let written = family.read(programmer)?;
+        if !family.decode(written, capacity).is_empty() || written.status & SRP != 0 {
+            anyhow::bail!(
+                "The registers read {written:02x?} after writing {target:02x?}; they may be \
+                 locked by SRP with WP# held low"
+            );
+        }
+    }
+
+    status(programmer)
+}
+
+/// Decode the range the flash currently protects.
+pub fn status(programmer: &mut FlashProgrammer) -> Result<String> {
+    let (family, capacity) = detect(programmer)?;
+    let registers = family.read(programmer)?;
+    let protected = family.decode(registers, capacity);
+
+    let mut output = format!(
+        "{family} flash, status register {:#04x}, {} {:#04x}",
+        registers.status,
+        family.extra_name(),
+        registers.extra
+    );
+    write!(output, "\n  Protected: {}", describe(&protected))?;
+    if protected == (0..capacity) {
+        output.push_str(", the whole flash");
+    }
+    write!(
+        output,
+        "\n  Status register protect: {}",
+        if registers.status & SRP != 0 {
+            "set, so the registers are locked while WP# is held low"
+        } else {
+            "clear"
+        }
+    )?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1 << 20;
+
+    fn winbond(status: u8, extra: u8, capacity: usize) -> Range<usize> {
+        Family::Winbond.decode(Registers { status, extra }, capacity)
+    }
+
+    fn macronix(status: u8, extra: u8, capacity: usize) -> Range<usize> {
+        Family::Macronix.decode(Registers { status, extra }, capacity)
+    }
+
+    #[test]
+    fn winbond_protects_fractions_from_either_end() {
+        let capacity = 4 * MIB;
+        assert_eq!(winbond(0, 0, capacity), 0..0);
+        assert_eq!(winbond(1 << 2, 0, capacity), 0x3F0000..capacity);
+        assert_eq!(winbond(4 << 2, 0, capacity), 0x380000..capacity);
+        assert_eq!(winbond(6 << 2, 0, capacity), 0x200000..capacity);
+        assert_eq!(winbond(7 << 2, 0, capacity), 0..capacity);
+        assert_eq!(winbond(W25Q_TB | 1 << 2, 0, capacity), 0..0x10000);
+        assert_eq!(winbond(W25Q_TB | 4 << 2, 0, capacity), 0..0x80000);
+        assert_eq!(winbond(W25Q_TB | 7 << 2, 0, capacity), 0..capacity);
+    }
+
+    #[test]
+    fn winbond_fractions_are_at_least_a_block() {
+        // A 64th of a 1 MiB chip is under a block, so the steps start at 64 KiB and the top
+        // ones cover the whole chip
+        let capacity = MIB;
+        assert_eq!(winbond(1 << 2, 0, capacity), 0xF0000..capacity);
+        assert_eq!(winbond(5 << 2, 0, capacity), 0..capacity);
+        assert_eq!(winbond(6 << 2, 0, capacity), 0..capacity);
+
+        let capacity = 16 * MIB;
+        assert_eq!(winbond(W25Q_TB | 1 << 2, 0, capacity), 0..0x40000);
+        assert_eq!(winbond(W25Q_TB | 6 << 2, 0, capacity), 0..0x800000);
+    }
+
+    #[test]
+    fn winbond_sectors_stop_at_32_kib() {
+        let capacity = 4 * MIB;
+        let sec = W25Q_SEC | W25Q_TB;
+        assert_eq!(winbond(sec | 1 << 2, 0, capacity), 0..0x1000);
+        assert_eq!(winbond(sec | 2 << 2, 0, capacity), 0..0x2000);
+        assert_eq!(winbond(sec | 3 << 2, 0, capacity), 0..0x4000);
+        assert_eq!(winbond(sec | 4 << 2, 0, capacity), 0..0x8000);
+        assert_eq!(winbond(sec | 6 << 2, 0, capacity), 0..0x8000);
+        assert_eq!(winbond(W25Q_SEC | 2 << 2, 0, capacity), 0x3FE000..capacity);
+        // SEC doesn't change the whole chip setting
+        assert_eq!(winbond(W25Q_SEC | 7 << 2, 0, capacity), 0..capacity);
+    }
+
+    #[test]
+    fn winbond_complement_inverts_the_range() {
+        let capacity = 4 * MIB;
+        assert_eq!(winbond(0, W25Q_CMP, capacity), 0..capacity);
+        assert_eq!(winbond(7 << 2, W25Q_CMP, capacity), 0..0);
+        assert_eq!(
+            winbond(W25Q_TB | 4 << 2, W25Q_CMP, capacity),
+            0x80000..capacity
+        );
+        assert_eq!(winbond(1 << 2, W25Q_CMP, capacity), 0..0x3F0000);
+        assert_eq!(
+            winbond(W25Q_SEC | W25Q_TB | 1 << 2, W25Q_CMP, capacity),
+            0x1000..capacity
+        );
+    }
+
+    #[test]
+    fn winbond_ignores_the_other_bits() {
+        let capacity = 4 * MIB;
+        let status = W25Q_TB | 3 << 2;
+        assert_eq!(
+            winbond(status | SRP | VOLATILE, 0x02, capacity),
+            winbond(status, 0, capacity)
+        );
+    }
+
+    #[test]
+    fn macronix_protects_blocks_from_the_tb_side() {
+        let capacity = 4 * MIB;
+        assert_eq!(macronix(0, 0, capacity), 0..0);
+        assert_eq!(macronix(1 << 2, 0, capacity), 0x3F0000..capacity);
+        assert_eq!(macronix(3 << 2, 0, capacity), 0x3C0000..capacity);
+        assert_eq!(macronix(7 << 2, 0, capacity), 0..capacity);
+        assert_eq!(macronix(15 << 2, 0, capacity), 0..capacity);
+        assert_eq!(macronix(1 << 2, MX25_TB, capacity), 0..0x10000);
+        assert_eq!(macronix(4 << 2, MX25_TB, capacity), 0..0x80000);
+        // Winbond's SEC and TB positions mean nothing here
+        assert_eq!(macronix(W25Q_SEC | 1 << 2, 0, capacity), 0x3F0000..capacity);
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn every_decoded_range_encodes_back() {
+        for (family, current) in [
+            (
+                Family::Winbond,
+                Registers {
+                    status: 0,
+                    extra: 0,
+                },
+            ),
+            (
+                Family::Macronix,
+                Registers {
+                    status: 0,
+                    extra: 0,
+                },
+            ),
+            (
+                Family::Macronix,
+                Registers {
+                    status: 0,
+                    extra: MX25_TB,
+                },
+            ),
+        ] {
+            for capacity in [MIB, 4 * MIB, 16 * MIB] {
+                for registers in family.candidates(current) {
+                    let range = family.decode(registers, capacity);
+                    let encoded = family.encode(current, capacity, &range).unwrap();
+                    assert_eq!(
+                        family.decode(encoded, capacity),
+                        range,
+                        "{family} {capacity:#x} {registers:02x?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn encoding_prefers_the_simplest_setting() {
+        let capacity = 4 * MIB;
+        let current = Registers {
+            status: 0x7C,
+            extra: W25Q_CMP,
+        };
+        let encode =
+            |range: Range<usize>| Family::Winbond.encode(current, capacity, &range).unwrap();
+
+        assert_eq!(
+            encode(0..0),
+            Registers {
+                status: 0,
+                extra: 0
+            }
+        );
+        assert_eq!(
+            encode(0..capacity),
+            Registers {
+                status: 7 << 2,
+                extra: 0
+            }
+        );
+        assert_eq!(
+            encode(0..0x80000),
+            Registers {
+                status: W25Q_TB | 4 << 2,
+                extra: 0
+            }
+        );
+        assert_eq!(
+            encode(0x3FC000..capacity),
+            Registers {
+                status: W25Q_SEC | 3 << 2,
+                extra: 0
+            }
+        );
+        // Only the complement reaches everything but the top block
+        assert_eq!(
+            encode(0..0x3F0000),
+            Registers {
+                status: 1 << 2,
+                extra: W25Q_CMP
+            }
+        );
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn encoding_keeps_the_other_bits() {
+        let current = Registers {
+            status: SRP | 5 << 2,
+            extra: 0x02,
+        };
+        let encoded = Family::Winbond
+            .encode(current, 4 * MIB, &(0..0x10000))
+            .unwrap();
+        assert_eq!(
+            encoded,
+            Registers {
+                status: SRP | W25Q_TB | 1 << 2,
+                extra: 0x02
+            }
+        );
+
+        let current = Registers {
+            status: SRP | 0x40,
+            extra: MX25_TB | 0x01,
+        };
+        let encoded = Family::Macronix
+            .encode(current, 4 * MIB, &(0..0x20000))
+            .unwrap();
+        assert_eq!(
+            encoded,
+            Registers {
+                status: SRP | 0x40 | 2 << 2,
+                extra: MX25_TB | 0x01
+            }
+        );
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn unencodable_ranges_name_the_smallest_covering_one() {
+        let current = Registers {
+            status: 0,
+            extra: 0,
+        };
+        let error = Family::Winbond
+            .encode(current, 4 * MIB, &(0x1000..0x2000))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "No Winbond block protection setting covers exactly 0x001000..0x002000 (4 KiB); the \
+             smallest covering it is 0x000000..0x002000 (8 KiB)"
+        );
+
+        // A range touching neither edge is only covered by complements reaching an edge
+        let error = Family::Winbond
+            .encode(current, 4 * MIB, &(0x100000..0x300000))
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("(3072 KiB)"), "{error}");
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn macronix_names_its_fixed_side() {
+        let top = Registers {
+            status: 0,
+            extra: 0,
+        };
+        let error = Family::Macronix
+            .encode(top, 4 * MIB, &(0..0x10000))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.ends_with(
+                "the smallest covering it is 0x000000..0x400000 (4096 KiB) (this chip's one-time \
+                 programmable TB bit protects from the top)"
+            ),
+            "{error}"
+        );
+
+        let bottom = Registers {
+            status: 0,
+            extra: MX25_TB,
+        };
+        let error = Family::Macronix
+            .encode(bottom, 4 * MIB, &(0x3F0000..0x400000))
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("protects from the bottom)"), "{error}");
+
+        // A size between the power-of-two steps has no setting on either side
+        let error = Family::Macronix
+            .encode(bottom, 4 * MIB, &(0..0x30000))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("the smallest covering it is 0x000000..0x040000 (256 KiB)"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn edges_and_complements() {
+        let capacity = 0x1000;
+        assert_eq!(edge(0, true, capacity), 0..0);
+        assert_eq!(edge(0, false, capacity), 0..0);
+        assert_eq!(edge(0x100, true, capacity), 0xF00..0x1000);
+        assert_eq!(edge(0x100, false, capacity), 0..0x100);
+        assert_eq!(edge(capacity, true, capacity), 0..capacity);
+
+        assert_eq!(complement(0..0, capacity), 0..capacity);
+        assert_eq!(complement(0..0x100, capacity), 0x100..capacity);
+        assert_eq!(complement(0xF00..capacity, capacity), 0..0xF00);
+        assert_eq!(complement(0..capacity, capacity), 0..0);
+    }
+
+    #[test]
+    fn describes_ranges() {
+        assert_eq!(describe(&(0..0)), "nothing");
+        assert_eq!(describe(&(0..0x80000)), "0x000000..0x080000 (512 KiB)");
+        assert_eq!(
+            describe(&(0x3FF000..0x400000)),
+            "0x3ff000..0x400000 (4 KiB)"
+        );
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("0x0:0x80000").unwrap(), 0..0x80000);
+        assert_eq!(parse_range("4096:8192").unwrap(), 0x1000..0x2000);
+        assert!(parse_range("0x80000").is_err());
+        assert!(parse_range("0x1000:0x1000").is_err());
+        assert!(parse_range("0x2000:0x1000").is_err());
+        assert!(parse_range("0x0:end").is_err());
+    }
+
+    #[test]
+    fn families_by_manufacturer() {
+        assert_eq!(Family::detect([0xEF, 0x40, 0x16]).unwrap(), Family::Winbond);
+        assert_eq!(
+            Family::detect([0xC2, 0x20, 0x16]).unwrap(),
+            Family::Macronix
+        );
+        let error = Family::detect([0x20, 0xBA, 0x16]).unwrap_err();
+        assert!(
+            error.to_string().ends_with("not JEDEC ID 20ba16"),
+            "{error}"
+        );
+    }
+
+    mod simulated {
+        use super::*;
+        use crate::mock::{MockFlash, Settings};
+        use crate::timing::Timing;
+
+        fn programmer() -> FlashProgrammer {
+            let flash = MockFlash::open(&Settings {
+                image: None,
+                size: 4 * MIB,
+                ignore_writes: false,
+                sector_erase_only: false,
+            })
+            .unwrap();
+            FlashProgrammer::with_port(Box::new(flash), &Timing::default(), None).unwrap()
+        }
+
+        #[test]
+        fn reports_an_unprotected_flash() {
+            let status = status(&mut programmer()).unwrap();
+            assert!(
+                status.starts_with("Winbond flash, status register 0x00"),
+                "{status}"
+            );
+            assert!(status.contains("Protected: nothing"), "{status}");
+            assert!(
+                status.ends_with("Status register protect: clear"),
+                "{status}"
+            );
+        }
+
+        #[cfg(not(feature = "read-only"))]
+        #[test]
+        fn locks_and_unlocks() {
+            let mut programmer = programmer();
+            let status = lock(&mut programmer, Some(&(0..0x80000)), true).unwrap();
+            assert!(
+                status.contains("Protected: 0x000000..0x080000 (512 KiB)"),
+                "{status}"
+            );
+            assert!(status.contains("Status register protect: set"), "{status}");
+
+            let registers = Family::Winbond.read(&mut programmer).unwrap();
+            assert_eq!(registers.status, SRP | W25Q_TB | 4 << 2);
+
+            let status = unlock(&mut programmer).unwrap();
+            assert!(status.contains("Protected: nothing"), "{status}");
+            assert!(status.ends_with("clear"), "{status}");
+        }
+
+        #[cfg(not(feature = "read-only"))]
+        #[test]
+        fn locks_the_whole_flash_by_default() {
+            let status = lock(&mut programmer(), None, false).unwrap();
+            assert!(status.contains(", the whole flash"), "{status}");
+        }
+
+        #[cfg(not(feature = "read-only"))]
+        #[test]
+        fn refuses_ranges_past_the_end() {
+            let error = lock(&mut programmer(), Some(&(0..8 * MIB)), false).unwrap_err();
+            assert!(error.to_string().contains("beyond the end"), "{error}");
+        }
+    }
+}
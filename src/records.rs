@@ -0,0 +1,219 @@
+//! Images given as Intel HEX or Motorola S-records rather than raw bytes, as toolchains emit
+//! combined bitstream and firmware images.
+//!
+//! Every record carries its own address, so a file can leave gaps between the regions it fills.
+//! It's parsed into segments of contiguous data, each at its offset from the start of wherever
+//! the image is written.
+
+use anyhow::{Context, Result};
+
+/// A text format of addressed records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ihex,
+    Srec,
+}
+
+/// A run of contiguous bytes at an offset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: usize,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    pub fn end(&self) -> usize {
+        self.offset + self.data.len()
+    }
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ihex => "Intel HEX",
+            Self::Srec => "S-records",
+        }
+    }
+
+    /// Parse `text` into its segments, in order of offset.
+    pub fn parse(self, text: &str) -> Result<Vec<Segment>> {
+        match self {
+            Self::Ihex => ihex(text),
+            Self::Srec => srec(text),
+        }
+    }
+}
+
+/// Decode the hex digits of a record after its start code.
+fn decode(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("It has an odd number of hex digits");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| "It isn't hex")
+}
+
+fn sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Decode an Intel HEX record after its start code into its type, address, and data, checking
+/// its length and checksum.
+fn ihex_record(hex: &str) -> Result<(u8, usize, Vec<u8>)> {
+    let bytes = decode(hex)?;
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        anyhow::bail!("Its length doesn't match its byte count");
+    }
+    if sum(&bytes) != 0 {
+        anyhow::bail!("Its checksum is wrong");
+    }
+
+    let address = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+    Ok((bytes[3], address, bytes[4..bytes.len() - 1].to_vec()))
+}
+
+/// Parse Intel HEX, with the extended segment and linear address records offsetting the data
+/// records after them.
+fn ihex(text: &str) -> Result<Vec<Segment>> {
+    let mut chunks = Vec::new();
+    let mut base = 0;
+    let mut ended = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if ended {
+            anyhow::bail!("Line {number} follows the end of file record");
+        }
+        let hex = line
+            .strip_prefix(':')
+            .with_context(|| format!("Line {number} doesn't start with ':'"))?;
+        let (kind, address, data) =
+            ihex_record(hex).with_context(|| format!("Invalid record on line {number}"))?;
+
+        match kind {
+            0x00 => chunks.push(Segment {
+                offset: base + address,
+                data,
+            }),
+            0x01 => ended = true,
+            0x02 | 0x04 if data.len() != 2 => {
+                anyhow::bail!("The address record on line {number} doesn't hold two bytes")
+            }
+            0x02 => base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 4,
+            0x04 => base = (u16::from_be_bytes([data[0], data[1]]) as usize) << 16,
+            // Start addresses, which mean nothing to the flash
+            0x03 | 0x05 => {}
+            kind => anyhow::bail!("Line {number} has the unknown record type {kind:#04x}"),
+        }
+    }
+    if !ended {
+        anyhow::bail!("The file ends without an end of file record");
+    }
+
+    merge(chunks)
+}
+
+/// Decode an S-record after its type into its address and data, given the width of its
+/// address, checking its length and checksum.
+fn srec_record(hex: &str, width: usize) -> Result<(usize, Vec<u8>)> {
+    let bytes = decode(hex)?;
+    if bytes.len() < width + 2 || bytes.len() != bytes[0] as usize + 1 {
+        anyhow::bail!("Its length doesn't match its byte count");
+    }
+    if sum(&bytes) != 0xFF {
+        anyhow::bail!("Its checksum is wrong");
+    }
+
+    let address = bytes[1..=width]
+        .iter()
+        .fold(0, |address, byte| address << 8 | *byte as usize);
+    Ok((address, bytes[width + 1..bytes.len() - 1].to_vec()))
+}
+
+/// Parse S-records, taking the data from S1, S2, and S3 records and ending at the S7, S8, or
+/// S9 record.
+fn srec(text: &str) -> Result<Vec<Segment>> {
+    let mut chunks = Vec::new();
+    let mut ended = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if ended {
+            anyhow::bail!("Line {number} follows the termination record");
+        }
+        let (kind, hex) = line
+            .strip_prefix(['S', 's'])
+            .and_then(|rest| Some((rest.chars().next()?, rest.get(1..)?)))
+            .with_context(|| format!("Line {number} doesn't start with an S-record type"))?;
+        let width = match kind {
+            // S0 is the header, and S5 and S6 count the data records
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            kind => anyhow::bail!("Line {number} has the unknown record type S{kind}"),
+        };
+        let (address, data) =
+            srec_record(hex, width).with_context(|| format!("Invalid record on line {number}"))?;
+
+        match kind {
+            '1' | '2' | '3' => chunks.push(Segment {
+                offset: address,
+                data,
+            }),
+            '7' | '8' | '9' => ended = true,
+            _ => {}
+        }
+    }
+    if !ended {
+        anyhow::bail!("The file ends without a termination record");
+    }
+
+    merge(chunks)
+}
+
+/// Join contiguous chunks into segments, refusing chunks that overlap.
+fn merge(mut chunks: Vec<Segment>) -> Result<Vec<Segment>> {
+    chunks.sort_by_key(|chunk| chunk.offset);
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for chunk in chunks.into_iter().filter(|chunk| !chunk.data.is_empty()) {
+        match segments.last_mut() {
+            Some(last) if chunk.offset < last.end() => anyhow::bail!(
+                "The record at {:#x} overlaps data ending at {:#x}",
+                chunk.offset,
+                last.end()
+            ),
+            Some(last) if chunk.offset == last.end() => last.data.extend(chunk.data),
+            _ => segments.push(chunk),
+        }
+    }
+    if segments.is_empty() {
+        anyhow::bail!("The file holds no data");
+    }
+
+    Ok(segments)
+}
+
+/// The bytes from the start of the first segment to the end of the last, with the gaps
+/// between them 0xFF, as erased flash reads.
+pub fn flatten(segments: &[Segment]) -> Vec<u8> {
+    let start = segments.first().map_or(0, |segment| segment.offset);
+    let end = segments.last().map_or(0, Segment::end);
+    let mut data = vec![0xFF; end - start];
+    for segment in segments {
+        data[segment.offset - start..segment.end() - start].copy_from_slice(&segment.data);
+    }
+
+    data
+}
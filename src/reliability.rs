@@ -0,0 +1,141 @@
+//! Measuring how reliably a bitstream configures the FPGA, for `sram --reliability-test`.
+//!
+//! The bitstream is programmed repeatedly at each baud rate, with CDONE checked after every
+//! iteration, and the success rate and spread of configuration times are tallied per rate.
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Options for repeatedly programming one bitstream.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Options {
+    /// Program the bitstream repeatedly, reporting how often CDONE rises and how long
+    /// configuration takes
+    ///
+    /// Post-program pulses aren't fired, and write errors are still retried as `--retries`
+    /// allows, so pass `--retries 0` to count every error as a failure.
+    #[arg(
+        long,
+        requires = "cdone",
+        conflicts_with_all = ["sequence", "post_program_pulse"]
+    )]
+    pub reliability_test: bool,
+
+    /// The number of times to program the bitstream at each baud rate
+    #[arg(long, default_value = "20")]
+    pub iterations: usize,
+
+    /// Test each of these comma separated baud rates in turn, instead of `--baud`
+    #[arg(long, value_delimiter = ',', requires = "reliability_test")]
+    pub baud_list: Vec<u32>,
+
+    /// Print the results as JSON
+    #[arg(long, requires = "reliability_test")]
+    pub json: bool,
+}
+
+/// The outcome of every iteration at one baud rate.
+#[derive(Clone, Debug)]
+pub struct Tally {
+    pub baud: u32,
+    /// How long each successful configuration took, from the reset pulse to CDONE.
+    times: Vec<Duration>,
+    failures: usize,
+}
+
+impl Tally {
+    pub fn new(baud: u32) -> Self {
+        Self {
+            baud,
+            times: Vec::new(),
+            failures: 0,
+        }
+    }
+
+    pub fn pass(&mut self, time: Duration) {
+        self.times.push(time);
+        self.times.sort();
+    }
+
+    pub fn fail(&mut self) {
+        self.failures += 1;
+    }
+
+    pub fn passed(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn iterations(&self) -> usize {
+        self.passed() + self.failures
+    }
+
+    /// The percentage of iterations that configured the FPGA.
+    pub fn rate(&self) -> f64 {
+        match self.iterations() {
+            0 => 0.0,
+            total => self.passed() as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// The configuration time below which `fraction` of the successful iterations fell.
+    fn percentile(&self, fraction: f64) -> Option<Duration> {
+        let last = self.times.len().checked_sub(1)?;
+        Some(self.times[(last as f64 * fraction).round() as usize])
+    }
+
+    fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.times.len()).ok().filter(|c| *c > 0)?;
+        Some(self.times.iter().sum::<Duration>() / count)
+    }
+
+    fn json(&self) -> String {
+        let millis = |time: Option<Duration>| {
+            time.map_or("null".into(), |t| {
+                format!("{:.3}", t.as_secs_f64() * 1000.0)
+            })
+        };
+
+        format!(
+            r#"{{"baud":{},"iterations":{},"passed":{},"rate":{:.1},"min_ms":{},"median_ms":{},"p90_ms":{},"max_ms":{},"mean_ms":{}}}"#,
+            self.baud,
+            self.iterations(),
+            self.passed(),
+            self.rate(),
+            millis(self.percentile(0.0)),
+            millis(self.percentile(0.5)),
+            millis(self.percentile(0.9)),
+            millis(self.percentile(1.0)),
+            millis(self.mean()),
+        )
+    }
+}
+
+/// A table comparing the tallies, one row per baud rate.
+pub fn table(tallies: &[Tally]) -> String {
+    let mut output = format!(
+        "{:>10}  {:>9}  {:>6}  {:>10}  {:>10}  {:>10}  {:>10}",
+        "Baud", "Passed", "Rate", "Min", "Median", "P90", "Max"
+    );
+
+    for tally in tallies {
+        let time = |time: Option<Duration>| time.map_or("-".into(), |t| format!("{t:.2?}"));
+        let _ = write!(
+            output,
+            "\n{:>10}  {:>9}  {:>5.1}%  {:>10}  {:>10}  {:>10}  {:>10}",
+            tally.baud,
+            format!("{}/{}", tally.passed(), tally.iterations()),
+            tally.rate(),
+            time(tally.percentile(0.0)),
+            time(tally.percentile(0.5)),
+            time(tally.percentile(0.9)),
+            time(tally.percentile(1.0)),
+        );
+    }
+
+    output
+}
+
+pub fn json(tallies: &[Tally]) -> String {
+    let results: Vec<_> = tallies.iter().map(Tally::json).collect();
+    format!(r#"{{"results":[{}]}}"#, results.join(","))
+}
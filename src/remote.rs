@@ -0,0 +1,233 @@
+//! Running commands on another machine's hardware, with `serve` on the machine owning the
+//! wires and `remote` on the machine with the files.
+//!
+//! The client sends its command line and the input file, if any, over TCP. The server writes
+//! the input to a temporary file, runs the command as a child process of itself, and relays
+//! the child's output line by line as it's written, ending with its exit code. If the client
+//! disconnects, the child is sent SIGINT, so a flash in progress stops cleanly after its
+//! current page.
+//!
+//! The protocol is line based:
+//!
+//! ```text
+//! client: lattice-prog 1 / <argument count> / <arguments, one per line> / <upload length or ->
+//!         followed by the upload bytes
+//! server: out <line> | err <line> ... then exit <code>
+//! ```
+
+use crate::{status, warning, watchdog};
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+
+/// The exit code used when the connection drops before the remote job finishes.
+pub const CONNECTION_LOST: i32 = 4;
+
+/// The argument standing in for the uploaded input file.
+pub const UPLOAD: &str = "@upload";
+
+const HELLO: &str = "lattice-prog 1";
+
+/// Run `args` on the server at `host`, uploading `upload` in place of the [`UPLOAD`] argument,
+/// and exit with the remote command's exit code.
+pub fn client(host: &str, args: &[String], upload: Option<&Path>) -> Result<()> {
+    let data = upload
+        .map(|path| {
+            std::fs::read(path).with_context(|| format!("Error reading {}", path.display()))
+        })
+        .transpose()?;
+
+    let stream = TcpStream::connect(host).with_context(|| format!("Failed to reach {host}"))?;
+    let mut writer = std::io::BufWriter::new(stream.try_clone()?);
+    writeln!(writer, "{HELLO}")?;
+    writeln!(writer, "{}", args.len())?;
+    for arg in args {
+        if arg.contains('\n') {
+            anyhow::bail!("Remote arguments can't contain newlines: {arg:?}");
+        }
+        writeln!(writer, "{arg}")?;
+    }
+    match &data {
+        Some(data) => {
+            writeln!(writer, "{}", data.len())?;
+            writer.write_all(data)?;
+        }
+        None => writeln!(writer, "-")?,
+    }
+    writer.flush()?;
+
+    // The remote side runs its own watchdog
+    watchdog::pause();
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+
+        match line.split_once(' ') {
+            Some(("out", text)) => status!("{text}"),
+            Some(("err", text)) => warning!("{text}"),
+            Some(("exit", code)) => std::process::exit(code.parse().unwrap_or(1)),
+            _ => warning!("Unexpected line from {host}: {line:?}"),
+        }
+    }
+
+    warning!(
+        "Lost the connection to {host} before the remote job finished; its outcome is unknown"
+    );
+    std::process::exit(CONNECTION_LOST);
+}
+
+/// Accept jobs on `listen` one at a time, forever.
+pub fn serve(listen: &str) -> Result<()> {
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Failed to listen on {listen}"))?;
+    status!("Listening on {}", listener.local_addr()?);
+    watchdog::pause();
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warning!("Failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or("an unknown peer".into(), |a| a.to_string());
+
+        match handle(stream) {
+            Ok(code) => status!("Job from {peer} exited with code {code}"),
+            Err(e) => warning!("Job from {peer} failed: {e:#}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Run one client's job, returning the child's exit code.
+fn handle(stream: TcpStream) -> Result<i32> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let hello = read_line(&mut reader)?;
+    if hello != HELLO {
+        anyhow::bail!("Unsupported client {hello:?}");
+    }
+
+    let count: usize = read_line(&mut reader)?
+        .parse()
+        .context("Invalid argument count")?;
+    let mut args = (0..count)
+        .map(|_| read_line(&mut reader))
+        .collect::<Result<Vec<_>>>()?;
+    if matches!(args.first().map(String::as_str), Some("serve" | "remote")) {
+        anyhow::bail!("Refusing to run {:?} remotely", args[0]);
+    }
+
+    let upload = match read_line(&mut reader)?.as_str() {
+        "-" => None,
+        length => {
+            let length: usize = length.parse().context("Invalid upload length")?;
+            let mut data = vec![0; length];
+            reader.read_exact(&mut data)?;
+
+            let path = std::env::temp_dir()
+                .join(format!("lattice-prog-upload-{}.bin", std::process::id()));
+            std::fs::write(&path, data)
+                .with_context(|| format!("Error writing {}", path.display()))?;
+            Some(path)
+        }
+    };
+    if let Some(path) = &upload {
+        for arg in args.iter_mut().filter(|arg| *arg == UPLOAD) {
+            *arg = path.display().to_string();
+        }
+    }
+
+    let result = run_child(&args, &mut writer);
+    if let Some(path) = upload {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Run the child, relaying its output to `writer` until it exits.
+fn run_child(args: &[String], writer: &mut TcpStream) -> Result<i32> {
+    let mut child = Command::new(std::env::current_exe()?)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to start the job")?;
+
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().map(|s| relay("out", s, sender.clone()));
+    let stderr = child.stderr.take().map(|s| relay("err", s, sender));
+
+    // Keep draining after a disconnect, so the child never blocks on a full pipe
+    let mut connected = true;
+    for line in receiver {
+        if connected && writeln!(writer, "{line}").is_err() {
+            connected = false;
+            warning!("The client disconnected, interrupting the job");
+            // SAFETY: sending a signal to our own child process
+            unsafe {
+                libc::kill(child.id() as libc::pid_t, libc::SIGINT);
+            }
+        }
+    }
+
+    for thread in [stdout, stderr].into_iter().flatten() {
+        let _ = thread.join();
+    }
+    let code = child.wait()?.code().unwrap_or(1);
+    if connected {
+        let _ = writeln!(writer, "exit {code}");
+    }
+
+    Ok(code)
+}
+
+fn relay(
+    prefix: &'static str,
+    stream: impl Read + Send + 'static,
+    sender: mpsc::Sender<String>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            if sender.send(format!("{prefix} {line}")).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        anyhow::bail!("The client disconnected");
+    }
+
+    Ok(line.trim_end_matches('\n').into())
+}
+
+/// Replace the first argument equal to `path` with [`UPLOAD`].
+pub fn mark_upload(args: &[String], path: &Path) -> Vec<String> {
+    let path = path.display().to_string();
+    let mut marked = false;
+
+    args.iter()
+        .map(|arg| {
+            if !marked && *arg == path {
+                marked = true;
+                UPLOAD.to_string()
+            } else {
+                arg.clone()
+            }
+        })
+        .collect()
+}
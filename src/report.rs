@@ -0,0 +1,216 @@
+//! How the programmers report what they're doing.
+//!
+//! The library writes nothing to the terminal itself. Its messages, its progress through each
+//! phase, and the address work has reached go to the [`Reporter`] installed with
+//! [`set_reporter`], and are dropped until one is.
+
+use std::fmt::Arguments;
+use std::sync::OnceLock;
+
+/// How prominent a message is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    /// Progress through an operation, for stdout.
+    Status,
+    /// Something that may need attention, for stderr.
+    Warning,
+    /// Detail only wanted when diagnosing a problem.
+    Verbose,
+}
+
+/// What a phase's progress counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Measure {
+    /// Bytes, drawn as a bar.
+    Bytes,
+    /// Operations, drawn as a bar.
+    Count,
+    /// Operations interleaved with another phase's bar, so not drawn themselves.
+    Events,
+}
+
+/// Progress through one phase, as returned by [`Reporter::progress`].
+pub trait Phase {
+    fn inc(&mut self, amount: usize);
+    fn finish(&self) {}
+}
+
+/// Where the library's messages and progress go.
+pub trait Reporter: Send + Sync {
+    fn message(&self, level: Level, message: Arguments);
+
+    /// Start tracking `total` units of `phase`.
+    fn progress(&self, phase: &'static str, total: usize, measure: Measure) -> Box<dyn Phase>;
+
+    /// Work in `phase` has reached `address`, called often enough to detect a hang by.
+    fn beat(&self, _phase: &'static str, _address: usize) {}
+}
+
+static REPORTER: OnceLock<Box<dyn Reporter>> = OnceLock::new();
+
+/// Send the library's reports to `reporter` for the rest of the process. Only the first call
+/// has any effect.
+pub fn set_reporter(reporter: Box<dyn Reporter>) {
+    let _ = REPORTER.set(reporter);
+}
+
+pub(crate) fn message(level: Level, message: Arguments) {
+    if let Some(reporter) = REPORTER.get() {
+        reporter.message(level, message);
+    }
+}
+
+pub(crate) fn beat(phase: &'static str, address: usize) {
+    if let Some(reporter) = REPORTER.get() {
+        reporter.beat(phase, address);
+    }
+}
+
+/// Progress through a phase, passed on to the reporter.
+pub(crate) struct Progress(Option<Box<dyn Phase>>);
+
+impl Progress {
+    fn new(phase: &'static str, total: usize, measure: Measure) -> Self {
+        Self(REPORTER.get().map(|r| r.progress(phase, total, measure)))
+    }
+
+    pub fn bytes(phase: &'static str, total: usize) -> Self {
+        Self::new(phase, total, Measure::Bytes)
+    }
+
+    pub fn count(phase: &'static str, total: usize) -> Self {
+        Self::new(phase, total, Measure::Count)
+    }
+
+    pub fn events(phase: &'static str, total: usize) -> Self {
+        Self::new(phase, total, Measure::Events)
+    }
+
+    pub fn inc(&mut self, amount: usize) {
+        if let Some(phase) = &mut self.0 {
+            phase.inc(amount);
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Some(phase) = &self.0 {
+            phase.finish();
+        }
+    }
+}
+
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::report::message($crate::report::Level::Status, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::report::message($crate::report::Level::Warning, format_args!($($arg)*))
+    };
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        $crate::report::message($crate::report::Level::Verbose, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {status, verbose, warning};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Every report, as a line; other tests report alongside, so each check filters by phase.
+    static REPORTS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Recorder;
+
+    struct Recorded(&'static str);
+
+    impl Phase for Recorded {
+        fn inc(&mut self, amount: usize) {
+            REPORTS
+                .lock()
+                .unwrap()
+                .push(format!("{} +{amount}", self.0));
+        }
+
+        fn finish(&self) {
+            REPORTS.lock().unwrap().push(format!("{} done", self.0));
+        }
+    }
+
+    impl Reporter for Recorder {
+        fn message(&self, level: Level, message: Arguments) {
+            REPORTS
+                .lock()
+                .unwrap()
+                .push(format!("{level:?}: {message}"));
+        }
+
+        fn progress(&self, phase: &'static str, total: usize, measure: Measure) -> Box<dyn Phase> {
+            REPORTS
+                .lock()
+                .unwrap()
+                .push(format!("{phase} of {total} {measure:?}"));
+            Box::new(Recorded(phase))
+        }
+
+        fn beat(&self, phase: &'static str, address: usize) {
+            REPORTS
+                .lock()
+                .unwrap()
+                .push(format!("{phase} at {address:#x}"));
+        }
+    }
+
+    fn reports(prefix: &str) -> Vec<String> {
+        REPORTS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.contains(prefix))
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn reports_reach_the_installed_reporter() {
+        set_reporter(Box::new(Recorder));
+
+        status!("report-test status {}", 1);
+        warning!("report-test warning");
+        verbose!("report-test verbose");
+        assert_eq!(
+            reports("report-test"),
+            [
+                "Status: report-test status 1",
+                "Warning: report-test warning",
+                "Verbose: report-test verbose"
+            ]
+        );
+
+        let mut progress = Progress::bytes("phase-bytes", 10);
+        progress.inc(4);
+        progress.inc(6);
+        progress.finish();
+        Progress::events("phase-events", 3).inc(1);
+        beat("phase-beat", 0x100);
+        assert_eq!(
+            reports("phase-"),
+            [
+                "phase-bytes of 10 Bytes",
+                "phase-bytes +4",
+                "phase-bytes +6",
+                "phase-bytes done",
+                "phase-events of 3 Events",
+                "phase-events +1",
+                "phase-beat at 0x100"
+            ]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! Restoring a full-chip dump bit for bit.
+//!
+//! Everything the dump covers is erased, with a chip erase when it covers the whole chip, and
+//! only the pages that aren't blank are programmed, so restoring a mostly empty chip doesn't
+//! spend hours writing 0xFF. The whole range is then hashed, blank regions included, and the
+//! restore only succeeds once the flash's SHA-256 equals the dump's.
+
+use crate::address::FlashAddress;
+use crate::backup::hex;
+use crate::cancel::CancellationToken;
+use crate::flash::FlashProgrammer;
+use crate::mask::Mask;
+use crate::plan::PAGE_SIZE;
+use crate::{status, warning};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// The JEDEC ID recorded in a backup's metadata sidecar, if it has one.
+pub fn recorded_jedec(path: &Path) -> Result<Option<[u8; 3]>> {
+    let sidecar = path.with_extension("json");
+    if !sidecar.exists() {
+        return Ok(None);
+    }
+
+    let text = std::fs::read_to_string(&sidecar)
+        .with_context(|| format!("Error reading {}", sidecar.display()))?;
+    let jedec = text
+        .split_once(r#""jedec":""#)
+        .and_then(|(_, rest)| rest.get(..6))
+        .and_then(|id| u32::from_str_radix(id, 16).ok())
+        .with_context(|| format!("No JEDEC ID in {}", sidecar.display()))?;
+
+    let [_, high, middle, low] = jedec.to_be_bytes();
+    Ok(Some([high, middle, low]))
+}
+
+/// The pages of `data` that are entirely blank.
+fn blank_pages(data: &[u8]) -> Mask {
+    Mask::new(
+        data.chunks(PAGE_SIZE)
+            .enumerate()
+            .filter(|(_, page)| page.iter().all(|b| *b == 0xFF))
+            .map(|(i, page)| i * PAGE_SIZE..i * PAGE_SIZE + page.len())
+            .collect(),
+    )
+}
+
+/// Restore `data`, dumped from the start of a flash whose JEDEC ID was `jedec`, returning the
+/// report with the attestation.
+pub fn restore(
+    programmer: &mut FlashProgrammer,
+    data: &[u8],
+    jedec: Option<[u8; 3]>,
+    allow_size_mismatch: bool,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    let info = programmer.info()?;
+    if let Some(jedec) = jedec.filter(|jedec| *jedec != info.jedec) {
+        warning!(
+            "WARNING: the dump was taken from a flash with JEDEC ID {}, but this flash is {}. \
+             Its contents may not mean the same thing on this part",
+            hex(&jedec),
+            hex(&info.jedec)
+        );
+    }
+
+    let capacity = info.capacity();
+    match capacity {
+        Some(capacity) if data.len() > capacity => anyhow::bail!(
+            "The dump is {} bytes, more than the {capacity} byte flash holds",
+            data.len()
+        ),
+        Some(capacity) if data.len() < capacity && !allow_size_mismatch => anyhow::bail!(
+            "The dump is {} bytes, but the flash holds {capacity}, so it may not be a full-chip \
+             dump (pass --allow-size-mismatch to restore it to the start of the flash anyway)",
+            data.len()
+        ),
+        Some(_) => {}
+        None => warning!("The flash doesn't report its capacity, so the dump's size isn't checked"),
+    }
+
+    let blank = blank_pages(data);
+    let images = [(data, FlashAddress::ZERO)];
+    let skipped = if capacity == Some(data.len()) {
+        status!("Erasing the whole chip...");
+        programmer.start_chip_erase()?;
+        programmer.await_ready()?;
+        programmer.check_chip_erased()?;
+
+        status!("Programming the pages that aren't blank...");
+        programmer.flash_images(&images, false, &blank, cancel)?
+    } else {
+        status!("Erasing and programming the pages that aren't blank...");
+        programmer.flash_images(&images, true, &blank, cancel)?
+    };
+    status!(
+        "Programmed {} bytes, leaving {skipped} blank",
+        data.len() - skipped
+    );
+
+    status!("Hashing the whole range, blank regions included...");
+    let expected: [u8; 32] = Sha256::digest(data).into();
+    let actual = programmer.hash_range(FlashAddress::ZERO, data.len(), &Mask::EMPTY)?;
+    if actual != expected {
+        // Find and report the first differing byte
+        programmer.verify_data(data, FlashAddress::ZERO, &Mask::EMPTY, cancel)?;
+        anyhow::bail!(
+            "The flash's SHA-256 {} doesn't match the dump's {}",
+            hex(&actual),
+            hex(&expected)
+        );
+    }
+
+    Ok(format!(
+        "Restored {} bytes bit for bit\n  Flash SHA-256: {}\n  Dump SHA-256:  {}",
+        data.len(),
+        hex(&actual),
+        hex(&expected)
+    ))
+}
@@ -0,0 +1,98 @@
+//! Seeded random choices of flash pages, for spot checks that don't read everything.
+
+use crate::plan::PAGE_SIZE;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// A small xorshift generator.
+pub struct Random(u64);
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    /// Seed from the clock, for runs that don't need to be reproduced.
+    pub fn seeded() -> Self {
+        Self::new(clock_seed())
+    }
+
+    pub fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+/// A seed taken from the clock.
+pub fn clock_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// The pages of an image chosen for a sample verification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub seed: u64,
+    /// The offsets of the chosen pages within the image, in order.
+    pub pages: Vec<usize>,
+    pub length: usize,
+}
+
+impl Sample {
+    /// Choose `count` pages of a `length` byte image with `seed`.
+    ///
+    /// The first and last pages are always included, the first holding any boot header. The
+    /// same arguments always choose the same pages.
+    pub fn select(length: usize, count: usize, seed: u64) -> Self {
+        let total = length.div_ceil(PAGE_SIZE);
+        let mut pages = BTreeSet::new();
+
+        if count >= total {
+            pages.extend(0..total);
+        } else if total > 0 {
+            let mut random = Random::new(seed);
+            pages.extend([0, total - 1]);
+            while pages.len() < count.max(2).min(total) {
+                pages.insert(random.below(total));
+            }
+        }
+
+        Self {
+            seed,
+            pages: pages.into_iter().map(|page| page * PAGE_SIZE).collect(),
+            length,
+        }
+    }
+
+    /// The percentage of the image's bytes the sample covers.
+    pub fn coverage(&self) -> f64 {
+        let covered: usize = self
+            .pages
+            .iter()
+            .map(|offset| PAGE_SIZE.min(self.length - offset))
+            .sum();
+
+        covered as f64 / self.length.max(1) as f64 * 100.0
+    }
+
+    /// A JSON report of the sample taken at `address`, with the error if verification failed.
+    pub fn json(&self, address: usize, error: Option<&str>) -> String {
+        let mut pages = String::new();
+        for (i, offset) in self.pages.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let _ = write!(pages, "{separator}{}", address + offset);
+        }
+
+        format!(
+            r#"{{"ok":{},"error":{},"seed":{},"coverage":{:.3},"pages":[{pages}]}}"#,
+            error.is_none(),
+            error.map_or("null".into(), |e| format!("{e:?}")),
+            self.seed,
+            self.coverage()
+        )
+    }
+}
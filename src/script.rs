@@ -0,0 +1,372 @@
+//! Scripted sessions of several flash operations, run by the `run` subcommand.
+//!
+//! A script is a TOML file listing steps in order, each naming its operation with `op`:
+//!
+//! ```toml
+//! [[step]]
+//! op = "erase"
+//! partition = "userdata"
+//!
+//! [[step]]
+//! op = "flash"
+//! path = "build/top.bin"
+//! offset = 0x0
+//!
+//! [[step]]
+//! op = "flash"
+//! text = "${serial}"
+//! partition = "serial"
+//! allow_failure = true
+//! ```
+//!
+//! `${name}` anywhere in a string parameter is replaced by the value given with
+//! `--var name=value`, and naming an undefined variable is an error.
+
+use crate::address::FlashAddress;
+use crate::layout::Region;
+use crate::{status, warning};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+pub struct Script {
+    #[serde(default, rename = "step")]
+    pub steps: Vec<Entry>,
+}
+
+/// A step along with how its failure is handled.
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+    #[serde(flatten)]
+    pub step: Step,
+
+    /// Carry on with the next step if this one fails
+    #[serde(default)]
+    pub allow_failure: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Step {
+    /// Write an image, read from `path` or given inline as `text`, then verify it.
+    Flash {
+        path: Option<PathBuf>,
+        text: Option<String>,
+        #[serde(flatten)]
+        region: Target,
+    },
+    /// Compare the flash against the image at `path`.
+    Verify {
+        path: PathBuf,
+        #[serde(flatten)]
+        region: Target,
+    },
+    /// Erase every block the region touches.
+    Erase {
+        #[serde(flatten)]
+        region: Target,
+    },
+    /// Save a region of the flash to `output`.
+    Dump {
+        output: PathBuf,
+        #[serde(flatten)]
+        region: Target,
+    },
+    /// Send an arbitrary command, as the `raw-cmd` subcommand does.
+    Raw {
+        write: Vec<u8>,
+        #[serde(default)]
+        read: usize,
+        #[serde(default)]
+        keep_write_enable: bool,
+        #[serde(default)]
+        allow_destructive: bool,
+    },
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::Flash { .. } => "flash",
+            Step::Verify { .. } => "verify",
+            Step::Erase { .. } => "erase",
+            Step::Dump { .. } => "dump",
+            Step::Raw { .. } => "raw",
+        })
+    }
+}
+
+/// Where a step operates, as a raw offset or a layout partition.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Target {
+    pub offset: Option<FlashAddress>,
+    pub length: Option<usize>,
+    pub partition: Option<String>,
+    #[serde(default)]
+    pub allow_cross_partition: bool,
+}
+
+impl From<&Target> for Region {
+    fn from(target: &Target) -> Self {
+        Region {
+            address: target.offset,
+            length: target.length,
+            partition: target.partition.clone(),
+            allow_cross_partition: target.allow_cross_partition,
+        }
+    }
+}
+
+/// Parse a `name=value` variable.
+pub fn parse_var(text: &str) -> Result<(String, String)> {
+    let (name, value) = text
+        .split_once('=')
+        .with_context(|| format!("Expected name=value, got {text:?}"))?;
+
+    Ok((name.trim().into(), value.into()))
+}
+
+impl Script {
+    pub fn load(path: &Path, vars: &[(String, String)]) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading script from {}", path.display()))?;
+
+        Self::parse(&text, vars).with_context(|| format!("Invalid script in {}", path.display()))
+    }
+
+    /// Parse a script, substituting `vars` into its string parameters.
+    ///
+    /// Substitution happens after the TOML is parsed, so a value can't change the script's
+    /// structure.
+    pub fn parse(text: &str, vars: &[(String, String)]) -> Result<Self> {
+        let mut value: toml::Value = toml::from_str(text)?;
+        substitute_all(&mut value, vars)?;
+
+        Ok(value.try_into()?)
+    }
+
+    /// Run each step with `execute`, stopping at the first failure of a step without
+    /// `allow_failure`.
+    ///
+    /// Returns the number of steps that failed but were allowed to.
+    pub fn run(&self, mut execute: impl FnMut(&Step) -> Result<()>) -> Result<usize> {
+        let mut allowed = 0;
+
+        for (index, entry) in self.steps.iter().enumerate() {
+            let number = index + 1;
+            status!("Step {number} of {} ({})", self.steps.len(), entry.step);
+
+            match execute(&entry.step) {
+                Ok(()) => {}
+                Err(e) if entry.allow_failure => {
+                    warning!("Step {number} ({}) failed, continuing: {e:#}", entry.step);
+                    allowed += 1;
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "Step {number} ({}) failed, skipping the remaining {} steps",
+                        entry.step,
+                        self.steps.len() - number
+                    )))
+                }
+            }
+        }
+
+        Ok(allowed)
+    }
+}
+
+fn substitute_all(value: &mut toml::Value, vars: &[(String, String)]) -> Result<()> {
+    match value {
+        toml::Value::String(text) => *text = substitute(text, vars)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                substitute_all(value, vars)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                substitute_all(value, vars)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Replace each `${name}` in `text` with its value from `vars`.
+pub fn substitute(text: &str, vars: &[(String, String)]) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unterminated variable in {text:?}"))?;
+        let name = &rest[start + 2..start + end];
+        let (_, value) = vars
+            .iter()
+            .find(|(var, _)| var == name)
+            .with_context(|| format!("Undefined variable {name:?}; pass --var {name}=..."))?;
+
+        output.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vec<(String, String)> {
+        vec![
+            ("serial".into(), "SN123".into()),
+            ("board".into(), "rev-b".into()),
+        ]
+    }
+
+    const SCRIPT: &str = r#"
+        [[step]]
+        op = "erase"
+        partition = "userdata"
+
+        [[step]]
+        op = "flash"
+        path = "build/${board}/top.bin"
+        offset = 0x20000
+
+        [[step]]
+        op = "flash"
+        text = "${serial}"
+        partition = "serial"
+        allow_failure = true
+
+        [[step]]
+        op = "raw"
+        write = [0x9f]
+        read = 3
+    "#;
+
+    #[test]
+    fn parses_steps_in_order() {
+        let script = Script::parse(SCRIPT, &vars()).unwrap();
+        let names: Vec<_> = script.steps.iter().map(|e| e.step.to_string()).collect();
+        assert_eq!(names, ["erase", "flash", "flash", "raw"]);
+        let allowed: Vec<_> = script.steps.iter().map(|e| e.allow_failure).collect();
+        assert_eq!(allowed, [false, false, true, false]);
+
+        let Step::Flash { path, region, .. } = &script.steps[1].step else {
+            panic!("{:?}", script.steps[1]);
+        };
+        assert_eq!(path.as_deref(), Some(Path::new("build/rev-b/top.bin")));
+        assert_eq!(region.offset, Some(FlashAddress::new(0x20000).unwrap()));
+
+        let Step::Flash { text, region, .. } = &script.steps[2].step else {
+            panic!("{:?}", script.steps[2]);
+        };
+        assert_eq!(text.as_deref(), Some("SN123"));
+        assert_eq!(region.partition.as_deref(), Some("serial"));
+
+        let Step::Raw { write, read, .. } = &script.steps[3].step else {
+            panic!("{:?}", script.steps[3]);
+        };
+        assert_eq!((&write[..], *read), (&[0x9F][..], 3));
+    }
+
+    #[test]
+    fn rejects_malformed_steps() {
+        assert!(Script::parse("[[step]]\nop = \"format\"", &[]).is_err());
+        assert!(Script::parse("[[step]]\nop = \"verify\"", &[]).is_err());
+        assert!(Script::parse("[[step]]\npath = \"top.bin\"", &[]).is_err());
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        let vars = vars();
+        assert_eq!(substitute("plain", &vars).unwrap(), "plain");
+        assert_eq!(
+            substitute("${board}/${serial}-${serial}", &vars).unwrap(),
+            "rev-b/SN123-SN123"
+        );
+        assert_eq!(
+            substitute("$serial {serial}", &vars).unwrap(),
+            "$serial {serial}"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_unterminated_variables() {
+        let error = substitute("${missing}", &vars()).unwrap_err();
+        assert!(error.to_string().contains("--var missing="), "{error}");
+        assert!(substitute("${serial", &vars()).is_err());
+        assert!(Script::parse(SCRIPT, &[]).is_err());
+    }
+
+    #[test]
+    fn substituted_values_stay_strings() {
+        let serial = "\"\n[[step]]\nop = \"erase\"";
+        let mut vars = vars();
+        vars[0].1 = serial.into();
+
+        let script = Script::parse(SCRIPT, &vars).unwrap();
+        assert_eq!(script.steps.len(), 4);
+        let Step::Flash { text, .. } = &script.steps[2].step else {
+            panic!("{:?}", script.steps[2]);
+        };
+        assert_eq!(text.as_deref(), Some(serial));
+    }
+
+    #[test]
+    fn parses_vars() {
+        assert_eq!(
+            parse_var(" serial =SN=1").unwrap(),
+            ("serial".to_string(), "SN=1".to_string())
+        );
+        assert!(parse_var("serial").is_err());
+    }
+
+    /// Run `script`, failing the steps whose numbers are in `failing`, and return the result
+    /// and the numbers of the steps that ran.
+    fn run(script: &Script, failing: &[usize]) -> (Result<usize>, Vec<usize>) {
+        let mut ran = Vec::new();
+        let result = script.run(|_| {
+            ran.push(ran.len() + 1);
+            if failing.contains(&ran.len()) {
+                anyhow::bail!("step failed");
+            }
+            Ok(())
+        });
+        (result, ran)
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        let script = Script::parse(SCRIPT, &vars()).unwrap();
+        let (result, ran) = run(&script, &[2]);
+        assert_eq!(ran, [1, 2]);
+        let error = format!("{:#}", result.unwrap_err());
+        assert!(
+            error.starts_with("Step 2 (flash) failed, skipping the remaining 2 steps"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn continues_past_allowed_failures() {
+        let script = Script::parse(SCRIPT, &vars()).unwrap();
+        let (result, ran) = run(&script, &[3]);
+        assert_eq!(ran, [1, 2, 3, 4]);
+        assert_eq!(result.unwrap(), 1);
+
+        let (result, ran) = run(&script, &[]);
+        assert_eq!(ran, [1, 2, 3, 4]);
+        assert_eq!(result.unwrap(), 0);
+    }
+}
@@ -0,0 +1,292 @@
+//! The Serial Flash Discoverable Parameters (JESD216) a chip describes itself with, read from
+//! the basic flash parameter table: address width, density, fast reads, erase types, page
+//! size, and where the quad enable bit is.
+
+use crate::address::THREE_BYTE_SPACE;
+use crate::chip::Erase;
+use std::fmt::{self, Write};
+
+pub const READ_SFDP: u8 = 0x5A;
+const SIGNATURE: &[u8; 4] = b"SFDP";
+
+/// The size of the SFDP header followed by the first parameter header, which is always the
+/// basic flash parameter table's.
+pub const HEADER_SIZE: usize = 16;
+
+/// The offset of the four erase types within the basic flash parameter table, in its 8th and
+/// 9th DWORDs.
+const ERASE_TYPES: usize = 28;
+const ERASE_TYPES_END: usize = ERASE_TYPES + 8;
+/// The offset of the 11th DWORD, which holds the page size in JESD216A and later.
+const PAGE_SIZE: usize = 40;
+/// The offset of the 15th DWORD, which holds the quad enable requirements in JESD216A and
+/// later.
+const QUAD_ENABLE: usize = 56;
+/// The length of the table up to the end of the last DWORD used.
+pub const TABLE_END: usize = QUAD_ENABLE + 4;
+
+/// The widths of address the flash accepts, from bits 17-18 of the 1st DWORD.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressBytes {
+    Three,
+    ThreeOrFour,
+    Four,
+}
+
+/// A fast read the flash implements, named by the lines used for the opcode, address, and data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FastRead {
+    pub name: &'static str,
+    pub opcode: u8,
+    /// Mode and wait clocks between the address and the data.
+    pub dummy_clocks: u8,
+}
+
+/// Where the bit enabling IO2 and IO3 for quad reads is, from bits 20-22 of the 15th DWORD.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuadEnable {
+    /// There's no bit, and quad reads always work.
+    None,
+    /// Bit 6 of status register 1.
+    Status1Bit6,
+    /// Bit 1 of status register 2, read with 0x35 and written with 0x31.
+    Status2Bit1,
+    /// Bit 1 of status register 2, read with 0x35 and written along with status register 1
+    /// through 0x01.
+    Status2Bit1Pair,
+    /// Bit 7 of status register 2, read with 0x3F and written with 0x3E.
+    Status2Bit7,
+}
+
+impl QuadEnable {
+    /// The opcode reading the register holding the bit, and the bit's mask.
+    pub fn location(self) -> Option<(u8, u8)> {
+        match self {
+            QuadEnable::None => None,
+            QuadEnable::Status1Bit6 => Some((0x05, 0x40)),
+            QuadEnable::Status2Bit1 | QuadEnable::Status2Bit1Pair => Some((0x35, 0x02)),
+            QuadEnable::Status2Bit7 => Some((0x3F, 0x80)),
+        }
+    }
+}
+
+/// The parameters of a basic flash parameter table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parameters {
+    pub address_bytes: AddressBytes,
+    /// The capacity in bytes.
+    pub capacity: Option<usize>,
+    pub fast_reads: Vec<FastRead>,
+    pub erases: Vec<Erase>,
+    /// The most bytes one page program writes, only given by JESD216A and later tables.
+    pub page_size: Option<usize>,
+    /// Only given by JESD216A and later tables.
+    pub quad_enable: Option<QuadEnable>,
+}
+
+/// The address and length of the basic flash parameter table, or `None` when the header
+/// doesn't hold one.
+pub fn parameter_table(header: &[u8]) -> Option<(usize, usize)> {
+    if header.len() < HEADER_SIZE || &header[..4] != SIGNATURE || header[8] != 0x00 {
+        return None;
+    }
+
+    let length = header[11] as usize * 4;
+    let address = u32::from_le_bytes([header[12], header[13], header[14], 0]) as usize;
+    Some((address, length))
+}
+
+/// The `n`th DWORD of the table, counting from 1 as JESD216 does.
+fn dword(table: &[u8], n: usize) -> Option<u32> {
+    let bytes = table.get((n - 1) * 4..n * 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().expect("four bytes")))
+}
+
+/// The erase types listed in a basic flash parameter table, each a size exponent and an
+/// opcode, with a size of zero for an unused type.
+pub fn erase_types(table: &[u8]) -> Vec<Erase> {
+    table
+        .get(ERASE_TYPES..ERASE_TYPES_END)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .filter(|erase| (1..32).contains(&erase[0]))
+        .map(|erase| Erase {
+            opcode: erase[1],
+            size: 1 << erase[0],
+        })
+        .collect()
+}
+
+impl Parameters {
+    /// Parse a basic flash parameter table, or `None` when it's too short to hold the first
+    /// two DWORDs.
+    pub fn parse(table: &[u8]) -> Option<Self> {
+        let first = dword(table, 1)?;
+        let density = dword(table, 2)?;
+
+        let address_bytes = match (first >> 17) & 0b11 {
+            0b00 => AddressBytes::Three,
+            0b01 => AddressBytes::ThreeOrFour,
+            _ => AddressBytes::Four,
+        };
+
+        // Bits, either one less than the count or, with the top bit set, its exponent
+        let capacity = if density & (1 << 31) == 0 {
+            Some((density as usize + 1) / 8)
+        } else {
+            let exponent = density & !(1 << 31);
+            (3..64).contains(&exponent).then(|| 1 << (exponent - 3))
+        };
+
+        // Each flag in the 1st DWORD with the half of the 3rd or 4th DWORD describing it
+        let mut fast_reads = Vec::new();
+        for (name, flag, n, shift) in [
+            ("1-1-2", 16, 4, 0),
+            ("1-2-2", 20, 4, 16),
+            ("1-1-4", 22, 3, 16),
+            ("1-4-4", 21, 3, 0),
+        ] {
+            if first & (1 << flag) == 0 {
+                continue;
+            }
+            if let Some(instruction) = dword(table, n).map(|d| (d >> shift) as u16) {
+                fast_reads.push(FastRead {
+                    name,
+                    opcode: (instruction >> 8) as u8,
+                    dummy_clocks: (instruction & 0x1F) as u8 + ((instruction >> 5) & 0x07) as u8,
+                });
+            }
+        }
+
+        let page_size = dword(table, 11).map(|d| 1 << ((d >> 4) & 0x0F));
+        let quad_enable = dword(table, 15).and_then(|d| match (d >> 20) & 0x07 {
+            0b000 => Some(QuadEnable::None),
+            0b001 | 0b100 | 0b101 => Some(QuadEnable::Status2Bit1Pair),
+            0b010 => Some(QuadEnable::Status1Bit6),
+            0b011 => Some(QuadEnable::Status2Bit7),
+            0b110 => Some(QuadEnable::Status2Bit1),
+            _ => None,
+        });
+
+        Some(Self {
+            address_bytes,
+            capacity,
+            fast_reads,
+            erases: erase_types(table),
+            page_size,
+            quad_enable,
+        })
+    }
+}
+
+impl fmt::Display for AddressBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressBytes::Three => "3 bytes",
+            AddressBytes::ThreeOrFour => "3 or 4 bytes",
+            AddressBytes::Four => "4 bytes",
+        })
+    }
+}
+
+impl fmt::Display for QuadEnable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QuadEnable::None => "not needed",
+            QuadEnable::Status1Bit6 => "bit 6 of status register 1",
+            QuadEnable::Status2Bit1 => "bit 1 of status register 2, written with 0x31",
+            QuadEnable::Status2Bit1Pair => "bit 1 of status register 2, written with 0x01",
+            QuadEnable::Status2Bit7 => "bit 7 of status register 2, written with 0x3e",
+        })
+    }
+}
+
+impl fmt::Display for Parameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Address width: {}", self.address_bytes)?;
+        match self.capacity {
+            Some(capacity) => write!(
+                f,
+                "\nCapacity:      {} KiB ({capacity:#x} bytes)",
+                capacity / 1024
+            )?,
+            None => f.write_str("\nCapacity:      invalid")?,
+        }
+
+        let mut reads = String::new();
+        for (i, read) in self.fast_reads.iter().enumerate() {
+            if i > 0 {
+                reads.push_str(", ");
+            }
+            write!(
+                reads,
+                "{} ({:#04x}, {} dummy clocks)",
+                read.name, read.opcode, read.dummy_clocks
+            )?;
+        }
+        write!(
+            f,
+            "\nFast reads:    {}",
+            if reads.is_empty() { "none" } else { &reads }
+        )?;
+
+        let erases: Vec<_> = self.erases.iter().map(Erase::to_string).collect();
+        write!(
+            f,
+            "\nErases:        {}",
+            if erases.is_empty() {
+                "none listed".into()
+            } else {
+                erases.join(", ")
+            }
+        )?;
+        match self.page_size {
+            Some(page_size) => write!(f, "\nPage size:     {page_size} bytes")?,
+            None => f.write_str("\nPage size:     not listed")?,
+        }
+        match self.quad_enable {
+            Some(quad_enable) => write!(f, "\nQuad enable:   {quad_enable}"),
+            None => f.write_str("\nQuad enable:   not listed"),
+        }
+    }
+}
+
+/// A header and basic flash parameter table for a flash of `capacity` bytes with 256-byte pages,
+/// Fast Read Dual and Quad Output, and `erases`, as a chip would return them. It takes 3-byte
+/// addresses, or 4-byte ones too when it's larger than 16 MiB, and its quad enable bit is bit 1
+/// of status register 2.
+pub fn encode(erases: &[Erase], capacity: usize) -> Vec<u8> {
+    let mut sfdp = SIGNATURE.to_vec();
+    // Revision 1.5 with a single parameter header, for a table right after it
+    sfdp.extend([0x05, 0x01, 0x00, 0xFF]);
+    sfdp.extend([0x00, 0x05, 0x01, (TABLE_END / 4) as u8]);
+    sfdp.extend([HEADER_SIZE as u8, 0x00, 0x00, 0xFF]);
+
+    let mut table = vec![0xFF; TABLE_END];
+    // 1-1-2 and 1-1-4 reads and 3-byte addresses, with the reserved bits set
+    let mut first = 0xFFC1_20E5_u32;
+    if capacity > THREE_BYTE_SPACE {
+        first |= 1 << 17;
+    }
+    table[..4].copy_from_slice(&first.to_le_bytes());
+    table[4..8].copy_from_slice(&((capacity * 8 - 1) as u32).to_le_bytes());
+    // Fast Read Dual and Quad Output with 8 wait clocks
+    table[10..12].copy_from_slice(&[0x08, 0x6B]);
+    table[12..14].copy_from_slice(&[0x08, 0x3B]);
+    for (i, slot) in table[ERASE_TYPES..ERASE_TYPES_END]
+        .chunks_exact_mut(2)
+        .enumerate()
+    {
+        match erases.get(i) {
+            Some(erase) => slot.copy_from_slice(&[erase.size.trailing_zeros() as u8, erase.opcode]),
+            None => slot.copy_from_slice(&[0x00, 0xFF]),
+        }
+    }
+    // 2^8 byte pages
+    table[PAGE_SIZE] = 0x80;
+    // Quad enable in bit 1 of status register 2, written along with status register 1
+    table[QUAD_ENABLE + 2] = 0xDF;
+    sfdp.extend(table);
+
+    sfdp
+}
@@ -0,0 +1,252 @@
+//! A/B update slots beside a golden image, chosen between by the multiboot header at offset 0.
+//!
+//! The layout names three partitions: `golden`, a known-good bitstream that's never written
+//! here, and `slot-a` and `slot-b`, which updates alternate between. The header's power-on entry
+//! points at the active slot, warm boot 0 at the golden image, and warm boots 1 and 2 at slots A
+//! and B, so a design can always fall back to the golden image through `SB_WARMBOOT`.
+//!
+//! An update writes the slot that isn't booting and verifies it, and only then rewrites the
+//! header to boot it. Until then the board boots what it did before, however the update fails.
+//! Rewriting the header, one sector erase and a page program, is the only moment losing power
+//! leaves the board with nothing to boot.
+
+use crate::address::FlashAddress;
+use crate::bitstream;
+#[cfg(not(feature = "read-only"))]
+use crate::cancel::CancellationToken;
+#[cfg(not(feature = "read-only"))]
+use crate::device::Device;
+use crate::flash::FlashProgrammer;
+use crate::layout::{Layout, Partition};
+#[cfg(not(feature = "read-only"))]
+use crate::mask::Mask;
+#[cfg(not(feature = "read-only"))]
+use crate::status;
+use anyhow::{Context, Result};
+use std::fmt::Write;
+
+/// How much of each slot is read to tell whether it holds a bitstream.
+const HEAD_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Slot {
+    Golden,
+    A,
+    B,
+}
+
+impl Slot {
+    const ALL: [Slot; 3] = [Slot::Golden, Slot::A, Slot::B];
+
+    fn partition(self) -> &'static str {
+        match self {
+            Slot::Golden => "golden",
+            Slot::A => "slot-a",
+            Slot::B => "slot-b",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::Golden => "the golden image",
+            Slot::A => "slot A",
+            Slot::B => "slot B",
+        }
+    }
+}
+
+/// The slots' partitions, in the order of [`Slot::ALL`].
+fn partitions(layout: Option<&Layout>) -> Result<[&Partition; 3]> {
+    let layout = layout.context(
+        "A/B slots need a layout with `golden`, `slot-a`, and `slot-b` partitions (see --layout)",
+    )?;
+    let [golden, a, b] = Slot::ALL.map(|slot| layout.partition(slot.partition()));
+    Ok([golden?, a?, b?])
+}
+
+fn partition(layout: Option<&Layout>, slot: Slot) -> Result<&Partition> {
+    let [golden, a, b] = partitions(layout)?;
+    Ok(match slot {
+        Slot::Golden => golden,
+        Slot::A => a,
+        Slot::B => b,
+    })
+}
+
+/// The slot the multiboot header boots at power-on, or `None` when there's no header or it
+/// points elsewhere.
+fn active(programmer: &mut FlashProgrammer, layout: Option<&Layout>) -> Result<Option<Slot>> {
+    let partitions = partitions(layout)?;
+    let head = programmer.read_arbitrary(FlashAddress::ZERO, bitstream::MULTIBOOT_HEADER_SIZE)?;
+    let Some(power_on) = bitstream::boot_addresses(&head).first().copied() else {
+        return Ok(None);
+    };
+
+    Ok(Slot::ALL
+        .into_iter()
+        .zip(partitions)
+        .find(|(_, partition)| partition.offset == power_on)
+        .map(|(slot, _)| slot))
+}
+
+/// Whether `slot` holds something that opens as a bitstream.
+fn holds_bitstream(
+    programmer: &mut FlashProgrammer,
+    layout: Option<&Layout>,
+    slot: Slot,
+) -> Result<bool> {
+    let partition = partition(layout, slot)?;
+    let head = programmer.read_arbitrary(
+        FlashAddress::new(partition.offset)?,
+        partition.size.min(HEAD_SIZE),
+    )?;
+    Ok(bitstream::check(&head).is_ok())
+}
+
+/// Describe which slot boots at power-on and what each holds.
+pub fn status(programmer: &mut FlashProgrammer, layout: Option<&Layout>) -> Result<String> {
+    let partitions = partitions(layout)?;
+    let active = active(programmer, layout)?;
+
+    let mut output = String::new();
+    match active {
+        Some(slot) => writeln!(output, "Booting {} at power-on", slot.name())?,
+        None => writeln!(
+            output,
+            "No multiboot header at offset 0 boots any of the slots"
+        )?,
+    }
+    for (slot, partition) in Slot::ALL.into_iter().zip(partitions) {
+        let head = programmer.read_arbitrary(
+            FlashAddress::new(partition.offset)?,
+            partition.size.min(HEAD_SIZE),
+        )?;
+        let contents = match bitstream::check(&head) {
+            Ok(()) => match bitstream::comment(&head) {
+                Some(comment) => format!("bitstream ({comment})"),
+                None => "bitstream".to_string(),
+            },
+            Err(_) if head.iter().all(|b| *b == 0xFF) => "blank".to_string(),
+            Err(_) => "no bitstream".to_string(),
+        };
+        let marker = if active == Some(slot) {
+            "  <- power-on"
+        } else {
+            ""
+        };
+        writeln!(
+            output,
+            "  {:<8} {:#08x}..{:#08x}  {contents}{marker}",
+            slot.partition(),
+            partition.offset,
+            partition.end()
+        )?;
+    }
+
+    Ok(output.trim_end().into())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Write the multiboot header booting `slot` at power-on, and read it back.
+fn boot(
+    programmer: &mut FlashProgrammer,
+    layout: Option<&Layout>,
+    slot: Slot,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let [golden, a, b] = partitions(layout)?;
+    let header = bitstream::warmboot_header(
+        partition(layout, slot)?.offset,
+        &[golden.offset, a.offset, b.offset],
+    );
+
+    // The header's erase mustn't reach into a slot
+    let erased = programmer
+        .plan_erases(FlashAddress::ZERO, header.len())?
+        .iter()
+        .map(|(address, erase)| address.get() + erase.size)
+        .max()
+        .unwrap_or(header.len());
+    if let Some(partition) = [golden, a, b].iter().find(|p| p.offset < erased) {
+        anyhow::bail!(
+            "The {:?} partition starts at {:#x}, within the first {erased:#x} bytes the \
+             multiboot header's erase covers",
+            partition.name,
+            partition.offset
+        );
+    }
+
+    status!("Pointing the multiboot header at {}...", slot.name());
+    programmer.flash_images(&[(&header, FlashAddress::ZERO)], true, &Mask::EMPTY, cancel)?;
+    Ok(programmer.verify_data(&header, FlashAddress::ZERO, &Mask::EMPTY, cancel)?)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Write `data` to the update slot that isn't booting, verify it, and only then boot it at
+/// power-on, returning the slot.
+pub fn update(
+    programmer: &mut FlashProgrammer,
+    layout: Option<&Layout>,
+    data: &[u8],
+    device: Option<Device>,
+    yes: bool,
+    cancel: &CancellationToken,
+) -> Result<Slot> {
+    bitstream::check(data).context("Only a bitstream can go in an update slot")?;
+    if let Some(device) = device {
+        device.check_target(data)?;
+    }
+
+    let slot = match active(programmer, layout)? {
+        Some(Slot::A) => Slot::B,
+        _ => Slot::A,
+    };
+    let partition = partition(layout, slot)?;
+    if partition.readonly {
+        anyhow::bail!("The {:?} partition is read-only", partition.name);
+    }
+    if data.len() > partition.size {
+        anyhow::bail!(
+            "The bitstream is {} bytes, but {} only holds {}",
+            data.len(),
+            slot.name(),
+            partition.size
+        );
+    }
+    if !holds_bitstream(programmer, layout, Slot::Golden)? {
+        crate::warning!(
+            "The golden image doesn't hold a bitstream, so there's nothing to fall back to"
+        );
+    }
+
+    let address = FlashAddress::new(partition.offset)?;
+    crate::confirm::overwrite(programmer, data, address, yes)?;
+    status!("Writing {} at {address:#x}...", slot.name());
+    programmer.flash_images(&[(data, address)], true, &Mask::EMPTY, cancel)?;
+    status!("Verifying {}...", slot.name());
+    programmer
+        .verify_data(data, address, &Mask::EMPTY, cancel)
+        .with_context(|| {
+            format!(
+                "The update to {} didn't verify, so the board still boots what it did before",
+                slot.name()
+            )
+        })?;
+
+    boot(programmer, layout, slot, cancel)?;
+    Ok(slot)
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Boot `slot` at power-on, once it's checked to hold a bitstream.
+pub fn select(
+    programmer: &mut FlashProgrammer,
+    layout: Option<&Layout>,
+    slot: Slot,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    if !holds_bitstream(programmer, layout, slot)? {
+        anyhow::bail!("There's no bitstream in {}", slot.name());
+    }
+    boot(programmer, layout, slot, cancel)
+}
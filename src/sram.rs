@@ -0,0 +1,531 @@
+//! Configuring the FPGA's SRAM directly over hardware SPI, as its slave configuration mode
+//! expects.
+
+use crate::bitstream;
+use crate::boot;
+use crate::cancel::CancellationToken;
+use crate::error::bail;
+use crate::pins::{ActivePin, PinConfig, SpiBus};
+use crate::report::{self, Progress};
+use crate::timing::Timing;
+use crate::{verbose, warning, Result};
+use anyhow::Context;
+use rppal::gpio::{Gpio, InputPin};
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use spin_sleep::sleep;
+
+/// Settings for the SRAM programmer's SPI transfers.
+#[derive(clap::Args, Clone, Debug)]
+pub struct SpiSettings {
+    /// SPI baud rate
+    ///
+    /// Values that are too low or too high seem to corrupt the bitstream.
+    #[arg(short, long, default_value = "10000000")]
+    pub baud: u32,
+
+    /// SPI transfer buffer size
+    ///
+    /// The maximum possible value is 65536, but any value above 4096 must be set in the Pi's
+    /// boot configuration (by inserting spidev.bufsiz=<desired value> in /boot/cmdline.txt).
+    ///
+    /// The bitstream's header, up to its first configuration data, always goes in one write,
+    /// since configuration fails when gaps between writes fall within it.
+    #[arg(short, long, default_value = "16384")]
+    pub transfer: usize,
+
+    /// Restart configuration from the reset pulse up to this many times after a write error
+    #[arg(long, default_value = "2")]
+    pub retries: usize,
+
+    /// Pause this many microseconds between SPI writes, for experimenting with marginal
+    /// configurations
+    ///
+    /// CS stays asserted and SCK idle between writes, so the FPGA only sees a pause in the clock.
+    #[arg(long, default_value = "0")]
+    pub inter_chunk_delay_us: u64,
+
+    /// Dummy clocks sent after the bitstream, rounded up to whole bytes
+    ///
+    /// The FPGA needs 49 after waiting up to 100 for configuration to complete.
+    #[arg(long, default_value = "144")]
+    pub trailing_clocks: usize,
+}
+
+/// Checks that the FPGA is present before a bitstream is streamed into it.
+#[derive(clap::Args, Clone, Debug)]
+pub struct Preflight {
+    /// The GPIO connected to the FPGA's CDONE output, checked to read low during reset and
+    /// high once configured
+    #[arg(long)]
+    pub cdone: Option<u8>,
+
+    /// Skip checking that CRESET_B follows its drive and CDONE falls during reset
+    ///
+    /// For boards that gate or buffer those signals.
+    #[arg(long)]
+    pub no_preflight: bool,
+}
+
+impl Preflight {
+    /// Check the reset line, and CDONE if configured, while the FPGA is held in reset.
+    fn check_reset(&self, port: &mut dyn Port) -> Result<()> {
+        if self.no_preflight {
+            return Ok(());
+        }
+
+        port.check_reset(true)?;
+
+        if let Some(cdone) = self.cdone {
+            if port.cdone(cdone)? {
+                bail!(
+                    "CDONE (GPIO {cdone}) reads high while the FPGA is held in reset; the FPGA \
+                     may be unpowered or disconnected"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that CDONE, if configured, rises once the whole bitstream has been sent.
+    fn check_done(&self, port: &mut dyn Port, timing: &Timing) -> Result<()> {
+        let Some(cdone) = self.cdone.filter(|_| !self.no_preflight) else {
+            return Ok(());
+        };
+
+        match boot::await_cdone(|| port.cdone(cdone), timing, timing.cdone_timeout)? {
+            Some(elapsed) => verbose!("CDONE rose {elapsed:.2?} after the bitstream was sent"),
+            None => bail!(
+                "CDONE (GPIO {cdone}) still reads low after the bitstream was sent ({}), so the \
+                 FPGA didn't accept it",
+                timing.describe_cdone(timing.cdone_timeout)
+            ),
+        }
+
+        Ok(())
+    }
+
+    /// Check the reset line once the FPGA has been released from reset.
+    fn check_release(&self, port: &mut dyn Port) -> Result<()> {
+        if self.no_preflight {
+            return Ok(());
+        }
+
+        Ok(port.check_reset(false)?)
+    }
+}
+
+/// The hardware underlying an [`SramProgrammer`]: the FPGA's control lines and the SPI bus the
+/// bitstream is streamed over.
+pub trait Port {
+    /// Drive CRESET_B, holding the FPGA in reset while `asserted`.
+    fn reset(&mut self, asserted: bool) -> anyhow::Result<()>;
+    /// Drive the FPGA's SPI_SS_B chip select.
+    fn select(&mut self, asserted: bool) -> anyhow::Result<()>;
+    /// Write `data` over SPI, returning how many bytes were written.
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize>;
+    /// Check that CRESET_B reads back at the level driven for `asserted`.
+    fn check_reset(&self, asserted: bool) -> anyhow::Result<()>;
+    /// Whether the FPGA's CDONE output, wired to GPIO `pin`, reads high.
+    fn cdone(&mut self, pin: u8) -> anyhow::Result<bool>;
+}
+
+/// Hardware SPI and the control lines on the Pi's GPIO.
+#[allow(dead_code)]
+struct Hardware {
+    spi: Spi,
+    gpio: Gpio,
+    fpga_reset: ActivePin,
+    fpga_cs: ActivePin,
+    /// Held deselected, so the flash stays off the bus.
+    flash_cs: ActivePin,
+    /// Acquired on first read.
+    cdone: Option<InputPin>,
+}
+
+impl Hardware {
+    /// Acquire the SPI bus and programming pins, with the FPGA held in reset.
+    fn new(baud: u32, pins: &PinConfig) -> anyhow::Result<Self> {
+        let bus = match pins.spi_bus {
+            SpiBus::Spi0 => Bus::Spi0,
+            SpiBus::Spi1 => Bus::Spi1,
+        };
+        let spi = Spi::new(bus, SlaveSelect::Ss0, baud, Mode::Mode0)
+            .with_context(|| "Failed to acquire SPI")?;
+
+        let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+        // Acquired already asserted, so a reset held by the flash programmer is never let go
+        let fpga_reset = ActivePin::new(
+            gpio.get(pins.reset)
+                .with_context(|| "Failed to acquire FPGA reset pin")?,
+            pins.reset_active_low,
+            true,
+        );
+        let fpga_cs = ActivePin::new(
+            gpio.get(pins.fpga_cs)
+                .with_context(|| "Failed to acquire FPGA CS pin")?,
+            pins.fpga_cs_active_low,
+            false,
+        );
+        let flash_cs = ActivePin::new(
+            gpio.get(pins.flash_cs)
+                .with_context(|| "Failed to acquire flash CS pin")?,
+            pins.flash_cs_active_low,
+            false,
+        );
+
+        Ok(Self {
+            spi,
+            gpio,
+            fpga_reset,
+            fpga_cs,
+            flash_cs,
+            cdone: None,
+        })
+    }
+}
+
+impl Port for Hardware {
+    fn reset(&mut self, asserted: bool) -> anyhow::Result<()> {
+        if asserted {
+            self.fpga_reset.assert();
+        } else {
+            self.fpga_reset.release();
+        }
+        Ok(())
+    }
+
+    fn select(&mut self, asserted: bool) -> anyhow::Result<()> {
+        if asserted {
+            self.fpga_cs.assert();
+        } else {
+            self.fpga_cs.release();
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.spi.write(data).map_err(|e| match e {
+            rppal::spi::Error::Io(e) => e,
+            e => std::io::Error::other(e),
+        })
+    }
+
+    fn check_reset(&self, asserted: bool) -> anyhow::Result<()> {
+        self.fpga_reset.check("CRESET_B", asserted)
+    }
+
+    fn cdone(&mut self, pin: u8) -> anyhow::Result<bool> {
+        let cdone = match &mut self.cdone {
+            Some(cdone) if cdone.pin() == pin => cdone,
+            cdone => cdone.insert(
+                self.gpio
+                    .get(pin)
+                    .with_context(|| format!("Failed to acquire CDONE pin {pin}"))?
+                    .into_input(),
+            ),
+        };
+        Ok(cdone.is_high())
+    }
+}
+
+/// A write failure after which the FPGA may have consumed part of a chunk, so the stream can't
+/// be resumed and configuration has to restart from the reset pulse.
+#[derive(Clone, Debug)]
+pub struct Corrupted(pub(crate) String);
+
+impl std::fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Corrupted {}
+
+pub struct SramProgrammer {
+    port: Box<dyn Port>,
+    timing: Timing,
+}
+
+impl SramProgrammer {
+    /// Acquire the SPI bus and programming pins, with the FPGA held in reset.
+    pub fn new(baud: u32, pins: &PinConfig, timing: &Timing) -> Result<Self> {
+        Ok(Self::with_port(
+            Box::new(Hardware::new(baud, pins)?),
+            timing,
+        ))
+    }
+
+    /// Configure the FPGA through `port`, which should hold the FPGA in reset until `begin`.
+    pub fn with_port(port: Box<dyn Port>, timing: &Timing) -> Self {
+        Self {
+            port,
+            timing: timing.clone(),
+        }
+    }
+
+    /// Reset the FPGA into configuration mode, failing before any of the bitstream is sent if
+    /// `preflight` finds the FPGA missing.
+    ///
+    /// Runs before every bitstream, so the same programmer can configure the FPGA repeatedly.
+    pub fn begin(&mut self, preflight: &Preflight) -> Result<()> {
+        sleep(self.timing.settle);
+        // Assert CRESET_B for at least 200 ns, ensuring the FPGA's CS is asserted when reset is
+        // released
+        self.port.reset(true)?;
+        self.port.select(true)?;
+        sleep(self.timing.reset_pulse);
+        preflight.check_reset(self.port.as_mut())?;
+        // Wait for at least 1200 us as the FPGA clears configuration memory
+        self.port.reset(false)?;
+        preflight.check_release(self.port.as_mut())?;
+        sleep(self.timing.post_reset_wait);
+
+        // Deassert CS and clock in 8 dummy bits
+        self.port.select(false)?;
+        self.port.write(&[0u8])?;
+        self.port.select(true)?;
+
+        // Device ready for configuration
+        Ok(())
+    }
+
+    /// Stream `data` into the FPGA, followed by `trailing_clocks` dummy clocks, checking
+    /// `cancel` before each chunk.
+    ///
+    /// The bitstream's header is always sent in a single write, however small `transfer` is,
+    /// since configuration reliably fails when the gaps between writes fall within it.
+    pub fn program_bytes(
+        &mut self,
+        data: &[u8],
+        spi: &SpiSettings,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let transfer = spi.transfer;
+        if transfer > 65536 {
+            bail!("SPI transfer buffer (set to {transfer}) must be less than 65536");
+        }
+
+        let header = bitstream::header_length(data).unwrap_or(0);
+        if header > transfer {
+            warning!(
+                "--transfer {transfer} would split the bitstream's {header} byte header across \
+                 writes; sending the header in one write"
+            );
+        }
+
+        let delay = std::time::Duration::from_micros(spi.inter_chunk_delay_us);
+        let mut bar = Progress::bytes("sram", data.len());
+        let mut offset = 0;
+        let mut chunks = 0;
+        let mut longest_gap = std::time::Duration::ZERO;
+        let mut last_write: Option<std::time::Instant> = None;
+
+        while offset < data.len() {
+            let end = if offset == 0 {
+                transfer.max(header)
+            } else {
+                offset + transfer
+            };
+            let block = &data[offset..end.min(data.len())];
+            cancel.check(offset)?;
+            report::beat("sram", offset);
+            if let Some(last) = last_write {
+                sleep(delay);
+                longest_gap = longest_gap.max(last.elapsed());
+            }
+
+            self.write_chunk(block)?;
+            last_write = Some(std::time::Instant::now());
+            chunks += 1;
+            offset += block.len();
+            bar.inc(block.len());
+        }
+        verbose!(
+            "Sent {} bytes in {chunks} writes; the longest gap between writes was {longest_gap:.2?}",
+            data.len()
+        );
+
+        // Sent separately so they're never counted as part of the image
+        let trailing = vec![0u8; spi.trailing_clocks.div_ceil(8)];
+        for block in trailing.chunks(transfer) {
+            self.write_chunk(block)?;
+        }
+
+        sleep(self.timing.settle);
+        self.port.select(false)?;
+        sleep(self.timing.settle);
+
+        Ok(())
+    }
+
+    /// Check that CDONE, if `preflight` names it, rises once the bitstream has been sent.
+    pub fn check_done(&mut self, preflight: &Preflight) -> Result<()> {
+        preflight.check_done(self.port.as_mut(), &self.timing)
+    }
+
+    /// The number of times a chunk is resent after being interrupted.
+    const INTERRUPT_RETRIES: usize = 8;
+
+    /// Write a chunk, resending it only when the write was interrupted before any of it was
+    /// transferred.
+    ///
+    /// Any other failure, including a short write, may have left part of the chunk in the FPGA,
+    /// so it's reported as [`Corrupted`] rather than resent.
+    fn write_chunk(&mut self, block: &[u8]) -> Result<()> {
+        for _ in 0..=Self::INTERRUPT_RETRIES {
+            match self.port.write(block) {
+                Ok(written) if written == block.len() => return Ok(()),
+                Ok(written) => {
+                    return Err(Corrupted(format!(
+                        "Only {written} of {} bytes were written to the SPI bus",
+                        block.len()
+                    ))
+                    .into())
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(Corrupted(format!("Error writing to SPI bus: {e}")).into()),
+            }
+        }
+
+        Err(Corrupted("SPI writes were interrupted too many times".into()).into())
+    }
+
+    /// Release the programming pins, along with any `extra` pins, to inputs.
+    pub fn reset(pins: &PinConfig, extra: &[u8]) -> Result<()> {
+        let gpio = Gpio::new().with_context(|| "Failed to acquire GPIO")?;
+
+        for pin in [pins.reset, pins.fpga_cs, pins.flash_cs]
+            .iter()
+            .chain(extra)
+        {
+            gpio.get(*pin)
+                .with_context(|| format!("Failed to acquire GPIO {pin}"))?
+                .into_input()
+                .set_reset_on_drop(false);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io::{Error, ErrorKind};
+    use std::rc::Rc;
+
+    /// How the port answers a write.
+    enum Outcome {
+        Complete,
+        Short(usize),
+        Fail(ErrorKind),
+        Eio,
+    }
+
+    /// A port answering writes with scripted outcomes, then completely, recording each write.
+    #[derive(Clone, Default)]
+    struct Scripted {
+        outcomes: Rc<RefCell<VecDeque<Outcome>>>,
+        writes: Rc<RefCell<Vec<Vec<u8>>>>,
+    }
+
+    impl Port for Scripted {
+        fn reset(&mut self, _: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn select(&mut self, _: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.writes.borrow_mut().push(data.to_vec());
+            match self.outcomes.borrow_mut().pop_front() {
+                None | Some(Outcome::Complete) => Ok(data.len()),
+                Some(Outcome::Short(written)) => Ok(written),
+                Some(Outcome::Fail(kind)) => Err(Error::from(kind)),
+                Some(Outcome::Eio) => Err(Error::from_raw_os_error(libc::EIO)),
+            }
+        }
+
+        fn check_reset(&self, _: bool) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn cdone(&mut self, _: u8) -> anyhow::Result<bool> {
+            Ok(true)
+        }
+    }
+
+    const DATA: [u8; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+    /// Program `DATA` in 4 byte chunks through a port answering with `outcomes`, returning the
+    /// result and the writes it saw.
+    fn program(outcomes: Vec<Outcome>) -> (Result<()>, Vec<Vec<u8>>) {
+        let port = Scripted::default();
+        *port.outcomes.borrow_mut() = outcomes.into();
+        let spi = SpiSettings {
+            baud: 1_000_000,
+            transfer: 4,
+            retries: 0,
+            inter_chunk_delay_us: 0,
+            trailing_clocks: 0,
+        };
+        let timing = Timing {
+            settle: std::time::Duration::ZERO,
+            ..Timing::default()
+        };
+
+        let mut programmer = SramProgrammer::with_port(Box::new(port.clone()), &timing);
+        let result = programmer.program_bytes(&DATA, &spi, &CancellationToken::new());
+        let writes = port.writes.borrow().clone();
+        (result, writes)
+    }
+
+    #[test]
+    fn interrupted_writes_are_resent() {
+        let (result, writes) = program(vec![
+            Outcome::Complete,
+            Outcome::Fail(ErrorKind::Interrupted),
+            Outcome::Fail(ErrorKind::Interrupted),
+        ]);
+        result.unwrap();
+        assert_eq!(
+            writes,
+            [
+                &DATA[..4],
+                &DATA[4..8],
+                &DATA[4..8],
+                &DATA[4..8],
+                &DATA[8..]
+            ]
+        );
+    }
+
+    #[test]
+    fn short_writes_are_corrupting() {
+        let (result, writes) = program(vec![Outcome::Complete, Outcome::Short(2)]);
+        assert!(result.unwrap_err().is_corrupted());
+        assert_eq!(writes, [&DATA[..4], &DATA[4..8]]);
+    }
+
+    #[test]
+    fn io_errors_are_corrupting() {
+        let (result, writes) = program(vec![Outcome::Eio]);
+        assert!(result.unwrap_err().is_corrupted());
+        assert_eq!(writes, [&DATA[..4]]);
+    }
+
+    #[test]
+    fn endless_interruptions_are_corrupting() {
+        let outcomes = (0..=SramProgrammer::INTERRUPT_RETRIES)
+            .map(|_| Outcome::Fail(ErrorKind::Interrupted))
+            .collect();
+        let (result, writes) = program(outcomes);
+        assert!(result.unwrap_err().is_corrupted());
+        assert_eq!(writes.len(), SramProgrammer::INTERRUPT_RETRIES + 1);
+    }
+}
@@ -0,0 +1,20 @@
+//! Status notifications for systemd, so `systemctl status` shows the current phase.
+//!
+//! Only built with the `systemd` feature. Notifications are sent to `$NOTIFY_SOCKET` as
+//! `sd_notify` does, and are silently dropped when it isn't set.
+
+/// Send a notification such as `STATUS=erase at 0x10000`.
+#[cfg(feature = "systemd")]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Ok(socket) = UnixDatagram::unbound() {
+        let _ = socket.send_to(state.as_bytes(), path);
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify(_state: &str) {}
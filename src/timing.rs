@@ -0,0 +1,234 @@
+use crate::chip::{ChipProfile, Erase};
+use crate::warning;
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// Delays used by the reset and wake sequences of both programmers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// Settling time after acquiring the pins and around the final CS release.
+    pub settle: Duration,
+    /// How long CRESET_B is held asserted.
+    pub reset_pulse: Duration,
+    /// How long to wait after releasing CRESET_B while the FPGA clears configuration memory.
+    pub post_reset_wait: Duration,
+    /// Delay after waking the flash before the first command.
+    pub wake_delay: Duration,
+    /// Delay after asserting the flash CS before clocking.
+    pub cs_setup: Duration,
+    /// Delay after releasing the flash CS before the next transaction.
+    pub cs_hold: Duration,
+    /// Delay after releasing CRESET_B, or sending the bitstream, before CDONE is first polled.
+    pub cdone_delay: Duration,
+    /// The interval between CDONE polls.
+    pub cdone_poll: Duration,
+    /// How long CDONE is polled before configuration counts as failed.
+    pub cdone_timeout: Duration,
+    /// Settling time before the pins are released to inputs at the end of a command.
+    pub release_settle: Duration,
+    /// The chip family whose busy limits apply, detected from the JEDEC ID when unset.
+    pub chip_profile: Option<ChipProfile>,
+    /// The erase commands the flash implements, read from its SFDP table when unset.
+    pub erases: Option<Vec<Erase>>,
+    /// How many times the session's median busy time makes a block erase or page program
+    /// slow enough to flag.
+    pub slow_factor: u32,
+    /// The flash's clock in Hz on a hardware SPI bus, each backend's own default when unset.
+    pub flash_clock: Option<u32>,
+    /// The dummy clocks after a Fast Read's address, for reading with 0x0B rather than 0x03.
+    pub fast_read: Option<u8>,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            settle: Duration::from_millis(1),
+            reset_pulse: Duration::from_millis(1),
+            post_reset_wait: Duration::from_millis(10),
+            wake_delay: Duration::from_micros(1),
+            cs_setup: Duration::from_micros(1),
+            cs_hold: Duration::from_micros(1),
+            cdone_delay: Duration::ZERO,
+            cdone_poll: Duration::from_micros(10),
+            cdone_timeout: Duration::from_secs(1),
+            release_settle: Duration::ZERO,
+            chip_profile: None,
+            erases: None,
+            slow_factor: 3,
+            flash_clock: None,
+            fast_read: None,
+        }
+    }
+}
+
+impl Timing {
+    pub const KEYS: [&'static str; 10] = [
+        "settle",
+        "reset_pulse",
+        "post_reset_wait",
+        "wake_delay",
+        "cs_setup",
+        "cs_hold",
+        "cdone_delay",
+        "cdone_poll",
+        "cdone_timeout",
+        "release_settle",
+    ];
+
+    fn field(&mut self, key: &str) -> Option<&mut Duration> {
+        match key {
+            "settle" => Some(&mut self.settle),
+            "reset_pulse" => Some(&mut self.reset_pulse),
+            "post_reset_wait" => Some(&mut self.post_reset_wait),
+            "wake_delay" => Some(&mut self.wake_delay),
+            "cs_setup" => Some(&mut self.cs_setup),
+            "cs_hold" => Some(&mut self.cs_hold),
+            "cdone_delay" => Some(&mut self.cdone_delay),
+            "cdone_poll" => Some(&mut self.cdone_poll),
+            "cdone_timeout" => Some(&mut self.cdone_timeout),
+            "release_settle" => Some(&mut self.release_settle),
+            _ => None,
+        }
+    }
+
+    /// The datasheet minimum for a field, if it has one.
+    fn minimum(key: &str) -> Option<Duration> {
+        match key {
+            // iCE40 CRESET_B low pulse width
+            "reset_pulse" => Some(Duration::from_nanos(200)),
+            // iCE40 configuration memory clear time
+            "post_reset_wait" => Some(Duration::from_micros(1200)),
+            // Flash CS setup and hold times
+            "cs_setup" | "cs_hold" => Some(Duration::from_nanos(5)),
+            _ => None,
+        }
+    }
+
+    /// Set a field by name, clamping it to its datasheet minimum with a warning.
+    pub fn set(&mut self, key: &str, value: Duration) -> Result<()> {
+        let minimum = Self::minimum(key);
+        let field = self.field(key).with_context(|| {
+            format!(
+                "Unknown timing {key:?} (expected one of {})",
+                Self::KEYS.join(", ")
+            )
+        })?;
+
+        *field = match minimum {
+            Some(minimum) if value < minimum => {
+                warning!(
+                    "Warning: timing {key} of {value:?} is below the minimum of {minimum:?}, \
+                     using {minimum:?}"
+                );
+                minimum
+            }
+            _ => value,
+        };
+
+        Ok(())
+    }
+
+    /// Build a timing from the defaults with the given overrides applied in order.
+    pub fn with_overrides(overrides: &[(String, Duration)]) -> Result<Self> {
+        let mut timing = Self::default();
+        for (key, value) in overrides {
+            timing.set(key, *value)?;
+        }
+        Ok(timing)
+    }
+
+    /// Every delay by name, in the order of [`Timing::KEYS`].
+    pub fn values(&self) -> Vec<(&'static str, Duration)> {
+        let mut timing = self.clone();
+        Self::KEYS
+            .iter()
+            .map(|key| (*key, *timing.field(key).unwrap()))
+            .collect()
+    }
+
+    /// Every delay by name, for `-v`.
+    pub fn describe(&self) -> String {
+        let fields: Vec<_> = self
+            .values()
+            .iter()
+            .map(|(key, value)| format!("{key}={value:?}"))
+            .collect();
+
+        fields.join(", ")
+    }
+
+    /// How CDONE is polled, for messages reporting that it never rose.
+    pub fn describe_cdone(&self, timeout: Duration) -> String {
+        let mut description = format!("polled every {:?} for {timeout:?}", self.cdone_poll);
+        if !self.cdone_delay.is_zero() {
+            description += &format!(" after a {:?} delay", self.cdone_delay);
+        }
+
+        description
+    }
+}
+
+/// Parse a duration such as `10ms`, `200ns`, `1.5s`, or `50us`.
+pub fn parse_duration(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .with_context(|| format!("Duration {text:?} is missing a unit (ns, us, ms, or s)"))?;
+    let (value, unit) = text.split_at(split);
+    let value: f64 = value
+        .parse()
+        .with_context(|| format!("Invalid duration {text:?}"))?;
+
+    let seconds = match unit.trim() {
+        "ns" => value / 1e9,
+        "us" | "µs" => value / 1e6,
+        "ms" => value / 1e3,
+        "s" => value,
+        unit => anyhow::bail!("Unknown duration unit {unit:?} (expected ns, us, ms, or s)"),
+    };
+
+    Duration::try_from_secs_f64(seconds)
+        .with_context(|| format!("Duration {text:?} is out of range"))
+}
+
+/// Parse a Fast Read's dummy clocks, which are clocked out as whole bytes.
+pub fn parse_dummy_clocks(text: &str) -> Result<u8> {
+    let clocks: u8 = text
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid number of dummy clocks {text:?}"))?;
+    if clocks == 0 || !clocks.is_multiple_of(8) || clocks > 32 {
+        anyhow::bail!("Dummy clocks are sent as whole bytes, so must be 8, 16, 24, or 32");
+    }
+
+    Ok(clocks)
+}
+
+/// Parse a `key=value` timing override.
+pub fn parse_override(text: &str) -> Result<(String, Duration)> {
+    let (key, value) = text
+        .split_once('=')
+        .with_context(|| format!("Expected key=value, got {text:?}"))?;
+
+    Ok((key.trim().into(), parse_duration(value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("250ns").unwrap(), Duration::from_nanos(250));
+        assert_eq!(
+            parse_duration("1.5 ms").unwrap(),
+            Duration::from_micros(1500)
+        );
+        assert_eq!(parse_duration("2s").unwrap(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn rejects_durations_out_of_range() {
+        assert!(parse_duration("99999999999999999999999s").is_err());
+    }
+}
@@ -0,0 +1,270 @@
+//! Recording and replay of flash transactions.
+//!
+//! A trace is a plain-text log with one CS-framed transaction per line, written as the bytes
+//! clocked out followed by the bytes clocked in:
+//!
+//! ```text
+//! # operation: flash
+//! # address: 0
+//! # length: 104090
+//! > ab
+//! > 05 < 00
+//! ```
+//!
+//! Replaying a trace feeds the recorded responses back into the same higher-level logic and
+//! checks that every byte it clocks out matches the recording, so the first divergence
+//! pinpoints where a failing run went wrong.
+
+use crate::flash::{Port, ReadWidth};
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::path::Path;
+
+/// The formats a trace can be exported to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// A Value Change Dump of the CS, SCK, SDI, SDO, WP#, and HOLD# lines
+    #[default]
+    Vcd,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transaction {
+    pub write: Vec<u8>,
+    pub read: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub operation: String,
+    pub address: usize,
+    pub length: usize,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Trace {
+    pub fn new(operation: &str, address: usize, length: usize) -> Self {
+        Self {
+            operation: operation.into(),
+            address,
+            length,
+            transactions: Vec::new(),
+        }
+    }
+
+    /// The dummy clocks of the recording's Fast Reads, or `None` when it read with 0x03.
+    pub fn fast_read(&self) -> Option<u8> {
+        self.transactions.iter().find_map(|transaction| {
+            let header = match transaction.write.first() {
+                Some(0x0B) => 4,
+                Some(0x0C) => 5,
+                _ => return None,
+            };
+            Some((transaction.write.len().saturating_sub(header) * 8) as u8)
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, self.to_string())
+            .with_context(|| format!("Error writing trace to {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading trace from {}", path.display()))?;
+        text.parse()
+    }
+}
+
+fn write_hex(output: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        write!(output, " {byte:02x}").unwrap();
+    }
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    text.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).with_context(|| format!("Invalid byte {byte:?}")))
+        .collect()
+}
+
+impl std::fmt::Display for Trace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# operation: {}", self.operation)?;
+        writeln!(f, "# address: {}", self.address)?;
+        writeln!(f, "# length: {}", self.length)?;
+
+        for transaction in &self.transactions {
+            let mut line = String::from(">");
+            write_hex(&mut line, &transaction.write);
+            if !transaction.read.is_empty() {
+                line.push_str(" <");
+                write_hex(&mut line, &transaction.read);
+            }
+            writeln!(f, "{line}")?;
+        }
+
+        std::fmt::Result::Ok(())
+    }
+}
+
+impl std::str::FromStr for Trace {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let mut trace = Trace::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let context = || format!("Malformed trace at line {}", number + 1);
+
+            if let Some(header) = line.strip_prefix('#') {
+                let Some((key, value)) = header.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim();
+                match key.trim() {
+                    "operation" => trace.operation = value.into(),
+                    "address" => trace.address = value.parse().with_context(context)?,
+                    "length" => trace.length = value.parse().with_context(context)?,
+                    _ => {}
+                }
+            } else if let Some(body) = line.strip_prefix('>') {
+                let (write, read) = body.split_once('<').unwrap_or((body, ""));
+                trace.transactions.push(Transaction {
+                    write: parse_hex(write).with_context(context)?,
+                    read: parse_hex(read).with_context(context)?,
+                });
+            } else if !line.is_empty() {
+                anyhow::bail!("{}", context());
+            }
+        }
+
+        Ok(trace)
+    }
+}
+
+/// A port that answers from a recorded trace, failing at the first divergence.
+pub struct Replay {
+    transactions: VecDeque<Transaction>,
+    /// The widest read in the recording, so the logic settles on the same width it did then.
+    width: ReadWidth,
+    current: Option<Transaction>,
+    index: usize,
+    written: usize,
+    read: usize,
+}
+
+impl Replay {
+    pub fn new(trace: Trace) -> Self {
+        let width = trace
+            .transactions
+            .iter()
+            .filter_map(|transaction| match transaction.write.first() {
+                Some(0x6B | 0x6C) => Some(ReadWidth::Quad),
+                Some(0x3B | 0x3C) => Some(ReadWidth::Dual),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(ReadWidth::Single);
+
+        Self {
+            width,
+            transactions: trace.transactions.into(),
+            current: None,
+            index: 0,
+            written: 0,
+            read: 0,
+        }
+    }
+
+    fn current(&mut self) -> Result<&Transaction> {
+        let index = self.index;
+        self.current
+            .as_ref()
+            .with_context(|| format!("Transaction {index}: logic clocked data with CS deasserted"))
+    }
+}
+
+impl Port for Replay {
+    fn select(&mut self) -> Result<()> {
+        let Some(next) = self.transactions.pop_front() else {
+            anyhow::bail!(
+                "The trace ended after {} transactions, but the logic began another",
+                self.index
+            );
+        };
+
+        self.index += 1;
+        self.current = Some(next);
+        self.written = 0;
+        self.read = 0;
+
+        Ok(())
+    }
+
+    fn deselect(&mut self) -> Result<()> {
+        let index = self.index;
+        let (written, read) = (self.written, self.read);
+        let current = self.current()?;
+
+        if written != current.write.len() || read != current.read.len() {
+            anyhow::bail!(
+                "Transaction {index}: the trace recorded {} bytes written and {} read, but the logic \
+                 wrote {written} and read {read}",
+                current.write.len(),
+                current.read.len(),
+            );
+        }
+
+        self.current = None;
+        Ok(())
+    }
+
+    fn write(&mut self, byte: u8) -> Result<()> {
+        let (index, written) = (self.index, self.written);
+        let current = self.current()?;
+
+        match current.write.get(written) {
+            Some(&expected) if expected == byte => {}
+            Some(&expected) => anyhow::bail!(
+                "Transaction {index}, byte {written}: the logic wrote {byte:#04x} where the trace \
+                 recorded {expected:#04x}"
+            ),
+            None => anyhow::bail!(
+                "Transaction {index}: the logic wrote {byte:#04x} beyond the {} recorded bytes",
+                current.write.len()
+            ),
+        }
+
+        self.written += 1;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        let (index, read) = (self.index, self.read);
+        let current = self.current()?;
+
+        let value = *current.read.get(read).with_context(|| {
+            format!(
+                "Transaction {index}: the logic read beyond the {} recorded bytes",
+                current.read.len()
+            )
+        })?;
+
+        self.read += 1;
+        Ok(value)
+    }
+
+    fn read_width(&self) -> ReadWidth {
+        self.width
+    }
+
+    fn read_wide(&mut self, _width: ReadWidth, buffer: &mut [u8]) -> Result<()> {
+        for byte in buffer {
+            *byte = self.read()?;
+        }
+        Ok(())
+    }
+}
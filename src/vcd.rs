@@ -0,0 +1,172 @@
+//! Exporting a recorded trace as a Value Change Dump, for viewing beside a logic analyzer
+//! capture in GTKWave or similar.
+//!
+//! The pin-level timeline is rebuilt from the recorded bytes the way the bit-banged port clocks
+//! them: MSB first, with the data line set while SCK is low and sampled on its rising edge, and
+//! two or four bits per clock for reads in a Fast Read Dual or Quad Output transaction, the
+//! last two on WP# and HOLD#. Every bit takes one
+//! period of the given clock, and CS is held around each transaction for the `cs_setup` and
+//! `cs_hold` timings, so the timing is consistent rather than what the run actually took.
+
+use crate::timing::Timing;
+use crate::trace::Trace;
+use std::fmt::Write;
+
+/// Fast Read Dual Output, with 3-byte and 4-byte addresses.
+const FAST_READ_DUAL: [u8; 2] = [0x3B, 0x3C];
+/// Fast Read Quad Output, with 3-byte and 4-byte addresses.
+const FAST_READ_QUAD: [u8; 2] = [0x6B, 0x6C];
+
+const CS: usize = 0;
+const SCK: usize = 1;
+const SDI: usize = 2;
+const SDO: usize = 3;
+const WP: usize = 4;
+const HOLD: usize = 5;
+const SIGNALS: [(&str, char); 6] = [
+    ("cs", '!'),
+    ("sck", '"'),
+    ("sdi", '#'),
+    ("sdo", '$'),
+    ("wp", '%'),
+    ("hold", '&'),
+];
+
+/// Value changes, stamped with the time each is made at.
+struct Timeline {
+    output: String,
+    now: u64,
+    stamped: Option<u64>,
+    levels: [char; 6],
+    half_period: u64,
+}
+
+impl Timeline {
+    fn set(&mut self, signal: usize, level: char) {
+        if self.levels[signal] == level {
+            return;
+        }
+        if self.stamped != Some(self.now) {
+            let _ = writeln!(self.output, "#{}", self.now);
+            self.stamped = Some(self.now);
+        }
+
+        let _ = writeln!(self.output, "{level}{}", SIGNALS[signal].1);
+        self.levels[signal] = level;
+    }
+
+    fn wait(&mut self, nanoseconds: u64) {
+        self.now += nanoseconds;
+    }
+
+    /// One clock with the data lines already set: SCK high for half a period, then low.
+    fn clock(&mut self) {
+        self.wait(self.half_period);
+        self.set(SCK, '1');
+        self.wait(self.half_period);
+        self.set(SCK, '0');
+    }
+}
+
+fn level(bit: bool) -> char {
+    if bit {
+        '1'
+    } else {
+        '0'
+    }
+}
+
+/// Render `trace` as a VCD, drawing each bit at `clock_khz` and CS at its electrical level.
+pub fn export(trace: &Trace, timing: &Timing, clock_khz: u32, cs_active_low: bool) -> String {
+    let mut output = String::new();
+    let _ = writeln!(
+        output,
+        "$comment lattice-prog {} trace, {} bytes at {:#x} $end",
+        trace.operation, trace.length, trace.address
+    );
+    output.push_str("$timescale 1ns $end\n$scope module flash $end\n");
+    for (name, id) in SIGNALS {
+        let _ = writeln!(output, "$var wire 1 {id} {name} $end");
+    }
+    output.push_str("$upscope $end\n$enddefinitions $end\n");
+
+    let inactive = level(cs_active_low);
+    let active = level(!cs_active_low);
+    let _ = writeln!(
+        output,
+        "#0\n$dumpvars\n{inactive}{}\n0{}\nx{}\nz{}\nz{}\nz{}\n$end",
+        SIGNALS[CS].1,
+        SIGNALS[SCK].1,
+        SIGNALS[SDI].1,
+        SIGNALS[SDO].1,
+        SIGNALS[WP].1,
+        SIGNALS[HOLD].1
+    );
+
+    let mut timeline = Timeline {
+        output,
+        now: 0,
+        stamped: Some(0),
+        levels: [inactive, '0', 'x', 'z', 'z', 'z'],
+        half_period: (500_000 / u64::from(clock_khz.max(1))).max(1),
+    };
+    let cs_setup = timing.cs_setup.as_nanos() as u64;
+    let cs_hold = timing.cs_hold.as_nanos() as u64;
+
+    for transaction in &trace.transactions {
+        timeline.wait(timeline.half_period * 2);
+        timeline.set(CS, active);
+        timeline.wait(cs_setup);
+
+        for byte in &transaction.write {
+            timeline.set(SDO, 'z');
+            for bit in (0..8).rev() {
+                timeline.set(SDI, level(byte & 1 << bit != 0));
+                timeline.clock();
+            }
+        }
+
+        let opcode = transaction.write.first();
+        let dual = opcode.is_some_and(|opcode| FAST_READ_DUAL.contains(opcode));
+        let quad = opcode.is_some_and(|opcode| FAST_READ_QUAD.contains(opcode));
+        for byte in &transaction.read {
+            if quad {
+                for nibble in (0..2).rev() {
+                    for (line, signal) in [SDI, SDO, WP, HOLD].into_iter().enumerate() {
+                        timeline.set(signal, level(byte & 1 << (nibble * 4 + line) != 0));
+                    }
+                    timeline.clock();
+                }
+            } else if dual {
+                for pair in (0..4).rev() {
+                    timeline.set(SDO, level(byte & 2 << (pair * 2) != 0));
+                    timeline.set(SDI, level(byte & 1 << (pair * 2) != 0));
+                    timeline.clock();
+                }
+            } else {
+                for bit in (0..8).rev() {
+                    timeline.set(SDO, level(byte & 1 << bit != 0));
+                    timeline.clock();
+                }
+            }
+        }
+
+        timeline.set(CS, inactive);
+        timeline.set(SDO, 'z');
+        if (dual || quad) && !transaction.read.is_empty() {
+            timeline.set(SDI, 'z');
+        }
+        if quad && !transaction.read.is_empty() {
+            timeline.set(WP, 'z');
+            timeline.set(HOLD, 'z');
+        }
+        timeline.wait(cs_hold);
+    }
+
+    let _ = writeln!(
+        timeline.output,
+        "#{}",
+        timeline.now + timeline.half_period * 2
+    );
+    timeline.output
+}
@@ -0,0 +1,218 @@
+//! Supervision of hardware work.
+//!
+//! The hardware-touching work runs on its own thread and reports progress through [`beat`].
+//! If no progress arrives within the timeout, the work is presumed wedged (for example inside
+//! a driver call), so the pins are released, a failure report is written for whoever finds the
+//! unit, and the process exits with [`EXIT_CODE`].
+//!
+//! After SIGTERM the work is given a grace period to stop at its next cancellation check. If
+//! it's still running when the period ends, the operation is abandoned the same way and the
+//! process exits with [`TERMINATED_EXIT_CODE`].
+
+use crate::pins::PinConfig;
+use crate::warning;
+use crate::{cancel, progress, systemd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The exit code used when the watchdog fires.
+pub const EXIT_CODE: i32 = 3;
+
+/// The exit code used when the work outlives the SIGTERM grace period, as if killed by it.
+pub const TERMINATED_EXIT_CODE: i32 = 128 + libc::SIGTERM;
+
+struct Progress {
+    phase: &'static str,
+    address: usize,
+    at: Instant,
+}
+
+static PROGRESS: Mutex<Option<Progress>> = Mutex::new(None);
+
+/// Whether releasing the pins leaves CRESET_B alone, for work reading while the FPGA runs.
+static KEEP_FPGA_RESET: AtomicBool = AtomicBool::new(false);
+
+/// The `--backend` the releasing process runs with, when not the Pi's.
+static BACKEND: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// The pin flags the releasing process runs with, so it releases the lines actually in use.
+static PINS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Where the failure report is written when work is abandoned.
+static REPORT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Report progress in the given phase.
+pub fn beat(phase: &'static str, address: usize) {
+    if let Ok(mut progress) = PROGRESS.lock() {
+        *progress = Some(Progress {
+            phase,
+            address,
+            at: Instant::now(),
+        });
+    }
+}
+
+/// Stop timing progress until the next beat, while waiting on the user.
+pub fn pause() {
+    if let Ok(mut progress) = PROGRESS.lock() {
+        *progress = None;
+    }
+}
+
+/// Leave CRESET_B untouched if the pins have to be released.
+pub fn keep_fpga_reset() {
+    KEEP_FPGA_RESET.store(true, Ordering::SeqCst);
+}
+
+/// Release the pins through `backend` rather than the Pi's.
+pub fn set_backend(backend: &'static str) {
+    if let Ok(mut current) = BACKEND.lock() {
+        *current = Some(backend);
+    }
+}
+
+/// Release the pins of `pins` rather than the default ones.
+pub fn set_pins(pins: &PinConfig) {
+    if let Ok(mut current) = PINS.lock() {
+        *current = pins.args();
+    }
+}
+
+/// Write the failure report to `path` when work is abandoned.
+pub fn set_report(path: PathBuf) {
+    if let Ok(mut current) = REPORT.lock() {
+        *current = Some(path);
+    }
+}
+
+/// Run `work` on a dedicated thread, aborting the process if it stops making progress or
+/// outlives `grace` after SIGTERM.
+///
+/// A timeout of zero disables the progress check.
+pub fn supervise<T, F>(timeout: Duration, grace: Duration, work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    beat("start", 0);
+    let mut terminated_at = None;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(work());
+    });
+
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(result) => return result,
+            Err(RecvTimeoutError::Disconnected) => {
+                warning!("The hardware thread panicked");
+                release_pins();
+                progress::done(false);
+                std::process::exit(1);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let last = PROGRESS.lock().ok().and_then(|progress| {
+            progress
+                .as_ref()
+                .map(|p| (p.phase, p.address, p.at.elapsed()))
+        });
+        if let Some((phase, address, _)) = last {
+            systemd::notify(&format!("STATUS={phase} at {address:#x}"));
+        }
+
+        if cancel::terminated() {
+            let since = *terminated_at.get_or_insert_with(|| {
+                systemd::notify("STOPPING=1");
+                Instant::now()
+            });
+
+            if since.elapsed() > grace {
+                let (phase, address, elapsed) = last.unwrap_or(("start", 0, since.elapsed()));
+                warning!(
+                    "Stopping after the {grace:?} SIGTERM grace period, abandoning the operation \
+                     mid-{phase} at {address:#x}; re-verify the flash before relying on it"
+                );
+                save_report("terminated", phase, address, elapsed);
+                release_pins();
+                progress::done(false);
+                std::process::exit(TERMINATED_EXIT_CODE);
+            }
+        }
+
+        let stalled = last.filter(|(_, _, elapsed)| !timeout.is_zero() && *elapsed > timeout);
+        if let Some((phase, address, elapsed)) = stalled {
+            warning!(
+                "Watchdog: no progress for {:.1?} (limit {timeout:?}); the last progress was in \
+                 phase {phase} at address {address:#x}",
+                elapsed
+            );
+            save_report("stalled", phase, address, elapsed);
+            release_pins();
+            progress::done(false);
+            std::process::exit(EXIT_CODE);
+        }
+    }
+}
+
+/// Release the pins from a separate process.
+///
+/// The wedged thread still owns the pins within this process, so they can't be reacquired
+/// here. A fresh process has no such claim and can return them to inputs.
+fn release_pins() {
+    let mut args = vec!["release".to_string()];
+    if KEEP_FPGA_RESET.load(Ordering::SeqCst) {
+        args.push("--no-fpga-reset".into());
+    }
+    if let Some(backend) = BACKEND.lock().ok().and_then(|backend| *backend) {
+        args.extend(["--backend".into(), backend.into()]);
+    }
+    if let Ok(pins) = PINS.lock() {
+        args.extend(pins.iter().cloned());
+    }
+    let released = std::env::current_exe()
+        .and_then(|exe| std::process::Command::new(exe).args(&args).status());
+
+    if let Err(e) = released {
+        warning!("Failed to release pins: {e}");
+    }
+}
+
+/// Write the failure report to the path set with [`set_report`], if any.
+fn save_report(reason: &str, phase: &str, address: usize, elapsed: Duration) {
+    let Some(path) = REPORT.lock().ok().and_then(|path| path.clone()) else {
+        return;
+    };
+    match write_report(&path, reason, phase, address, elapsed) {
+        Ok(()) => warning!("Wrote the failure report to {}", path.display()),
+        Err(e) => warning!(
+            "Failed to write the failure report to {}: {e}",
+            path.display()
+        ),
+    }
+}
+
+/// Write a failure report to `path`: why the work was abandoned, and its last progress.
+fn write_report(
+    path: &Path,
+    reason: &str,
+    phase: &str,
+    address: usize,
+    elapsed: Duration,
+) -> std::io::Result<()> {
+    let unix = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    std::fs::write(
+        path,
+        format!(
+            "{{\"reason\":\"{reason}\",\"phase\":\"{phase}\",\"address\":{address},\
+             \"elapsed_ms\":{},\"time\":{unix}}}\n",
+            elapsed.as_millis()
+        ),
+    )
+}
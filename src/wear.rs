@@ -0,0 +1,155 @@
+//! Per-block erase counts, kept in a wear file to warn before the flash wears out.
+//!
+//! The file is TOML, with a table per flash chip keyed by its JEDEC ID and unique ID (where the
+//! chip has one), mapping each 64 KiB block's address to the number of times it was erased,
+//! wholly or in part.
+//! A second table per chip keeps the block's most recent erase times in milliseconds, oldest
+//! first, since worn blocks take longer to erase:
+//!
+//! ```toml
+//! [chip.ef4018-d26358b7cb4f2a2c]
+//! 0x000000 = 12
+//! 0x010000 = 4031
+//!
+//! [erase_ms.ef4018-d26358b7cb4f2a2c]
+//! 0x010000 = [410, 415, 432]
+//! ```
+//!
+//! Each erase is written out as it's issued, by replacing the file with an updated copy, so
+//! the counts stay accurate and the file intact however a run ends.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// The number of erase times kept for each block.
+const HISTORY: usize = 16;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct WearFile {
+    #[serde(default)]
+    chip: BTreeMap<String, BTreeMap<String, u64>>,
+    #[serde(default)]
+    erase_ms: BTreeMap<String, BTreeMap<String, Vec<u64>>>,
+}
+
+impl WearFile {
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)
+                .with_context(|| format!("Invalid wear file {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Error reading {}", path.display())),
+        }
+    }
+
+    /// Replace the file in one step, so it's never left partly written.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+
+        std::fs::write(&temporary, toml::to_string(self)?)
+            .with_context(|| format!("Error writing {}", temporary.display()))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("Error replacing {}", path.display()))
+    }
+}
+
+#[cfg(not(feature = "read-only"))]
+/// Count every block `programmer` erases from now on in the wear file at `path`, along with
+/// how long each erase took.
+///
+/// Failing to update the file only warns, since the counts are for observation and mustn't
+/// stop a flash partway.
+pub fn track(programmer: &mut crate::flash::FlashProgrammer, path: &Path) -> Result<()> {
+    let chip = chip(programmer)?;
+    let path = path.to_owned();
+    programmer.on_erase(Box::new(move |block, duration| {
+        let result = WearFile::load(&path).and_then(|mut file| {
+            let key = format!("{block:#08x}");
+            *file
+                .chip
+                .entry(chip.clone())
+                .or_default()
+                .entry(key.clone())
+                .or_default() += 1;
+
+            let history = file
+                .erase_ms
+                .entry(chip.clone())
+                .or_default()
+                .entry(key)
+                .or_default();
+            history.push(duration.as_millis() as u64);
+            if history.len() > HISTORY {
+                history.drain(..history.len() - HISTORY);
+            }
+
+            file.save(&path)
+        });
+
+        if let Err(e) = result {
+            crate::warning!("Failed to record the erase of {block:#x} in the wear file: {e:#}");
+        }
+    }));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+/// The key a flash chip's records are kept under: its JEDEC ID, and its unique ID where it has
+/// one.
+pub fn chip(programmer: &mut crate::flash::FlashProgrammer) -> Result<String> {
+    let jedec = programmer.info()?.jedec;
+    let mut chip = crate::backup::hex(&jedec);
+    if let Some(id) = programmer.unique_id()? {
+        write!(chip, "-{}", crate::backup::hex(&id))?;
+    }
+
+    Ok(chip)
+}
+
+/// Summarize the wear file at `path`, marking blocks erased at least `threshold` times.
+pub fn report(path: &Path, threshold: u64) -> Result<String> {
+    let file = WearFile::load(path)?;
+    if file.chip.is_empty() {
+        return Ok(format!("No erases recorded in {}", path.display()));
+    }
+
+    let mut output = String::new();
+    for (chip, blocks) in &file.chip {
+        let total: u64 = blocks.values().sum();
+        writeln!(
+            output,
+            "Flash {chip}: {total} erases over {} blocks",
+            blocks.len()
+        )?;
+
+        let times = file.erase_ms.get(chip);
+        for (block, count) in blocks {
+            let marker = if *count >= threshold { "  <- worn" } else { "" };
+            write!(output, "  {block}: {count}{marker}")?;
+            if let Some(history) = times.and_then(|times| times.get(block)) {
+                let history: Vec<_> = history.iter().map(u64::to_string).collect();
+                write!(output, "  (erase ms: {})", history.join(", "))?;
+            }
+            writeln!(output)?;
+        }
+
+        if let Some((block, max)) = blocks.iter().max_by_key(|(_, count)| **count) {
+            writeln!(output, "  Maximum: {max} at {block}")?;
+        }
+        let worn = blocks.values().filter(|count| **count >= threshold).count();
+        if worn > 0 {
+            writeln!(
+                output,
+                "  {worn} blocks have reached the warning threshold of {threshold} erases"
+            )?;
+        }
+    }
+
+    Ok(output.trim_end().into())
+}
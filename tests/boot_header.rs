@@ -0,0 +1,136 @@
+//! Flashing a bitstream away from offset 0 against the simulated flash: the placement is
+//! refused unless a multiboot header at offset 0 points at it, and `--write-boot-header` writes
+//! one that does.
+#![cfg(not(feature = "read-only"))]
+
+use lattice_prog::bitstream;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A simulated flash image and a bitstream to write to it, unique to the test.
+struct Files {
+    image: PathBuf,
+    bitstream: PathBuf,
+}
+
+impl Files {
+    fn new(name: &str) -> Self {
+        let path = |kind: &str| {
+            std::env::temp_dir().join(format!(
+                "lattice-prog-boot-header-{name}-{kind}-{}.bin",
+                std::process::id()
+            ))
+        };
+        let files = Self {
+            image: path("flash"),
+            bitstream: path("bitstream"),
+        };
+        let _ = std::fs::remove_file(&files.image);
+
+        // An empty comment, the preamble, and some configuration data
+        let mut data = vec![0xFF, 0x00, 0x00, 0xFF, 0x7E, 0xAA, 0x99, 0x7E];
+        data.extend((0..1024).map(|i| (i * 3) as u8));
+        std::fs::write(&files.bitstream, data).unwrap();
+        files
+    }
+
+    /// Flash the bitstream at `offset` with `args`, returning stderr.
+    fn flash(&self, offset: &str, args: &[&str]) -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+            .args(["--backend", "mock", "--yes", "--mock-image"])
+            .arg(&self.image)
+            .args(["flash", "-o", offset])
+            .args(args)
+            .arg(&self.bitstream)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    }
+
+    fn flash_contents(&self) -> Vec<u8> {
+        std::fs::read(&self.image).unwrap_or_default()
+    }
+
+    /// Whether the flash holds the bitstream at `address`.
+    fn holds(&self, address: usize) -> bool {
+        let bitstream = std::fs::read(&self.bitstream).unwrap();
+        self.flash_contents()
+            .get(address..address + bitstream.len())
+            .is_some_and(|data| data == bitstream)
+    }
+}
+
+impl Drop for Files {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.image);
+        let _ = std::fs::remove_file(&self.bitstream);
+    }
+}
+
+/// Fail if a flash reported an error or warning, past its progress lines.
+fn check_clean(stderr: &str) {
+    assert!(
+        !stderr.contains("Failed") && !stderr.contains("Warning"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn refuses_a_bitstream_nothing_boots() {
+    let files = Files::new("refuse");
+    let stderr = files.flash("0x20000", &[]);
+    assert!(
+        stderr.contains("there's no multiboot header at offset 0, so it won't find the image"),
+        "{stderr}"
+    );
+    assert!(!files.holds(0x20000));
+}
+
+#[test]
+fn writes_a_header_pointing_at_the_bitstream() {
+    let files = Files::new("write");
+    files.flash("0x20000", &["--write-boot-header"]);
+    assert!(files.holds(0x20000));
+
+    let head = &files.flash_contents()[..bitstream::MULTIBOOT_HEADER_SIZE];
+    assert_eq!(head, bitstream::multiboot_header(0x20000));
+    assert_eq!(bitstream::boot_addresses(head), [0x20000; 5]);
+
+    // The header is read back on the next flash, so the same placement is accepted
+    let stderr = files.flash("0x20000", &[]);
+    check_clean(&stderr);
+}
+
+#[test]
+fn refuses_a_header_pointing_elsewhere() {
+    let files = Files::new("elsewhere");
+    files.flash("0x20000", &["--write-boot-header"]);
+
+    let stderr = files.flash("0x40000", &[]);
+    assert!(
+        stderr.contains("the multiboot header at offset 0 only points at 0x20000"),
+        "{stderr}"
+    );
+    assert!(!files.holds(0x40000));
+}
+
+#[test]
+fn allows_unbootable_placements_with_a_warning() {
+    let files = Files::new("allow");
+    let stderr = files.flash("0x20000", &["--allow-unbootable"]);
+    assert!(
+        stderr.contains("Warning: The FPGA configures from offset 0"),
+        "{stderr}"
+    );
+    assert!(files.holds(0x20000));
+    assert!(bitstream::boot_addresses(&files.flash_contents()).is_empty());
+}
+
+#[test]
+fn bitstreams_at_offset_0_need_no_header() {
+    let files = Files::new("zero");
+    // The test bitstream carries no configuration data, which is only checked at offset 0
+    let stderr = files.flash("0", &["--force"]);
+    check_clean(&stderr);
+    assert!(files.holds(0));
+}
@@ -0,0 +1,103 @@
+//! The binary run against the simulated flash with its output streams closed: a reader that
+//! goes away, or a stderr with nowhere to go, mustn't stop an operation halfway.
+//!
+//! The simulated flash is saved when the programmer is dropped, which on real hardware is what
+//! releases the pins, so finding the image written shows the run got through its teardown too.
+
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+
+const SIZE: usize = 1 << 20;
+
+fn binary() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+}
+
+fn temporary(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "lattice-prog-closed-output-{name}-{}.bin",
+        std::process::id()
+    ))
+}
+
+/// Run the simulated flash in `image` with `args`, closing stdout and/or stderr as soon as the
+/// process starts.
+fn run(image: &PathBuf, args: &[&str], stdout: bool, stderr: bool) -> ExitStatus {
+    let mut child = binary()
+        .args(["--backend", "mock", "--yes", "--mock-image"])
+        .arg(image)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut stdout = child.stdout.take().filter(|_| !stdout);
+    let mut stderr = child.stderr.take().filter(|_| !stderr);
+    // Drain whichever streams are left open, so the child never blocks on a full pipe
+    let drain = std::thread::spawn(move || {
+        if let Some(stderr) = &mut stderr {
+            std::io::copy(stderr, &mut std::io::sink()).unwrap();
+        }
+    });
+    if let Some(stdout) = &mut stdout {
+        std::io::copy(stdout, &mut std::io::sink()).unwrap();
+    }
+    drain.join().unwrap();
+
+    child.wait().unwrap()
+}
+
+/// A panic exits with 101; the binary otherwise always exits cleanly.
+fn check_no_panic(status: ExitStatus) {
+    assert_ne!(status.code(), Some(101), "the binary panicked");
+    assert!(status.success(), "{status}");
+}
+
+#[test]
+fn dump_survives_a_closed_stdout() {
+    let image = temporary("dump");
+    std::fs::write(&image, vec![0x5A; SIZE]).unwrap();
+
+    // More than a pipe holds, so the dump is still writing when it finds stdout gone
+    let length = SIZE.to_string();
+    for stderr in [false, true] {
+        let status = run(&image, &["dump", "-a", "0", "-l", &length], true, stderr);
+        check_no_panic(status);
+    }
+
+    assert_eq!(std::fs::read(&image).unwrap(), vec![0x5A; SIZE]);
+    std::fs::remove_file(image).unwrap();
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn flash_completes_with_output_closed() {
+    let data: Vec<u8> = (0..20_000).map(|i| (i * 7 + i / 256) as u8).collect();
+    let input = temporary("flash-input");
+    std::fs::write(&input, &data).unwrap();
+    let input = input.to_str().unwrap();
+
+    for (stdout, stderr) in [(true, false), (false, true), (true, true)] {
+        let image = temporary(&format!("flash-{stdout}-{stderr}"));
+        let _ = std::fs::remove_file(&image);
+
+        let status = run(
+            &image,
+            &["flash", "--force", "-o", "0x10000", input],
+            stdout,
+            stderr,
+        );
+        check_no_panic(status);
+
+        let written = std::fs::read(&image).unwrap();
+        assert_eq!(
+            written[0x10000..0x10000 + data.len()],
+            data,
+            "stdout closed: {stdout}, stderr closed: {stderr}"
+        );
+        std::fs::remove_file(image).unwrap();
+    }
+    std::fs::remove_file(input).unwrap();
+}
@@ -0,0 +1,188 @@
+//! Target device inference from bitstream headers, and the checks against a declared device,
+//! run against the simulated FPGA and flash.
+//!
+//! The fixtures in `tests/fixtures/bitstreams` are bitstreams for one device of each die, as
+//! icepack lays them out: the comment, the preamble, and the commands up to the first bank's
+//! configuration data, which is cut short after a few hundred bytes to keep them small.
+
+use lattice_prog::bitstream;
+use lattice_prog::device::Device;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/bitstreams")
+        .join(format!("{name}.bin"))
+}
+
+/// Run the binary against the simulated hardware, returning stdout and stderr.
+fn run(name: &str, args: &[&str]) -> (String, String) {
+    let image = std::env::temp_dir().join(format!(
+        "lattice-prog-device-{name}-{}.bin",
+        std::process::id()
+    ));
+    let output = Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+        .args(["--backend", "sim", "--yes", "--mock-image"])
+        .arg(&image)
+        .args(args)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_file(image);
+    (
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn infers_each_fixture_die() {
+    for (name, device, die) in [
+        ("lp384", Device::Lp384, "iCE40LP384"),
+        ("hx1k", Device::Hx1k, "iCE40HX1K or iCE40LP1K"),
+        (
+            "hx8k",
+            Device::Hx8k,
+            "iCE40HX8K or iCE40LP8K or iCE40HX4K or iCE40LP4K",
+        ),
+        ("up5k", Device::Up5k, "iCE40UP5K or iCE40UP3K"),
+    ] {
+        let data = std::fs::read(fixture(name)).unwrap();
+        bitstream::check(&data).unwrap();
+        assert_eq!(
+            bitstream::bank_dimensions(&data),
+            Some(device.cram_bank()),
+            "{name}"
+        );
+        let inferred = Device::infer(&data).unwrap();
+        assert_eq!(inferred, device, "{name}");
+        assert_eq!(inferred.die_names(), die);
+        device.check_size(data.len()).unwrap();
+    }
+}
+
+#[test]
+fn fixtures_check_against_their_die_only() {
+    let names = ["lp384", "hx1k", "hx8k", "up5k"];
+    for name in names {
+        let data = std::fs::read(fixture(name)).unwrap();
+        let inferred = Device::infer(&data).unwrap();
+        for declared in Device::ALL {
+            let result = declared.check_target(&data);
+            assert_eq!(
+                result.is_ok(),
+                declared.cram_bank() == inferred.cram_bank(),
+                "{name} declared as {declared:?}"
+            );
+        }
+    }
+
+    let error = Device::Hx1k
+        .check_target(&std::fs::read(fixture("up5k")).unwrap())
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "The bitstream was built for an iCE40UP5K or iCE40UP3K, not the declared iCE40HX1K"
+    );
+}
+
+#[test]
+fn reports_the_inferred_target() {
+    let bitstream = fixture("hx8k");
+    let (stdout, stderr) = run("inferred", &["sram", bitstream.to_str().unwrap()]);
+    assert!(
+        stdout
+            .contains("The bitstream targets an iCE40HX8K or iCE40LP8K or iCE40HX4K or iCE40LP4K"),
+        "{stdout}{stderr}"
+    );
+    assert!(
+        stdout.contains("The simulated FPGA configured"),
+        "{stdout}{stderr}"
+    );
+}
+
+#[test]
+fn sram_refuses_another_device() {
+    let bitstream = fixture("up5k");
+    let (stdout, stderr) = run(
+        "refused",
+        &["sram", "--device", "hx1k", bitstream.to_str().unwrap()],
+    );
+    assert!(
+        stderr.contains(
+            "The bitstream was built for an iCE40UP5K or iCE40UP3K, not the declared iCE40HX1K; \
+             pass --force to program it anyway"
+        ),
+        "{stderr}"
+    );
+    // Refused before the FPGA was touched
+    assert!(!stdout.contains("The simulated FPGA"), "{stdout}");
+}
+
+#[test]
+fn sram_accepts_a_device_sharing_the_die() {
+    let bitstream = fixture("up5k");
+    let (stdout, stderr) = run(
+        "shared",
+        &["sram", "--device", "up3k", bitstream.to_str().unwrap()],
+    );
+    assert!(
+        stdout.contains("The simulated FPGA configured"),
+        "{stdout}{stderr}"
+    );
+    assert!(!stdout.contains("The bitstream targets"), "{stdout}");
+}
+
+#[test]
+fn force_programs_another_device_with_a_warning() {
+    let bitstream = fixture("lp384");
+    let (stdout, stderr) = run(
+        "forced",
+        &[
+            "sram",
+            "--force",
+            "--device",
+            "up5k",
+            bitstream.to_str().unwrap(),
+        ],
+    );
+    assert!(
+        stderr.contains("The bitstream was built for an iCE40LP384, not the declared iCE40UP5K"),
+        "{stderr}"
+    );
+    assert!(
+        stdout.contains("The simulated FPGA configured"),
+        "{stdout}{stderr}"
+    );
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn flash_refuses_another_device() {
+    let image = std::env::temp_dir().join(format!(
+        "lattice-prog-device-flash-{}.bin",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&image);
+    let bitstream = fixture("hx1k");
+    let output = Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+        .args(["--backend", "mock", "--yes", "--mock-image"])
+        .arg(&image)
+        .args(["flash", "--device", "hx8k"])
+        .arg(&bitstream)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "The bitstream was built for an iCE40HX1K or iCE40LP1K, not the declared iCE40HX8K"
+        ),
+        "{stderr}"
+    );
+
+    // Nothing was written
+    let data = std::fs::read(&bitstream).unwrap();
+    let flash = std::fs::read(&image).unwrap_or_default();
+    assert!(!flash.starts_with(&data));
+    let _ = std::fs::remove_file(image);
+}
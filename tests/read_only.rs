@@ -0,0 +1,100 @@
+//! The read paths of the `read-only` build, run against the simulated flash: dumps, verifies,
+//! and checksums all work without any of the write primitives compiled in.
+#![cfg(feature = "read-only")]
+
+use lattice_prog::address::FlashAddress;
+use lattice_prog::cancel::CancellationToken;
+use lattice_prog::flash::FlashProgrammer;
+use lattice_prog::mask::Mask;
+use lattice_prog::mock::{MockFlash, Settings};
+use lattice_prog::timing::Timing;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SIZE: usize = 1 << 16;
+
+/// A simulated flash image holding a pattern, unique to the test.
+fn image(name: &str) -> (PathBuf, Vec<u8>) {
+    let contents: Vec<u8> = (0..SIZE).map(|i| (i * 13 + i / 256) as u8).collect();
+    let path = std::env::temp_dir().join(format!(
+        "lattice-prog-read-only-{name}-{}.bin",
+        std::process::id()
+    ));
+    std::fs::write(&path, &contents).unwrap();
+    (path, contents)
+}
+
+fn programmer(path: &Path) -> FlashProgrammer {
+    let flash = MockFlash::open(&Settings {
+        image: Some(path.to_path_buf()),
+        size: SIZE,
+        ignore_writes: false,
+        sector_erase_only: false,
+    })
+    .unwrap();
+    FlashProgrammer::with_port(Box::new(flash), &Timing::default(), None).unwrap()
+}
+
+#[test]
+fn dumps() {
+    let (path, contents) = image("dump");
+    let data = programmer(&path)
+        .read_arbitrary(FlashAddress::new(0x100).unwrap(), 0x1000)
+        .unwrap();
+    assert_eq!(data, contents[0x100..0x1100]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn verifies() {
+    let (path, mut contents) = image("verify");
+    let mut programmer = programmer(&path);
+    let address = FlashAddress::new(0x200).unwrap();
+    let cancel = CancellationToken::new();
+
+    let expected = &mut contents[0x200..0x2200];
+    programmer
+        .verify_data(expected, address, &Mask::EMPTY, &cancel)
+        .unwrap();
+    programmer
+        .verify_windowed(
+            &mut &expected[..],
+            expected.len(),
+            address,
+            &Mask::EMPTY,
+            &cancel,
+        )
+        .unwrap();
+
+    expected[0x1000] ^= 0xFF;
+    assert!(programmer
+        .verify_data(expected, address, &Mask::EMPTY, &cancel)
+        .is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn checksums() {
+    let (path, contents) = image("checksum");
+    let digest = programmer(&path)
+        .hash_range(FlashAddress::ZERO, SIZE, &Mask::EMPTY)
+        .unwrap();
+    assert_eq!(digest[..], Sha256::digest(&contents)[..]);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn leaves_out_the_write_commands() {
+    let binary = env!("CARGO_BIN_EXE_lattice-prog");
+    let version = Command::new(binary).arg("--version").output().unwrap();
+    assert!(String::from_utf8_lossy(&version.stdout).contains("read-only"));
+
+    for command in ["flash", "erase", "recover", "raw-cmd", "run"] {
+        let output = Command::new(binary)
+            .args([command, "--help"])
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{command} is still available");
+    }
+}
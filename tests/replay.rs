@@ -0,0 +1,121 @@
+//! Golden-file regression tests: traces recorded against the simulated flash, replayed through
+//! the programmer so any change in the commands it sends shows up as a divergence.
+//!
+//! To re-record after an intended change, run the traced command against a fresh mock, e.g.
+//! `lattice-prog --backend mock --mock-image /tmp/fresh.bin --yes flash --force -o 0x1000
+//! --trace tests/fixtures/flash.trace tests/fixtures/flash.bin`.
+//!
+//! The traces are of the full build. The read-only build never sets the quad enable bit, so
+//! even its dumps read differently, and these are left out of it.
+#![cfg(not(feature = "read-only"))]
+
+use lattice_prog::address::FlashAddress;
+use lattice_prog::flash::FlashProgrammer;
+use lattice_prog::timing::Timing;
+use lattice_prog::trace::{Replay, Trace};
+use std::path::{Path, PathBuf};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name)
+}
+
+/// A programmer answering from `trace`, recording what the logic sends.
+fn replay(trace: &Trace) -> lattice_prog::Result<FlashProgrammer> {
+    FlashProgrammer::with_port(
+        Box::new(Replay::new(trace.clone())),
+        &Timing {
+            fast_read: trace.fast_read(),
+            ..Timing::default()
+        },
+        Some(Trace::new(&trace.operation, trace.address, trace.length)),
+    )
+}
+
+/// Fail unless the whole trace was replayed.
+fn check_complete(programmer: &mut FlashProgrammer, trace: &Trace) {
+    let replayed = programmer.take_trace().unwrap();
+    assert_eq!(replayed.transactions, trace.transactions);
+}
+
+#[test]
+fn dump_replays() {
+    let trace = Trace::load(&fixture("dump.trace")).unwrap();
+    let mut programmer = replay(&trace).unwrap();
+    let address = FlashAddress::new(trace.address).unwrap();
+    let data = programmer.read_arbitrary(address, trace.length).unwrap();
+    check_complete(&mut programmer, &trace);
+
+    // The dump was of the flash after the traced flash, with the rest of it erased
+    let image = std::fs::read(fixture("flash.bin")).unwrap();
+    assert_eq!(data[..image.len()], image);
+    assert!(data[image.len()..].iter().all(|b| *b == 0xFF));
+}
+
+#[test]
+fn truncated_trace_diverges() {
+    let mut trace = Trace::load(&fixture("dump.trace")).unwrap();
+    trace.transactions.pop();
+    let mut programmer = replay(&trace).unwrap();
+    let error = programmer
+        .read_arbitrary(FlashAddress::new(trace.address).unwrap(), trace.length)
+        .unwrap_err();
+    assert!(error.to_string().contains("The trace ended"), "{error}");
+}
+
+mod flash {
+    use super::*;
+    use lattice_prog::cancel::CancellationToken;
+    use lattice_prog::mask::Mask;
+
+    /// Write and verify `data` as the traced flash did.
+    fn flash(trace: &Trace, data: &[u8]) -> lattice_prog::Result<FlashProgrammer> {
+        let mut programmer = replay(trace)?;
+        let address = FlashAddress::new(trace.address)?;
+        let cancel = CancellationToken::new();
+        programmer.flash_images(&[(data, address)], true, &Mask::EMPTY, &cancel)?;
+        programmer.verify_data(data, address, &Mask::EMPTY, &cancel)?;
+        Ok(programmer)
+    }
+
+    #[test]
+    fn flash_replays() {
+        let trace = Trace::load(&fixture("flash.trace")).unwrap();
+        let data = std::fs::read(fixture("flash.bin")).unwrap();
+        assert_eq!(data.len(), trace.length);
+
+        let mut programmer = flash(&trace, &data).unwrap();
+        check_complete(&mut programmer, &trace);
+    }
+
+    #[test]
+    fn different_image_diverges() {
+        let trace = Trace::load(&fixture("flash.trace")).unwrap();
+        let mut data = std::fs::read(fixture("flash.bin")).unwrap();
+        data[100] ^= 0xFF;
+
+        let error = flash(&trace, &data).err().unwrap();
+        assert!(error.to_string().starts_with("Transaction "), "{error}");
+    }
+
+    #[test]
+    fn busy_that_never_clears_diverges() {
+        let mut trace = Trace::load(&fixture("flash.trace")).unwrap();
+        // Every status poll after the erase reports the flash still busy, so the logic keeps
+        // polling where the recording moved on
+        let erase = trace
+            .transactions
+            .iter()
+            .position(|t| matches!(t.write.first(), Some(0x20 | 0x52 | 0xD8)))
+            .unwrap();
+        for transaction in &mut trace.transactions[erase..] {
+            if transaction.write == [0x05] {
+                transaction.read = vec![0x01];
+            }
+        }
+
+        let data = std::fs::read(fixture("flash.bin")).unwrap();
+        assert!(flash(&trace, &data).is_err());
+    }
+}
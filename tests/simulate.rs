@@ -0,0 +1,305 @@
+//! A session of subcommands run against `--backend sim`, with the simulated flash kept in a
+//! file between runs as an exercise would.
+#![cfg(not(feature = "read-only"))]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn temporary(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "lattice-prog-simulate-{name}-{}.bin",
+        std::process::id()
+    ))
+}
+
+/// Run the binary against the simulated flash in `image`, returning stdout and stderr.
+fn run(image: &Path, args: &[&str]) -> (String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+        .args(["--backend", "sim", "--yes", "--mock-image"])
+        .arg(image)
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn session_persists_between_runs() {
+    let image = temporary("flash");
+    let bitstream = temporary("bitstream");
+    let _ = std::fs::remove_file(&image);
+    let mut data = vec![0xFF, 0x00, 0x00, 0xFF, 0x7E, 0xAA, 0x99, 0x7E];
+    data.extend((0..3000).map(|i| (i * 5) as u8));
+    std::fs::write(&bitstream, &data).unwrap();
+    let bitstream = bitstream.to_str().unwrap();
+
+    let (stdout, _) = run(&image, &["detect"]);
+    assert!(stdout.contains("Part:          Winbond W25Q32"), "{stdout}");
+
+    let (stdout, stderr) = run(
+        &image,
+        &["flash", "--allow-unbootable", "-o", "0x10000", bitstream],
+    );
+    assert!(
+        stdout.contains("Succesfully flashed device!"),
+        "{stdout}{stderr}"
+    );
+
+    // Each run opens the file the last one saved
+    let (stdout, stderr) = run(&image, &["verify", "-o", "0x10000", bitstream]);
+    assert!(
+        stdout.contains("Flash at 0x10000 matches the image"),
+        "{stdout}{stderr}"
+    );
+    let dump = temporary("dump");
+    run(
+        &image,
+        &[
+            "dump",
+            "-a",
+            "65536",
+            "-l",
+            "3008",
+            "--output",
+            dump.to_str().unwrap(),
+        ],
+    );
+    assert_eq!(std::fs::read(&dump).unwrap(), data);
+    std::fs::remove_file(dump).unwrap();
+
+    let (stdout, stderr) = run(&image, &["erase", "--all"]);
+    assert!(stdout.contains("Erased the flash"), "{stdout}{stderr}");
+    let (stdout, stderr) = run(&image, &["blank-check"]);
+    assert!(
+        stdout.contains("is blank (4194304 bytes)"),
+        "{stdout}{stderr}"
+    );
+    let (_, stderr) = run(&image, &["verify", "-o", "0x10000", bitstream]);
+    assert!(stderr.contains("Failed to verify"), "{stderr}");
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(bitstream).unwrap();
+}
+
+#[test]
+fn simulated_fpga_configures_from_a_bitstream() {
+    let image = temporary("sram");
+    let bitstream = temporary("sram-bitstream");
+    std::fs::write(
+        &bitstream,
+        [0xFF, 0x00, 0x00, 0xFF, 0x7E, 0xAA, 0x99, 0x7E, 0x01],
+    )
+    .unwrap();
+
+    let (stdout, stderr) = run(&image, &["sram", "--force", bitstream.to_str().unwrap()]);
+    assert!(
+        stdout.contains("The simulated FPGA configured and raised CDONE"),
+        "{stdout}{stderr}"
+    );
+
+    std::fs::write(&bitstream, [0x00; 64]).unwrap();
+    let (_, stderr) = run(&image, &["sram", "--force", bitstream.to_str().unwrap()]);
+    assert!(stderr.contains("CDONE stayed low"), "{stderr}");
+
+    let _ = std::fs::remove_file(image);
+    std::fs::remove_file(bitstream).unwrap();
+}
+
+#[test]
+fn flash_ignoring_writes_is_caught_at_the_first_erase() {
+    let image = temporary("ignoring");
+    let input = temporary("ignoring-input");
+    std::fs::write(&image, vec![0x00; 4 << 20]).unwrap();
+    std::fs::write(&input, vec![0xA5; 0x20000]).unwrap();
+    let input = input.to_str().unwrap();
+
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+            .args([
+                "--backend",
+                "sim",
+                "--yes",
+                "--mock-ignore-writes",
+                "--mock-image",
+            ])
+            .arg(&image)
+            .args(["flash", "--allow-unbootable", "-o", "0x10000"])
+            .args(args)
+            .arg(input)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    let stderr = run(&[]);
+    assert!(
+        stderr.contains("Flash contents did not change after erase: 0x10000 still reads 0x00"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("Failed to verify"), "{stderr}");
+
+    // Assuming the flash blank skips the erase, and with it the check, so only verify fails
+    let stderr = run(&["--assume-blank"]);
+    assert!(!stderr.contains("did not change after erase"), "{stderr}");
+    assert!(stderr.contains("--assume-blank"), "{stderr}");
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(input).unwrap();
+}
+
+#[test]
+fn sparse_holes_are_left_erased() {
+    use std::os::unix::fs::FileExt;
+
+    let image = temporary("sparse");
+    let input = temporary("sparse-input");
+    let _ = std::fs::remove_file(&image);
+    let file = std::fs::File::create(&input).unwrap();
+    file.set_len(0x10000).unwrap();
+    file.write_all_at(&[0xA5; 0x1000], 0x1000).unwrap();
+    file.write_all_at(&[0x00; 0x1000], 0x3000).unwrap();
+    drop(file);
+
+    let (stdout, stderr) = run(
+        &image,
+        &[
+            "flash",
+            "--allow-unbootable",
+            "--skip-zero-blocks",
+            "-o",
+            "0x10000",
+            input.to_str().unwrap(),
+        ],
+    );
+    assert!(
+        stdout.contains("Skipped 57344 bytes in holes of the sparse input"),
+        "{stdout}{stderr}"
+    );
+    assert!(
+        stdout.contains("Succesfully flashed device!"),
+        "{stdout}{stderr}"
+    );
+
+    let flash = std::fs::read(&image).unwrap();
+    let written = &flash[0x10000..0x20000];
+    assert!(written[..0x1000].iter().all(|b| *b == 0xFF));
+    assert!(written[0x1000..0x2000].iter().all(|b| *b == 0xA5));
+    assert!(written[0x2000..0x3000].iter().all(|b| *b == 0xFF));
+    // Zeros written to the file are data, so they're programmed
+    assert!(written[0x3000..0x4000].iter().all(|b| *b == 0x00));
+    assert!(written[0x4000..].iter().all(|b| *b == 0xFF));
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(input).unwrap();
+}
+
+#[test]
+fn utilization_counts_everything_written_into_the_containing_partition() {
+    let image = temporary("utilization");
+    let layout = temporary("utilization-layout");
+    let (first, second) = (
+        temporary("utilization-first"),
+        temporary("utilization-second"),
+    );
+    let _ = std::fs::remove_file(&image);
+    std::fs::write(
+        &layout,
+        "[[partition]]\nname = \"gateware\"\noffset = 0x10000\nsize = 0x10000\n",
+    )
+    .unwrap();
+    std::fs::write(&first, vec![0x5A; 0x4000]).unwrap();
+    std::fs::write(&second, vec![0xA5; 0x4000]).unwrap();
+    let placement = format!("{}@0x1c000", second.to_str().unwrap());
+
+    // The first image starts partway into the partition, and the second shares it
+    let (stdout, stderr) = run(
+        &image,
+        &[
+            "--layout",
+            layout.to_str().unwrap(),
+            "flash",
+            "--allow-unbootable",
+            "-o",
+            "0x14000",
+            "--image",
+            &placement,
+            first.to_str().unwrap(),
+        ],
+    );
+    assert!(
+        stdout.contains("The image fills 50.0% of partition \"gateware\" (32768 of 65536 bytes)"),
+        "{stdout}{stderr}"
+    );
+    assert!(
+        stdout.contains("Succesfully flashed device!"),
+        "{stdout}{stderr}"
+    );
+
+    for path in [image, layout, first, second] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn partitions_are_erased_and_checksummed_alone() {
+    use sha2::{Digest, Sha256};
+
+    let image = temporary("partitions");
+    let layout = temporary("partitions-layout");
+    let input = temporary("partitions-input");
+    let _ = std::fs::remove_file(&image);
+    std::fs::write(
+        &layout,
+        "[[partition]]\nname = \"bitstream\"\noffset = 0\nsize = 0x20000\nreadonly = true\n\n\
+         [[partition]]\nname = \"userdata\"\noffset = 0x20000\nsize = 0x10000\n\n\
+         [[partition]]\nname = \"assets\"\noffset = 0x30000\nsize = 0x10000\n",
+    )
+    .unwrap();
+    std::fs::write(&input, vec![0x5A; 0x20000]).unwrap();
+    let layout = layout.to_str().unwrap();
+    let with_layout = |args: &[&str]| run(&image, &[&["--layout", layout], args].concat());
+
+    with_layout(&[
+        "flash",
+        "--allow-unbootable",
+        "-o",
+        "0x20000",
+        input.to_str().unwrap(),
+    ]);
+    let (stdout, stderr) = with_layout(&["checksum", "--partition", "userdata"]);
+    let digest: String = Sha256::digest([0x5A; 0x10000])
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    assert!(
+        stdout.contains(&format!("{digest}  0x20000..0x30000")),
+        "{stdout}{stderr}"
+    );
+
+    let (stdout, stderr) = with_layout(&["erase", "--partition", "userdata"]);
+    assert!(
+        stdout.contains("Erased 0x20000..0x30000 (65536 bytes)"),
+        "{stdout}{stderr}"
+    );
+    let flash = std::fs::read(&image).unwrap();
+    assert!(flash[0x20000..0x30000].iter().all(|b| *b == 0xFF));
+    assert!(flash[0x30000..0x40000].iter().all(|b| *b == 0x5A));
+
+    let (_, stderr) = with_layout(&["erase", "--partition", "bitstream"]);
+    assert!(
+        stderr.contains("The partition \"bitstream\" is read-only"),
+        "{stderr}"
+    );
+    let (_, stderr) = with_layout(&["erase", "-a", "0x30800", "-l", "2048"]);
+    assert!(
+        stderr.contains("would also erase outside it, from 0x30000..0x31000"),
+        "{stderr}"
+    );
+
+    std::fs::remove_file(image).unwrap();
+    std::fs::remove_file(layout).unwrap();
+    std::fs::remove_file(input).unwrap();
+}
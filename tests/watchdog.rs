@@ -0,0 +1,45 @@
+//! The watchdog firing on work that stops making progress: a wake delay longer than the limit
+//! stalls the simulated flash before its first beat.
+
+use std::process::Command;
+
+#[test]
+fn stalled_work_writes_a_failure_report() {
+    let path = |kind: &str| {
+        std::env::temp_dir().join(format!(
+            "lattice-prog-watchdog-{kind}-{}",
+            std::process::id()
+        ))
+    };
+    let (image, report) = (path("flash.bin"), path("report.json"));
+    let _ = std::fs::remove_file(&report);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_lattice-prog"))
+        .args(["--backend", "mock", "--yes", "--mock-image"])
+        .arg(&image)
+        .args(["--watchdog-seconds", "1", "--watchdog-report"])
+        .arg(&report)
+        .args(["--timing", "wake_delay=5s", "detect"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(3), "{stderr}");
+    assert!(stderr.contains("Wrote the failure report"), "{stderr}");
+
+    let report = std::fs::read_to_string(&report).unwrap();
+    assert!(
+        report.starts_with(r#"{"reason":"stalled","phase":"start","address":0,"elapsed_ms":"#),
+        "{report}"
+    );
+    let elapsed: u64 = report
+        .split("\"elapsed_ms\":")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1000..5000).contains(&elapsed), "{report}");
+
+    let _ = std::fs::remove_file(image);
+    let _ = std::fs::remove_file(path("report.json"));
+}